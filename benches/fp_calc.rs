@@ -0,0 +1,30 @@
+//! Example benchmarks that count events with `EventMeasurement`
+//! instead of wall time, using the `fp-calc` sample's workload.
+//! Run with `cargo bench --features criterion`.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use ruperf::measurement::EventMeasurement;
+
+fn fp_calc(c: &mut Criterion<EventMeasurement>) {
+    let mut group = c.benchmark_group("fp_calc");
+    for end in [1_000, 10_000].iter() {
+        group.bench_with_input(BenchmarkId::from_parameter(end), end, |b, &end| {
+            b.iter(|| fp_calc::calc(black_box(100), black_box(end)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group! {
+    name = instructions;
+    config = Criterion::default().with_measurement(EventMeasurement::instructions());
+    targets = fp_calc
+}
+
+criterion_group! {
+    name = cycles;
+    config = Criterion::default().with_measurement(EventMeasurement::cycles());
+    targets = fp_calc
+}
+
+criterion_main!(instructions, cycles);
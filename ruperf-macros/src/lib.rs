@@ -0,0 +1,107 @@
+//! Procedural macros for `ruperf`. Use them through
+//! the `ruperf` crate, which re-exports them, e.g.
+//! `#[ruperf::measure(events = "cycles,instructions")]`.
+
+extern crate proc_macro;
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{parse_macro_input, parse_quote, AttributeArgs, ItemFn, Lit, Meta, NestedMeta};
+
+/// Events counted when `events` is not given.
+const DEFAULT_EVENTS: &str = "cycles,instructions";
+
+/// The names `ruperf::stat::StatEvent` parses. This crate cannot
+/// depend on `ruperf`, so keep the two in step.
+const SUPPORTED_EVENTS: &[&str] = &[
+    "cycles",
+    "instructions",
+    "task-clock",
+    "cpu-clock",
+    "context-switches",
+    "L1D-cache-reads",
+    "L1D-cache-writes",
+    "L1D-cache-read-misses",
+    "L1I-cache-read-misses",
+];
+
+/// Count events on every call to the annotated function.
+///
+/// Takes an optional comma-separated list of `ruperf stat` event
+/// names, `#[measure(events = "cycles,instructions")]`, and an
+/// optional `name` under which results are recorded, which
+/// defaults to the function's path. Results go to the registry
+/// in `ruperf::registry`.
+#[proc_macro_attribute]
+pub fn measure(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(attr as AttributeArgs);
+    let mut function = parse_macro_input!(item as ItemFn);
+
+    if let Some(asyncness) = &function.sig.asyncness {
+        return syn::Error::new_spanned(asyncness, "#[measure] does not support async functions")
+            .to_compile_error()
+            .into();
+    }
+
+    let mut events = DEFAULT_EVENTS.to_string();
+    let mut events_lit = None;
+    let mut name = None;
+    for arg in &args {
+        match arg {
+            NestedMeta::Meta(Meta::NameValue(nv)) if nv.path.is_ident("events") => match &nv.lit {
+                Lit::Str(s) => {
+                    events = s.value();
+                    events_lit = Some(s);
+                }
+                lit => return error(lit, "expected a string, e.g. \"cycles,instructions\""),
+            },
+            NestedMeta::Meta(Meta::NameValue(nv)) if nv.path.is_ident("name") => match &nv.lit {
+                Lit::Str(s) => name = Some(s.value()),
+                lit => return error(lit, "expected a string"),
+            },
+            arg => return error(arg, "expected `events = \"...\"` or `name = \"...\"`"),
+        }
+    }
+
+    let events: Vec<String> = events
+        .split(',')
+        .map(|e| e.trim().to_string())
+        .filter(|e| !e.is_empty())
+        .collect();
+    if events.is_empty() {
+        return error(&function.sig.ident, "#[measure] needs at least one event");
+    }
+    if let Some(unknown) = events
+        .iter()
+        .find(|e| !SUPPORTED_EVENTS.contains(&e.as_str()))
+    {
+        let message = format!(
+            "unknown event '{}', expected one of: {}",
+            unknown,
+            SUPPORTED_EVENTS.join(", ")
+        );
+        return match events_lit {
+            Some(lit) => error(lit, &message),
+            None => error(&function.sig.ident, &message),
+        };
+    }
+
+    let name = match name {
+        Some(name) => quote!(#name),
+        None => {
+            let ident = function.sig.ident.to_string();
+            quote!(concat!(module_path!(), "::", #ident))
+        }
+    };
+    let block = &function.block;
+    function.block = parse_quote!({
+        let __ruperf_guard = ::ruperf::registry::Guard::start(#name, &[#(#events),*]);
+        #block
+    });
+    quote!(#function).into()
+}
+
+fn error<T: quote::ToTokens>(tokens: T, message: &str) -> TokenStream {
+    syn::Error::new_spanned(tokens, message)
+        .to_compile_error()
+        .into()
+}
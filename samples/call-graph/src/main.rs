@@ -0,0 +1,48 @@
+//! Sample program to run `ruperf record -g` against.
+//! Spends about two thirds of its time in `heavy` and a third in
+//! `light`, both called from `work`. Build it with frame pointers
+//! so the kernel can walk its stack:
+//! `RUSTFLAGS="-C force-frame-pointers=yes" cargo build -p call-graph`,
+//! or record it with `--call-graph dwarf` instead.
+
+use std::hint::black_box;
+
+/// Spin for `n` rounds of integer mixing.
+#[inline(never)]
+fn spin(n: u64) -> u64 {
+    let mut x = n;
+    for i in 0..n {
+        x = x.rotate_left(5) ^ i.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    }
+    x
+}
+
+#[inline(never)]
+fn heavy(n: u64) -> u64 {
+    // Use the result so the call is not turned into a jump.
+    spin(2 * n) ^ n
+}
+
+#[inline(never)]
+fn light(n: u64) -> u64 {
+    spin(n) ^ n
+}
+
+#[inline(never)]
+fn work(rounds: u64) -> u64 {
+    let mut total = 0u64;
+    for _ in 0..rounds {
+        // Keep the optimizer from hoisting the calls out of the loop.
+        let n = black_box(100_000);
+        total = total.wrapping_add(heavy(n)).wrapping_add(light(n));
+    }
+    total
+}
+
+fn main() {
+    let rounds = std::env::args()
+        .nth(1)
+        .and_then(|arg| arg.parse().ok())
+        .unwrap_or(300);
+    println!("{}", work(rounds));
+}
@@ -0,0 +1,29 @@
+//! Floating point workload shared by the `fp-calc`
+//! sample program and ruperf's example benchmarks.
+
+/// Perform some floating point computations for each `i` in `start..end`.
+/// Returns the sum of the results so the work cannot be optimized away.
+pub fn calc(start: i32, end: i32) -> f64 {
+    let mut total = 0.0;
+    for i in start..end {
+        let a: f64 = i as f64;
+        let b: f64 = (i - 1) as f64;
+
+        let x = (a * b).sqrt();
+
+        let y1: f64;
+        let y2: f64;
+
+        // Try branching
+        if i % 2 == 0 {
+            y1 = (x * a).sin();
+            y2 = (x * b).sin();
+        } else {
+            y1 = (x * a).asin();
+            y2 = (x * b).asin();
+        }
+
+        total += y1 / y2;
+    }
+    total
+}
@@ -0,0 +1,483 @@
+//! # Annotate driver.
+//! <p> Usage: <em> ruperf annotate [-i FILE] [--no-source] [--no-demangle | --strip-hash] [--stdio | --json] SYMBOL </em>
+//! Disassembles a function and shows how the samples taken in it
+//! spread over its instructions, with the source lines they were
+//! compiled from when the file has debug info. The blocks that jumps
+//! land on and take the most samples are marked. </p>
+
+extern crate structopt;
+pub mod disasm;
+
+use crate::data::{PerfData, Record};
+use crate::session::Session;
+use crate::symbols::{demangle, Demangle, DemangleOptions, Symbol};
+use crate::utils::AnnotateError;
+use disasm::Instruction;
+use object::{Object, ObjectSection};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::prelude::*;
+use std::path::PathBuf;
+use std::rc::Rc;
+use structopt::StructOpt;
+
+/// How many of the hottest jump targets are marked.
+const HOT_TARGETS: usize = 3;
+
+/// Configuration settings for running annotate.
+/// See `./ruperf annotate --help` for more information.
+#[derive(Debug, StructOpt)]
+pub struct AnnotateOptions {
+    #[structopt(help = "Function to annotate, demangled or as in the file")]
+    pub symbol: String,
+
+    #[structopt(
+        short,
+        long,
+        default_value = "perf.data",
+        help = "File to read samples from",
+        parse(from_os_str)
+    )]
+    pub input: PathBuf,
+
+    #[structopt(long = "no-source", help = "Leave source lines out")]
+    pub no_source: bool,
+
+    #[structopt(flatten)]
+    pub demangle: DemangleOptions,
+
+    #[structopt(long, help = "Print a plain text listing (the default)")]
+    pub stdio: bool,
+
+    #[structopt(long, help = "Print the listing as JSON", conflicts_with = "stdio")]
+    pub json: bool,
+}
+
+/// One instruction of an annotated function.
+#[derive(Debug, Clone, Serialize)]
+pub struct Line {
+    /// As the file's symbols see it.
+    pub address: u64,
+    /// From the start of the function.
+    pub offset: u64,
+    pub text: String,
+    /// Period of the samples taken here, per event.
+    pub period: Vec<u64>,
+    /// Share of the function's period, per event.
+    pub percent: Vec<f64>,
+    /// Where a jump goes, as an address.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<u64>,
+    /// Whether a jump in the function lands here.
+    pub jump_target: bool,
+    /// Whether this is one of the hottest jump targets.
+    pub hot: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub srcline: Option<String>,
+    /// The text of `srcline`, when the source file could be read.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+}
+
+/// A function with its samples spread over its instructions.
+#[derive(Debug, Clone, Serialize)]
+pub struct Annotation {
+    pub symbol: String,
+    pub dso: String,
+    pub address: u64,
+    pub size: u64,
+    /// Names of the events the columns are for.
+    pub events: Vec<String>,
+    /// Samples taken in the function, per event.
+    pub samples: Vec<u64>,
+    pub lines: Vec<Line>,
+}
+
+/// The samples taken in one function.
+pub(crate) struct Hits {
+    dso: Rc<str>,
+    symbol: Symbol,
+    /// Period per event, by address.
+    periods: BTreeMap<u64, Vec<u64>>,
+    samples: Vec<u64>,
+}
+
+impl Hits {
+    pub(crate) fn new(dso: Rc<str>, symbol: Symbol, events: usize) -> Self {
+        Self {
+            dso,
+            symbol,
+            periods: BTreeMap::new(),
+            samples: vec![0; events],
+        }
+    }
+
+    /// Count a sample of the `index`th event at `address`.
+    pub(crate) fn add(&mut self, address: u64, index: usize, period: u64) {
+        let events = self.samples.len();
+        self.samples[index] += 1;
+        self.periods
+            .entry(address)
+            .or_insert_with(|| vec![0; events])[index] += period;
+    }
+}
+
+/// Whether the file's `name` is `wanted`, mangled or demangled.
+fn matches(name: &str, wanted: &str) -> bool {
+    name == wanted
+        || [Demangle::Full, Demangle::StripHash]
+            .iter()
+            .any(|&mode| demangle(name, mode) == wanted)
+}
+
+/// Gather the samples of every function called `wanted`.
+fn collect(data: &PerfData, session: &mut Session, wanted: &str) -> Vec<Hits> {
+    let events = data.attrs.len();
+    let mut hits: HashMap<(Rc<str>, u64), Hits> = HashMap::new();
+    for record in data.ordered_records() {
+        let sample = match record {
+            Record::Sample(sample) => sample,
+            other => {
+                session.update(&other);
+                continue;
+            }
+        };
+        let index = match data.attr_index(sample.id) {
+            Some(index) => index,
+            None => continue,
+        };
+        let location = session.resolve(sample.pid, sample.ip, sample.is_kernel());
+        let (dso, symbol) = match (location.dso, location.symbol) {
+            (Some(dso), Some(symbol)) if matches(&symbol.name, wanted) => (dso, symbol),
+            _ => continue,
+        };
+        hits.entry((dso.clone(), symbol.start))
+            .or_insert_with(|| Hits::new(dso, symbol, events))
+            .add(location.address, index, sample.period);
+    }
+    let mut hits: Vec<Hits> = hits.into_values().collect();
+    hits.sort_by_key(|h| std::cmp::Reverse(h.samples.iter().sum::<u64>()));
+    hits
+}
+
+/// The machine code from `start` to `end` in the file
+/// at `path`, and the architecture it is for.
+fn code(path: &str, start: u64, end: u64) -> Option<(object::Architecture, Vec<u8>)> {
+    let data = std::fs::read(path).ok()?;
+    let file = object::File::parse(&*data).ok()?;
+    let section = file
+        .sections()
+        .find(|s| start >= s.address() && start < s.address() + s.size())?;
+    let bytes = section.data().ok()?;
+    let from = (start - section.address()) as usize;
+    let to = (end.min(section.address() + section.size()) - section.address()) as usize;
+    Some((file.architecture(), bytes.get(from..to)?.to_vec()))
+}
+
+/// Mark the jump targets of `lines`, and the `HOT_TARGETS` whose
+/// blocks, up to the next jump or target, took the most samples.
+fn mark_targets(lines: &mut [Line], instructions: &[Instruction]) {
+    let targets: HashSet<u64> = instructions.iter().filter_map(|i| i.target).collect();
+    let mut heat: Vec<(usize, f64)> = Vec::new();
+    let mut block: Option<usize> = None;
+    for (i, instruction) in instructions.iter().enumerate() {
+        if targets.contains(&instruction.address) {
+            lines[i].jump_target = true;
+            heat.push((i, 0.0));
+            block = Some(heat.len() - 1);
+        }
+        if let Some(block) = block {
+            heat[block].1 += lines[i].percent.iter().sum::<f64>();
+        }
+        if instruction.ends_block {
+            block = None;
+        }
+    }
+    heat.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+    for &(i, _) in heat.iter().take(HOT_TARGETS).filter(|(_, h)| *h > 0.0) {
+        lines[i].hot = true;
+    }
+}
+
+/// Disassemble the function `hits` were taken in and spread
+/// them over its instructions, with a column for each of `events`.
+pub(crate) fn annotate(
+    events: &[String],
+    session: &mut Session,
+    hits: &Hits,
+    source: bool,
+    mode: Demangle,
+) -> Result<Annotation, AnnotateError> {
+    let name = demangle(&hits.symbol.name, mode);
+    let not_read = || AnnotateError::Code(name.clone(), hits.dso.to_string());
+    let dso = session.dso(&hits.dso).ok_or_else(not_read)?;
+    let start = hits.symbol.start;
+    let end = dso.end(&hits.symbol);
+    let (arch, bytes) = code(&hits.dso, start, end).ok_or_else(not_read)?;
+    let instructions = disasm::disassemble(arch, &bytes, start)?;
+    let debug_info = if source {
+        session.debug_info(&hits.dso)
+    } else {
+        None
+    };
+
+    let totals: Vec<u64> = (0..events.len())
+        .map(|event| hits.periods.values().map(|p| p[event]).sum())
+        .collect();
+    let mut files: HashMap<String, Option<Vec<String>>> = HashMap::new();
+    let mut lines: Vec<Line> = instructions
+        .iter()
+        .map(|instruction| {
+            let period = (instruction.address..instruction.address + instruction.size)
+                .filter_map(|address| hits.periods.get(&address))
+                .fold(vec![0; totals.len()], |mut sum, p| {
+                    sum.iter_mut().zip(p).for_each(|(s, p)| *s += p);
+                    sum
+                });
+            let percent = period
+                .iter()
+                .zip(&totals)
+                .map(|(&p, &total)| 100.0 * p as f64 / total.max(1) as f64)
+                .collect();
+            // Line 0 is code the compiler made up, such as spills.
+            let srcline = debug_info
+                .as_ref()
+                .and_then(|info| info.srcline(instruction.address))
+                .filter(|srcline| srcline.line > 0);
+            let source = srcline.as_ref().and_then(|srcline| {
+                let text = files
+                    .entry(srcline.file.clone())
+                    .or_insert_with(|| {
+                        std::fs::read_to_string(&srcline.file)
+                            .ok()
+                            .map(|text| text.lines().map(str::to_string).collect())
+                    })
+                    .as_ref()?;
+                text.get((srcline.line as usize).checked_sub(1)?).cloned()
+            });
+            Line {
+                address: instruction.address,
+                offset: instruction.address - start,
+                text: instruction.text.clone(),
+                period,
+                percent,
+                target: instruction.target,
+                jump_target: false,
+                hot: false,
+                srcline: srcline.map(|s| s.to_string()),
+                source,
+            }
+        })
+        .collect();
+    mark_targets(&mut lines, &instructions);
+
+    Ok(Annotation {
+        symbol: name,
+        dso: hits.dso.to_string(),
+        address: start,
+        size: end - start,
+        events: events.to_vec(),
+        samples: hits.samples.clone(),
+        lines,
+    })
+}
+
+/// Print an annotation the way `perf annotate --stdio` does: a
+/// percentage column per event, source lines above their code.
+pub fn print_annotation(out: &mut dyn Write, annotation: &Annotation) -> std::io::Result<()> {
+    let samples: Vec<String> = annotation
+        .events
+        .iter()
+        .zip(&annotation.samples)
+        .map(|(event, samples)| format!("{} samples of {}", samples, event))
+        .collect();
+    writeln!(
+        out,
+        "# {} in {} ({})",
+        annotation.symbol,
+        annotation.dso,
+        samples.join(", ")
+    )?;
+    let header: Vec<String> = annotation
+        .events
+        .iter()
+        .map(|event| format!("{:>9}", event.chars().take(9).collect::<String>()))
+        .collect();
+    writeln!(out, "{} :  Source lines and disassembly", header.join(""))?;
+    writeln!(out, "{}", "-".repeat(9 * header.len() + 40))?;
+    let blank = " ".repeat(9 * annotation.events.len() + 1);
+    let mut last_srcline = None;
+    for line in &annotation.lines {
+        if line.srcline.is_some() && line.srcline != last_srcline {
+            writeln!(out, "{}:", blank)?;
+            let source = format!(
+                "{}  {}",
+                line.srcline.as_deref().unwrap_or(""),
+                line.source.as_deref().map(str::trim).unwrap_or("")
+            );
+            writeln!(out, "{}:  {}", blank, source.trim_end())?;
+            last_srcline = line.srcline.clone();
+        }
+        let percent: String = line
+            .percent
+            .iter()
+            .map(|p| {
+                if *p > 0.0 {
+                    format!("{:>9.2}", p)
+                } else {
+                    " ".repeat(9)
+                }
+            })
+            .collect();
+        let marker = match (line.hot, line.jump_target) {
+            (true, _) => '*',
+            (false, true) => '>',
+            _ => ' ',
+        };
+        let target = match line.target {
+            Some(target) if target >= annotation.address => format!(
+                "  <{}+{:#x}>",
+                annotation.symbol,
+                target - annotation.address
+            ),
+            _ => String::new(),
+        };
+        writeln!(
+            out,
+            "{} : {} {:>8x}:  {}{}",
+            percent, marker, line.address, line.text, target
+        )?;
+    }
+    writeln!(
+        out,
+        "\n# > jump target, * one of the {} jump targets taking the most samples\n",
+        HOT_TARGETS
+    )
+}
+
+/// Entry point for `ruperf annotate`.
+pub fn run_annotate(options: AnnotateOptions) -> i32 {
+    let data = match PerfData::open(&options.input) {
+        Ok(data) => data,
+        Err(e) => {
+            eprintln!("Could not read {}: {}", options.input.display(), e);
+            return 1;
+        }
+    };
+    let mut session = Session::new();
+    // Symbols are matched by both their mangled and demangled names.
+    session.set_demangle(Demangle::Off);
+    let hits = collect(&data, &mut session, &options.symbol);
+    if hits.is_empty() {
+        eprintln!("{}", AnnotateError::NoSamples(options.symbol));
+        return 1;
+    }
+    let events: Vec<String> = (0..data.attrs.len()).map(|i| data.event_name(i)).collect();
+    let mut annotations = Vec::new();
+    for hits in &hits {
+        match annotate(
+            &events,
+            &mut session,
+            hits,
+            !options.no_source,
+            options.demangle.mode(),
+        ) {
+            Ok(annotation) => annotations.push(annotation),
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+    if annotations.is_empty() {
+        return 1;
+    }
+
+    let out = &mut std::io::stdout();
+    let printed = if options.json {
+        writeln!(
+            out,
+            "{}",
+            serde_json::to_string_pretty(&annotations).unwrap()
+        )
+    } else {
+        annotations
+            .iter()
+            .try_for_each(|annotation| print_annotation(out, annotation))
+    };
+    match printed {
+        Ok(()) => 0,
+        // Listings are often piped into `head`, so a closed pipe is not an error.
+        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn mark_targets_test() {
+    let instruction = |address, target, ends_block| Instruction {
+        address,
+        size: 1,
+        text: String::new(),
+        target,
+        ends_block,
+    };
+    // 0: jump to 3, 1..2: cold block at 1, 3..4: hot loop at 3.
+    let instructions = vec![
+        instruction(0, Some(3), true),
+        instruction(1, None, false),
+        instruction(2, Some(1), true),
+        instruction(3, None, false),
+        instruction(4, Some(3), true),
+    ];
+    let percent = [0.0, 5.0, 5.0, 60.0, 30.0];
+    let mut lines: Vec<Line> = instructions
+        .iter()
+        .zip(&percent)
+        .map(|(i, &p)| Line {
+            address: i.address,
+            offset: i.address,
+            text: String::new(),
+            period: vec![0],
+            percent: vec![p],
+            target: i.target,
+            jump_target: false,
+            hot: false,
+            srcline: None,
+            source: None,
+        })
+        .collect();
+    mark_targets(&mut lines, &instructions);
+    let targets: Vec<bool> = lines.iter().map(|l| l.jump_target).collect();
+    assert_eq!(targets, vec![false, true, false, true, false]);
+    assert!(lines[3].hot && lines[1].hot);
+    assert!(!lines[0].hot);
+    assert!(matches("_ZN4main4spin17h0123456789abcdefE", "main::spin"));
+    assert!(!matches("_ZN4main4spin17h0123456789abcdefE", "main::work"));
+}
+
+#[test]
+fn annotate_test() {
+    use crate::report::record_call_graph_sample;
+
+    let (exe, data) = record_call_graph_sample("annotate", &["-g", "-O"], None);
+    let mut session = Session::new();
+    session.set_demangle(Demangle::Off);
+    let hits = collect(&data, &mut session, "main::spin");
+    assert_eq!(hits.len(), 1);
+    let events = vec![data.event_name(0)];
+    let annotation = annotate(&events, &mut session, &hits[0], true, Demangle::StripHash).unwrap();
+    std::fs::remove_file(&exe).unwrap();
+
+    assert_eq!(annotation.symbol, "main::spin");
+    let total: f64 = annotation.lines.iter().map(|l| l.percent[0]).sum();
+    assert!((total - 100.0).abs() < 1e-6, "{}", total);
+    // The loop in spin jumps back to its start.
+    assert!(annotation.lines.iter().any(|l| l.hot));
+    assert!(annotation.lines.iter().any(|l| l
+        .srcline
+        .as_deref()
+        .unwrap_or("")
+        .contains("call-graph/src/main.rs:")));
+}
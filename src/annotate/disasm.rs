@@ -0,0 +1,121 @@
+//! Disassembly for `ruperf annotate`, with capstone's
+//! x86_64 and aarch64 decoders.
+
+use crate::utils::AnnotateError;
+use capstone::arch::arm64::{self, Arm64OperandType};
+use capstone::arch::x86::{self, X86OperandType};
+use capstone::arch::ArchOperand;
+use capstone::prelude::*;
+use object::Architecture;
+
+/// A decoded instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instruction {
+    pub address: u64,
+    pub size: u64,
+    /// Mnemonic and operands, in the decoder's syntax.
+    pub text: String,
+    /// Where a direct jump or branch goes.
+    pub target: Option<u64>,
+    /// Whether the instruction ends a run of straight line
+    /// code: a jump, branch, return or trap.
+    pub ends_block: bool,
+}
+
+fn decoder(arch: Architecture) -> Result<Capstone, AnnotateError> {
+    let built = match arch {
+        Architecture::X86_64 => Capstone::new()
+            .x86()
+            .mode(x86::ArchMode::Mode64)
+            .detail(true)
+            .build(),
+        Architecture::Aarch64 => Capstone::new()
+            .arm64()
+            .mode(arm64::ArchMode::Arm)
+            .detail(true)
+            .build(),
+        _ => return Err(AnnotateError::Architecture(arch)),
+    };
+    built.map_err(|e| AnnotateError::Disassemble(format!("{:?}", arch), e.to_string()))
+}
+
+/// Decode `code`, which starts at `address`. Bytes that do
+/// not decode end the listing.
+pub fn disassemble(
+    arch: Architecture,
+    code: &[u8],
+    address: u64,
+) -> Result<Vec<Instruction>, AnnotateError> {
+    let cs = decoder(arch)?;
+    let error =
+        |e: capstone::Error| AnnotateError::Disassemble(format!("{:#x}", address), e.to_string());
+    let insns = cs.disasm_all(code, address).map_err(error)?;
+    let mut instructions = Vec::with_capacity(insns.len());
+    for insn in insns.iter() {
+        let detail = cs.insn_detail(&insn).map_err(error)?;
+        let groups: Vec<String> = detail
+            .groups()
+            .filter_map(|group| cs.group_name(group))
+            .collect();
+        let in_group = |name: &str| groups.iter().any(|g| g == name);
+        let jump = in_group("jump");
+        // Calls come back, so only jumps have targets worth marking.
+        let target =
+            if jump {
+                detail.arch_detail().operands().into_iter().rev().find_map(
+                    |operand| match operand {
+                        ArchOperand::X86Operand(op) => match op.op_type {
+                            X86OperandType::Imm(imm) => Some(imm as u64),
+                            _ => None,
+                        },
+                        ArchOperand::Arm64Operand(op) => match op.op_type {
+                            Arm64OperandType::Imm(imm) => Some(imm as u64),
+                            _ => None,
+                        },
+                        _ => None,
+                    },
+                )
+            } else {
+                None
+            };
+        let text = match insn.op_str() {
+            Some(operands) if !operands.is_empty() => {
+                format!("{} {}", insn.mnemonic().unwrap_or(""), operands)
+            }
+            _ => insn.mnemonic().unwrap_or("").to_string(),
+        };
+        instructions.push(Instruction {
+            address: insn.address(),
+            size: insn.bytes().len() as u64,
+            text,
+            target,
+            ends_block: jump || in_group("ret") || in_group("int"),
+        });
+    }
+    Ok(instructions)
+}
+
+#[cfg(test)]
+#[test]
+fn disassemble_test() {
+    // push rbp; mov rbp, rsp; jne -4 (back to the mov); ret
+    let code = [0x55, 0x48, 0x89, 0xe5, 0x75, 0xfb, 0xc3];
+    let instructions = disassemble(Architecture::X86_64, &code, 0x1000).unwrap();
+    let text: Vec<&str> = instructions.iter().map(|i| i.text.as_str()).collect();
+    assert_eq!(text, vec!["push rbp", "mov rbp, rsp", "jne 0x1001", "ret"]);
+    assert_eq!(instructions[2].target, Some(0x1001));
+    assert!(instructions[2].ends_block && instructions[3].ends_block);
+    assert!(!instructions[1].ends_block);
+
+    // stp x29, x30, [sp, #-0x10]!; cbz x0, +8; ret
+    let code = [
+        0xfd, 0x7b, 0xbf, 0xa9, 0x40, 0x00, 0x00, 0xb4, 0xc0, 0x03, 0x5f, 0xd6,
+    ];
+    let instructions = disassemble(Architecture::Aarch64, &code, 0x2000).unwrap();
+    assert_eq!(instructions.len(), 3);
+    assert!(instructions[1].text.starts_with("cbz x0"));
+    assert_eq!(instructions[1].target, Some(0x200c));
+    assert_eq!(instructions[2].text, "ret");
+
+    assert!(disassemble(Architecture::Riscv64, &code, 0).is_err());
+}
@@ -0,0 +1,144 @@
+//! In-process measurement with ruperf's counters.
+//!
+//! `Counters` collects a set of events and counts them while
+//! running a closure. Counters are opened with pid 0 and cpu -1,
+//! so only work done by the calling thread is counted.
+//!
+//! ```no_run
+//! use ruperf::Counters;
+//!
+//! let (sum, report) = Counters::new()
+//!     .event("cycles")
+//!     .event("instructions")
+//!     .measure(|| (0..1_000_000u64).sum::<u64>())
+//!     .unwrap();
+//! assert_eq!(sum, 499_999_500_000);
+//! println!("{}", serde_json::to_string(&report).unwrap());
+//! ```
+
+use crate::event::open::Event;
+use crate::stat::StatEvent;
+use crate::utils::CounterError;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/// Builder for a set of counters on the calling thread.
+#[derive(Debug, Default, Clone)]
+pub struct Counters {
+    events: Vec<StatEvent>,
+    /// First event name that failed to parse, reported by `measure`.
+    invalid: Option<String>,
+}
+
+/// Counts collected while running a closure.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Report {
+    /// Counter deltas keyed by event name, as given to `Counters::event`.
+    pub counters: BTreeMap<String, u64>,
+    /// Wall-clock time spent in the closure.
+    pub elapsed: Duration,
+}
+
+impl Report {
+    /// Look up the count for an event by name.
+    pub fn get(&self, name: &str) -> Option<u64> {
+        self.counters.get(name).copied()
+    }
+}
+
+impl Counters {
+    /// An empty set of counters.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an event by the name used with `ruperf stat --event`.
+    /// An unknown name makes `measure` return an error.
+    pub fn event(mut self, name: &str) -> Self {
+        match name.parse::<StatEvent>() {
+            Ok(event) => self.events.push(event),
+            Err(_) => {
+                self.invalid.get_or_insert_with(|| name.to_string());
+            }
+        }
+        self
+    }
+
+    /// Add an already parsed event.
+    pub fn with(mut self, event: StatEvent) -> Self {
+        self.events.push(event);
+        self
+    }
+
+    /// Run `f` on the calling thread and count its events.
+    /// Returns the closure's result along with the counts.
+    pub fn measure<F, R>(&self, f: F) -> Result<(R, Report), CounterError>
+    where
+        F: FnOnce() -> R,
+    {
+        if let Some(name) = &self.invalid {
+            return Err(CounterError::InvalidEvent(name.clone()));
+        }
+        let events = self
+            .events
+            .iter()
+            .map(|e| Event::try_new(*e, None).map_err(|_| CounterError::Open(e.to_string())))
+            .collect::<Result<Vec<Event>, CounterError>>()?;
+
+        let mut starts = Vec::with_capacity(events.len());
+        for event in &events {
+            let start = event
+                .start_counter()
+                .map_err(|_| CounterError::Read(event.event.to_string()))?;
+            starts.push(start);
+        }
+        let instant = Instant::now();
+        // Keep the work inside the counted region even if the caller
+        // ignores the result.
+        let result = std::hint::black_box(f());
+        let elapsed = instant.elapsed();
+        let mut report = Report {
+            counters: BTreeMap::new(),
+            elapsed,
+        };
+        for (event, start) in events.iter().zip(starts) {
+            let stop = event
+                .stop_counter()
+                .map_err(|_| CounterError::Read(event.event.to_string()))?;
+            report.counters.insert(
+                event.event.as_str().to_string(),
+                (stop - start).max(0) as u64,
+            );
+        }
+        Ok((result, report))
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn measure_test() {
+    let (value, report) = Counters::new()
+        .event("task-clock")
+        .event("context-switches")
+        .measure(|| {
+            std::thread::sleep(Duration::from_millis(5));
+            42
+        })
+        .unwrap();
+    assert_eq!(value, 42);
+    assert!(report.elapsed >= Duration::from_millis(5));
+    assert!(report.get("task-clock").is_some());
+    assert!(report.get("context-switches").unwrap() >= 1);
+    let json = serde_json::to_string(&report).unwrap();
+    assert_eq!(serde_json::from_str::<Report>(&json).unwrap(), report);
+}
+
+#[test]
+fn invalid_event_test() {
+    let result = Counters::new()
+        .event("cycles")
+        .event("bogus")
+        .measure(|| ());
+    assert!(matches!(result, Err(CounterError::InvalidEvent(name)) if name == "bogus"));
+}
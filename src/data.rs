@@ -0,0 +1,413 @@
+//! # perf.data files.
+//! Recordings are stored in the format Linux perf uses:
+//! a file header, the ids of every event, the event attributes,
+//! the records copied out of the ring buffers, then feature
+//! sections describing the machine and the recording.
+//! See `tools/perf/Documentation/perf.data-file-format.txt`
+//! in the Linux source tree.
+
+mod features;
+mod reader;
+mod sample;
+
+pub use features::{BuildId, EventDesc, Features, NrCpus};
+pub use reader::{PerfData, RawRecord, Records};
+pub use sample::{Comm, Mmap, Record, Sample, Switch, Task, UserRegs};
+
+use crate::bindings::perf_event_attr;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// "PERFILE2" read as a little endian `u64`.
+pub const MAGIC: u64 = u64::from_le_bytes(*b"PERFILE2");
+
+/// Size of the file header on disk.
+const HEADER_SIZE: u64 = 104;
+
+/// Size of a `FileSection` on disk.
+const SECTION_SIZE: u64 = 16;
+
+/// `PERF_RECORD_FINISHED_ROUND`, written by user space after
+/// every pass over the ring buffers. Holds no data.
+pub const PERF_RECORD_FINISHED_ROUND: u32 = 68;
+
+/// Where a part of the file starts and how long it is.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FileSection {
+    pub offset: u64,
+    pub size: u64,
+}
+
+impl FileSection {
+    /// Where the section ends, or `None` when that is past `u64::MAX`,
+    /// as only a corrupt file would have it.
+    pub fn end(&self) -> Option<u64> {
+        self.offset.checked_add(self.size)
+    }
+
+    fn write(&self, out: &mut dyn Write) -> io::Result<()> {
+        out.write_all(&self.offset.to_le_bytes())?;
+        out.write_all(&self.size.to_le_bytes())
+    }
+}
+
+/// The header at the start of every perf.data file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileHeader {
+    /// Size of one attr entry: a `perf_event_attr`
+    /// followed by the `FileSection` of its ids.
+    pub attr_size: u64,
+    pub attrs: FileSection,
+    pub data: FileSection,
+    /// Unused by modern perf, always empty.
+    pub event_types: FileSection,
+    /// Bitmap of the feature sections following the data.
+    pub features: [u64; 4],
+}
+
+impl FileHeader {
+    fn write(&self, out: &mut dyn Write) -> io::Result<()> {
+        out.write_all(&MAGIC.to_le_bytes())?;
+        out.write_all(&HEADER_SIZE.to_le_bytes())?;
+        out.write_all(&self.attr_size.to_le_bytes())?;
+        self.attrs.write(out)?;
+        self.data.write(out)?;
+        self.event_types.write(out)?;
+        for word in &self.features {
+            out.write_all(&word.to_le_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+/// An event's attributes as raw `perf_event_attr` bytes,
+/// and the ids the kernel gave each of its file descriptors.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Attr {
+    pub bytes: Vec<u8>,
+    pub ids: Vec<u64>,
+}
+
+impl Attr {
+    /// Copy `attr` as the kernel sees it.
+    pub(crate) fn new(attr: &perf_event_attr, ids: Vec<u64>) -> Self {
+        let bytes = unsafe {
+            std::slice::from_raw_parts(
+                attr as *const perf_event_attr as *const u8,
+                std::mem::size_of::<perf_event_attr>(),
+            )
+        };
+        Self {
+            bytes: bytes.to_vec(),
+            ids,
+        }
+    }
+
+    fn u64_at(&self, offset: usize) -> u64 {
+        self.bytes
+            .get(offset..offset + 8)
+            .map_or(0, |b| u64::from_le_bytes(b.try_into().unwrap()))
+    }
+
+    /// `perf_event_attr.type`: hardware, software, cache...
+    pub fn kind(&self) -> u32 {
+        self.u64_at(0) as u32
+    }
+
+    /// `perf_event_attr.config`: which event of that type.
+    pub fn config(&self) -> u64 {
+        self.u64_at(8)
+    }
+
+    /// Which `PERF_SAMPLE_*` fields a sample carries.
+    pub fn sample_type(&self) -> u64 {
+        self.u64_at(24)
+    }
+
+    /// Which user registers a sample with `PERF_SAMPLE_REGS_USER` holds.
+    pub fn sample_regs_user(&self) -> u64 {
+        self.u64_at(80)
+    }
+
+    /// Whether non-sample records end with the sample's id fields.
+    pub fn sample_id_all(&self) -> bool {
+        self.u64_at(40) & (1 << 18) != 0
+    }
+}
+
+/// Writes a perf.data file. The header is written last,
+/// once the size of the data section is known.
+pub struct Writer<W: Write + Seek> {
+    out: W,
+    header: FileHeader,
+}
+
+impl Writer<BufWriter<File>> {
+    /// Create the file at `path` for events with `attrs`.
+    pub fn create<P: AsRef<Path>>(path: P, attrs: &[Attr]) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?), attrs)
+    }
+}
+
+impl<W: Write + Seek> Writer<W> {
+    /// Write everything before the data section.
+    pub fn new(mut out: W, attrs: &[Attr]) -> io::Result<Self> {
+        let attr_size = attrs.first().map_or(0, |a| a.bytes.len() as u64) + SECTION_SIZE;
+        if attrs
+            .iter()
+            .any(|a| a.bytes.len() as u64 + SECTION_SIZE != attr_size)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "attrs differ in size",
+            ));
+        }
+
+        // Ids first, so the attrs can point at them.
+        out.seek(SeekFrom::Start(HEADER_SIZE))?;
+        let mut offset = HEADER_SIZE;
+        let mut sections = Vec::with_capacity(attrs.len());
+        for attr in attrs {
+            for id in &attr.ids {
+                out.write_all(&id.to_le_bytes())?;
+            }
+            let size = 8 * attr.ids.len() as u64;
+            sections.push(FileSection { offset, size });
+            offset += size;
+        }
+
+        let attrs_section = FileSection {
+            offset,
+            size: attr_size * attrs.len() as u64,
+        };
+        for (attr, ids) in attrs.iter().zip(sections) {
+            out.write_all(&attr.bytes)?;
+            ids.write(&mut out)?;
+        }
+
+        let header = FileHeader {
+            attr_size,
+            attrs: attrs_section,
+            data: FileSection {
+                offset: attrs_section.offset + attrs_section.size,
+                size: 0,
+            },
+            ..Default::default()
+        };
+        Ok(Self { out, header })
+    }
+
+    /// Append one record, header included.
+    pub fn write_record(&mut self, record: &[u8]) -> io::Result<()> {
+        self.out.write_all(record)?;
+        self.header.data.size += record.len() as u64;
+        Ok(())
+    }
+
+    /// Bytes of records written so far.
+    pub fn data_size(&self) -> u64 {
+        self.header.data.size
+    }
+
+    /// Write the feature sections and the header,
+    /// then hand back the output.
+    pub fn finish(mut self, features: &Features) -> io::Result<W> {
+        let offset = self.header.data.offset + self.header.data.size;
+        self.header.features = features.write(&mut self.out, offset)?;
+        self.out.seek(SeekFrom::Start(0))?;
+        self.header.write(&mut self.out)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn write_layout_test() {
+    use std::io::Cursor;
+
+    let attrs = vec![
+        Attr {
+            bytes: vec![1; 112],
+            ids: vec![7, 8],
+        },
+        Attr {
+            bytes: vec![2; 112],
+            ids: vec![9],
+        },
+    ];
+    let mut writer = Writer::new(Cursor::new(Vec::new()), &attrs).unwrap();
+    // A PERF_RECORD_FINISHED_ROUND: type, misc, size.
+    let round = [68, 0, 0, 0, 0, 0, 8, 0];
+    writer.write_record(&round).unwrap();
+    let file = writer.finish(&Features::default()).unwrap().into_inner();
+
+    let u64_at = |offset: usize| u64::from_le_bytes(file[offset..offset + 8].try_into().unwrap());
+    assert_eq!(&file[..8], b"PERFILE2");
+    assert_eq!(u64_at(8), HEADER_SIZE);
+    assert_eq!(u64_at(16), 112 + 16);
+    // Ids follow the header.
+    assert_eq!(u64_at(104), 7);
+    assert_eq!(u64_at(120), 9);
+    // Attrs follow the ids, each pointing at its ids.
+    let attrs_offset = u64_at(24) as usize;
+    assert_eq!(attrs_offset, 128);
+    assert_eq!(u64_at(32), 2 * 128);
+    assert_eq!(u64_at(attrs_offset + 112), 104);
+    assert_eq!(u64_at(attrs_offset + 120), 16);
+    assert_eq!(u64_at(attrs_offset + 128 + 112), 120);
+    // Then the data.
+    let data_offset = u64_at(40) as usize;
+    assert_eq!(data_offset, attrs_offset + 256);
+    assert_eq!(u64_at(48), 8);
+    assert_eq!(&file[data_offset..], &round);
+}
+
+/// The fixtures were written by `ruperf record`; `perf_tool_test`
+/// checks files written by perf itself.
+#[test]
+fn fixture_round_trip_test() {
+    use std::io::Cursor;
+
+    let fixture = |name: &str| {
+        std::fs::read(format!(
+            "{}/tests/fixtures/{}",
+            env!("CARGO_MANIFEST_DIR"),
+            name
+        ))
+        .unwrap()
+    };
+    for (name, events) in &[
+        ("cpu-clock.data", vec!["cpu-clock"]),
+        ("two-events.data", vec!["cpu-clock", "task-clock"]),
+    ] {
+        let bytes = fixture(name);
+        let data = PerfData::parse(&bytes).unwrap();
+        assert_eq!(data.attrs.len(), events.len());
+        assert!(data.attrs.iter().all(Attr::sample_id_all));
+
+        let features = &data.features;
+        assert_eq!(features.hostname.as_deref(), Some("fixture-host"));
+        assert_eq!(features.osrelease.as_deref(), Some("6.1.0"));
+        assert_eq!(features.cmdline.as_ref().unwrap()[2], "record");
+        assert!(features.nr_cpus.unwrap().online >= 1);
+        assert!(!features.build_ids.as_ref().unwrap().is_empty());
+        let descs = features.event_desc.as_ref().unwrap();
+        let names: Vec<&str> = descs.iter().map(|d| d.name.as_str()).collect();
+        assert_eq!(&names, events);
+        for (desc, attr) in descs.iter().zip(&data.attrs) {
+            assert_eq!(&desc.attr, attr);
+        }
+
+        // Every sample comes from one of the attrs.
+        let samples: Vec<RawRecord> = data
+            .records()
+            .filter(|r| r.kind == crate::bindings::perf_event_type_PERF_RECORD_SAMPLE)
+            .collect();
+        assert!(!samples.is_empty());
+        for sample in samples {
+            // PERF_SAMPLE_IDENTIFIER comes first.
+            let id = u64::from_le_bytes(sample.bytes[8..16].try_into().unwrap());
+            assert!(data.attr_for_id(id).is_some());
+        }
+
+        let written = data.write(Cursor::new(Vec::new())).unwrap().into_inner();
+        assert_eq!(written, bytes, "{} changed on a round trip", name);
+    }
+}
+
+#[test]
+fn corrupt_section_test() {
+    let path = format!(
+        "{}/tests/fixtures/cpu-clock.data",
+        env!("CARGO_MANIFEST_DIR")
+    );
+    let mut bytes = std::fs::read(path).unwrap();
+    // A data section whose end is past u64::MAX.
+    bytes[48..56].copy_from_slice(&u64::MAX.to_le_bytes());
+    let err = PerfData::parse(&bytes).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
+
+/// Linux perf reads what ruperf writes, and ruperf what perf writes.
+/// Needs `perf` on the `PATH` and permission to record; run it with
+/// `cargo test -- --ignored`.
+#[test]
+#[ignore = "needs perf on the PATH"]
+fn perf_tool_test() {
+    use std::io::Cursor;
+    use std::process::Command;
+
+    let perf = |args: &[&str]| Command::new("perf").args(args).output();
+    let stdout = |output: &std::process::Output| {
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+
+    let fixture = format!(
+        "{}/tests/fixtures/two-events.data",
+        env!("CARGO_MANIFEST_DIR")
+    );
+    let header = stdout(&perf(&["report", "--force", "--header-only", "-i", &fixture]).unwrap());
+    assert!(header.contains("# hostname : fixture-host"), "{}", header);
+    assert!(header.contains("# os release : 6.1.0"), "{}", header);
+    let script =
+        stdout(&perf(&["script", "--force", "-F", "comm,tid,event", "-i", &fixture]).unwrap());
+    assert!(script.contains("cpu-clock") && script.contains("task-clock"));
+
+    let output = std::env::temp_dir().join(format!("ruperf-perf-{}.data", std::process::id()));
+    let output = output.to_str().unwrap();
+    let recorded = perf(&[
+        "record",
+        "-q",
+        "-e",
+        "cpu-clock",
+        "-o",
+        output,
+        "--",
+        "sh",
+        "-c",
+        "i=0; while [ $i -lt 100000 ]; do i=$((i+1)); done",
+    ])
+    .unwrap();
+    stdout(&recorded);
+    let data = PerfData::open(output).unwrap();
+    std::fs::remove_file(output).unwrap();
+    // Perf lays sections out differently, so compare what was parsed.
+    let written = data.write(Cursor::new(Vec::new())).unwrap().into_inner();
+    assert_eq!(PerfData::parse(&written).unwrap(), data);
+    let proc_value = |name: &str| {
+        std::fs::read_to_string(format!("/proc/sys/kernel/{}", name))
+            .unwrap()
+            .trim()
+            .to_string()
+    };
+    let features = &data.features;
+    assert_eq!(features.hostname, Some(proc_value("hostname")));
+    assert_eq!(features.osrelease, Some(proc_value("osrelease")));
+    let cmdline = features.cmdline.as_ref().unwrap();
+    assert_eq!(&cmdline[1..4], &["record", "-q", "-e"]);
+    let nr_cpus = features.nr_cpus.unwrap();
+    assert!(nr_cpus.online >= 1 && nr_cpus.available >= nr_cpus.online);
+    assert!(features
+        .build_ids
+        .as_ref()
+        .unwrap()
+        .iter()
+        .all(|b| !b.id.is_empty()));
+    let descs = features.event_desc.as_ref().unwrap();
+    assert_eq!(descs[0].name, "cpu-clock");
+    assert_eq!(descs[0].attr.ids, data.attrs[0].ids);
+    assert!(
+        data.records()
+            .filter(|r| r.kind == crate::bindings::perf_event_type_PERF_RECORD_SAMPLE)
+            .count()
+            > 0
+    );
+}
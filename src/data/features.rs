@@ -0,0 +1,476 @@
+//! Feature sections, stored after the data section.
+//! Each set bit in the header's feature bitmap has a
+//! `FileSection` in a table right after the data, in bit order.
+//! Sections ruperf does not understand are kept as raw bytes,
+//! so a file can be read and written back without losing them.
+
+use super::{Attr, FileSection};
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::io::{self, Write};
+
+pub const HEADER_BUILD_ID: u32 = 2;
+pub const HEADER_HOSTNAME: u32 = 3;
+pub const HEADER_OSRELEASE: u32 = 4;
+pub const HEADER_NRCPUS: u32 = 7;
+pub const HEADER_CMDLINE: u32 = 11;
+pub const HEADER_EVENT_DESC: u32 = 12;
+
+/// Strings are padded with NULs to a multiple of this.
+const NAME_ALIGN: usize = 64;
+
+/// Build-ids are padded to 24 bytes.
+const BUILD_ID_SPACE: usize = 24;
+
+/// Longest build-id, a SHA-1.
+const BUILD_ID_SIZE: usize = 20;
+
+/// Set in a build-id entry's `misc` when the byte after
+/// the first 20 holds the build-id's real length.
+pub const PERF_RECORD_MISC_BUILD_ID_SIZE: u16 = 1 << 15;
+
+/// `PERF_RECORD_MISC_USER`, for build-ids of user space files.
+pub const PERF_RECORD_MISC_USER: u16 = 2;
+
+/// The build-id of a file that was mapped while recording.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BuildId {
+    /// -1 for files on the host.
+    pub pid: i32,
+    pub misc: u16,
+    pub id: Vec<u8>,
+    pub filename: String,
+}
+
+impl BuildId {
+    /// Read the build-id note of the ELF file at `path`.
+    pub fn for_file<P: AsRef<std::path::Path>>(path: P) -> Option<Self> {
+        use object::Object;
+        let path = path.as_ref();
+        let data = std::fs::read(path).ok()?;
+        let id = object::File::parse(&*data).ok()?.build_id().ok()??;
+        Some(BuildId {
+            pid: -1,
+            misc: PERF_RECORD_MISC_USER | PERF_RECORD_MISC_BUILD_ID_SIZE,
+            id: id.to_vec(),
+            filename: path.display().to_string(),
+        })
+    }
+}
+
+/// CPUs the machine could have, and those that were online.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NrCpus {
+    pub available: u32,
+    pub online: u32,
+}
+
+/// An event's name along with its attributes and ids.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EventDesc {
+    pub name: String,
+    pub attr: Attr,
+}
+
+/// Everything stored in feature sections.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Features {
+    pub build_ids: Option<Vec<BuildId>>,
+    pub hostname: Option<String>,
+    pub osrelease: Option<String>,
+    pub nr_cpus: Option<NrCpus>,
+    /// The recording command line, led by the path to the recorder.
+    pub cmdline: Option<Vec<String>>,
+    pub event_desc: Option<Vec<EventDesc>>,
+    /// Sections ruperf does not parse, by feature bit.
+    pub other: BTreeMap<u32, Vec<u8>>,
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("malformed {} feature section", what),
+    )
+}
+
+/// Reads little endian values from a feature section.
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    what: &'static str,
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.bytes.len() < n {
+            return Err(invalid(self.what));
+        }
+        let (head, tail) = self.bytes.split_at(n);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        let len = self.u32()? as usize;
+        Ok(nul_terminated(self.take(len)?))
+    }
+
+    fn strings(&mut self) -> io::Result<Vec<String>> {
+        (0..self.u32()?).map(|_| self.string()).collect()
+    }
+}
+
+fn nul_terminated(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+/// NUL-padded `name` as stored inline, without a length.
+fn padded(name: &str) -> Vec<u8> {
+    // Room for at least one NUL.
+    let len = (name.len() / NAME_ALIGN + 1) * NAME_ALIGN;
+    let mut bytes = name.as_bytes().to_vec();
+    bytes.resize(len, 0);
+    bytes
+}
+
+fn write_string(out: &mut Vec<u8>, s: &str) {
+    let bytes = padded(s);
+    out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    out.extend_from_slice(&bytes);
+}
+
+fn write_strings(out: &mut Vec<u8>, strings: &[String]) {
+    out.extend_from_slice(&(strings.len() as u32).to_le_bytes());
+    for s in strings {
+        write_string(out, s);
+    }
+}
+
+fn parse_build_ids(mut bytes: &[u8]) -> io::Result<Vec<BuildId>> {
+    let mut build_ids = Vec::new();
+    // header, pid, build-id
+    let fixed = 8 + 4 + BUILD_ID_SPACE;
+    while bytes.len() >= 8 {
+        let misc = u16::from_le_bytes([bytes[4], bytes[5]]);
+        let size = u16::from_le_bytes([bytes[6], bytes[7]]) as usize;
+        if size < fixed || size > bytes.len() {
+            return Err(invalid("build-id"));
+        }
+        let pid = i32::from_le_bytes(bytes[8..12].try_into().unwrap());
+        let id = &bytes[12..12 + BUILD_ID_SPACE];
+        let len = if misc & PERF_RECORD_MISC_BUILD_ID_SIZE != 0 {
+            (id[BUILD_ID_SIZE] as usize).min(BUILD_ID_SIZE)
+        } else {
+            BUILD_ID_SIZE
+        };
+        build_ids.push(BuildId {
+            pid,
+            misc,
+            id: id[..len].to_vec(),
+            filename: nul_terminated(&bytes[fixed..size]),
+        });
+        bytes = &bytes[size..];
+    }
+    Ok(build_ids)
+}
+
+fn write_build_ids(out: &mut Vec<u8>, build_ids: &[BuildId]) {
+    for build_id in build_ids {
+        let filename = padded(&build_id.filename);
+        let size = 8 + 4 + BUILD_ID_SPACE + filename.len();
+        let mut id = [0; BUILD_ID_SPACE];
+        let len = build_id.id.len().min(BUILD_ID_SIZE);
+        id[..len].copy_from_slice(&build_id.id[..len]);
+        if build_id.misc & PERF_RECORD_MISC_BUILD_ID_SIZE != 0 {
+            id[BUILD_ID_SIZE] = len as u8;
+        }
+        // perf leaves the type in the header zero.
+        out.extend_from_slice(&0u32.to_le_bytes());
+        out.extend_from_slice(&build_id.misc.to_le_bytes());
+        out.extend_from_slice(&(size as u16).to_le_bytes());
+        out.extend_from_slice(&build_id.pid.to_le_bytes());
+        out.extend_from_slice(&id);
+        out.extend_from_slice(&filename);
+    }
+}
+
+fn parse_event_desc(bytes: &[u8]) -> io::Result<Vec<EventDesc>> {
+    let mut cursor = Cursor {
+        bytes,
+        what: "event_desc",
+    };
+    let events = cursor.u32()?;
+    let attr_size = cursor.u32()? as usize;
+    let mut descs = Vec::new();
+    for _ in 0..events {
+        let attr = cursor.take(attr_size)?.to_vec();
+        let nr_ids = cursor.u32()?;
+        let name = cursor.string()?;
+        let ids = (0..nr_ids)
+            .map(|_| cursor.u64())
+            .collect::<io::Result<_>>()?;
+        descs.push(EventDesc {
+            name,
+            attr: Attr { bytes: attr, ids },
+        });
+    }
+    Ok(descs)
+}
+
+fn write_event_desc(out: &mut Vec<u8>, descs: &[EventDesc]) {
+    let attr_size = descs.first().map_or(0, |d| d.attr.bytes.len());
+    out.extend_from_slice(&(descs.len() as u32).to_le_bytes());
+    out.extend_from_slice(&(attr_size as u32).to_le_bytes());
+    for desc in descs {
+        out.extend_from_slice(&desc.attr.bytes);
+        out.extend_from_slice(&(desc.attr.ids.len() as u32).to_le_bytes());
+        write_string(out, &desc.name);
+        for id in &desc.attr.ids {
+            out.extend_from_slice(&id.to_le_bytes());
+        }
+    }
+}
+
+impl Features {
+    /// Parse the sections for the features set in `bitmap`.
+    /// `file` is the whole file and `offset` where the table starts.
+    pub fn parse(file: &[u8], offset: u64, bitmap: &[u64; 4]) -> io::Result<Self> {
+        let mut features = Features::default();
+        let mut table = Cursor {
+            bytes: file.get(offset as usize..).unwrap_or(&[]),
+            what: "feature table",
+        };
+        for bit in (0..256).filter(|bit| bitmap[bit / 64] & (1 << (bit % 64)) != 0) {
+            let section = FileSection {
+                offset: table.u64()?,
+                size: table.u64()?,
+            };
+            let bytes = section
+                .end()
+                .and_then(|end| file.get(section.offset as usize..end as usize))
+                .ok_or_else(|| invalid("feature"))?;
+            let cursor = |what| Cursor { bytes, what };
+            match bit as u32 {
+                HEADER_BUILD_ID => features.build_ids = Some(parse_build_ids(bytes)?),
+                HEADER_HOSTNAME => features.hostname = Some(cursor("hostname").string()?),
+                HEADER_OSRELEASE => features.osrelease = Some(cursor("osrelease").string()?),
+                HEADER_NRCPUS => {
+                    let mut cursor = cursor("nrcpus");
+                    features.nr_cpus = Some(NrCpus {
+                        available: cursor.u32()?,
+                        online: cursor.u32()?,
+                    });
+                }
+                HEADER_CMDLINE => features.cmdline = Some(cursor("cmdline").strings()?),
+                HEADER_EVENT_DESC => features.event_desc = Some(parse_event_desc(bytes)?),
+                bit => {
+                    features.other.insert(bit, bytes.to_vec());
+                }
+            }
+        }
+        Ok(features)
+    }
+
+    /// Every present section by feature bit, encoded.
+    fn sections(&self) -> BTreeMap<u32, Vec<u8>> {
+        let mut sections = self.other.clone();
+        let mut add = |bit, encode: &dyn Fn(&mut Vec<u8>)| {
+            let mut bytes = Vec::new();
+            encode(&mut bytes);
+            sections.insert(bit, bytes);
+        };
+        if let Some(build_ids) = &self.build_ids {
+            add(HEADER_BUILD_ID, &|out| write_build_ids(out, build_ids));
+        }
+        if let Some(hostname) = &self.hostname {
+            add(HEADER_HOSTNAME, &|out| write_string(out, hostname));
+        }
+        if let Some(osrelease) = &self.osrelease {
+            add(HEADER_OSRELEASE, &|out| write_string(out, osrelease));
+        }
+        if let Some(nr_cpus) = &self.nr_cpus {
+            add(HEADER_NRCPUS, &|out| {
+                out.extend_from_slice(&nr_cpus.available.to_le_bytes());
+                out.extend_from_slice(&nr_cpus.online.to_le_bytes());
+            });
+        }
+        if let Some(cmdline) = &self.cmdline {
+            add(HEADER_CMDLINE, &|out| write_strings(out, cmdline));
+        }
+        if let Some(descs) = &self.event_desc {
+            add(HEADER_EVENT_DESC, &|out| write_event_desc(out, descs));
+        }
+        sections
+    }
+
+    /// Write the section table and sections starting at `offset`.
+    /// Returns the feature bitmap for the file header.
+    pub fn write(&self, out: &mut dyn Write, offset: u64) -> io::Result<[u64; 4]> {
+        let sections = self.sections();
+        let mut bitmap = [0; 4];
+        let table = 16 * sections.len() as u64;
+        let mut start = offset
+            .checked_add(table)
+            .ok_or_else(|| invalid("feature table"))?;
+        for (bit, bytes) in &sections {
+            bitmap[*bit as usize / 64] |= 1 << (bit % 64);
+            let section = FileSection {
+                offset: start,
+                size: bytes.len() as u64,
+            };
+            section.write(out)?;
+            start = section.end().ok_or_else(|| invalid("feature table"))?;
+        }
+        for bytes in sections.values() {
+            out.write_all(bytes)?;
+        }
+        Ok(bitmap)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn features_round_trip_test() {
+    let features = Features {
+        build_ids: Some(vec![BuildId {
+            pid: -1,
+            misc: PERF_RECORD_MISC_USER | PERF_RECORD_MISC_BUILD_ID_SIZE,
+            id: vec![0xab; 20],
+            filename: "/usr/bin/true".to_string(),
+        }]),
+        hostname: Some("host".to_string()),
+        osrelease: Some("5.10.0".to_string()),
+        nr_cpus: Some(NrCpus {
+            available: 4,
+            online: 2,
+        }),
+        cmdline: Some(vec!["/usr/bin/ruperf".to_string(), "record".to_string()]),
+        event_desc: Some(vec![EventDesc {
+            name: "cpu-clock".to_string(),
+            attr: Attr {
+                bytes: vec![7; 120],
+                ids: vec![1, 2],
+            },
+        }]),
+        other: vec![(9, b"GenuineIntel,6,85,4".to_vec())]
+            .into_iter()
+            .collect(),
+    };
+
+    // Pretend the table lands 100 bytes into a file.
+    let mut file = vec![0; 100];
+    let bitmap = features.write(&mut file, 100).unwrap();
+    assert_eq!(
+        bitmap[0],
+        1 << 2 | 1 << 3 | 1 << 4 | 1 << 7 | 1 << 9 | 1 << 11 | 1 << 12
+    );
+    // Strings are length prefixed and padded to 64 bytes.
+    let hostname_offset = u64::from_le_bytes(file[116..124].try_into().unwrap()) as usize;
+    assert_eq!(
+        &file[hostname_offset..hostname_offset + 8],
+        &[64, 0, 0, 0, b'h', b'o', b's', b't']
+    );
+
+    assert_eq!(Features::parse(&file, 100, &bitmap).unwrap(), features);
+}
+
+/// The sections as perf's `perf.data-file-format.txt` lays them out,
+/// spelled out byte by byte rather than with the writers above.
+#[test]
+fn perf_layout_test() {
+    let features = Features {
+        build_ids: Some(vec![BuildId {
+            pid: -1,
+            misc: PERF_RECORD_MISC_USER | PERF_RECORD_MISC_BUILD_ID_SIZE,
+            id: vec![0xab; 20],
+            filename: "/bin/true".to_string(),
+        }]),
+        hostname: Some("host".to_string()),
+        osrelease: Some("6.1.0".to_string()),
+        nr_cpus: Some(NrCpus {
+            available: 8,
+            online: 4,
+        }),
+        cmdline: Some(vec!["perf".to_string(), "record".to_string()]),
+        event_desc: Some(vec![EventDesc {
+            name: "cycles".to_string(),
+            attr: Attr {
+                bytes: vec![1; 8],
+                ids: vec![5],
+            },
+        }]),
+        other: BTreeMap::new(),
+    };
+    // struct perf_header_string: u32 len, then the string with a NUL,
+    // padded to 64 bytes.
+    let string = |s: &str| {
+        let mut bytes = 64u32.to_le_bytes().to_vec();
+        bytes.extend_from_slice(s.as_bytes());
+        bytes.resize(4 + 64, 0);
+        bytes
+    };
+    let mut build_id = Vec::new();
+    // struct perf_event_header: type 0, misc USER | BUILD_ID_SIZE, size.
+    build_id.extend_from_slice(&[0, 0, 0, 0, 0x02, 0x80, 100, 0]);
+    build_id.extend_from_slice(&(-1i32).to_le_bytes());
+    build_id.extend_from_slice(&[0xab; 20]);
+    // The build-id's size, then two reserved fields.
+    build_id.extend_from_slice(&[20, 0, 0, 0]);
+    build_id.extend_from_slice(&string("/bin/true")[4..]);
+    let mut nr_cpus = 8u32.to_le_bytes().to_vec();
+    nr_cpus.extend_from_slice(&4u32.to_le_bytes());
+    let mut cmdline = 2u32.to_le_bytes().to_vec();
+    cmdline.extend(string("perf"));
+    cmdline.extend(string("record"));
+    // nre, attr size, then each attr, its number of ids, name and ids.
+    let mut event_desc = vec![1, 0, 0, 0, 8, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0];
+    event_desc.extend(string("cycles"));
+    event_desc.extend_from_slice(&5u64.to_le_bytes());
+    let sections = [
+        build_id,
+        string("host"),
+        string("6.1.0"),
+        nr_cpus,
+        cmdline,
+        event_desc,
+    ];
+
+    let mut expected = Vec::new();
+    let mut offset = 16 * sections.len() as u64;
+    for section in &sections {
+        expected.extend_from_slice(&offset.to_le_bytes());
+        expected.extend_from_slice(&(section.len() as u64).to_le_bytes());
+        offset += section.len() as u64;
+    }
+    for section in &sections {
+        expected.extend_from_slice(section);
+    }
+
+    let mut file = Vec::new();
+    let bitmap = features.write(&mut file, 0).unwrap();
+    assert_eq!(
+        bitmap,
+        [
+            1 << 2 | 1 << 3 | 1 << 4 | 1 << 7 | 1 << 11 | 1 << 12,
+            0,
+            0,
+            0
+        ]
+    );
+    assert_eq!(file, expected);
+
+    // A section reaching past u64::MAX is an error, not an overflow.
+    let mut table = Vec::new();
+    table.extend_from_slice(&u64::MAX.to_le_bytes());
+    table.extend_from_slice(&2u64.to_le_bytes());
+    assert!(Features::parse(&table, 0, &[1 << HEADER_HOSTNAME, 0, 0, 0]).is_err());
+    assert!(features.write(&mut Vec::new(), u64::MAX - 8).is_err());
+}
@@ -0,0 +1,171 @@
+//! Reading perf.data files written by ruperf or Linux perf.
+//! Only the native byte order is supported, and not the
+//! pipe mode that `perf record -o -` writes.
+
+use super::*;
+use std::convert::TryInto;
+
+/// A whole perf.data file held in memory.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PerfData {
+    pub attrs: Vec<Attr>,
+    /// The data section: records back to back.
+    pub data: Vec<u8>,
+    pub features: Features,
+}
+
+/// One record from the data section.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawRecord<'a> {
+    /// One of the `PERF_RECORD_*` types.
+    pub kind: u32,
+    pub misc: u16,
+    /// The whole record, header included.
+    pub bytes: &'a [u8],
+}
+
+/// Iterator over the records in a data section.
+pub struct Records<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = RawRecord<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.len() < 8 {
+            return None;
+        }
+        let kind = u32::from_le_bytes(self.data[..4].try_into().unwrap());
+        let misc = u16::from_le_bytes([self.data[4], self.data[5]]);
+        let size = u16::from_le_bytes([self.data[6], self.data[7]]) as usize;
+        // A truncated or corrupt record ends the data.
+        if size < 8 || size > self.data.len() {
+            self.data = &[];
+            return None;
+        }
+        let (bytes, rest) = self.data.split_at(size);
+        self.data = rest;
+        Some(RawRecord { kind, misc, bytes })
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> io::Result<u64> {
+    bytes
+        .get(offset..offset + 8)
+        .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| invalid("perf.data header is truncated"))
+}
+
+fn section_at(bytes: &[u8], offset: usize) -> io::Result<FileSection> {
+    Ok(FileSection {
+        offset: u64_at(bytes, offset)?,
+        size: u64_at(bytes, offset + 8)?,
+    })
+}
+
+fn slice(bytes: &[u8], section: FileSection) -> io::Result<&[u8]> {
+    let outside = || invalid("perf.data section lies outside the file");
+    let end = section.end().ok_or_else(outside)?;
+    bytes
+        .get(section.offset as usize..end as usize)
+        .ok_or_else(outside)
+}
+
+impl PerfData {
+    /// Read the file at `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::parse(&std::fs::read(path)?)
+    }
+
+    /// Parse a file already in memory.
+    pub fn parse(bytes: &[u8]) -> io::Result<Self> {
+        let magic = u64_at(bytes, 0)?;
+        if magic == MAGIC.swap_bytes() {
+            return Err(invalid(
+                "perf.data is from a machine of the other byte order",
+            ));
+        }
+        if magic != MAGIC {
+            return Err(invalid("not a perf.data file"));
+        }
+        if u64_at(bytes, 8)? != HEADER_SIZE {
+            return Err(invalid("perf.data in pipe mode is not supported"));
+        }
+        let attr_size = u64_at(bytes, 16)?;
+        let attrs_section = section_at(bytes, 24)?;
+        let data_section = section_at(bytes, 40)?;
+        let mut bitmap = [0; 4];
+        for (i, word) in bitmap.iter_mut().enumerate() {
+            *word = u64_at(bytes, 72 + 8 * i)?;
+        }
+
+        if attr_size <= SECTION_SIZE {
+            return Err(invalid("perf.data attr size is too small"));
+        }
+        let attrs = slice(bytes, attrs_section)?
+            .chunks_exact(attr_size as usize)
+            .map(|entry| {
+                let split = entry.len() - SECTION_SIZE as usize;
+                let ids = slice(bytes, section_at(entry, split)?)?
+                    .chunks_exact(8)
+                    .map(|id| u64::from_le_bytes(id.try_into().unwrap()))
+                    .collect();
+                Ok(Attr {
+                    bytes: entry[..split].to_vec(),
+                    ids,
+                })
+            })
+            .collect::<io::Result<_>>()?;
+
+        let data = slice(bytes, data_section)?.to_vec();
+        // `slice` has checked that the data section ends in the file.
+        let features = Features::parse(bytes, data_section.end().unwrap(), &bitmap)?;
+        Ok(Self {
+            attrs,
+            data,
+            features,
+        })
+    }
+
+    /// The records in the data section, in file order.
+    pub fn records(&self) -> Records<'_> {
+        Records { data: &self.data }
+    }
+
+    /// The attr whose ids include `id`. Files with one attr
+    /// may leave out ids, so that attr matches any id.
+    pub fn attr_for_id(&self, id: u64) -> Option<&Attr> {
+        match self.attrs.as_slice() {
+            [attr] => Some(attr),
+            attrs => attrs.iter().find(|attr| attr.ids.contains(&id)),
+        }
+    }
+
+    /// The position in `attrs` of the attr for `id`.
+    pub fn attr_index(&self, id: u64) -> Option<usize> {
+        let attr = self.attr_for_id(id)?;
+        self.attrs.iter().position(|a| std::ptr::eq(a, attr))
+    }
+
+    /// Name of the `index`th event, from the `event_desc` feature
+    /// when the file has one.
+    pub fn event_name(&self, index: usize) -> String {
+        if let Some(desc) = self.features.event_desc.as_ref().and_then(|d| d.get(index)) {
+            return desc.name.clone();
+        }
+        let attr = &self.attrs[index];
+        format!("type {} config {:#x}", attr.kind(), attr.config())
+    }
+
+    /// Write the file back out.
+    pub fn write<W: Write + Seek>(&self, out: W) -> io::Result<W> {
+        let mut writer = Writer::new(out, &self.attrs)?;
+        writer.write_record(&self.data)?;
+        writer.finish(&self.features)
+    }
+}
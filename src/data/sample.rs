@@ -0,0 +1,567 @@
+//! Decoding the records in the data section. Which fields a
+//! sample holds depends on the `sample_type` of its event, so
+//! records are decoded against the attrs of the file they came from.
+
+use super::{Attr, PerfData, RawRecord};
+use crate::bindings::*;
+use std::convert::TryInto;
+
+/// `PERF_RECORD_MISC_CPUMODE_MASK`: where the CPU was for a sample.
+const CPUMODE_MASK: u16 = 7;
+
+/// Set on a `PERF_RECORD_COMM` caused by `exec()`.
+const PERF_RECORD_MISC_COMM_EXEC: u16 = 1 << 13;
+
+/// One sample, with the fields its event asked for.
+/// Fields that were not asked for are zero.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Sample {
+    pub id: u64,
+    pub ip: u64,
+    pub pid: u32,
+    pub tid: u32,
+    pub time: u64,
+    pub addr: u64,
+    pub cpu: u32,
+    /// Events counted since the previous sample, 1 if not recorded.
+    pub period: u64,
+    /// Return addresses, innermost first, with `PERF_CONTEXT_*` markers.
+    pub callchain: Vec<u64>,
+    /// `PERF_RECORD_MISC_KERNEL`, `PERF_RECORD_MISC_USER`...
+    pub cpumode: u16,
+    /// User space registers, for unwinding `user_stack`.
+    pub user_regs: UserRegs,
+    /// A copy of the user stack, starting at the stack pointer.
+    pub user_stack: Vec<u8>,
+}
+
+/// Registers from `PERF_SAMPLE_REGS_USER`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UserRegs {
+    /// `PERF_SAMPLE_REGS_ABI_*`: 0 when the sample was taken in a
+    /// kernel thread and there are no registers.
+    pub abi: u64,
+    /// The attr's `sample_regs_user`: bit `n` set when register `n` is present.
+    pub mask: u64,
+    /// The present registers, lowest bit first.
+    pub values: Vec<u64>,
+}
+
+impl UserRegs {
+    /// Register `index` in the architecture's `perf_regs.h` numbering.
+    pub fn get(&self, index: u32) -> Option<u64> {
+        if index >= 64 || self.mask & (1 << index) == 0 {
+            return None;
+        }
+        let position = (self.mask & ((1 << index) - 1)).count_ones();
+        self.values.get(position as usize).copied()
+    }
+}
+
+impl Sample {
+    /// Whether the sample was taken in the kernel.
+    pub fn is_kernel(&self) -> bool {
+        self.cpumode == PERF_RECORD_MISC_KERNEL as u16
+            || self.cpumode == PERF_RECORD_MISC_GUEST_KERNEL as u16
+    }
+}
+
+/// A file mapped into a process, from `PERF_RECORD_MMAP`
+/// or `PERF_RECORD_MMAP2`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Mmap {
+    pub pid: u32,
+    pub tid: u32,
+    pub start: u64,
+    pub len: u64,
+    /// Offset into the file of `start`.
+    pub pgoff: u64,
+    pub filename: String,
+    pub cpumode: u16,
+}
+
+/// A thread's name, set at `exec()` or `prctl(PR_SET_NAME)`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Comm {
+    pub pid: u32,
+    pub tid: u32,
+    pub comm: String,
+    pub exec: bool,
+}
+
+/// A thread created or exiting.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Task {
+    pub pid: u32,
+    pub ppid: u32,
+    pub tid: u32,
+    pub ptid: u32,
+    pub time: u64,
+}
+
+/// The kernel switching a thread in or out, from `PERF_RECORD_SWITCH`
+/// or `PERF_RECORD_SWITCH_CPU_WIDE`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Switch {
+    pub pid: u32,
+    pub tid: u32,
+    pub cpu: u32,
+    pub time: u64,
+    /// Whether the thread was switched out rather than in.
+    pub out: bool,
+    /// The pid and tid of the thread switched to or from,
+    /// for CPU-wide switches.
+    pub other: Option<(u32, u32)>,
+}
+
+/// A decoded record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Record {
+    Sample(Sample),
+    Mmap(Mmap),
+    Comm(Comm),
+    Fork(Task),
+    Exit(Task),
+    Switch(Switch),
+    /// Records the kernel dropped because a ring buffer was full.
+    Lost {
+        id: u64,
+        lost: u64,
+    },
+    /// Any other type, by `PERF_RECORD_*` number.
+    Other(u32),
+}
+
+/// Reads little-endian values from a record body.
+struct Fields<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Fields<'a> {
+    fn u64(&mut self) -> Option<u64> {
+        let value = u64::from_le_bytes(self.bytes.get(..8)?.try_into().unwrap());
+        self.bytes = &self.bytes[8..];
+        Some(value)
+    }
+
+    /// Two `u32`s packed into one `u64` field.
+    fn u32_pair(&mut self) -> Option<(u32, u32)> {
+        let value = self.u64()?;
+        Some((value as u32, (value >> 32) as u32))
+    }
+
+    fn skip(&mut self, n: usize) -> Option<()> {
+        self.bytes = self.bytes.get(n..)?;
+        Some(())
+    }
+
+    fn string(&self) -> String {
+        let end = self
+            .bytes
+            .iter()
+            .position(|b| *b == 0)
+            .unwrap_or(self.bytes.len());
+        String::from_utf8_lossy(&self.bytes[..end]).into_owned()
+    }
+}
+
+fn sample_bit(sample_type: u64, bit: perf_event_sample_format) -> bool {
+    sample_type & bit != 0
+}
+
+/// Decode a sample's body in `sample_type` order. A field ruperf does
+/// not know how to skip ends decoding, leaving the rest zero.
+fn parse_sample(body: &[u8], cpumode: u16, attr: &Attr) -> Sample {
+    let sample_type = attr.sample_type();
+    let mut fields = Fields { bytes: body };
+    let mut sample = Sample {
+        period: 1,
+        cpumode,
+        ..Default::default()
+    };
+    let _ = (|| -> Option<()> {
+        if sample_bit(sample_type, perf_event_sample_format_PERF_SAMPLE_IDENTIFIER) {
+            sample.id = fields.u64()?;
+        }
+        if sample_bit(sample_type, perf_event_sample_format_PERF_SAMPLE_IP) {
+            sample.ip = fields.u64()?;
+        }
+        if sample_bit(sample_type, perf_event_sample_format_PERF_SAMPLE_TID) {
+            let (pid, tid) = fields.u32_pair()?;
+            sample.pid = pid;
+            sample.tid = tid;
+        }
+        if sample_bit(sample_type, perf_event_sample_format_PERF_SAMPLE_TIME) {
+            sample.time = fields.u64()?;
+        }
+        if sample_bit(sample_type, perf_event_sample_format_PERF_SAMPLE_ADDR) {
+            sample.addr = fields.u64()?;
+        }
+        if sample_bit(sample_type, perf_event_sample_format_PERF_SAMPLE_ID) {
+            sample.id = fields.u64()?;
+        }
+        if sample_bit(sample_type, perf_event_sample_format_PERF_SAMPLE_STREAM_ID) {
+            fields.u64()?;
+        }
+        if sample_bit(sample_type, perf_event_sample_format_PERF_SAMPLE_CPU) {
+            sample.cpu = fields.u32_pair()?.0;
+        }
+        if sample_bit(sample_type, perf_event_sample_format_PERF_SAMPLE_PERIOD) {
+            sample.period = fields.u64()?;
+        }
+        if sample_bit(sample_type, perf_event_sample_format_PERF_SAMPLE_READ) {
+            return None;
+        }
+        if sample_bit(sample_type, perf_event_sample_format_PERF_SAMPLE_CALLCHAIN) {
+            let nr = fields.u64()?;
+            for _ in 0..nr {
+                sample.callchain.push(fields.u64()?);
+            }
+        }
+        if sample_bit(sample_type, perf_event_sample_format_PERF_SAMPLE_RAW) {
+            let size = u32::from_le_bytes(fields.bytes.get(..4)?.try_into().unwrap());
+            fields.skip(4 + size as usize)?;
+        }
+        if sample_bit(
+            sample_type,
+            perf_event_sample_format_PERF_SAMPLE_BRANCH_STACK,
+        ) {
+            return None;
+        }
+        if sample_bit(sample_type, perf_event_sample_format_PERF_SAMPLE_REGS_USER) {
+            sample.user_regs.abi = fields.u64()?;
+            if sample.user_regs.abi != 0 {
+                sample.user_regs.mask = attr.sample_regs_user();
+                for _ in 0..sample.user_regs.mask.count_ones() {
+                    sample.user_regs.values.push(fields.u64()?);
+                }
+            }
+        }
+        if sample_bit(sample_type, perf_event_sample_format_PERF_SAMPLE_STACK_USER) {
+            let size = fields.u64()? as usize;
+            if size > 0 {
+                let stack = fields.bytes.get(..size)?;
+                fields.skip(size)?;
+                // Only the first `dyn_size` bytes were filled in.
+                let used = fields.u64()? as usize;
+                sample.user_stack = stack[..used.min(size)].to_vec();
+            }
+        }
+        Some(())
+    })();
+    sample
+}
+
+/// The fields that end non-sample records when `sample_id_all` is set.
+#[derive(Debug, Clone, Copy, Default)]
+struct SampleId {
+    pid: u32,
+    tid: u32,
+    time: u64,
+    cpu: u32,
+}
+
+/// Read the `sample_id_all` fields by counting back from the end.
+#[allow(non_upper_case_globals)]
+fn parse_sample_id(body: &[u8], attr: &Attr) -> SampleId {
+    let sample_type = attr.sample_type();
+    let fields = [
+        perf_event_sample_format_PERF_SAMPLE_TID,
+        perf_event_sample_format_PERF_SAMPLE_TIME,
+        perf_event_sample_format_PERF_SAMPLE_ID,
+        perf_event_sample_format_PERF_SAMPLE_STREAM_ID,
+        perf_event_sample_format_PERF_SAMPLE_CPU,
+        perf_event_sample_format_PERF_SAMPLE_IDENTIFIER,
+    ];
+    let present = fields
+        .iter()
+        .filter(|bit| sample_bit(sample_type, **bit))
+        .count();
+    let mut id = SampleId::default();
+    let start = match body.len().checked_sub(8 * present) {
+        Some(start) if attr.sample_id_all() => start,
+        _ => return id,
+    };
+    let mut trailer = Fields {
+        bytes: &body[start..],
+    };
+    for bit in &fields {
+        if !sample_bit(sample_type, *bit) {
+            continue;
+        }
+        let value = trailer.u64().unwrap_or(0);
+        match *bit {
+            perf_event_sample_format_PERF_SAMPLE_TID => {
+                id.pid = value as u32;
+                id.tid = (value >> 32) as u32;
+            }
+            perf_event_sample_format_PERF_SAMPLE_TIME => id.time = value,
+            perf_event_sample_format_PERF_SAMPLE_CPU => id.cpu = value as u32,
+            _ => {}
+        }
+    }
+    id
+}
+
+impl PerfData {
+    /// The attr a record belongs to. Records start (samples) or end
+    /// (everything else) with their id when `PERF_SAMPLE_IDENTIFIER`
+    /// is set; otherwise only single-event files can tell.
+    pub fn attr_for_record(&self, record: &RawRecord) -> Option<&Attr> {
+        let first = self.attrs.first()?;
+        if !sample_bit(
+            first.sample_type(),
+            perf_event_sample_format_PERF_SAMPLE_IDENTIFIER,
+        ) {
+            return self.attr_for_id(0);
+        }
+        let body = &record.bytes[8..];
+        let id = if record.kind == perf_event_type_PERF_RECORD_SAMPLE {
+            body.get(..8)
+        } else {
+            body.len().checked_sub(8).map(|start| &body[start..])
+        }?;
+        self.attr_for_id(u64::from_le_bytes(id.try_into().unwrap()))
+    }
+
+    /// Decode `record`. Returns it with its timestamp,
+    /// which is 0 when the record does not carry one.
+    #[allow(non_upper_case_globals)]
+    pub fn decode(&self, record: &RawRecord) -> (u64, Record) {
+        let body = &record.bytes[8..];
+        let attr = match self.attr_for_record(record) {
+            Some(attr) => attr,
+            None => return (0, Record::Other(record.kind)),
+        };
+        let mut fields = Fields { bytes: body };
+        let id = parse_sample_id(body, attr);
+        let decoded = (|| -> Option<Record> {
+            Some(match record.kind {
+                perf_event_type_PERF_RECORD_SAMPLE => {
+                    let sample = parse_sample(body, record.misc & CPUMODE_MASK, attr);
+                    return Some(Record::Sample(sample));
+                }
+                perf_event_type_PERF_RECORD_MMAP | perf_event_type_PERF_RECORD_MMAP2 => {
+                    let (pid, tid) = fields.u32_pair()?;
+                    let start = fields.u64()?;
+                    let len = fields.u64()?;
+                    let pgoff = fields.u64()?;
+                    if record.kind == perf_event_type_PERF_RECORD_MMAP2 {
+                        // maj, min, ino, ino_generation or a build-id,
+                        // both 24 bytes; then prot and flags.
+                        fields.skip(24 + 8)?;
+                    }
+                    Record::Mmap(Mmap {
+                        pid,
+                        tid,
+                        start,
+                        len,
+                        pgoff,
+                        filename: fields.string(),
+                        cpumode: record.misc & CPUMODE_MASK,
+                    })
+                }
+                perf_event_type_PERF_RECORD_COMM => {
+                    let (pid, tid) = fields.u32_pair()?;
+                    Record::Comm(Comm {
+                        pid,
+                        tid,
+                        comm: fields.string(),
+                        exec: record.misc & PERF_RECORD_MISC_COMM_EXEC != 0,
+                    })
+                }
+                perf_event_type_PERF_RECORD_FORK | perf_event_type_PERF_RECORD_EXIT => {
+                    let (pid, ppid) = fields.u32_pair()?;
+                    let (tid, ptid) = fields.u32_pair()?;
+                    let task = Task {
+                        pid,
+                        ppid,
+                        tid,
+                        ptid,
+                        time: fields.u64()?,
+                    };
+                    if record.kind == perf_event_type_PERF_RECORD_FORK {
+                        Record::Fork(task)
+                    } else {
+                        Record::Exit(task)
+                    }
+                }
+                perf_event_type_PERF_RECORD_SWITCH
+                | perf_event_type_PERF_RECORD_SWITCH_CPU_WIDE => {
+                    let other = if record.kind == perf_event_type_PERF_RECORD_SWITCH_CPU_WIDE {
+                        Some(fields.u32_pair()?)
+                    } else {
+                        None
+                    };
+                    Record::Switch(Switch {
+                        pid: id.pid,
+                        tid: id.tid,
+                        cpu: id.cpu,
+                        time: id.time,
+                        out: record.misc & PERF_RECORD_MISC_SWITCH_OUT as u16 != 0,
+                        other,
+                    })
+                }
+                perf_event_type_PERF_RECORD_LOST => Record::Lost {
+                    id: fields.u64()?,
+                    lost: fields.u64()?,
+                },
+                kind => Record::Other(kind),
+            })
+        })()
+        .unwrap_or(Record::Other(record.kind));
+        let time = match &decoded {
+            Record::Sample(sample) => sample.time,
+            _ => id.time,
+        };
+        (time, decoded)
+    }
+
+    /// Every record decoded, in time order. The kernel writes each
+    /// CPU's ring buffer in order, but the buffers are drained one after
+    /// another, so records are sorted within each round, i.e. between
+    /// `PERF_RECORD_FINISHED_ROUND`s.
+    pub fn ordered_records(&self) -> Vec<Record> {
+        let mut ordered = Vec::new();
+        let mut round: Vec<(u64, Record)> = Vec::new();
+        let flush = |round: &mut Vec<(u64, Record)>, ordered: &mut Vec<Record>| {
+            // Stable, so untimed records stay next to what preceded them.
+            round.sort_by_key(|(time, _)| *time);
+            ordered.extend(round.drain(..).map(|(_, record)| record));
+        };
+        for raw in self.records() {
+            if raw.kind == super::PERF_RECORD_FINISHED_ROUND {
+                flush(&mut round, &mut ordered);
+                continue;
+            }
+            let (time, record) = self.decode(&raw);
+            // Keep untimed records in place relative to the previous one.
+            let time = if time == 0 {
+                round.last().map_or(0, |(t, _)| *t)
+            } else {
+                time
+            };
+            round.push((time, record));
+        }
+        flush(&mut round, &mut ordered);
+        ordered
+    }
+
+    /// The samples of the `index`th event, in time order.
+    #[cfg(test)]
+    pub(crate) fn samples_of(&self, index: usize) -> Vec<Sample> {
+        self.ordered_records()
+            .into_iter()
+            .filter_map(|record| match record {
+                Record::Sample(sample) if self.attr_index(sample.id) == Some(index) => Some(sample),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn decode_test() {
+    let sample_type = perf_event_sample_format_PERF_SAMPLE_IDENTIFIER
+        | perf_event_sample_format_PERF_SAMPLE_IP
+        | perf_event_sample_format_PERF_SAMPLE_TID
+        | perf_event_sample_format_PERF_SAMPLE_TIME
+        | perf_event_sample_format_PERF_SAMPLE_PERIOD;
+    let mut attr = vec![0; 120];
+    attr[24..32].copy_from_slice(&sample_type.to_le_bytes());
+    attr[40..48].copy_from_slice(&(1u64 << 18).to_le_bytes());
+    let data = PerfData {
+        attrs: vec![Attr {
+            bytes: attr,
+            ids: vec![42],
+        }],
+        ..Default::default()
+    };
+
+    let record = |kind: u32, misc: u16, body: &[u64], tail: &[u8]| {
+        let size = 8 + 8 * body.len() + tail.len();
+        let mut bytes = kind.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&misc.to_le_bytes());
+        bytes.extend_from_slice(&(size as u16).to_le_bytes());
+        for field in body {
+            bytes.extend_from_slice(&field.to_le_bytes());
+        }
+        bytes.extend_from_slice(tail);
+        bytes
+    };
+    let decode = |bytes: &[u8]| {
+        let raw = RawRecord {
+            kind: u32::from_le_bytes(bytes[..4].try_into().unwrap()),
+            misc: u16::from_le_bytes([bytes[4], bytes[5]]),
+            bytes,
+        };
+        data.decode(&raw)
+    };
+
+    let pid_tid = 7 | 8 << 32;
+    let sample = record(9, 2, &[42, 0x401000, pid_tid, 1000, 250_000], &[]);
+    assert_eq!(
+        decode(&sample),
+        (
+            1000,
+            Record::Sample(Sample {
+                id: 42,
+                ip: 0x401000,
+                pid: 7,
+                tid: 8,
+                time: 1000,
+                period: 250_000,
+                cpumode: 2,
+                ..Default::default()
+            })
+        )
+    );
+
+    // COMM: pid/tid, name padded to 8 bytes, then pid/tid, time, id.
+    let mut tail = b"true\0\0\0\0".to_vec();
+    for field in &[pid_tid, 900, 42] {
+        tail.extend_from_slice(&field.to_le_bytes());
+    }
+    let comm = record(3, 1 << 13, &[pid_tid], &tail);
+    assert_eq!(
+        decode(&comm),
+        (
+            900,
+            Record::Comm(Comm {
+                pid: 7,
+                tid: 8,
+                comm: "true".to_string(),
+                exec: true,
+            })
+        )
+    );
+
+    // SWITCH_CPU_WIDE out: the next pid/tid, then pid/tid, time, id.
+    let switch = record(15, 1 << 13, &[9 | 10 << 32, pid_tid, 950, 42], &[]);
+    assert_eq!(
+        decode(&switch),
+        (
+            950,
+            Record::Switch(Switch {
+                pid: 7,
+                tid: 8,
+                time: 950,
+                out: true,
+                other: Some((9, 10)),
+                ..Default::default()
+            })
+        )
+    );
+
+    // Registers are packed in mask order.
+    let regs = UserRegs {
+        abi: 2,
+        mask: 1 << 5 | 1 << 7,
+        values: vec![50, 70],
+    };
+    assert_eq!(
+        (regs.get(5), regs.get(6), regs.get(7)),
+        (Some(50), None, Some(70))
+    );
+}
@@ -24,16 +24,29 @@ impl FileDesc {
     /// configured event without any flags.
     /// Panics if `perf_event_open()` fails.
     pub fn new(event: &mut perf_event_attr, pid: Option<i32>, cpu: i32, group_fd: i32) -> Self {
-        let ret: i32;
+        match Self::try_new(event, pid, cpu, group_fd) {
+            Ok(fd) => fd,
+            Err(_) => panic!("Panic: system call perf_event_open() failed in FileDesc::new()"),
+        }
+    }
+    /// Set up performance monitoring for
+    /// configured event without any flags.
+    /// Returns an error if `perf_event_open()` fails.
+    pub fn try_new(
+        event: &mut perf_event_attr,
+        pid: Option<i32>,
+        cpu: i32,
+        group_fd: i32,
+    ) -> Result<Self, SysErr> {
         let pid = match pid {
             Some(x) => x as pid_t,
             None => 0_i32,
         };
-        ret = perf_event_open(event, pid as pid_t, cpu, group_fd, 0) as i32;
+        let ret = perf_event_open(event, pid as pid_t, cpu, group_fd, 0) as i32;
         if ret == -1 {
-            panic!("Panic: system call perf_event_open() failed in FileDesc::new()");
+            return Err(SysErr::OpenFail);
         }
-        Self(ret)
+        Ok(Self(ret))
     }
     /// Enable the performance counter
     /// associated with `fd`.
@@ -98,14 +111,22 @@ impl FileDesc {
         Ok(())
     }
     /// Report counter information to
-    /// specific file descriptor.
-    pub fn set_output(&self) -> Result<(), SysErr> {
-        todo!()
+    /// the ring buffer mapped for `target`.
+    pub fn set_output(&self, target: &FileDesc) -> Result<(), SysErr> {
+        let ret = unsafe { libc::ioctl(self.0, SET_OUTPUT as u64, target.0) };
+        if ret == -1 {
+            return Err(SysErr::IoFail);
+        }
+        Ok(())
     }
     /// Ignore counter output for event
     /// associated with `fd`.
     pub fn ignore_output(&self) -> Result<(), SysErr> {
-        todo!()
+        let ret = unsafe { libc::ioctl(self.0, SET_OUTPUT as u64, -1) };
+        if ret == -1 {
+            return Err(SysErr::IoFail);
+        }
+        Ok(())
     }
     /// Return event ID value
     /// associated with `fd`.
@@ -126,18 +147,33 @@ impl FileDesc {
     }
     /// Pause writing to ring-buffer
     /// for associated file descriptor.
+    /// Fails unless a ring buffer is mapped.
     pub fn pause_output(&self) -> Result<(), SysErr> {
-        todo!()
+        self.set_paused(1)
     }
     /// Resume writing to ring-buffer
     /// for associated file descriptor.
     pub fn resume_output(&self) -> Result<(), SysErr> {
-        todo!()
+        self.set_paused(0)
+    }
+    /// `PAUSE_OUTPUT` takes its flag by value, not by pointer.
+    fn set_paused(&self, paused: c_ulong) -> Result<(), SysErr> {
+        let ret = unsafe { libc::ioctl(self.0, PAUSE_OUTPUT as u64, paused) };
+        if ret == -1 {
+            return Err(SysErr::IoFail);
+        }
+        Ok(())
     }
     /// Modify the attributes for
     /// a specified event.
-    pub fn modify_attributes(&self, _event: *const perf_event_attr) -> Result<(), SysErr> {
-        todo!()
+    /// The kernel only supports this for breakpoint events.
+    pub fn modify_attributes(&self, event: &perf_event_attr) -> Result<(), SysErr> {
+        let arg: *const perf_event_attr = event;
+        let ret = unsafe { libc::ioctl(self.0, MODIFY_ATTRIBUTES as u64, arg) };
+        if ret == -1 {
+            return Err(SysErr::IoFail);
+        }
+        Ok(())
     }
     /// Read counter value associated
     /// with field of `FileDesc` caller.
@@ -150,6 +186,23 @@ impl FileDesc {
     }
 }
 
+/// Expose the raw file descriptor for
+/// `mmap()` and `poll()`.
+impl std::os::unix::io::AsRawFd for FileDesc {
+    fn as_raw_fd(&self) -> i32 {
+        self.0
+    }
+}
+
+/// Close the file descriptor, releasing the counter.
+impl Drop for FileDesc {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.0);
+        }
+    }
+}
+
 /// For documentation on `perf_event_open()`
 /// system call, see the Linux man page.
 fn perf_event_open(
@@ -216,3 +269,25 @@ fn read_test() {
     assert_ne!(cnt, 0);
     assert!(cnt > 0, "cnt = {}", cnt);
 }
+
+#[test]
+fn output_test() {
+    use crate::event::ring::RingBuffer;
+
+    let event = &mut perf_event_attr {
+        type_: perf_type_id_PERF_TYPE_SOFTWARE,
+        size: std::mem::size_of::<perf_event_attr>() as u32,
+        config: perf_sw_ids_PERF_COUNT_SW_CPU_CLOCK as u64,
+        ..Default::default()
+    };
+    event.__bindgen_anon_1.sample_period = 100_000;
+    event.set_disabled(1);
+    let fd = FileDesc::new(event, None, -1, -1);
+    // Nothing to pause until a ring buffer is mapped.
+    assert!(fd.pause_output().is_err());
+    let _ring = RingBuffer::new(&fd, 1).unwrap();
+    fd.pause_output().unwrap();
+    fd.resume_output().unwrap();
+    // Only breakpoints can be modified.
+    assert!(fd.modify_attributes(event).is_err());
+}
@@ -6,10 +6,11 @@
 // Disable cargo build warnings created due to using bindgen.
 #![allow(dead_code)]
 
-mod fd;
+pub(crate) mod fd;
 pub mod open;
+pub(crate) mod ring;
 mod sys;
-mod utils;
+pub(crate) mod utils;
 
 pub fn perf_event_hello() {
     println!("hello from your friendly perf_event file");
@@ -64,6 +64,18 @@ pub fn event_open(event: &StatEvent) -> Result<perf_event_attr, EventErr> {
             event_open.set_exclude_hv(1);
             Ok(*event_open)
         }
+        StatEvent::CpuClock => {
+            let event_open = &mut perf_event_attr {
+                type_: perf_type_id_PERF_TYPE_SOFTWARE,
+                size: PERF_EVENT_ATTR_SIZE,
+                config: perf_sw_ids_PERF_COUNT_SW_CPU_CLOCK as u64,
+                ..Default::default()
+            };
+            event_open.set_disabled(1);
+            event_open.set_exclude_kernel(1);
+            event_open.set_exclude_hv(1);
+            Ok(*event_open)
+        }
         StatEvent::ContextSwitches => {
             let event_open = &mut perf_event_attr {
                 type_: perf_type_id_PERF_TYPE_SOFTWARE,
@@ -154,6 +166,13 @@ impl Event {
         let fd = fd::FileDesc::new(e, pid, -1, -1);
         Self { fd, event }
     }
+    /// Construct a new event, returning an
+    /// error rather than panicking on failure.
+    pub fn try_new(event: StatEvent, pid: Option<i32>) -> Result<Self, SysErr> {
+        let e: &mut perf_event_attr = &mut event_open(&event).map_err(|_| SysErr::OpenFail)?;
+        let fd = fd::FileDesc::try_new(e, pid, -1, -1)?;
+        Ok(Self { fd, event })
+    }
     /// Start the counter on an event.
     pub fn start_counter(&self) -> Result<isize, SysErr> {
         match self.fd.enable() {
@@ -0,0 +1,110 @@
+//! A `RingBuffer` maps the ring buffer the kernel writes
+//! sampling records into, and copies records out of it.
+//! See "MMAP layout" in the `perf_event_open()` man page.
+
+use crate::bindings::*;
+use crate::event::fd::FileDesc;
+use crate::event::utils::*;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{fence, Ordering};
+
+/// Size of a `perf_event_header`.
+const HEADER_SIZE: usize = std::mem::size_of::<perf_event_header>();
+
+/// A mapped ring buffer: one metadata page
+/// followed by a power of two data pages.
+pub struct RingBuffer {
+    base: *mut u8,
+    len: usize,
+    page_size: usize,
+    /// Copy of the record being read, for records that wrap.
+    record: Vec<u8>,
+}
+
+impl RingBuffer {
+    /// Map `pages` data pages for `fd`, which must be a sampling event.
+    pub fn new(fd: &FileDesc, pages: usize) -> Result<Self, SysErr> {
+        if !pages.is_power_of_two() {
+            return Err(SysErr::MmapFail);
+        }
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let len = (pages + 1) * page_size;
+        let base = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd.as_raw_fd(),
+                0,
+            )
+        };
+        if base == libc::MAP_FAILED {
+            return Err(SysErr::MmapFail);
+        }
+        Ok(Self {
+            base: base as *mut u8,
+            len,
+            page_size,
+            record: Vec::new(),
+        })
+    }
+
+    fn metadata(&self) -> *mut perf_event_mmap_page {
+        self.base as *mut perf_event_mmap_page
+    }
+
+    /// Number of bytes in the data area.
+    pub fn data_size(&self) -> usize {
+        self.len - self.page_size
+    }
+
+    /// Pass every record written since the last call to `f`,
+    /// header included, then hand the space back to the kernel.
+    /// Returns the number of bytes read.
+    pub fn drain<F: FnMut(&[u8])>(&mut self, mut f: F) -> usize {
+        let size = self.data_size() as u64;
+        let meta = self.metadata();
+        let head = unsafe { std::ptr::read_volatile(&(*meta).data_head) };
+        // Pairs with the kernel's barrier after writing `data_head`.
+        fence(Ordering::Acquire);
+        let start = unsafe { std::ptr::read_volatile(&(*meta).data_tail) };
+        let data = unsafe { self.base.add(self.page_size) };
+
+        let mut tail = start;
+        while head - tail >= HEADER_SIZE as u64 {
+            let offset = (tail % size) as usize;
+            // Records are 8 byte aligned, so a header never wraps.
+            let header =
+                unsafe { std::ptr::read_unaligned(data.add(offset) as *const perf_event_header) };
+            let len = header.size as usize;
+            if len < HEADER_SIZE || head - tail < len as u64 {
+                break;
+            }
+            self.record.clear();
+            let first = len.min(size as usize - offset);
+            unsafe {
+                self.record
+                    .extend_from_slice(std::slice::from_raw_parts(data.add(offset), first));
+                self.record
+                    .extend_from_slice(std::slice::from_raw_parts(data, len - first));
+            }
+            f(&self.record);
+            tail += len as u64;
+        }
+
+        // Finish reading before the kernel may overwrite the space.
+        fence(Ordering::SeqCst);
+        unsafe { std::ptr::write_volatile(&mut (*meta).data_tail, tail) };
+        (tail - start) as usize
+    }
+}
+
+/// Unmap the ring buffer.
+impl Drop for RingBuffer {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.base as *mut libc::c_void, self.len);
+        }
+    }
+}
@@ -8,10 +8,12 @@ type Result<T, E> = std::result::Result<T, E>;
 /// Errors related to system calls.
 #[derive(Debug)]
 pub enum SysErr {
+    OpenFail,
     ReadFail,
     IoFail,
     IoArg,
     IoId,
+    MmapFail,
 }
 
 /// Errors related to handling specific events.
@@ -155,6 +155,14 @@ impl Application for Gui {
                         data_state.launch_options.verbose = value;
                     }
 
+                    Message::NoDemangleToggled(value) => {
+                        data_state.launch_options.no_demangle = value;
+                    }
+
+                    Message::StripHashToggled(value) => {
+                        data_state.launch_options.strip_hash = value;
+                    }
+
                     Message::InputChanged(value) => {
                         data_state.input_value = value;
                     }
@@ -179,16 +187,74 @@ impl Application for Gui {
                                 }
                             }
                             PerfEvent::Record => {
-                                //TODO: Add program here
+                                let task = task::Task::new(
+                                    Some(PerfEvent::Record),
+                                    Some(data_state.get_options()),
+                                    Some(data_state.input_value.to_string()),
+                                );
+
+                                match task {
+                                    Ok(t) => {
+                                        run_program(&t, data_state);
+                                        state.tasks.push(t);
+                                    }
+                                    Err(s) => {
+                                        println!("Error: {}", s);
+                                    }
+                                }
                             }
                             PerfEvent::Report => {
-                                //TODO: Add program here
+                                let input = data_state.input_value.to_string();
+                                let task = task::Task::new(
+                                    Some(PerfEvent::Report),
+                                    Some(data_state.get_options()),
+                                    Some(input).filter(|i| !i.is_empty()),
+                                );
+
+                                match task {
+                                    Ok(t) => {
+                                        run_program(&t, data_state);
+                                        state.tasks.push(t);
+                                    }
+                                    Err(s) => {
+                                        println!("Error: {}", s);
+                                    }
+                                }
                             }
                             PerfEvent::Annotate => {
-                                //TODO: Add program here
+                                let task = task::Task::new(
+                                    Some(PerfEvent::Annotate),
+                                    Some(data_state.get_options()),
+                                    Some(data_state.input_value.to_string()),
+                                );
+
+                                match task {
+                                    Ok(t) => {
+                                        run_program(&t, data_state);
+                                        state.tasks.push(t);
+                                    }
+                                    Err(s) => {
+                                        println!("Error: {}", s);
+                                    }
+                                }
                             }
                             PerfEvent::Top => {
-                                //TODO: Add program here
+                                let pid = data_state.input_value.to_string();
+                                let task = task::Task::new(
+                                    Some(PerfEvent::Top),
+                                    Some(data_state.get_options()),
+                                    Some(pid).filter(|p| !p.is_empty()),
+                                );
+
+                                match task {
+                                    Ok(t) => {
+                                        run_program(&t, data_state);
+                                        state.tasks.push(t);
+                                    }
+                                    Err(s) => {
+                                        println!("Error: {}", s);
+                                    }
+                                }
                             }
                             PerfEvent::Bench => {
                                 //TODO: Add program here
@@ -313,6 +379,9 @@ fn run_program(task: &task::Task, data_state: &mut Content) {
     // save task data
     // saveState.tasks.push(task);
 
-    //output to data pane
+    //output to data pane, followed by any diagnostics
     data_state.data = s.to_string();
+    data_state
+        .data
+        .push_str(&String::from_utf8_lossy(&output.stderr));
 }
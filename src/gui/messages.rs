@@ -16,6 +16,8 @@ pub mod main {
         JsonToggled(bool),
         ListToggled(bool),
         VerboseToggled(bool),
+        NoDemangleToggled(bool),
+        StripHashToggled(bool),
         LaunchCommand,
         RecieveTask(usize, TaskMessage),
     }
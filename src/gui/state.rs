@@ -117,11 +117,22 @@ pub mod pane {
             }
         }
 
+        /// Flags for the commands that show symbol names.
+        fn demangle_options(&self) -> &'static str {
+            if self.launch_options.no_demangle {
+                " --no-demangle"
+            } else if self.launch_options.strip_hash {
+                " --strip-hash"
+            } else {
+                ""
+            }
+        }
+
         pub fn get_options(&self) -> String {
             let mut res = String::new();
 
             match self.selected_command {
-                perf::PerfEvent::Stat => {
+                perf::PerfEvent::Stat | perf::PerfEvent::Record => {
                     if self.launch_options.cycles {
                         res.push_str(" --event cycles");
                     }
@@ -130,6 +141,27 @@ pub mod pane {
                     }
                 }
 
+                perf::PerfEvent::Report => {
+                    res.push_str(" --stdio");
+                    res.push_str(&self.demangle_options());
+                    if !self.input_value.is_empty() {
+                        res.push_str(" --input");
+                    }
+                }
+
+                perf::PerfEvent::Annotate => {
+                    res.push_str(" --stdio");
+                    res.push_str(&self.demangle_options());
+                }
+
+                perf::PerfEvent::Top => {
+                    res.push_str(" --stdio -n 3");
+                    res.push_str(&self.demangle_options());
+                    if !self.input_value.is_empty() {
+                        res.push_str(" -p");
+                    }
+                }
+
                 perf::PerfEvent::Test => {
                     if self.launch_options.json {
                         res.push_str(" --json");
@@ -171,6 +203,8 @@ pub mod pane {
         pub json: bool,
         pub list: bool,
         pub verbose: bool,
+        pub no_demangle: bool,
+        pub strip_hash: bool,
     }
 
     impl Default for Options {
@@ -181,6 +215,8 @@ pub mod pane {
                 json: false,
                 list: false,
                 verbose: false,
+                no_demangle: false,
+                strip_hash: false,
             }
         }
     }
@@ -118,8 +118,34 @@ pub mod panes {
                                         // Space::new(Length::Fill, Length::from(100)).into(),
                                         {
                                             match content.selected_command {
-                                                PerfEvent::Stat => Column::with_children(vec![
-                                                    Text::new("Program to run:")
+                                                PerfEvent::Stat | PerfEvent::Record => {
+                                                    Column::with_children(vec![
+                                                        Text::new("Program to run:")
+                                                            .color(style::widget::TEXT_COLOR)
+                                                            .into(),
+                                                        input.into(),
+                                                        Rule::horizontal(100).into(),
+                                                    ])
+                                                    .into()
+                                                }
+                                                PerfEvent::Report => Column::with_children(vec![
+                                                    Text::new("Data file (default perf.data):")
+                                                        .color(style::widget::TEXT_COLOR)
+                                                        .into(),
+                                                    input.into(),
+                                                    Rule::horizontal(100).into(),
+                                                ])
+                                                .into(),
+                                                PerfEvent::Annotate => Column::with_children(vec![
+                                                    Text::new("Function to annotate:")
+                                                        .color(style::widget::TEXT_COLOR)
+                                                        .into(),
+                                                    input.into(),
+                                                    Rule::horizontal(100).into(),
+                                                ])
+                                                .into(),
+                                                PerfEvent::Top => Column::with_children(vec![
+                                                    Text::new("Process id (empty for all):")
                                                         .color(style::widget::TEXT_COLOR)
                                                         .into(),
                                                     input.into(),
@@ -137,7 +163,7 @@ pub mod panes {
                                         {
                                             //these are the options for each individual event selected:
                                             match content.selected_command {
-                                                PerfEvent::Stat => {
+                                                PerfEvent::Stat | PerfEvent::Record => {
                                                     Container::new(Column::with_children(vec![
                                                         Checkbox::new(
                                                             content.launch_options.cycles,
@@ -183,6 +209,27 @@ pub mod panes {
                                                     ]))
                                                     .into()
                                                 }
+                                                PerfEvent::Report
+                                                | PerfEvent::Annotate
+                                                | PerfEvent::Top => {
+                                                    Container::new(Column::with_children(vec![
+                                                        Checkbox::new(
+                                                            content.launch_options.no_demangle,
+                                                            "Mangled names",
+                                                            Message::NoDemangleToggled,
+                                                        )
+                                                        .into(),
+                                                        Space::new(Length::Fill, Length::from(10))
+                                                            .into(),
+                                                        Checkbox::new(
+                                                            content.launch_options.strip_hash,
+                                                            "Strip Rust hashes",
+                                                            Message::StripHashToggled,
+                                                        )
+                                                        .into(),
+                                                    ]))
+                                                    .into()
+                                                }
 
                                                 _ => Container::new(Column::with_children(vec![]))
                                                     .into(),
@@ -262,7 +309,12 @@ pub mod task {
             match options {
                 Some(res) => {
                     command.push_str(res.as_str());
-                    if task_event == perf::PerfEvent::Stat {
+                    if task_event == perf::PerfEvent::Stat
+                        || task_event == perf::PerfEvent::Record
+                        || (task_event == perf::PerfEvent::Report && program.is_some())
+                        || task_event == perf::PerfEvent::Annotate
+                        || (task_event == perf::PerfEvent::Top && program.is_some())
+                    {
                         command.push(' ');
                     }
                     task_options = res;
@@ -0,0 +1,261 @@
+//! A `tracing-subscriber` layer that counts events per span.
+//!
+//! On every span enter and exit the layer reads the calling
+//! thread's counters (shared with `registry`) and adds the deltas
+//! to the span. When a span closes, its totals are added to
+//! aggregates keyed by `target::name`, so every request type
+//! gets its own hardware cost:
+//!
+//! ```no_run
+//! use tracing_subscriber::prelude::*;
+//!
+//! let layer = ruperf::layer::CounterLayer::new();
+//! let stats = layer.stats();
+//! tracing_subscriber::registry().with(layer).init();
+//! // ... spans are entered and closed ...
+//! stats.write_json(&mut std::io::stdout()).unwrap();
+//! ```
+
+use crate::registry::{self, FunctionStats};
+use crate::stat::StatEvent;
+use crate::utils::CounterError;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::thread::ThreadId;
+use std::time::{Duration, Instant};
+use tracing_core::span::{Attributes, Id};
+use tracing_core::Subscriber;
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+/// Events counted by `CounterLayer::new`.
+pub const DEFAULT_EVENTS: [StatEvent; 3] = [
+    StatEvent::Cycles,
+    StatEvent::Instructions,
+    StatEvent::ContextSwitches,
+];
+
+/// Counts events for every span entered on any thread.
+pub struct CounterLayer {
+    events: Vec<&'static str>,
+    stats: SpanStats,
+}
+
+/// Aggregated per-span totals, shared with the layer that fills them.
+/// `calls` counts closed spans and `elapsed` the time spent inside them.
+#[derive(Debug, Clone, Default)]
+pub struct SpanStats(Arc<Mutex<BTreeMap<String, FunctionStats>>>);
+
+/// When a span was entered, and the counters at the time.
+type Enter = (Instant, Vec<Option<isize>>);
+
+/// Per-span state kept in the span's extensions.
+#[derive(Default)]
+struct Timings {
+    /// Counter values at each enter not yet exited, innermost last,
+    /// by thread: a span can be entered on several threads at once,
+    /// and counters are only comparable on the thread that read them.
+    entered: HashMap<ThreadId, Vec<Enter>>,
+    busy: Duration,
+    counters: BTreeMap<&'static str, u64>,
+}
+
+impl CounterLayer {
+    /// Count cycles, instructions and context switches.
+    pub fn new() -> Self {
+        CounterLayer {
+            events: DEFAULT_EVENTS.iter().map(StatEvent::as_str).collect(),
+            stats: SpanStats::default(),
+        }
+    }
+
+    /// Count the given `ruperf stat` events instead.
+    pub fn with_events(events: &[&str]) -> Result<Self, CounterError> {
+        let events = events
+            .iter()
+            .map(|name| {
+                name.parse::<StatEvent>()
+                    .map(|event| event.as_str())
+                    .map_err(|_| CounterError::InvalidEvent(name.to_string()))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(CounterLayer {
+            events,
+            stats: SpanStats::default(),
+        })
+    }
+
+    /// A handle to the aggregates, usable after the
+    /// layer has been moved into a subscriber.
+    pub fn stats(&self) -> SpanStats {
+        self.stats.clone()
+    }
+
+    fn read(&self) -> Vec<Option<isize>> {
+        self.events.iter().map(|e| registry::read(e)).collect()
+    }
+}
+
+impl Default for CounterLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S> Layer<S> for CounterLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, _attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(Timings::default());
+        }
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        let starts = self.read();
+        if let Some(span) = ctx.span(id) {
+            if let Some(timings) = span.extensions_mut().get_mut::<Timings>() {
+                timings
+                    .entered
+                    .entry(std::thread::current().id())
+                    .or_default()
+                    .push((Instant::now(), starts));
+            }
+        }
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        let stops = self.read();
+        if let Some(span) = ctx.span(id) {
+            if let Some(timings) = span.extensions_mut().get_mut::<Timings>() {
+                let thread = std::thread::current().id();
+                let entered = timings.entered.get_mut(&thread).and_then(Vec::pop);
+                if timings.entered.get(&thread).is_some_and(Vec::is_empty) {
+                    timings.entered.remove(&thread);
+                }
+                if let Some((instant, starts)) = entered {
+                    timings.busy += instant.elapsed();
+                    for ((event, start), stop) in self.events.iter().zip(starts).zip(stops) {
+                        if let (Some(start), Some(stop)) = (start, stop) {
+                            *timings.counters.entry(event).or_insert(0) +=
+                                (stop - start).max(0) as u64;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(&id) {
+            Some(span) => span,
+            None => return,
+        };
+        let timings = match span.extensions_mut().remove::<Timings>() {
+            Some(timings) => timings,
+            None => return,
+        };
+        let name = format!("{}::{}", span.metadata().target(), span.name());
+        let mut stats = self.stats.0.lock().unwrap();
+        let stats = stats.entry(name).or_default();
+        stats.calls += 1;
+        stats.elapsed += timings.busy;
+        for (event, count) in timings.counters {
+            *stats.counters.entry(event.to_string()).or_insert(0) += count;
+        }
+    }
+}
+
+impl SpanStats {
+    /// A copy of the aggregates, keyed by `target::name`.
+    pub fn snapshot(&self) -> BTreeMap<String, FunctionStats> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Write the aggregates as JSON.
+    pub fn write_json(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        let json = serde_json::to_string_pretty(&self.snapshot()).map_err(std::io::Error::from)?;
+        writeln!(out, "{}", json)
+    }
+}
+
+#[test]
+fn span_stats_test() {
+    use tracing_subscriber::prelude::*;
+
+    let layer = CounterLayer::with_events(&["task-clock", "context-switches"]).unwrap();
+    let stats = layer.stats();
+    let subscriber = tracing_subscriber::registry().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        for _ in 0..2 {
+            let span = tracing::info_span!("request");
+            let _enter = span.enter();
+            std::thread::sleep(Duration::from_millis(2));
+        }
+    });
+
+    let snapshot = stats.snapshot();
+    let request = &snapshot["ruperf::layer::request"];
+    assert_eq!(request.calls, 2);
+    assert!(request.elapsed >= Duration::from_millis(4));
+    assert!(request.counters["context-switches"] >= 2);
+    assert!(request.counters.contains_key("task-clock"));
+
+    let mut json = Vec::new();
+    stats.write_json(&mut json).unwrap();
+    let parsed: BTreeMap<String, FunctionStats> = serde_json::from_slice(&json).unwrap();
+    assert_eq!(parsed, snapshot);
+
+    assert!(CounterLayer::with_events(&["bogus"]).is_err());
+}
+
+#[test]
+fn two_threads_test() {
+    use std::sync::Barrier;
+    use tracing_subscriber::prelude::*;
+
+    let spin = |time: Duration| {
+        let start = Instant::now();
+        while start.elapsed() < time {
+            std::hint::spin_loop();
+        }
+    };
+    let layer = CounterLayer::with_events(&["task-clock"]).unwrap();
+    let stats = layer.stats();
+    let subscriber = tracing_subscriber::registry().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        let span = tracing::info_span!("shared");
+        let barrier = Barrier::new(2);
+        // Both threads are inside the span when the first one leaves it.
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                // Run up this thread's task-clock before entering.
+                registry::read("task-clock");
+                spin(Duration::from_millis(50));
+                let enter = span.enter();
+                barrier.wait();
+                barrier.wait();
+                spin(Duration::from_millis(10));
+                drop(enter);
+                barrier.wait();
+            });
+            scope.spawn(|| {
+                barrier.wait();
+                let enter = span.enter();
+                barrier.wait();
+                barrier.wait();
+                drop(enter);
+            });
+        });
+    });
+
+    let snapshot = stats.snapshot();
+    let shared = &snapshot["ruperf::layer::shared"];
+    assert_eq!(shared.calls, 1);
+    // About 10ms of spinning; pairing one thread's exit with the other's
+    // enter would count the first 50ms too.
+    let task_clock = Duration::from_nanos(shared.counters["task-clock"]);
+    assert!(task_clock < Duration::from_millis(40), "{:?}", task_clock);
+}
@@ -0,0 +1,32 @@
+//! # ruperf
+//! Library interface to the performance counters behind the
+//! `ruperf` command line tool, for measuring code from inside
+//! Rust binaries and tests. See `Counters` to get started.
+
+// Lets `#[measure]` refer to `::ruperf` from inside this crate.
+extern crate self as ruperf;
+
+pub mod annotate;
+mod bindings;
+pub mod counters;
+pub mod data;
+pub mod event;
+#[cfg(feature = "tracing")]
+pub mod layer;
+#[cfg(feature = "criterion")]
+pub mod measurement;
+pub mod record;
+pub mod registry;
+pub mod report;
+pub mod script;
+pub mod session;
+pub mod srcline;
+pub mod stat;
+pub mod symbols;
+pub mod top;
+pub mod unwind;
+pub mod utils;
+
+pub use counters::{Counters, Report};
+pub use ruperf_macros::measure;
+pub use stat::StatEvent;
@@ -4,19 +4,27 @@
 //! <ul>
 //! <li>test</li>
 //! <li>stat</li>
+//! <li>stat-diff</li>
+//! <li>record</li>
+//! <li>report</li>
+//! <li>diff</li>
+//! <li>annotate</li>
+//! <li>script</li>
+//! <li>top</li>
 //! <li>gui</li>
 //! </ul>
 
-mod bindings;
-mod event;
 mod gui;
-mod stat;
 mod test;
-mod utils;
 
 extern crate structopt;
 use gui::*;
-use stat::*;
+use ruperf::annotate::*;
+use ruperf::record::*;
+use ruperf::report::*;
+use ruperf::script::*;
+use ruperf::stat::*;
+use ruperf::top::*;
 use structopt::StructOpt;
 use test::*;
 
@@ -30,6 +38,37 @@ enum Opt {
         about = "Collects hardware/software event counters",
     )]
     Stat(StatOptions),
+    #[structopt(
+        name = "stat-diff",
+        about = "Compares two sets of results saved with `stat --json`"
+    )]
+    StatDiff(StatDiffOptions),
+    #[structopt(
+        setting = structopt::clap::AppSettings::TrailingVarArg,
+        setting = structopt::clap::AppSettings::AllowLeadingHyphen,
+        name = "record",
+        about = "Samples a command into a perf.data file",
+    )]
+    Record(RecordOptions),
+    #[structopt(name = "report", about = "Summarizes the samples in a perf.data file")]
+    Report(ReportOptions),
+    #[structopt(name = "diff", about = "Compares the profiles of two perf.data files")]
+    Diff(DiffOptions),
+    #[structopt(
+        name = "annotate",
+        about = "Shows the samples of a function over its disassembly"
+    )]
+    Annotate(AnnotateOptions),
+    #[structopt(
+        name = "script",
+        about = "Prints the samples in a perf.data file, one per line"
+    )]
+    Script(ScriptOptions),
+    #[structopt(
+        name = "top",
+        about = "Shows the functions taking the most samples, live"
+    )]
+    Top(TopOptions),
     #[structopt(
         setting = structopt::clap::AppSettings::TrailingVarArg,
         setting = structopt::clap::AppSettings::AllowLeadingHyphen,
@@ -49,7 +88,14 @@ enum Opt {
 fn main() {
     let opt = Opt::from_args();
     match opt {
-        Opt::Stat(x) => run_stat(x),
+        Opt::Stat(x) => std::process::exit(run_stat(x)),
+        Opt::StatDiff(x) => std::process::exit(run_stat_diff(x)),
+        Opt::Record(x) => std::process::exit(run_record(x)),
+        Opt::Report(x) => std::process::exit(run_report(x)),
+        Opt::Diff(x) => std::process::exit(run_diff(x)),
+        Opt::Annotate(x) => std::process::exit(run_annotate(x)),
+        Opt::Script(x) => std::process::exit(run_script(x)),
+        Opt::Top(x) => std::process::exit(run_top(x)),
         Opt::Test(x) => run_test(&x),
         Opt::Gui(x) => {
             run_gui(&x).unwrap();
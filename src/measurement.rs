@@ -0,0 +1,116 @@
+//! A criterion `Measurement` backed by ruperf's counters.
+//!
+//! Enabled with the `criterion` cargo feature. Wall time is noisy
+//! on shared machines, while counts such as instructions retired
+//! barely move between runs. `EventMeasurement` reports any
+//! `StatEvent` per iteration in place of wall time:
+//!
+//! ```no_run
+//! use criterion::{criterion_group, criterion_main, Criterion};
+//! use ruperf::measurement::EventMeasurement;
+//!
+//! fn bench(c: &mut Criterion<EventMeasurement>) {
+//!     c.bench_function("sum", |b| b.iter(|| (0..1000u64).sum::<u64>()));
+//! }
+//!
+//! criterion_group! {
+//!     name = benches;
+//!     config = Criterion::default().with_measurement(EventMeasurement::instructions());
+//!     targets = bench
+//! }
+//! criterion_main!(benches);
+//! ```
+
+use crate::event::open::Event;
+use crate::stat::StatEvent;
+use criterion::measurement::{Measurement, ValueFormatter};
+use criterion::Throughput;
+
+/// Counts one event on the benchmarking thread.
+pub struct EventMeasurement {
+    event: Event,
+}
+
+impl EventMeasurement {
+    /// Count `event` on the calling thread.
+    /// Panics if the counter cannot be opened.
+    pub fn new(event: StatEvent) -> Self {
+        Self {
+            event: Event::new(event, None),
+        }
+    }
+
+    /// Count instructions retired.
+    pub fn instructions() -> Self {
+        Self::new(StatEvent::Instructions)
+    }
+
+    /// Count CPU cycles.
+    pub fn cycles() -> Self {
+        Self::new(StatEvent::Cycles)
+    }
+}
+
+/// Counts instructions, the most stable choice.
+impl Default for EventMeasurement {
+    fn default() -> Self {
+        Self::instructions()
+    }
+}
+
+impl Measurement for EventMeasurement {
+    type Intermediate = isize;
+    type Value = u64;
+
+    fn start(&self) -> Self::Intermediate {
+        self.event.start_counter().expect("Could not start counter")
+    }
+
+    fn end(&self, start: Self::Intermediate) -> Self::Value {
+        let stop = self.event.stop_counter().expect("Could not stop counter");
+        (stop - start).max(0) as u64
+    }
+
+    fn add(&self, v1: &Self::Value, v2: &Self::Value) -> Self::Value {
+        v1 + v2
+    }
+
+    fn zero(&self) -> Self::Value {
+        0
+    }
+
+    fn to_f64(&self, value: &Self::Value) -> f64 {
+        *value as f64
+    }
+
+    fn formatter(&self) -> &dyn ValueFormatter {
+        self
+    }
+}
+
+/// Counts are shown unscaled, labelled with the event name.
+impl ValueFormatter for EventMeasurement {
+    fn scale_values(&self, _typical_value: f64, _values: &mut [f64]) -> &'static str {
+        self.event.event.as_str()
+    }
+
+    fn scale_throughputs(
+        &self,
+        _typical_value: f64,
+        throughput: &Throughput,
+        values: &mut [f64],
+    ) -> &'static str {
+        let (per, unit) = match throughput {
+            Throughput::Bytes(n) => (*n, "per byte"),
+            Throughput::Elements(n) => (*n, "per element"),
+        };
+        for value in values {
+            *value /= per as f64;
+        }
+        unit
+    }
+
+    fn scale_for_machines(&self, _values: &mut [f64]) -> &'static str {
+        self.event.event.as_str()
+    }
+}
@@ -0,0 +1,737 @@
+//! # Record driver.
+//! <p> Usage: <em> ruperf record [-e EVENT] [-F FREQ | -c PERIOD] [-g | --call-graph MODE] [--switch-events] [COMMAND] [ARGS] </em>
+//! Samples COMMAND and its children on every CPU, copying each record
+//! the kernel writes to the ring buffers into a perf.data file. </p>
+
+extern crate structopt;
+use crate::bindings::*;
+use crate::data::{self, Attr, BuildId, EventDesc, Features, NrCpus, Writer};
+use crate::event::fd::FileDesc;
+use crate::event::open::event_open;
+use crate::event::ring::RingBuffer;
+use crate::stat::{launch_stat_process, rusage, StatEvent};
+use crate::unwind::PERF_REGS_MASK;
+use crate::utils::{ParseError, RecordError};
+use os_pipe::{pipe, PipeReader, PipeWriter};
+use std::collections::BTreeSet;
+use std::io::prelude::*;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Instant;
+use structopt::StructOpt;
+
+/// Sampling frequency when neither `-F` nor `-c` is given.
+const DEFAULT_FREQ: u64 = 4000;
+
+/// Bytes of user stack copied with each sample for `--call-graph dwarf`.
+const DEFAULT_STACK_DUMP: u32 = 8192;
+
+/// The most stack a sample can carry, as its size is a `u16`.
+const MAX_STACK_DUMP: u32 = 65528;
+
+/// Configuration settings for running record. A program to profile is a
+/// required argument. Cycles are sampled if no events are specified.
+/// See `./ruperf record --help` for more information.
+#[derive(Debug, StructOpt)]
+pub struct RecordOptions {
+    #[structopt(short, long, help = "Event to sample", number_of_values = 1)]
+    pub event: Vec<StatEvent>,
+
+    #[structopt(
+        short = "F",
+        long,
+        help = "Sample at this average rate in Hz",
+        conflicts_with = "count"
+    )]
+    pub freq: Option<u64>,
+
+    #[structopt(short = "c", long, help = "Sample once every n events")]
+    pub count: Option<u64>,
+
+    #[structopt(short = "g", help = "Record call chains by walking frame pointers")]
+    pub call_graph: bool,
+
+    #[structopt(
+        long = "call-graph",
+        help = "Record call chains with fp, or dwarf[,size] to copy `size` bytes of user stack"
+    )]
+    pub call_graph_mode: Option<CallGraphMode>,
+
+    #[structopt(
+        short,
+        long,
+        default_value = "perf.data",
+        help = "File to write samples to",
+        parse(from_os_str)
+    )]
+    pub output: PathBuf,
+
+    #[structopt(
+        short,
+        long = "mmap-pages",
+        default_value = "128",
+        parse(try_from_str = parse_mmap_pages),
+        help = "Data pages in each ring buffer, a power of two"
+    )]
+    pub mmap_pages: usize,
+
+    #[structopt(
+        long = "switch-events",
+        help = "Record when threads are switched in and out"
+    )]
+    pub switch_events: bool,
+
+    // Allows multiple arguments to be passed, collects everything remaining on
+    // the command line
+    #[structopt(required = true, help = "Command to run")]
+    pub command: Vec<String>,
+}
+
+impl RecordOptions {
+    /// How call chains are recorded, if at all.
+    pub fn call_chains(&self) -> Option<CallGraphMode> {
+        match self.call_graph_mode {
+            Some(mode) => Some(mode),
+            None if self.call_graph => Some(CallGraphMode::FramePointer),
+            None => None,
+        }
+    }
+}
+
+/// How call chains are recorded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallGraphMode {
+    /// The kernel walks the frame pointers.
+    FramePointer,
+    /// Copy this many bytes of user stack, along with the user
+    /// registers, to be unwound by `ruperf report`.
+    Dwarf(u32),
+}
+
+impl FromStr for CallGraphMode {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseError::InvalidCallGraph(s.to_string());
+        let mut parts = s.splitn(2, ',');
+        match (parts.next(), parts.next()) {
+            (Some("fp"), None) => Ok(CallGraphMode::FramePointer),
+            (Some("dwarf"), None) => Ok(CallGraphMode::Dwarf(DEFAULT_STACK_DUMP)),
+            (Some("dwarf"), Some(size)) => {
+                let size: u32 = size.parse().map_err(|_| invalid())?;
+                if size == 0 || size > MAX_STACK_DUMP {
+                    return Err(invalid());
+                }
+                // The kernel wants whole words.
+                Ok(CallGraphMode::Dwarf(size.div_ceil(8) * 8))
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// How often an event takes a sample.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Period {
+    /// Let the kernel adjust the period to hit this many samples a second.
+    Freq(u64),
+    /// Sample once every this many events.
+    Count(u64),
+}
+
+/// Build the attributes for sampling `event`. `tracking` asks for
+/// the mmap, comm and fork records needed to make sense of samples,
+/// which only one event per ring buffer has to carry, and for context
+/// switch records as well with `switch_events`.
+fn sampling_attr(
+    event: StatEvent,
+    period: Period,
+    call_graph: Option<CallGraphMode>,
+    tracking: bool,
+    switch_events: bool,
+    wakeup_bytes: u32,
+) -> perf_event_attr {
+    let mut attr = event_open(&event).unwrap();
+    match period {
+        Period::Freq(freq) => {
+            attr.set_freq(1);
+            attr.__bindgen_anon_1.sample_freq = freq;
+        }
+        Period::Count(count) => attr.__bindgen_anon_1.sample_period = count,
+    }
+    attr.sample_type = perf_event_sample_format_PERF_SAMPLE_IDENTIFIER
+        | perf_event_sample_format_PERF_SAMPLE_IP
+        | perf_event_sample_format_PERF_SAMPLE_TID
+        | perf_event_sample_format_PERF_SAMPLE_TIME
+        | perf_event_sample_format_PERF_SAMPLE_CPU
+        | perf_event_sample_format_PERF_SAMPLE_PERIOD;
+    match call_graph {
+        Some(CallGraphMode::FramePointer) => {
+            attr.sample_type |= perf_event_sample_format_PERF_SAMPLE_CALLCHAIN;
+        }
+        Some(CallGraphMode::Dwarf(size)) => {
+            // The kernel still walks its own frames.
+            attr.sample_type |= perf_event_sample_format_PERF_SAMPLE_CALLCHAIN
+                | perf_event_sample_format_PERF_SAMPLE_REGS_USER
+                | perf_event_sample_format_PERF_SAMPLE_STACK_USER;
+            attr.set_exclude_callchain_user(1);
+            attr.sample_regs_user = PERF_REGS_MASK;
+            attr.sample_stack_user = size;
+        }
+        None => {}
+    }
+    attr.set_sample_id_all(1);
+    // Follow the command into its children, starting at exec.
+    attr.set_inherit(1);
+    attr.set_enable_on_exec(1);
+    attr.set_watermark(1);
+    attr.__bindgen_anon_2.wakeup_watermark = wakeup_bytes;
+    if tracking {
+        attr.set_mmap(1);
+        attr.set_mmap2(1);
+        attr.set_comm(1);
+        attr.set_comm_exec(1);
+        attr.set_task(1);
+        attr.set_context_switch(switch_events as u64);
+    }
+    attr
+}
+
+/// Parse `--mmap-pages`, which must be a power of two.
+pub fn parse_mmap_pages(s: &str) -> Result<usize, ParseError> {
+    match s.parse::<usize>() {
+        Ok(pages) if pages.is_power_of_two() => Ok(pages),
+        _ => Err(ParseError::InvalidMmapPages(s.to_string())),
+    }
+}
+
+/// Parse a CPU list such as `0-3,8`, as found in
+/// `/sys/devices/system/cpu/online`.
+fn parse_cpu_list(list: &str) -> Vec<i32> {
+    let mut cpus = Vec::new();
+    for range in list.trim().split(',').filter(|r| !r.is_empty()) {
+        let mut bounds = range.splitn(2, '-').map(|n| n.trim().parse::<i32>());
+        match (bounds.next(), bounds.next()) {
+            (Some(Ok(first)), None) => cpus.push(first),
+            (Some(Ok(first)), Some(Ok(last))) => cpus.extend(first..=last),
+            _ => {}
+        }
+    }
+    cpus
+}
+
+/// Every CPU that is up.
+pub(crate) fn online_cpus() -> Vec<i32> {
+    std::fs::read_to_string("/sys/devices/system/cpu/online")
+        .map(|list| parse_cpu_list(&list))
+        .unwrap_or_else(|_| vec![0])
+}
+
+/// Cap a requested frequency at what the kernel allows.
+pub(crate) fn max_sample_rate(freq: u64) -> u64 {
+    let max = std::fs::read_to_string("/proc/sys/kernel/perf_event_max_sample_rate")
+        .ok()
+        .and_then(|rate| rate.trim().parse::<u64>().ok());
+    match max {
+        Some(max) if freq > max => {
+            eprintln!(
+                "Warning: frequency {} Hz is above perf_event_max_sample_rate, using {} Hz",
+                freq, max
+            );
+            max
+        }
+        _ => freq,
+    }
+}
+
+/// Sampling events for a set of (pid, cpu) targets, with one ring
+/// buffer per target that all events on that target write into.
+pub struct Sampler {
+    attrs: Vec<Attr>,
+    rings: Vec<RingBuffer>,
+    fds: Vec<FileDesc>,
+}
+
+impl Sampler {
+    /// Open every event in `events` for each `(pid, cpu)` of `targets`,
+    /// as `perf_event_open` takes them: a pid of -1 is every process on
+    /// the CPU, a CPU of -1 is the process on any CPU.
+    pub fn open(
+        events: &[StatEvent],
+        targets: &[(i32, i32)],
+        period: Period,
+        call_graph: Option<CallGraphMode>,
+        switch_events: bool,
+        pages: usize,
+    ) -> Result<Self, RecordError> {
+        // Checked here so that the error does not come from a stale errno.
+        if !pages.is_power_of_two() {
+            return Err(RecordError::MmapPages(pages));
+        }
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let wakeup_bytes = (pages * page_size / 4) as u32;
+        let mut attrs: Vec<perf_event_attr> = events
+            .iter()
+            .enumerate()
+            .map(|(i, event)| {
+                sampling_attr(
+                    *event,
+                    period,
+                    call_graph,
+                    i == 0,
+                    switch_events,
+                    wakeup_bytes,
+                )
+            })
+            .collect();
+        let mut ids = vec![Vec::new(); events.len()];
+        let mut rings = Vec::with_capacity(targets.len());
+        let mut fds = Vec::with_capacity(targets.len() * events.len());
+
+        for (pid, cpu) in targets {
+            let first = fds.len();
+            for (i, attr) in attrs.iter_mut().enumerate() {
+                let fd = FileDesc::try_new(attr, Some(*pid), *cpu, -1).map_err(|_| {
+                    RecordError::Open(
+                        events[i].as_str().to_string(),
+                        *cpu,
+                        std::io::Error::last_os_error(),
+                    )
+                })?;
+                if i == 0 {
+                    rings.push(
+                        RingBuffer::new(&fd, pages).map_err(|_| {
+                            RecordError::Mmap(pages, std::io::Error::last_os_error())
+                        })?,
+                    );
+                } else {
+                    fd.set_output(&fds[first]).map_err(|_| {
+                        RecordError::Open(
+                            events[i].as_str().to_string(),
+                            *cpu,
+                            std::io::Error::last_os_error(),
+                        )
+                    })?;
+                }
+                // Without its id, the event's samples could not be told apart.
+                let id = fd.id().map_err(|_| {
+                    RecordError::Open(
+                        events[i].as_str().to_string(),
+                        *cpu,
+                        std::io::Error::last_os_error(),
+                    )
+                })?;
+                ids[i].push(id as u64);
+                fds.push(fd);
+            }
+        }
+
+        let attrs = attrs
+            .iter()
+            .zip(ids)
+            .map(|(attr, ids)| Attr::new(attr, ids))
+            .collect();
+        Ok(Self { attrs, rings, fds })
+    }
+
+    /// Attributes and ids of every event, as stored in perf.data.
+    pub fn attrs(&self) -> &[Attr] {
+        &self.attrs
+    }
+
+    /// Start every event now, rather than when the command execs.
+    pub fn enable(&self) -> std::io::Result<()> {
+        for fd in &self.fds {
+            fd.enable().map_err(|_| std::io::Error::last_os_error())?;
+        }
+        Ok(())
+    }
+
+    /// Wait until a ring buffer fills past its watermark, `pidfd` becomes
+    /// readable, or `timeout_ms` passes. Returns whether a ring woke us.
+    pub fn poll(&self, pidfd: i32, timeout_ms: i32) -> bool {
+        let mut fds: Vec<libc::pollfd> = self
+            .rings
+            .iter()
+            .enumerate()
+            .map(|(i, _)| libc::pollfd {
+                fd: self.fds[i * self.attrs.len()].as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+        if pidfd >= 0 {
+            fds.push(libc::pollfd {
+                fd: pidfd,
+                events: libc::POLLIN,
+                revents: 0,
+            });
+        }
+        unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout_ms) };
+        fds[..self.rings.len()]
+            .iter()
+            .any(|fd| fd.revents & libc::POLLIN != 0)
+    }
+
+    /// Pass every record waiting in the ring buffers to `f`.
+    /// Returns the number of bytes read.
+    pub fn drain<F: FnMut(&[u8])>(&mut self, mut f: F) -> usize {
+        self.rings.iter_mut().map(|ring| ring.drain(&mut f)).sum()
+    }
+}
+
+/// What was written, for the summary printed at the end.
+#[derive(Debug, Default, PartialEq)]
+struct Totals {
+    wakeups: usize,
+    samples: u64,
+    lost_records: u64,
+    lost_samples: u64,
+    /// Files mapped by the command, to look up build-ids for.
+    mapped: BTreeSet<String>,
+}
+
+impl Totals {
+    fn count(&mut self, record: &[u8]) {
+        let kind = u32::from_le_bytes([record[0], record[1], record[2], record[3]]);
+        if kind == perf_event_type_PERF_RECORD_SAMPLE {
+            self.samples += 1;
+        } else if kind == perf_event_type_PERF_RECORD_LOST && record.len() >= 24 {
+            // header, id, lost
+            let mut lost = [0; 8];
+            lost.copy_from_slice(&record[16..24]);
+            self.lost_records += 1;
+            self.lost_samples += u64::from_le_bytes(lost);
+        } else if let Some(filename) = mapped_file(kind, record) {
+            if filename.starts_with('/') && !self.mapped.contains(filename) {
+                self.mapped.insert(filename.to_string());
+            }
+        }
+    }
+}
+
+/// The file an MMAP or MMAP2 record maps, if any.
+fn mapped_file(kind: u32, record: &[u8]) -> Option<&str> {
+    // header, pid, tid, addr, len, pgoff,
+    // then for MMAP2: maj, min, ino, ino_generation, prot, flags
+    let offset = if kind == perf_event_type_PERF_RECORD_MMAP {
+        40
+    } else if kind == perf_event_type_PERF_RECORD_MMAP2 {
+        72
+    } else {
+        return None;
+    };
+    let name = record.get(offset..)?;
+    let end = name.iter().position(|b| *b == 0)?;
+    std::str::from_utf8(&name[..end]).ok()
+}
+
+/// Describe the machine and the recording for the feature sections.
+fn features(events: &[StatEvent], attrs: &[Attr], mapped: &BTreeSet<String>) -> Features {
+    let mut uts: libc::utsname = unsafe { std::mem::zeroed() };
+    let uts_field = |field: &[libc::c_char]| {
+        let bytes: Vec<u8> = field
+            .iter()
+            .take_while(|c| **c != 0)
+            .map(|c| *c as u8)
+            .collect();
+        String::from_utf8_lossy(&bytes).into_owned()
+    };
+    let (hostname, osrelease) = if unsafe { libc::uname(&mut uts) } == 0 {
+        (
+            Some(uts_field(&uts.nodename)),
+            Some(uts_field(&uts.release)),
+        )
+    } else {
+        (None, None)
+    };
+    let present = std::fs::read_to_string("/sys/devices/system/cpu/present")
+        .map(|list| parse_cpu_list(&list))
+        .unwrap_or_default();
+    let online = online_cpus();
+    let exe = std::env::current_exe()
+        .map(|path| path.display().to_string())
+        .unwrap_or_default();
+
+    Features {
+        build_ids: Some(mapped.iter().filter_map(BuildId::for_file).collect()),
+        hostname,
+        osrelease,
+        nr_cpus: Some(NrCpus {
+            available: present
+                .iter()
+                .max()
+                .map_or(online.len() as u32, |max| *max as u32 + 1),
+            online: online.len() as u32,
+        }),
+        cmdline: Some(std::iter::once(exe).chain(std::env::args()).collect()),
+        event_desc: Some(
+            events
+                .iter()
+                .zip(attrs)
+                .map(|(event, attr)| EventDesc {
+                    name: event.as_str().to_string(),
+                    attr: attr.clone(),
+                })
+                .collect(),
+        ),
+        ..Default::default()
+    }
+}
+
+/// Copy one pass over the ring buffers to `writer`,
+/// closing it with a `PERF_RECORD_FINISHED_ROUND`.
+fn write_round<W: Write + std::io::Seek>(
+    sampler: &mut Sampler,
+    writer: &mut Writer<W>,
+    totals: &mut Totals,
+) -> std::io::Result<()> {
+    let mut result = Ok(());
+    let read = sampler.drain(|record| {
+        totals.count(record);
+        if result.is_ok() {
+            result = writer.write_record(record);
+        }
+    });
+    result?;
+    if read > 0 {
+        let mut round = [0; 8];
+        round[..4].copy_from_slice(&data::PERF_RECORD_FINISHED_ROUND.to_le_bytes());
+        round[6..].copy_from_slice(&8u16.to_le_bytes());
+        writer.write_record(&round)?;
+    }
+    Ok(())
+}
+
+/// Sample `command` until it exits, writing
+/// every record to `options.output`.
+fn record(options: &RecordOptions, events: &[StatEvent]) -> Result<Totals, RecordError> {
+    let period = match (options.freq, options.count) {
+        (_, Some(count)) => Period::Count(count),
+        (Some(freq), None) => Period::Freq(max_sample_rate(freq)),
+        (None, None) => Period::Freq(max_sample_rate(DEFAULT_FREQ)),
+    };
+
+    let (reader, writer) = pipe().unwrap();
+    let (parent_reader, parent_writer) = pipe().unwrap();
+    let child_reader = reader.try_clone().unwrap();
+    let child_writer = parent_writer.try_clone().unwrap();
+    let pid = launch_stat_process(
+        Instant::now(),
+        options.command.clone(),
+        child_reader,
+        child_writer,
+    );
+
+    let result = sample(options, events, pid, period, writer, parent_reader);
+    if result.is_err() {
+        // The child may still be waiting on the pipe to exec.
+        unsafe { libc::kill(pid, libc::SIGKILL) };
+    }
+    let _ = rusage::reap(pid);
+    result
+}
+
+/// The part of `record` after `pid` has forked: open the events, let
+/// the command exec and write its records until it exits. Leaves `pid`
+/// unreaped for the caller.
+fn sample(
+    options: &RecordOptions,
+    events: &[StatEvent],
+    pid: i32,
+    period: Period,
+    mut writer: PipeWriter,
+    mut parent_reader: PipeReader,
+) -> Result<Totals, RecordError> {
+    let targets: Vec<(i32, i32)> = online_cpus().into_iter().map(|cpu| (pid, cpu)).collect();
+    let mut sampler = Sampler::open(
+        events,
+        &targets,
+        period,
+        options.call_chains(),
+        options.switch_events,
+        options.mmap_pages,
+    )?;
+    let output = options.output.display().to_string();
+    let mut file = Writer::create(&options.output, sampler.attrs())
+        .map_err(|e| RecordError::Output(output.clone(), e))?;
+
+    // Let the child exec, which enables the events.
+    writer.write_all(&[1]).unwrap();
+    writer.flush().unwrap();
+    let mut buffer = [0; 16];
+    parent_reader.read_exact(&mut buffer).unwrap();
+
+    let pidfd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) } as i32;
+    let timeout_ms = if pidfd >= 0 { -1 } else { 100 };
+    let mut totals = Totals::default();
+    let written = (|| {
+        loop {
+            if sampler.poll(pidfd, timeout_ms) {
+                totals.wakeups += 1;
+            }
+            write_round(&mut sampler, &mut file, &mut totals)?;
+            if rusage::has_exited(pid).unwrap_or(true) {
+                break;
+            }
+        }
+        write_round(&mut sampler, &mut file, &mut totals)
+    })();
+    if pidfd >= 0 {
+        unsafe { libc::close(pidfd) };
+    }
+    written.map_err(|e| RecordError::Output(output.clone(), e))?;
+
+    let size = file.data_size();
+    file.finish(&features(events, sampler.attrs(), &totals.mapped))
+        .map_err(|e| RecordError::Output(output.clone(), e))?;
+    eprintln!(
+        "[ ruperf record: Woken up {} times to write data ]",
+        totals.wakeups
+    );
+    eprintln!(
+        "[ ruperf record: Captured and wrote {:.3} MB {} ({} samples) ]",
+        size as f64 / (1024.0 * 1024.0),
+        output,
+        totals.samples
+    );
+    Ok(totals)
+}
+
+/// Run record, falling back from cycles to cpu-clock when no event
+/// was asked for and the CPU has no cycle counter (e.g. in a VM).
+/// Returns the process exit code.
+pub fn run_record(options: RecordOptions) -> i32 {
+    let mut events = options.event.clone();
+    let fallback = events.is_empty();
+    if fallback {
+        events.push(StatEvent::Cycles);
+    }
+
+    let mut result = record(&options, &events);
+    if fallback && matches!(result, Err(RecordError::Open(..))) {
+        eprintln!("cycles is not supported here, sampling cpu-clock instead");
+        result = record(&options, &[StatEvent::CpuClock]);
+    }
+
+    match result {
+        Ok(totals) => {
+            if totals.lost_records > 0 {
+                eprintln!(
+                    "Warning: lost {} samples in {} LOST records. \
+                     Try a larger --mmap-pages or a lower --freq.",
+                    totals.lost_samples, totals.lost_records
+                );
+            }
+            0
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn parse_cpu_list_test() {
+    assert_eq!(parse_cpu_list("0-3,8\n"), vec![0, 1, 2, 3, 8]);
+    assert_eq!(parse_cpu_list("5"), vec![5]);
+    assert_eq!(parse_cpu_list(""), Vec::<i32>::new());
+}
+
+#[test]
+fn call_graph_mode_test() {
+    assert_eq!("fp".parse().ok(), Some(CallGraphMode::FramePointer));
+    assert_eq!(
+        "dwarf".parse().ok(),
+        Some(CallGraphMode::Dwarf(DEFAULT_STACK_DUMP))
+    );
+    assert_eq!("dwarf,1000".parse().ok(), Some(CallGraphMode::Dwarf(1000)));
+    assert_eq!("dwarf,1001".parse().ok(), Some(CallGraphMode::Dwarf(1008)));
+    assert!("dwarf,0".parse::<CallGraphMode>().is_err());
+    assert!("dwarf,70000".parse::<CallGraphMode>().is_err());
+    assert!("lbr".parse::<CallGraphMode>().is_err());
+}
+
+#[test]
+fn mmap_pages_test() {
+    assert_eq!(parse_mmap_pages("64").unwrap(), 64);
+    assert!(parse_mmap_pages("0").is_err());
+    assert!(parse_mmap_pages("100").is_err());
+    let opened = Sampler::open(
+        &[StatEvent::CpuClock],
+        &[(0, -1)],
+        Period::Freq(1000),
+        None,
+        false,
+        3,
+    );
+    assert!(matches!(opened, Err(RecordError::MmapPages(3))));
+}
+
+#[test]
+fn record_test() {
+    let output = std::env::temp_dir().join(format!("ruperf-record-{}.data", std::process::id()));
+    let options = RecordOptions {
+        event: vec![StatEvent::CpuClock],
+        freq: Some(1000),
+        count: None,
+        call_graph: false,
+        call_graph_mode: None,
+        output: output.clone(),
+        mmap_pages: 16,
+        switch_events: true,
+        command: [
+            "sh",
+            "-c",
+            "i=0; while [ $i -lt 100000 ]; do i=$((i+1)); done",
+        ]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect(),
+    };
+    let totals = record(&options, &options.event).unwrap();
+    assert!(totals.samples > 0, "{:?}", totals);
+
+    let data = crate::data::PerfData::open(&output).unwrap();
+    std::fs::remove_file(&output).unwrap();
+    assert_eq!(data.attrs.len(), 1);
+    let samples = data
+        .records()
+        .filter(|r| r.kind == perf_event_type_PERF_RECORD_SAMPLE)
+        .count();
+    assert_eq!(samples as u64, totals.samples);
+    assert!(data
+        .ordered_records()
+        .iter()
+        .any(|r| matches!(r, crate::data::Record::Switch(s) if s.out && s.time > 0)));
+    let descs = data.features.event_desc.unwrap();
+    assert_eq!(descs[0].name, "cpu-clock");
+    assert!(data
+        .features
+        .build_ids
+        .unwrap()
+        .iter()
+        .any(|b| b.filename.ends_with("sh") || b.filename.ends_with("dash")));
+}
+
+#[test]
+fn record_output_error_test() {
+    let options = RecordOptions {
+        event: vec![StatEvent::CpuClock],
+        freq: Some(1000),
+        count: None,
+        call_graph: false,
+        call_graph_mode: None,
+        output: PathBuf::from("/nonexistent/ruperf/perf.data"),
+        mmap_pages: 16,
+        switch_events: false,
+        command: vec!["true".to_string()],
+    };
+    let result = record(&options, &options.event);
+    assert!(matches!(result, Err(RecordError::Output(..))));
+    // The child forked for the command has been killed and reaped.
+    let children = std::fs::read_to_string("/proc/thread-self/children").unwrap();
+    assert_eq!(children.trim(), "");
+}
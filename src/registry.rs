@@ -0,0 +1,242 @@
+//! Function-level counting for `#[ruperf::measure]`.
+//!
+//! Each thread keeps one counter per event open and running for
+//! its whole life, so a measured call only costs a `read()` per
+//! event on entry and exit. Deltas are summed per function in a
+//! global registry, which can be printed as a summary table or
+//! written as JSON, either on demand or at process exit:
+//!
+//! ```no_run
+//! #[ruperf::measure(events = "cycles,instructions")]
+//! fn work() -> u64 {
+//!     (0..1000).sum()
+//! }
+//!
+//! ruperf::registry::dump_at_exit(ruperf::registry::Dump::Summary);
+//! work();
+//! ```
+//!
+//! Event names are checked when the function is compiled:
+//!
+//! ```compile_fail
+//! #[ruperf::measure(events = "cycles,bogus")]
+//! fn work() {}
+//! ```
+
+use crate::event::open::Event;
+use crate::stat::StatEvent;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Totals for every call to one measured function.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FunctionStats {
+    pub calls: u64,
+    /// Summed counter deltas keyed by event name.
+    pub counters: BTreeMap<String, u64>,
+    /// Summed wall-clock time.
+    pub elapsed: Duration,
+}
+
+/// Where to send the registry at process exit.
+#[derive(Debug, Clone)]
+pub enum Dump {
+    /// A summary table on stderr.
+    Summary,
+    /// JSON written to a file.
+    Json(PathBuf),
+}
+
+static REGISTRY: Mutex<BTreeMap<String, FunctionStats>> = Mutex::new(BTreeMap::new());
+static DUMP: Mutex<Option<Dump>> = Mutex::new(None);
+
+thread_local! {
+    /// Running counters for the current thread, keyed by event name.
+    /// `None` marks an event that could not be opened here.
+    static COUNTERS: RefCell<HashMap<&'static str, Option<Event>>> = RefCell::new(HashMap::new());
+}
+
+/// Current value of `event` on this thread, opening
+/// and enabling its counter on first use. An unknown
+/// event is treated like one that could not be opened.
+pub(crate) fn read(event: &'static str) -> Option<isize> {
+    COUNTERS.with(|counters| {
+        let mut counters = counters.borrow_mut();
+        let counter = counters.entry(event).or_insert_with(|| {
+            let parsed = event.parse::<StatEvent>().ok()?;
+            let counter = Event::try_new(parsed, None).ok()?;
+            counter.start_counter().ok()?;
+            Some(counter)
+        });
+        counter.as_ref()?.fd.read().ok()
+    })
+}
+
+/// Records counter deltas for one call when dropped.
+/// Created by the code `#[ruperf::measure]` generates.
+pub struct Guard {
+    name: &'static str,
+    starts: Vec<(&'static str, Option<isize>)>,
+    instant: Instant,
+}
+
+impl Guard {
+    /// Read every event's counter at the start of a call.
+    pub fn start(name: &'static str, events: &[&'static str]) -> Self {
+        let starts = events.iter().map(|e| (*e, read(e))).collect();
+        Guard {
+            name,
+            starts,
+            instant: Instant::now(),
+        }
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        let elapsed = self.instant.elapsed();
+        let deltas: Vec<(&'static str, u64)> = self
+            .starts
+            .iter()
+            .filter_map(|(event, start)| {
+                let stop = read(event)?;
+                Some((*event, (stop - (*start)?).max(0) as u64))
+            })
+            .collect();
+        let mut registry = REGISTRY.lock().unwrap();
+        let stats = registry.entry(self.name.to_string()).or_default();
+        stats.calls += 1;
+        stats.elapsed += elapsed;
+        for (event, delta) in deltas {
+            *stats.counters.entry(event.to_string()).or_insert(0) += delta;
+        }
+    }
+}
+
+/// A copy of everything recorded so far, keyed by function.
+pub fn snapshot() -> BTreeMap<String, FunctionStats> {
+    REGISTRY.lock().unwrap().clone()
+}
+
+/// Forget everything recorded so far.
+pub fn clear() {
+    REGISTRY.lock().unwrap().clear();
+}
+
+/// Write the registry as JSON.
+pub fn write_json(out: &mut dyn Write) -> std::io::Result<()> {
+    let json = serde_json::to_string_pretty(&snapshot()).map_err(std::io::Error::from)?;
+    writeln!(out, "{}", json)
+}
+
+/// Write a table with the calls, mean time and
+/// mean count of every event for each function.
+pub fn write_summary(out: &mut dyn Write) -> std::io::Result<()> {
+    let registry = snapshot();
+    writeln!(
+        out,
+        " {:<40} {:>10} {:>14} {:>14} Event",
+        "Function", "Calls", "ns/call", "per call"
+    )?;
+    for (name, stats) in &registry {
+        let calls = stats.calls.max(1) as f64;
+        writeln!(
+            out,
+            " {:<40} {:>10} {:>14.1}",
+            name,
+            stats.calls,
+            stats.elapsed.as_nanos() as f64 / calls
+        )?;
+        for (event, total) in &stats.counters {
+            writeln!(
+                out,
+                " {:<40} {:>10} {:>14} {:>14.1} {}",
+                "",
+                "",
+                "",
+                *total as f64 / calls,
+                event
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Dump the registry when the process exits normally.
+pub fn dump_at_exit(dump: Dump) {
+    let first = DUMP.lock().unwrap().replace(dump).is_none();
+    if first {
+        unsafe {
+            libc::atexit(dump_now);
+        }
+    }
+}
+
+extern "C" fn dump_now() {
+    let dump = match DUMP.lock() {
+        Ok(dump) => dump.clone(),
+        Err(_) => return,
+    };
+    let result = match dump {
+        Some(Dump::Summary) => write_summary(&mut std::io::stderr()),
+        Some(Dump::Json(path)) => {
+            std::fs::File::create(&path).and_then(|mut file| write_json(&mut file))
+        }
+        None => Ok(()),
+    };
+    if let Err(e) = result {
+        eprintln!("ruperf: could not dump measurements: {}", e);
+    }
+}
+
+#[cfg(test)]
+#[crate::measure(events = "task-clock, context-switches")]
+fn measured_sleep(ms: u64) -> u64 {
+    if ms == 0 {
+        return 0;
+    }
+    std::thread::sleep(Duration::from_millis(ms));
+    ms
+}
+
+#[test]
+fn measure_macro_test() {
+    assert_eq!(measured_sleep(2), 2);
+    assert_eq!(measured_sleep(0), 0);
+    let registry = snapshot();
+    let stats = &registry["ruperf::registry::measured_sleep"];
+    assert_eq!(stats.calls, 2);
+    assert!(stats.elapsed >= Duration::from_millis(2));
+    assert!(stats.counters["context-switches"] >= 1);
+    assert!(stats.counters.contains_key("task-clock"));
+    let mut json = Vec::new();
+    write_json(&mut json).unwrap();
+    let parsed: BTreeMap<String, FunctionStats> = serde_json::from_slice(&json).unwrap();
+    assert_eq!(parsed["ruperf::registry::measured_sleep"], *stats);
+
+    // Per-call counts line up under their heading.
+    let mut summary = Vec::new();
+    write_summary(&mut summary).unwrap();
+    let summary = String::from_utf8(summary).unwrap();
+    let header = summary.lines().next().unwrap();
+    let end = header.find("per call").unwrap() + "per call".len();
+    let row = summary
+        .lines()
+        .find(|line| line.ends_with(" context-switches"))
+        .unwrap();
+    assert!(row[..end].ends_with(|c: char| c.is_ascii_digit()));
+    assert_eq!(&row[end..end + 1], " ");
+    assert_eq!(header[end + 1..].trim_end(), "Event");
+}
+
+#[test]
+fn unknown_event_test() {
+    assert_eq!(read("bogus"), None);
+    drop(Guard::start("ruperf::registry::unknown", &["bogus"]));
+    assert!(snapshot()["ruperf::registry::unknown"].counters.is_empty());
+}
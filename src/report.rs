@@ -0,0 +1,936 @@
+//! # Report driver.
+//! <p> Usage: <em> ruperf report [-i FILE] [--sort KEYS] [--percent-limit N] [-g [ORDER]] [--pid PIDS] [--tid TIDS] [--comm COMMS] [--no-demangle | --strip-hash] [--stdio | --json | --flamegraph SVG | --folded | --pprof FILE | --gecko JSON] </em>
+//! Reads a perf.data file and shows where its samples were taken,
+//! grouped by process, shared object, symbol or source line. With `-g`,
+//! samples recorded with call chains also count towards every function
+//! on their stack, inlined functions included. `--flamegraph` draws
+//! the call chains as an interactive flame graph instead, and `--folded`
+//! prints them as collapsed stacks for other flame graph tools. `--pprof`
+//! writes them as a profile for pprof and `--gecko` as one for the
+//! Firefox Profiler. </p>
+
+extern crate structopt;
+pub mod callchain;
+mod diff;
+pub mod flamegraph;
+pub mod folded;
+pub mod gecko;
+pub mod pprof;
+
+use crate::data::{PerfData, Record, Sample};
+use crate::session::{Location, Session};
+use crate::symbols::{Demangle, DemangleOptions};
+use crate::utils::ParseError;
+use callchain::{CallGraph, CallNode, CallOrder};
+pub use diff::{run_diff, DiffOptions};
+use flamegraph::{FlameColor, FlameOptions};
+use folded::FoldedOptions;
+use gecko::GeckoOptions;
+use pprof::PprofOptions;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::prelude::*;
+use std::path::PathBuf;
+use std::str::FromStr;
+use structopt::StructOpt;
+
+/// Configuration settings for running report.
+/// See `./ruperf report --help` for more information.
+#[derive(Debug, StructOpt)]
+pub struct ReportOptions {
+    #[structopt(
+        short,
+        long,
+        default_value = "perf.data",
+        help = "File to read samples from",
+        parse(from_os_str)
+    )]
+    pub input: PathBuf,
+
+    #[structopt(
+        short,
+        long,
+        default_value = "comm,dso,symbol",
+        help = "Columns to group samples by: comm, pid, dso, symbol, srcline"
+    )]
+    pub sort: SortKeys,
+
+    #[structopt(
+        long = "percent-limit",
+        default_value = "0",
+        help = "Hide entries below this overhead percentage"
+    )]
+    pub percent_limit: f64,
+
+    #[structopt(
+        short = "g",
+        long = "call-graph",
+        help = "Show call chains under each entry: callee (its callers) or caller (what it called)"
+    )]
+    pub call_graph: Option<Option<CallOrder>>,
+
+    #[structopt(
+        long = "max-stack",
+        default_value = "127",
+        help = "Frames of each call chain to look at"
+    )]
+    pub max_stack: usize,
+
+    #[structopt(
+        long = "no-inline",
+        help = "Leave inlined functions out of call chains"
+    )]
+    pub no_inline: bool,
+
+    #[structopt(
+        long,
+        use_delimiter = true,
+        help = "Only look at samples of these processes"
+    )]
+    pub pid: Vec<u32>,
+
+    #[structopt(
+        long,
+        use_delimiter = true,
+        help = "Only look at samples of these threads"
+    )]
+    pub tid: Vec<u32>,
+
+    #[structopt(
+        long,
+        use_delimiter = true,
+        help = "Only look at samples of threads with these names"
+    )]
+    pub comm: Vec<String>,
+
+    #[structopt(flatten)]
+    pub demangle: DemangleOptions,
+
+    #[structopt(long, help = "Print a plain text table (the default)")]
+    pub stdio: bool,
+
+    #[structopt(long, help = "Print the report as JSON", conflicts_with = "stdio")]
+    pub json: bool,
+
+    #[structopt(
+        long,
+        help = "Write a flame graph of the first event to this SVG file",
+        parse(from_os_str)
+    )]
+    pub flamegraph: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        help = "Draw the flame graph from the top down",
+        requires = "flamegraph"
+    )]
+    pub icicle: bool,
+
+    #[structopt(
+        long,
+        help = "Merge stacks from the sampled functions down to main",
+        requires = "flamegraph"
+    )]
+    pub reverse: bool,
+
+    #[structopt(
+        long = "flame-color",
+        default_value = "kernel",
+        help = "Colour flame graph frames by kernel or user code, or by dso"
+    )]
+    pub flame_color: FlameColor,
+
+    #[structopt(
+        long,
+        help = "Print the first event's call chains as collapsed stacks",
+        conflicts_with_all = &["json", "flamegraph"]
+    )]
+    pub folded: bool,
+
+    #[structopt(
+        long = "per-process",
+        help = "Start each collapsed stack with the process's name and pid",
+        requires = "folded"
+    )]
+    pub per_process: bool,
+
+    #[structopt(
+        long = "annotate-kernel",
+        help = "Add _[k] to the kernel functions in collapsed stacks",
+        requires = "folded"
+    )]
+    pub annotate_kernel: bool,
+
+    #[structopt(
+        long,
+        help = "Write a gzipped pprof profile of the first event to this file",
+        parse(from_os_str),
+        conflicts_with_all = &["json", "flamegraph", "folded"]
+    )]
+    pub pprof: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        help = "Write a Firefox Profiler profile of the first event to this file",
+        parse(from_os_str),
+        conflicts_with_all = &["json", "flamegraph", "folded", "pprof"]
+    )]
+    pub gecko: Option<PathBuf>,
+}
+
+impl ReportOptions {
+    /// The samples to look at.
+    pub fn filter(&self) -> Filter {
+        Filter {
+            pids: self.pid.clone(),
+            tids: self.tid.clone(),
+            comms: self.comm.clone(),
+        }
+    }
+}
+
+/// Which samples a report looks at. An empty list lets every
+/// sample through.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Filter {
+    pub pids: Vec<u32>,
+    pub tids: Vec<u32>,
+    pub comms: Vec<String>,
+}
+
+impl Filter {
+    /// Whether `sample` is looked at.
+    pub fn keeps(&self, session: &Session, sample: &Sample) -> bool {
+        (self.pids.is_empty() || self.pids.contains(&sample.pid))
+            && (self.tids.is_empty() || self.tids.contains(&sample.tid))
+            && (self.comms.is_empty() || self.comms.contains(&session.comm(sample.pid, sample.tid)))
+    }
+}
+
+/// A column samples can be grouped by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    Comm,
+    Pid,
+    Dso,
+    Symbol,
+    Srcline,
+}
+
+impl FromStr for SortKey {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "comm" => Ok(SortKey::Comm),
+            "pid" => Ok(SortKey::Pid),
+            "dso" => Ok(SortKey::Dso),
+            "symbol" | "sym" => Ok(SortKey::Symbol),
+            "srcline" => Ok(SortKey::Srcline),
+            _ => Err(ParseError::InvalidSortKey(s.to_string())),
+        }
+    }
+}
+
+impl SortKey {
+    /// Name of the key as given to `--sort`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SortKey::Comm => "comm",
+            SortKey::Pid => "pid",
+            SortKey::Dso => "dso",
+            SortKey::Symbol => "symbol",
+            SortKey::Srcline => "srcline",
+        }
+    }
+
+    /// Column header in the text table.
+    pub(crate) fn header(&self) -> &'static str {
+        match self {
+            SortKey::Comm => "Command",
+            SortKey::Pid => "Pid",
+            SortKey::Dso => "Shared Object",
+            SortKey::Symbol => "Symbol",
+            SortKey::Srcline => "Source:Line",
+        }
+    }
+}
+
+/// A comma separated list of `SortKey`s.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SortKeys(pub Vec<SortKey>);
+
+impl FromStr for SortKeys {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(|key| key.trim().parse())
+            .collect::<Result<_, _>>()
+            .map(SortKeys)
+    }
+}
+
+/// Samples that share every sort key.
+#[derive(Debug, Clone, Serialize)]
+pub struct Entry {
+    /// Share of the event's total period, in percent.
+    pub overhead: f64,
+    pub samples: u64,
+    pub period: u64,
+    /// Value of each sort key, by name.
+    #[serde(flatten)]
+    pub keys: BTreeMap<String, String>,
+    /// With `-g`: the share of samples with this entry anywhere on their
+    /// call chain, the entry's own samples (`overhead`) included.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub children_overhead: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub children_period: Option<u64>,
+    /// With `-g`: the callers or callees of the entry.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub callchain: Vec<CallNode>,
+}
+
+impl Entry {
+    /// The share the entry is sorted and filtered by.
+    fn weight(&self) -> f64 {
+        self.children_overhead.unwrap_or(self.overhead)
+    }
+}
+
+/// Running totals for one entry.
+#[derive(Debug, Default)]
+struct Group {
+    samples: u64,
+    period: u64,
+    children: u64,
+    callchain: Vec<CallNode>,
+}
+
+/// Everything sampled for one event.
+#[derive(Debug, Clone, Serialize)]
+pub struct EventReport {
+    pub event: String,
+    pub samples: u64,
+    /// Sum of every sample's period: the approximate event count.
+    pub period: u64,
+    /// Heaviest first.
+    pub entries: Vec<Entry>,
+}
+
+/// The shared object column: a file name, or the kernel's pseudo name.
+fn dso_name(location: &Location) -> String {
+    match &location.dso {
+        Some(path) => path.rsplit('/').next().unwrap_or(path).to_string(),
+        None => "[unknown]".to_string(),
+    }
+}
+
+/// A function's name, or its address when it has no symbol.
+fn frame_name(location: &Location) -> String {
+    match &location.symbol {
+        Some(symbol) if location.inlined => format!("{} (inlined)", symbol.name),
+        Some(symbol) => symbol.name.clone(),
+        None => format!("{:#018x}", location.address),
+    }
+}
+
+/// The source line column, `??:0` without debug info.
+fn srcline_name(location: &Location) -> String {
+    match &location.srcline {
+        Some(srcline) => srcline.to_string(),
+        None => "??:0".to_string(),
+    }
+}
+
+/// The symbol column, tagged `[k]` for kernel and `[.]` for user code.
+fn symbol_name(location: &Location) -> String {
+    let tag = if location.kernel { "[k]" } else { "[.]" };
+    format!("{} {}", tag, frame_name(location))
+}
+
+/// The values of `sort` for a sample taken at, or passing through, `location`.
+pub(crate) fn entry_key(
+    sort: &[SortKey],
+    session: &Session,
+    sample: &Sample,
+    location: &Location,
+) -> Vec<String> {
+    sort.iter()
+        .map(|key| match key {
+            SortKey::Comm => session.comm(sample.pid, sample.tid),
+            SortKey::Pid => sample.pid.to_string(),
+            SortKey::Dso => dso_name(location),
+            SortKey::Symbol => symbol_name(location),
+            SortKey::Srcline => srcline_name(location),
+        })
+        .collect()
+}
+
+/// Group the samples of every event in `data` by `sort`. With a
+/// `call_graph`, every function on a sample's call chain gets its
+/// period as children, and a tree of its callers or callees. Symbol
+/// names are shown as `demangle` asks, and only samples `filter` keeps
+/// are counted. Also returns the number of samples the kernel reported
+/// as lost.
+pub fn aggregate(
+    data: &PerfData,
+    sort: &[SortKey],
+    call_graph: Option<CallGraph>,
+    demangle: Demangle,
+    filter: &Filter,
+) -> (Vec<EventReport>, u64) {
+    let mut session = Session::new();
+    session.set_demangle(demangle);
+    session.set_srclines(sort.contains(&SortKey::Srcline));
+    session.set_inline(call_graph.is_some_and(|graph| graph.inline));
+    let mut groups: Vec<HashMap<Vec<String>, Group>> = Vec::new();
+    groups.resize_with(data.attrs.len(), HashMap::new);
+    let mut lost = 0;
+    for record in data.ordered_records() {
+        let sample = match record {
+            Record::Sample(sample) => sample,
+            Record::Lost { lost: n, .. } => {
+                lost += n;
+                continue;
+            }
+            other => {
+                session.update(&other);
+                continue;
+            }
+        };
+        if !filter.keeps(&session, &sample) {
+            continue;
+        }
+        let index = match data.attr_index(sample.id) {
+            Some(index) => index,
+            None => continue,
+        };
+        let groups = &mut groups[index];
+        let graph = match call_graph {
+            Some(graph) => graph,
+            None => {
+                let location = session.resolve(sample.pid, sample.ip, sample.is_kernel());
+                let group = groups
+                    .entry(entry_key(sort, &session, &sample, &location))
+                    .or_default();
+                group.samples += 1;
+                group.period += sample.period;
+                continue;
+            }
+        };
+
+        let frames = session.callchain(&sample, graph.max_stack);
+        let names: Vec<String> = frames.iter().map(frame_name).collect();
+        let mut seen = HashSet::new();
+        for (depth, location) in frames.iter().enumerate() {
+            let key = entry_key(sort, &session, &sample, location);
+            // Recursive functions count once per sample.
+            if !seen.insert(key.clone()) {
+                continue;
+            }
+            let group = groups.entry(key).or_default();
+            if depth == 0 {
+                group.samples += 1;
+                group.period += sample.period;
+            }
+            group.children += sample.period;
+            let path: Vec<&str> = match graph.order {
+                CallOrder::Callee => names[depth + 1..].iter().map(|n| n.as_str()).collect(),
+                CallOrder::Caller => names[..depth].iter().rev().map(|n| n.as_str()).collect(),
+            };
+            callchain::insert(&mut group.callchain, path, sample.period);
+        }
+    }
+
+    let reports = groups
+        .into_iter()
+        .enumerate()
+        .map(|(index, groups)| {
+            let samples = groups.values().map(|g| g.samples).sum();
+            let period: u64 = groups.values().map(|g| g.period).sum();
+            let percent = |p: u64| 100.0 * p as f64 / period.max(1) as f64;
+            let mut entries: Vec<Entry> = groups
+                .into_iter()
+                .map(|(values, mut group)| {
+                    callchain::sort(&mut group.callchain);
+                    Entry {
+                        overhead: percent(group.period),
+                        samples: group.samples,
+                        period: group.period,
+                        keys: sort
+                            .iter()
+                            .map(|key| key.as_str().to_string())
+                            .zip(values)
+                            .collect(),
+                        children_overhead: call_graph.map(|_| percent(group.children)),
+                        children_period: call_graph.map(|_| group.children),
+                        callchain: group.callchain,
+                    }
+                })
+                .collect();
+            entries.sort_by(|a, b| {
+                let weight = |e: &Entry| e.children_period.unwrap_or(e.period);
+                weight(b)
+                    .cmp(&weight(a))
+                    .then_with(|| b.period.cmp(&a.period))
+                    .then_with(|| a.keys.values().cmp(b.keys.values()))
+            });
+            EventReport {
+                event: data.event_name(index),
+                samples,
+                period,
+                entries,
+            }
+        })
+        .collect();
+    (reports, lost)
+}
+
+/// Print `reports` as perf-style tables. Call trees are shown
+/// with `call_graph`, leaving out branches under `limit` percent.
+fn print_reports(
+    out: &mut dyn Write,
+    reports: &[EventReport],
+    sort: &[SortKey],
+    call_graph: bool,
+    limit: f64,
+) -> std::io::Result<()> {
+    for report in reports {
+        writeln!(
+            out,
+            "# Samples: {} of event '{}'",
+            report.samples, report.event
+        )?;
+        writeln!(out, "# Event count (approx.): {}", report.period)?;
+        writeln!(out, "#")?;
+        let widths: Vec<usize> = sort
+            .iter()
+            .map(|key| {
+                report
+                    .entries
+                    .iter()
+                    .map(|entry| entry.keys[key.as_str()].len())
+                    .chain(std::iter::once(key.header().len()))
+                    .max()
+                    .unwrap()
+            })
+            .collect();
+        let row = |numbers: &[&str], cells: Vec<&str>| {
+            let numbers: Vec<String> = numbers.iter().map(|n| format!("{:>10}", n)).collect();
+            let cells: Vec<String> = cells
+                .iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{:width$}", cell, width = width))
+                .collect();
+            format!("{}  {}", numbers.join(""), cells.join("  "))
+                .trim_end()
+                .to_string()
+        };
+        let (headers, dots): (&[&str], &[&str]) = if call_graph {
+            (&["Children", "Self"], &["........", "........"])
+        } else {
+            (&["Overhead"], &["........"])
+        };
+        let names = sort.iter().map(|key| key.header()).collect();
+        writeln!(out, "#{}", &row(headers, names)[1..])?;
+        let lines: Vec<String> = widths.iter().map(|width| ".".repeat(*width)).collect();
+        writeln!(
+            out,
+            "#{}",
+            &row(dots, lines.iter().map(|d| d.as_str()).collect())[1..]
+        )?;
+        writeln!(out, "#")?;
+        for entry in &report.entries {
+            let cells = sort
+                .iter()
+                .map(|key| entry.keys[key.as_str()].as_str())
+                .collect();
+            let overhead = format!("{:.2}%", entry.overhead);
+            match entry.children_overhead {
+                Some(children) => {
+                    let children = format!("{:.2}%", children);
+                    writeln!(out, "{}", row(&[&children, &overhead], cells))?;
+                }
+                None => writeln!(out, "{}", row(&[&overhead], cells))?,
+            }
+            if call_graph && !entry.callchain.is_empty() {
+                // The tree starts at the entry's function, without its tag.
+                let root = match entry.keys.get(SortKey::Symbol.as_str()) {
+                    Some(symbol) => symbol
+                        .split_once(' ')
+                        .map_or(symbol.clone(), |s| s.1.into()),
+                    None => entry.keys.values().cloned().collect::<Vec<_>>().join(" "),
+                };
+                callchain::print(
+                    out,
+                    &root,
+                    &entry.callchain,
+                    report.period,
+                    limit,
+                    &" ".repeat(12),
+                )?;
+                writeln!(out)?;
+            }
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+/// Pass the call chain, innermost frame first, of every sample of the
+/// first event in `data` that `filter` keeps to `f`, resolved by
+/// `session`.
+fn for_each_stack<F>(
+    data: &PerfData,
+    mut session: Session,
+    max_stack: usize,
+    filter: &Filter,
+    mut f: F,
+) where
+    F: FnMut(&Session, &Sample, &[Location]),
+{
+    for record in data.ordered_records() {
+        let sample = match record {
+            Record::Sample(sample) => sample,
+            other => {
+                session.update(&other);
+                continue;
+            }
+        };
+        if data.attr_index(sample.id) != Some(0) || !filter.keeps(&session, &sample) {
+            continue;
+        }
+        let frames = session.callchain(&sample, max_stack);
+        f(&session, &sample, &frames);
+    }
+}
+
+/// Drop entries below `limit` percent, counting
+/// children when call chains are shown.
+fn apply_percent_limit(reports: &mut [EventReport], limit: f64) {
+    for report in reports {
+        report.entries.retain(|entry| entry.weight() >= limit);
+    }
+}
+
+/// Main entry point for report. Returns the exit code for `ruperf`.
+pub fn run_report(options: ReportOptions) -> i32 {
+    let data = match PerfData::open(&options.input) {
+        Ok(data) => data,
+        Err(e) => {
+            eprintln!("Could not read {}: {}", options.input.display(), e);
+            return 1;
+        }
+    };
+    let filter = options.filter();
+    if options.folded {
+        let folded = FoldedOptions {
+            per_process: options.per_process,
+            annotate_kernel: options.annotate_kernel,
+            max_stack: options.max_stack,
+            inline: !options.no_inline,
+        };
+        let stacks = folded::collapse(&data, &folded, options.demangle.mode(), &filter);
+        let stdout = std::io::stdout();
+        let mut out = std::io::BufWriter::new(stdout.lock());
+        return match folded::print(&mut out, &stacks).and_then(|()| out.flush()) {
+            Ok(()) => 0,
+            // Often piped into `head`, so a closed pipe is not an error.
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => 0,
+            Err(e) => {
+                eprintln!("Could not print the stacks: {}", e);
+                1
+            }
+        };
+    }
+    if let Some(path) = &options.gecko {
+        let gecko = GeckoOptions {
+            max_stack: options.max_stack,
+            inline: !options.no_inline,
+        };
+        let profile = gecko::build(&data, &gecko, options.demangle.mode(), &filter);
+        let written = std::fs::File::create(path).and_then(|file| {
+            let mut out = std::io::BufWriter::new(file);
+            gecko::write(&mut out, &profile)?;
+            out.flush()
+        });
+        return match written {
+            Ok(()) => {
+                eprintln!(
+                    "Wrote a profile of {} threads to {}",
+                    profile["threads"].as_array().map_or(0, Vec::len),
+                    path.display()
+                );
+                0
+            }
+            Err(e) => {
+                eprintln!("Could not write {}: {}", path.display(), e);
+                1
+            }
+        };
+    }
+    if let Some(path) = &options.pprof {
+        let pprof = PprofOptions {
+            max_stack: options.max_stack,
+            inline: !options.no_inline,
+        };
+        let profile = pprof::build(&data, &pprof, options.demangle.mode(), &filter);
+        let written = std::fs::File::create(path)
+            .and_then(|file| pprof::write(std::io::BufWriter::new(file), &profile));
+        return match written {
+            Ok(()) => {
+                eprintln!(
+                    "Wrote a profile of {} samples to {}",
+                    profile.sample.iter().map(|s| s.value[0]).sum::<i64>(),
+                    path.display()
+                );
+                0
+            }
+            Err(e) => {
+                eprintln!("Could not write {}: {}", path.display(), e);
+                1
+            }
+        };
+    }
+    if let Some(path) = &options.flamegraph {
+        let flame = FlameOptions {
+            icicle: options.icicle,
+            reverse: options.reverse,
+            color: options.flame_color,
+            max_stack: options.max_stack,
+            inline: !options.no_inline,
+        };
+        let graph = flamegraph::build(&data, &flame, options.demangle.mode(), &filter);
+        let written = std::fs::File::create(path).and_then(|file| {
+            let mut out = std::io::BufWriter::new(file);
+            flamegraph::write_svg(&mut out, &graph, &flame)?;
+            out.flush()
+        });
+        return match written {
+            Ok(()) => {
+                eprintln!(
+                    "Wrote a flame graph of {} to {}",
+                    graph.event,
+                    path.display()
+                );
+                0
+            }
+            Err(e) => {
+                eprintln!("Could not write {}: {}", path.display(), e);
+                1
+            }
+        };
+    }
+    let call_graph = options.call_graph.map(|order| CallGraph {
+        order: order.unwrap_or(CallOrder::Callee),
+        max_stack: options.max_stack,
+        inline: !options.no_inline,
+    });
+    let (mut reports, lost) = aggregate(
+        &data,
+        &options.sort.0,
+        call_graph,
+        options.demangle.mode(),
+        &filter,
+    );
+    if lost > 0 {
+        eprintln!("Warning: {} samples were lost while recording", lost);
+    }
+    apply_percent_limit(&mut reports, options.percent_limit);
+
+    let out = &mut std::io::stdout();
+    let printed = if options.json {
+        writeln!(out, "{}", serde_json::to_string_pretty(&reports).unwrap())
+    } else {
+        print_reports(
+            out,
+            &reports,
+            &options.sort.0,
+            call_graph.is_some(),
+            options.percent_limit,
+        )
+    };
+    match printed {
+        Ok(()) => 0,
+        // Reports are often piped into `head`, so a closed pipe is not an error.
+        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn report_test() {
+    let data = PerfData::open("tests/fixtures/two-events.data").unwrap();
+    let sort = "comm,dso".parse::<SortKeys>().unwrap().0;
+    let (mut reports, _) = aggregate(&data, &sort, None, Demangle::Full, &Filter::default());
+    assert_eq!(reports.len(), 2);
+    for report in &reports {
+        assert!(report.samples > 0);
+        assert_eq!(
+            report.entries.iter().map(|e| e.samples).sum::<u64>(),
+            report.samples
+        );
+        let total: f64 = report.entries.iter().map(|e| e.overhead).sum();
+        assert!((total - 100.0).abs() < 1e-6);
+        assert!(report
+            .entries
+            .windows(2)
+            .all(|w| w[0].period >= w[1].period));
+    }
+
+    apply_percent_limit(&mut reports, 100.1);
+    assert!(reports.iter().all(|r| r.entries.is_empty()));
+    assert!("comm,bogus".parse::<SortKeys>().is_err());
+}
+
+/// The name the exporters give the innermost frame of the first
+/// sample of the first event in `data`, resolved by a session of its
+/// own so that tests do not take it from the code they check.
+#[cfg(test)]
+pub(crate) fn first_frame_name(data: &PerfData) -> String {
+    let mut session = Session::new();
+    session.set_demangle(Demangle::Full);
+    for record in data.ordered_records() {
+        match record {
+            Record::Sample(sample) if data.attr_index(sample.id) == Some(0) => {
+                let location = session.resolve(sample.pid, sample.ip, sample.is_kernel());
+                return frame_name(&location);
+            }
+            other => session.update(&other),
+        }
+    }
+    panic!("no samples of the first event");
+}
+
+/// Build `samples/call-graph` with `flags` and record it as
+/// `name`. Returns the program, for the caller to remove once its
+/// symbols have been read, and the recording.
+#[cfg(test)]
+pub(crate) fn record_call_graph_sample(
+    name: &str,
+    flags: &[&str],
+    call_graph_mode: Option<crate::record::CallGraphMode>,
+) -> (PathBuf, PerfData) {
+    use crate::record::{run_record, RecordOptions};
+    use crate::stat::StatEvent;
+
+    let exe = std::env::temp_dir().join(format!("ruperf-{}-{}", name, std::process::id()));
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let status = std::process::Command::new(rustc)
+        .args(flags)
+        .arg("-o")
+        .arg(&exe)
+        .arg("samples/call-graph/src/main.rs")
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let output = exe.with_extension("data");
+    let options = RecordOptions {
+        event: vec![StatEvent::CpuClock],
+        freq: Some(1000),
+        count: None,
+        call_graph: true,
+        call_graph_mode,
+        output: output.clone(),
+        mmap_pages: 64,
+        switch_events: false,
+        command: vec![exe.to_str().unwrap().to_string()],
+    };
+    assert_eq!(run_record(options), 0);
+    let data = PerfData::open(&output).unwrap();
+    std::fs::remove_file(&output).unwrap();
+    (exe, data)
+}
+
+/// Record `samples/call-graph` built with `flags` and check
+/// that the trees show `work` calling `heavy` more than `light`.
+#[cfg(test)]
+fn check_call_graph(
+    name: &str,
+    flags: &[&str],
+    call_graph_mode: Option<crate::record::CallGraphMode>,
+) {
+    let (exe, data) = record_call_graph_sample(name, flags, call_graph_mode);
+
+    let graph = |order| CallGraph {
+        order,
+        max_stack: 127,
+        inline: true,
+    };
+    let find = |reports: &[EventReport], name: &str| {
+        reports[0]
+            .entries
+            .iter()
+            .find(|e| e.keys["symbol"].contains(name))
+            .cloned()
+            .unwrap()
+    };
+    let (reports, _) = aggregate(
+        &data,
+        &[SortKey::Symbol],
+        Some(graph(CallOrder::Callee)),
+        Demangle::StripHash,
+        &Filter::default(),
+    );
+    let work = find(&reports, "main::work");
+    let heavy = find(&reports, "main::heavy");
+    let light = find(&reports, "main::light");
+    let spin = find(&reports, "main::spin");
+    assert!(work.children_overhead.unwrap() > 90.0, "{:?}", work);
+    assert!(work.overhead < 5.0, "{:?}", work);
+    assert!(heavy.children_period > light.children_period);
+    // Callee order: spin is called from heavy more than from light.
+    let callers: Vec<&str> = spin.callchain.iter().map(|n| n.symbol.as_str()).collect();
+    assert!(callers[0].contains("main::heavy"), "{:?}", callers);
+    assert!(callers.iter().any(|c| c.contains("main::light")));
+
+    // Caller order: work calls heavy and light.
+    let (reports, _) = aggregate(
+        &data,
+        &[SortKey::Symbol],
+        Some(graph(CallOrder::Caller)),
+        Demangle::StripHash,
+        &Filter::default(),
+    );
+    let callees: Vec<String> = find(&reports, "main::work")
+        .callchain
+        .iter()
+        .map(|n| n.symbol.clone())
+        .collect();
+    assert!(callees[0].contains("main::heavy"), "{:?}", callees);
+    assert!(callees.iter().any(|c| c.contains("main::light")));
+
+    let (reports, _) = aggregate(
+        &data,
+        &[SortKey::Srcline],
+        None,
+        Demangle::Full,
+        &Filter::default(),
+    );
+    assert!(reports[0]
+        .entries
+        .iter()
+        .any(|e| e.keys["srcline"].contains("call-graph/src/main.rs:")));
+    std::fs::remove_file(&exe).unwrap();
+}
+
+#[test]
+fn call_graph_test() {
+    check_call_graph("fp", &["-g", "-C", "force-frame-pointers=yes"], None);
+}
+
+#[test]
+fn dwarf_call_graph_test() {
+    use crate::record::CallGraphMode;
+
+    // Without frame pointers the stack copies have to be unwound.
+    check_call_graph(
+        "dwarf",
+        &["-g", "-C", "force-frame-pointers=no"],
+        Some(CallGraphMode::Dwarf(8192)),
+    );
+}
@@ -0,0 +1,153 @@
+//! Call trees for `ruperf report -g`.
+
+use crate::utils::ParseError;
+use serde::Serialize;
+use std::io::prelude::*;
+use std::str::FromStr;
+
+/// Which way call chains are shown under an entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallOrder {
+    /// The functions that called the entry, nearest first.
+    Callee,
+    /// The functions the entry called, down to where samples were taken.
+    Caller,
+}
+
+impl FromStr for CallOrder {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "callee" => Ok(CallOrder::Callee),
+            "caller" => Ok(CallOrder::Caller),
+            _ => Err(ParseError::InvalidCallOrder(s.to_string())),
+        }
+    }
+}
+
+/// How call chains are gathered and shown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CallGraph {
+    pub order: CallOrder,
+    /// Frames of each chain to look at, innermost first.
+    pub max_stack: usize,
+    /// Whether functions inlined into a frame get frames of their own.
+    pub inline: bool,
+}
+
+/// A function in a call tree, with the period of
+/// the samples that reached it along this path.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct CallNode {
+    pub symbol: String,
+    pub period: u64,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<CallNode>,
+}
+
+/// Add a sample's `period` along `path`, starting below `nodes`.
+pub fn insert<'a, I>(nodes: &mut Vec<CallNode>, path: I, period: u64)
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let mut nodes = nodes;
+    for symbol in path {
+        let index = match nodes.iter().position(|node| node.symbol == symbol) {
+            Some(index) => index,
+            None => {
+                nodes.push(CallNode {
+                    symbol: symbol.to_string(),
+                    ..Default::default()
+                });
+                nodes.len() - 1
+            }
+        };
+        let node = &mut nodes[index];
+        node.period += period;
+        nodes = &mut node.children;
+    }
+}
+
+/// Order every level heaviest first.
+pub fn sort(nodes: &mut Vec<CallNode>) {
+    nodes.sort_by(|a, b| {
+        b.period
+            .cmp(&a.period)
+            .then_with(|| a.symbol.cmp(&b.symbol))
+    });
+    for node in nodes {
+        sort(&mut node.children);
+    }
+}
+
+/// Print a tree the way `perf report --stdio` does, with each
+/// branch as a percentage of `total`. Branches under `limit`
+/// percent are left out.
+pub fn print(
+    out: &mut dyn Write,
+    root: &str,
+    nodes: &[CallNode],
+    total: u64,
+    limit: f64,
+    indent: &str,
+) -> std::io::Result<()> {
+    writeln!(out, "{}|", indent)?;
+    writeln!(out, "{}---{}", indent, root)?;
+    print_nodes(out, nodes, total, limit, &format!("{}   ", indent))
+}
+
+fn print_nodes(
+    out: &mut dyn Write,
+    nodes: &[CallNode],
+    total: u64,
+    limit: f64,
+    indent: &str,
+) -> std::io::Result<()> {
+    let percent = |node: &CallNode| 100.0 * node.period as f64 / total.max(1) as f64;
+    let shown: Vec<&CallNode> = nodes.iter().filter(|n| percent(n) >= limit).collect();
+    for (i, node) in shown.iter().enumerate() {
+        let last = i + 1 == shown.len();
+        let branch = format!("--{:.2}%--", percent(node));
+        writeln!(
+            out,
+            "{}{}{}{}",
+            indent,
+            if last { " " } else { "|" },
+            branch,
+            node.symbol
+        )?;
+        let below = format!(
+            "{}{}{}",
+            indent,
+            if last { " " } else { "|" },
+            " ".repeat(branch.len())
+        );
+        print_nodes(out, &node.children, total, limit, &below)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[test]
+fn call_tree_test() {
+    let mut nodes = Vec::new();
+    insert(&mut nodes, vec!["light", "spin"], 1);
+    insert(&mut nodes, vec!["heavy", "spin"], 2);
+    insert(&mut nodes, vec!["heavy"], 1);
+    sort(&mut nodes);
+    assert_eq!(nodes[0].symbol, "heavy");
+    assert_eq!(nodes[0].period, 3);
+    assert_eq!(nodes[0].children[0].period, 2);
+
+    let mut out = Vec::new();
+    print(&mut out, "work", &nodes, 4, 0.0, "").unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "|\n\
+         ---work\n   \
+         |--75.00%--heavy\n   \
+         |           --50.00%--spin\n    \
+         --25.00%--light\n               \
+         --25.00%--spin\n"
+    );
+}
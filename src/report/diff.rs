@@ -0,0 +1,352 @@
+//! # Diff driver.
+//! <p> Usage: <em> ruperf diff [--sort KEYS] [--weighted W1,W2] [--flamegraph SVG] BASE NEW </em>
+//! where BASE and NEW are perf.data files. </p>
+//!
+//! Lines up the entries of two recordings by their sort keys and prints
+//! each one's share of the samples in both, with the change between
+//! them, biggest changes first. Entries found in only one recording are
+//! shown with the other side blank. `--weighted` ranks entries by their
+//! weighted difference in period instead, and `--flamegraph` draws the
+//! new call chains coloured by how their share changed.
+
+use super::flamegraph::{self, FlameColor, FlameOptions};
+use super::{aggregate, EventReport, Filter, SortKey, SortKeys};
+use crate::data::PerfData;
+use crate::symbols::DemangleOptions;
+use crate::utils::ParseError;
+use std::collections::BTreeMap;
+use std::io::prelude::*;
+use std::path::PathBuf;
+use std::str::FromStr;
+use structopt::StructOpt;
+
+/// Configuration settings for running diff.
+/// See `./ruperf diff --help` for more information.
+#[derive(Debug, StructOpt)]
+pub struct DiffOptions {
+    #[structopt(parse(from_os_str), help = "Baseline recording")]
+    pub base: PathBuf,
+
+    #[structopt(
+        parse(from_os_str),
+        help = "New recording to compare with the baseline"
+    )]
+    pub new: PathBuf,
+
+    #[structopt(
+        short,
+        long,
+        default_value = "dso,symbol",
+        help = "Columns to line entries up by: comm, pid, dso, symbol, srcline"
+    )]
+    pub sort: SortKeys,
+
+    #[structopt(
+        long,
+        help = "Rank entries by NEW period * W2 - BASE period * W1, given as W1,W2"
+    )]
+    pub weighted: Option<Weights>,
+
+    #[structopt(
+        long,
+        help = "Write a differential flame graph of the first event to this SVG file",
+        parse(from_os_str)
+    )]
+    pub flamegraph: Option<PathBuf>,
+
+    #[structopt(
+        long = "max-stack",
+        default_value = "127",
+        help = "Frames of each call chain to look at"
+    )]
+    pub max_stack: usize,
+
+    #[structopt(
+        long = "no-inline",
+        help = "Leave inlined functions out of call chains"
+    )]
+    pub no_inline: bool,
+
+    #[structopt(flatten)]
+    pub demangle: DemangleOptions,
+}
+
+/// The weights of the baseline and new periods for `--weighted`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Weights(pub i64, pub i64);
+
+impl FromStr for Weights {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || ParseError::InvalidWeights(s.to_string());
+        let (base, new) = s.split_once(',').ok_or_else(error)?;
+        match (base.trim().parse(), new.trim().parse()) {
+            (Ok(base), Ok(new)) => Ok(Weights(base, new)),
+            _ => Err(error()),
+        }
+    }
+}
+
+/// One entry of either recording. Shares are percentages of the
+/// event's total period; `None` when the entry is missing on that side.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiffEntry {
+    /// Value of each sort key, in `--sort` order.
+    pub keys: Vec<String>,
+    pub base: Option<f64>,
+    pub new: Option<f64>,
+    pub base_period: u64,
+    pub new_period: u64,
+}
+
+impl DiffEntry {
+    /// Change in share, in percentage points.
+    pub fn delta(&self) -> f64 {
+        self.new.unwrap_or(0.0) - self.base.unwrap_or(0.0)
+    }
+
+    /// The period difference with each side scaled by `weights`.
+    /// Each product fits an `i128`; only their difference can saturate.
+    pub fn weighted(&self, weights: Weights) -> i128 {
+        let new = i128::from(self.new_period) * i128::from(weights.1);
+        let base = i128::from(self.base_period) * i128::from(weights.0);
+        new.saturating_sub(base)
+    }
+}
+
+/// Pair up the entries of `base` and `new` that share every `sort` key,
+/// biggest change first, or biggest weighted difference with `weights`.
+pub fn diff_entries(
+    base: &EventReport,
+    new: &EventReport,
+    sort: &[SortKey],
+    weights: Option<Weights>,
+) -> Vec<DiffEntry> {
+    let mut entries: BTreeMap<Vec<String>, DiffEntry> = BTreeMap::new();
+    for (report, is_new) in [(base, false), (new, true)] {
+        for entry in &report.entries {
+            let keys: Vec<String> = sort
+                .iter()
+                .map(|key| entry.keys[key.as_str()].clone())
+                .collect();
+            let diff = entries.entry(keys.clone()).or_insert_with(|| DiffEntry {
+                keys,
+                base: None,
+                new: None,
+                base_period: 0,
+                new_period: 0,
+            });
+            if is_new {
+                diff.new = Some(entry.overhead);
+                diff.new_period = entry.period;
+            } else {
+                diff.base = Some(entry.overhead);
+                diff.base_period = entry.period;
+            }
+        }
+    }
+    let mut entries: Vec<DiffEntry> = entries.into_values().collect();
+    // The map leaves ties in key order.
+    match weights {
+        Some(weights) => {
+            entries.sort_by_key(|e| std::cmp::Reverse(e.weighted(weights).unsigned_abs()))
+        }
+        None => entries.sort_by(|a, b| b.delta().abs().total_cmp(&a.delta().abs())),
+    }
+    entries
+}
+
+/// Print the entries of one event as a perf-diff-style table.
+fn print_diff(
+    out: &mut dyn Write,
+    event: &str,
+    entries: &[DiffEntry],
+    sort: &[SortKey],
+    weights: Option<Weights>,
+) -> std::io::Result<()> {
+    writeln!(out, "# Event '{}'", event)?;
+    writeln!(out, "#")?;
+    let widths: Vec<usize> = sort
+        .iter()
+        .enumerate()
+        .map(|(i, key)| {
+            entries
+                .iter()
+                .map(|entry| entry.keys[i].len())
+                .chain(std::iter::once(key.header().len()))
+                .max()
+                .unwrap()
+        })
+        .collect();
+    let percent = |share: Option<f64>| share.map_or(String::new(), |p| format!("{:.2}%", p));
+    let changes: Vec<String> = entries
+        .iter()
+        .map(|entry| match weights {
+            Some(weights) => entry.weighted(weights).to_string(),
+            None => format!("{:+.2}%", entry.delta()),
+        })
+        .collect();
+    // Weighted differences can outgrow the usual column.
+    let change_width = changes.iter().map(|c| c.len() + 2).fold(10, usize::max);
+    let row = |numbers: [&str; 3], cells: &[&str]| {
+        let cells: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect();
+        format!(
+            "{:>10}{:>10}{:>width$}  {}",
+            numbers[0],
+            numbers[1],
+            numbers[2],
+            cells.join("  "),
+            width = change_width
+        )
+        .trim_end()
+        .to_string()
+    };
+    let change = if weights.is_some() {
+        "Weighted"
+    } else {
+        "Delta"
+    };
+    let names: Vec<&str> = sort.iter().map(|key| key.header()).collect();
+    writeln!(out, "#{}", &row(["Baseline", "New", change], &names)[1..])?;
+    let lines: Vec<String> = widths.iter().map(|width| ".".repeat(*width)).collect();
+    let lines: Vec<&str> = lines.iter().map(|d| d.as_str()).collect();
+    let dots = "........";
+    writeln!(out, "#{}", &row([dots, dots, dots], &lines)[1..])?;
+    writeln!(out, "#")?;
+    for (entry, change) in entries.iter().zip(&changes) {
+        let cells: Vec<&str> = entry.keys.iter().map(|k| k.as_str()).collect();
+        writeln!(
+            out,
+            "{}",
+            row([&percent(entry.base), &percent(entry.new), change], &cells)
+        )?;
+    }
+    writeln!(out)
+}
+
+/// Main entry point for diff. Returns the exit code for `ruperf`.
+pub fn run_diff(options: DiffOptions) -> i32 {
+    let open = |path: &PathBuf| {
+        PerfData::open(path).map_err(|e| eprintln!("Could not read {}: {}", path.display(), e))
+    };
+    let (base, new) = match (open(&options.base), open(&options.new)) {
+        (Ok(base), Ok(new)) => (base, new),
+        _ => return 1,
+    };
+    let demangle = options.demangle.mode();
+    let sort = &options.sort.0;
+    let filter = Filter::default();
+    let (base_reports, _) = aggregate(&base, sort, None, demangle, &filter);
+    let (new_reports, _) = aggregate(&new, sort, None, demangle, &filter);
+
+    let out = &mut std::io::stdout();
+    for report in &new_reports {
+        let baseline = match base_reports.iter().find(|b| b.event == report.event) {
+            Some(baseline) => baseline,
+            None => {
+                eprintln!("Event '{}' is not in the baseline", report.event);
+                continue;
+            }
+        };
+        let entries = diff_entries(baseline, report, sort, options.weighted);
+        match print_diff(out, &report.event, &entries, sort, options.weighted) {
+            Ok(()) => {}
+            // Often piped into `head`, so a closed pipe is not an error.
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => return 0,
+            Err(e) => {
+                eprintln!("{}", e);
+                return 1;
+            }
+        }
+    }
+    for report in &base_reports {
+        if !new_reports.iter().any(|n| n.event == report.event) {
+            eprintln!("Event '{}' is only in the baseline", report.event);
+        }
+    }
+
+    if let Some(path) = &options.flamegraph {
+        let flame = FlameOptions {
+            icicle: false,
+            reverse: false,
+            color: FlameColor::Kernel,
+            max_stack: options.max_stack,
+            inline: !options.no_inline,
+        };
+        let baseline = flamegraph::build(&base, &flame, demangle, &filter);
+        let graph = flamegraph::build(&new, &flame, demangle, &filter).diff(&baseline);
+        let written = std::fs::File::create(path).and_then(|file| {
+            let mut out = std::io::BufWriter::new(file);
+            flamegraph::write_svg(&mut out, &graph, &flame)?;
+            out.flush()
+        });
+        match written {
+            Ok(()) => eprintln!(
+                "Wrote a differential flame graph of {} to {}",
+                graph.event,
+                path.display()
+            ),
+            Err(e) => {
+                eprintln!("Could not write {}: {}", path.display(), e);
+                return 1;
+            }
+        }
+    }
+    0
+}
+
+#[cfg(test)]
+#[test]
+fn diff_test() {
+    use crate::symbols::Demangle;
+
+    let data = PerfData::open("tests/fixtures/two-events.data").unwrap();
+    let sort = "dso,symbol".parse::<SortKeys>().unwrap().0;
+    let (reports, _) = aggregate(&data, &sort, None, Demangle::Full, &Filter::default());
+    let report = &reports[0];
+    let entries = diff_entries(report, report, &sort, None);
+    assert_eq!(entries.len(), report.entries.len());
+    assert!(entries.iter().all(|e| e.delta() == 0.0 && e.base == e.new));
+
+    // An entry that went away, and one that is new.
+    let mut new = report.clone();
+    let gone = new.entries.remove(0);
+    new.entries[0]
+        .keys
+        .insert("symbol".to_string(), "[.] fresh".to_string());
+    let entries = diff_entries(report, &new, &sort, Some(Weights(1, 2)));
+    let find = |symbol: &str| entries.iter().find(|e| e.keys[1] == symbol).unwrap();
+    let removed = find(&gone.keys["symbol"]);
+    assert_eq!((removed.base, removed.new), (Some(gone.overhead), None));
+    assert_eq!(removed.weighted(Weights(1, 2)), -i128::from(gone.period));
+    let fresh = find("[.] fresh");
+    assert_eq!(fresh.base, None);
+    assert_eq!(
+        fresh.weighted(Weights(1, 2)),
+        2 * i128::from(fresh.new_period)
+    );
+    let ranks: Vec<u128> = entries
+        .iter()
+        .map(|e| e.weighted(Weights(1, 2)).unsigned_abs())
+        .collect();
+    assert!(ranks.windows(2).all(|w| w[0] >= w[1]));
+
+    // Periods past i64::MAX and weights at either extreme.
+    let huge = DiffEntry {
+        keys: Vec::new(),
+        base: None,
+        new: None,
+        base_period: u64::MAX,
+        new_period: u64::MAX,
+    };
+    assert_eq!(huge.weighted(Weights(1, 2)), i128::from(u64::MAX));
+    assert_eq!(huge.weighted(Weights(i64::MIN, i64::MAX)), i128::MAX);
+    assert_eq!(huge.weighted(Weights(i64::MAX, i64::MIN)), i128::MIN);
+
+    assert_eq!("1, 3".parse::<Weights>().unwrap(), Weights(1, 3));
+    assert!("1".parse::<Weights>().is_err());
+}
@@ -0,0 +1,648 @@
+//! Flame graphs for `ruperf report --flamegraph`: the call chains
+//! of every sample merged into a tree and drawn as an SVG file, with
+//! a script to zoom into a frame and to search for functions. A
+//! differential graph, for `ruperf diff`, colours each frame by how its
+//! share of the samples changed from a baseline.
+
+use super::{for_each_stack, frame_name, Filter};
+use crate::data::PerfData;
+use crate::session::Session;
+use crate::symbols::Demangle;
+use crate::utils::ParseError;
+use std::collections::BTreeMap;
+use std::io::prelude::*;
+use std::rc::Rc;
+use std::str::FromStr;
+
+const IMAGE_WIDTH: f64 = 1200.0;
+const FRAME_HEIGHT: f64 = 16.0;
+const FONT_SIZE: f64 = 12.0;
+/// Average width of a character, relative to the font size.
+const FONT_WIDTH: f64 = 0.59;
+const X_PAD: f64 = 10.0;
+/// Room for the title and buttons above the frames...
+const TOP_PAD: f64 = FONT_SIZE * 3.0;
+/// ...and for the details of the frame under the pointer below them.
+const BOTTOM_PAD: f64 = FONT_SIZE * 2.0 + 10.0;
+/// Frames narrower than this many pixels are left out.
+const MIN_WIDTH: f64 = 0.1;
+
+/// How frames are coloured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlameColor {
+    /// Kernel code orange, user code red to yellow.
+    Kernel,
+    /// A hue for each shared object.
+    Dso,
+}
+
+impl FromStr for FlameColor {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "kernel" => Ok(FlameColor::Kernel),
+            "dso" => Ok(FlameColor::Dso),
+            _ => Err(ParseError::InvalidFlameColor(s.to_string())),
+        }
+    }
+}
+
+/// How a flame graph is built and drawn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlameOptions {
+    /// Draw from the top down, as an icicle graph.
+    pub icicle: bool,
+    /// Merge stacks from the sampled functions, rather than from `main`.
+    pub reverse: bool,
+    pub color: FlameColor,
+    /// Frames of each call chain to look at, innermost first.
+    pub max_stack: usize,
+    /// Whether functions inlined into a frame get frames of their own.
+    pub inline: bool,
+}
+
+/// A function on the merged stacks, with the period of the samples
+/// whose stack passed through it along this path.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Frame {
+    pub period: u64,
+    /// The period along this path in the baseline of a differential graph.
+    pub baseline: u64,
+    pub dso: Option<Rc<str>>,
+    pub kernel: bool,
+    /// By name, so that siblings are drawn in alphabetical order.
+    pub children: BTreeMap<String, Frame>,
+}
+
+impl Frame {
+    /// Add a sample's `period` along `stack`, outermost frame first.
+    pub fn insert<I>(&mut self, stack: I, period: u64)
+    where
+        I: IntoIterator<Item = (String, Option<Rc<str>>, bool)>,
+    {
+        self.period += period;
+        let mut frame = self;
+        for (name, dso, kernel) in stack {
+            frame = frame.children.entry(name).or_insert_with(|| Frame {
+                dso,
+                kernel,
+                ..Default::default()
+            });
+            frame.period += period;
+        }
+    }
+}
+
+/// A flame graph of one event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlameGraph {
+    pub event: String,
+    /// The `all` frame, under every stack.
+    pub root: Frame,
+    /// The baseline's total period, for a differential graph.
+    pub baseline: Option<u64>,
+}
+
+impl FlameGraph {
+    /// A differential graph: `self`'s frames, each with the period of
+    /// the same path in `baseline`. Paths only in the baseline are left
+    /// out, as the graph shows where the new samples went.
+    pub fn diff(mut self, baseline: &FlameGraph) -> FlameGraph {
+        fn walk(frame: &mut Frame, baseline: Option<&Frame>) {
+            frame.baseline = baseline.map_or(0, |base| base.period);
+            for (name, child) in frame.children.iter_mut() {
+                walk(child, baseline.and_then(|base| base.children.get(name)));
+            }
+        }
+        walk(&mut self.root, Some(&baseline.root));
+        self.baseline = Some(baseline.root.period);
+        self
+    }
+}
+
+/// Merge the call chains of the first event's samples in `data` that
+/// `filter` keeps.
+pub fn build(
+    data: &PerfData,
+    options: &FlameOptions,
+    demangle: Demangle,
+    filter: &Filter,
+) -> FlameGraph {
+    let mut root = Frame::default();
+    let mut session = Session::new();
+    session.set_demangle(demangle);
+    session.set_inline(options.inline);
+    for_each_stack(
+        data,
+        session,
+        options.max_stack,
+        filter,
+        |_, sample, frames| {
+            let stack = frames
+                .iter()
+                .map(|location| (frame_name(location), location.dso.clone(), location.kernel));
+            if options.reverse {
+                root.insert(stack, sample.period);
+            } else {
+                root.insert(stack.rev(), sample.period);
+            }
+        },
+    );
+    FlameGraph {
+        event: if data.attrs.is_empty() {
+            String::new()
+        } else {
+            data.event_name(0)
+        },
+        root,
+        baseline: None,
+    }
+}
+
+/// A frame where it is drawn, in pixels from the left padding.
+struct Rect<'a> {
+    name: &'a str,
+    frame: &'a Frame,
+    depth: usize,
+    x: f64,
+    width: f64,
+}
+
+/// Lay out `frame` and what is above it, from `x`.
+fn layout<'a>(
+    rects: &mut Vec<Rect<'a>>,
+    name: &'a str,
+    frame: &'a Frame,
+    depth: usize,
+    x: f64,
+    scale: f64,
+) {
+    let width = frame.period as f64 * scale;
+    if width < MIN_WIDTH {
+        return;
+    }
+    rects.push(Rect {
+        name,
+        frame,
+        depth,
+        x,
+        width,
+    });
+    let mut x = x;
+    for (name, child) in &frame.children {
+        layout(rects, name, child, depth + 1, x, scale);
+        x += child.period as f64 * scale;
+    }
+}
+
+/// A number from 0 to 1 that only depends on `text`.
+fn hash(text: &str) -> f64 {
+    // FNV-1a
+    let hash = text.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    (hash >> 40) as f64 / (1u64 << 24) as f64
+}
+
+/// The fill of a frame, as `rgb(r,g,b)`.
+fn color(mode: FlameColor, name: &str, frame: &Frame) -> String {
+    let v = hash(name);
+    let (r, g, b) = match (mode, &frame.dso) {
+        (FlameColor::Kernel, _) if frame.kernel => (190.0 + 65.0 * v, 90.0 + 65.0 * v, 0.0),
+        (FlameColor::Kernel, _) => (205.0 + 50.0 * v, 230.0 * (v * 1000.0).fract(), 55.0 * v),
+        (FlameColor::Dso, Some(dso)) => {
+            // A pale shade of the DSO's hue, a little different for each function.
+            let hue = hash(dso);
+            let channel = |offset: f64| {
+                let angle = (hue + offset) * std::f64::consts::TAU;
+                150.0 + 80.0 * angle.cos() + 20.0 * v
+            };
+            (channel(0.0), channel(1.0 / 3.0), channel(2.0 / 3.0))
+        }
+        (FlameColor::Dso, None) => (180.0 + 20.0 * v, 180.0 + 20.0 * v, 180.0 + 20.0 * v),
+    };
+    format!("rgb({},{},{})", r as u8, g as u8, b as u8)
+}
+
+/// The fill of a frame whose share of the samples changed by `delta`
+/// points: red for more, blue for less, stronger up to `max`.
+fn diff_color(delta: f64, max: f64) -> String {
+    let v = if max > 0.0 {
+        (210.0 * delta.abs() / max) as u8
+    } else {
+        0
+    };
+    if delta > 0.0 {
+        format!("rgb(255,{},{})", 255 - v, 255 - v)
+    } else {
+        format!("rgb({},{},255)", 255 - v, 255 - v)
+    }
+}
+
+/// `name` cut to fit `width` pixels, or nothing when too little would show.
+fn label(name: &str, width: f64) -> String {
+    let fits = (width / (FONT_SIZE * FONT_WIDTH)) as usize;
+    if fits < 3 {
+        return String::new();
+    }
+    if name.chars().count() <= fits {
+        return name.to_string();
+    }
+    let mut label: String = name.chars().take(fits - 2).collect();
+    label.push_str("..");
+    label
+}
+
+/// `text` with the characters XML gives a meaning escaped.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Zooms into a frame when it is clicked, and searches frame
+/// names for a regular expression with the search button or
+/// Ctrl-F. Escape undoes both.
+const SCRIPT: &str = r#"
+var frames, details, unzoombtn, searchbtn, matchedtxt, searching = false;
+function init(evt) {
+    frames = document.getElementById("frames");
+    details = document.getElementById("details").firstChild;
+    unzoombtn = document.getElementById("unzoom");
+    searchbtn = document.getElementById("search");
+    matchedtxt = document.getElementById("matched");
+    frames.addEventListener("click", function (e) {
+        var g = frame(e.target);
+        if (g) zoom(g);
+    });
+    frames.addEventListener("mouseover", function (e) {
+        var g = frame(e.target);
+        if (g) details.nodeValue = g.querySelector("title").textContent;
+    });
+    frames.addEventListener("mouseout", function () { details.nodeValue = " "; });
+    unzoombtn.addEventListener("click", unzoom);
+    searchbtn.addEventListener("click", function () {
+        if (searching) resetSearch(); else search();
+    });
+    window.addEventListener("keydown", function (e) {
+        if (e.key === "f" && (e.ctrlKey || e.metaKey)) {
+            e.preventDefault();
+            search();
+        } else if (e.key === "Escape") {
+            unzoom();
+            resetSearch();
+        }
+    });
+}
+function frame(el) {
+    while (el && el !== frames) {
+        if (el.classList && el.classList.contains("frame")) return el;
+        el = el.parentNode;
+    }
+    return null;
+}
+function attr(g, name) { return parseFloat(g.getAttribute("data-" + name)); }
+function place(g, x, w) {
+    var rect = g.querySelector("rect"), text = g.querySelector("text");
+    var name = g.getAttribute("data-name");
+    rect.setAttribute("x", xpad + x);
+    rect.setAttribute("width", w);
+    text.setAttribute("x", xpad + x + 3);
+    var fits = Math.floor(w / (fontsize * fontwidth));
+    if (fits < 3) text.textContent = "";
+    else if (name.length <= fits) text.textContent = name;
+    else text.textContent = name.substring(0, fits - 2) + "..";
+}
+function zoom(target) {
+    var tx = attr(target, "x"), tw = attr(target, "w"), td = attr(target, "depth");
+    var ratio = width / tw, eps = 1e-6;
+    unzoombtn.classList.remove("hide");
+    for (var i = 0; i < frames.children.length; i++) {
+        var g = frames.children[i];
+        var x = attr(g, "x"), w = attr(g, "w"), d = attr(g, "depth");
+        g.classList.remove("hide");
+        g.classList.remove("parent");
+        if (d < td && x <= tx + eps && x + w >= tx + tw - eps) {
+            g.classList.add("parent");
+            place(g, 0, width);
+        } else if (d >= td && x >= tx - eps && x + w <= tx + tw + eps) {
+            place(g, (x - tx) * ratio, w * ratio);
+        } else {
+            g.classList.add("hide");
+        }
+    }
+}
+function unzoom() {
+    unzoombtn.classList.add("hide");
+    for (var i = 0; i < frames.children.length; i++) {
+        var g = frames.children[i];
+        g.classList.remove("hide");
+        g.classList.remove("parent");
+        place(g, attr(g, "x"), attr(g, "w"));
+    }
+}
+function search() {
+    var term = prompt("Enter a search term (regexp allowed, eg: ^std::)", "");
+    if (!term) return;
+    var re = new RegExp(term), spans = [];
+    for (var i = 0; i < frames.children.length; i++) {
+        var g = frames.children[i], rect = g.querySelector("rect");
+        if (!rect.hasAttribute("data-fill")) rect.setAttribute("data-fill", rect.getAttribute("fill"));
+        if (re.test(g.getAttribute("data-name"))) {
+            rect.setAttribute("fill", "rgb(230,0,230)");
+            spans.push([attr(g, "x"), attr(g, "x") + attr(g, "w")]);
+        } else {
+            rect.setAttribute("fill", rect.getAttribute("data-fill"));
+        }
+    }
+    // A match inside another counts once.
+    spans.sort(function (a, b) { return a[0] - b[0]; });
+    var covered = 0, end = 0;
+    spans.forEach(function (s) {
+        if (s[1] > end) {
+            covered += s[1] - Math.max(s[0], end);
+            end = s[1];
+        }
+    });
+    searching = true;
+    searchbtn.textContent = "Reset Search";
+    matchedtxt.textContent = "Matched: " + (100 * covered / width).toFixed(1) + "%";
+}
+function resetSearch() {
+    for (var i = 0; i < frames.children.length; i++) {
+        var rect = frames.children[i].querySelector("rect");
+        if (rect.hasAttribute("data-fill")) rect.setAttribute("fill", rect.getAttribute("data-fill"));
+    }
+    searching = false;
+    searchbtn.textContent = "Search";
+    matchedtxt.textContent = " ";
+}
+"#;
+
+/// Draw `graph` as an SVG file with the script to explore it.
+pub fn write_svg(
+    out: &mut dyn Write,
+    graph: &FlameGraph,
+    options: &FlameOptions,
+) -> std::io::Result<()> {
+    let width = IMAGE_WIDTH - 2.0 * X_PAD;
+    let total = graph.root.period.max(1);
+    let mut rects = Vec::new();
+    layout(&mut rects, "all", &graph.root, 0, 0.0, width / total as f64);
+    let depth = rects.iter().map(|rect| rect.depth).max().unwrap_or(0);
+    let height = TOP_PAD + BOTTOM_PAD + (depth + 1) as f64 * FRAME_HEIGHT;
+    let title = match (options.icicle, graph.baseline) {
+        (_, Some(_)) => "Differential Flame Graph",
+        (true, None) => "Icicle Graph",
+        (false, None) => "Flame Graph",
+    };
+    // Change in each frame's share of the samples, in percentage points.
+    let delta = |frame: &Frame| {
+        let base = graph.baseline.unwrap_or(0).max(1);
+        100.0 * (frame.period as f64 / total as f64 - frame.baseline as f64 / base as f64)
+    };
+    let max_delta = rects
+        .iter()
+        .map(|rect| delta(rect.frame).abs())
+        .fold(0.0, f64::max);
+
+    writeln!(out, r#"<?xml version="1.0" standalone="no"?>"#)?;
+    writeln!(
+        out,
+        r#"<!DOCTYPE svg PUBLIC "-//W3C//DTD SVG 1.1//EN" "http://www.w3.org/Graphics/SVG/1.1/DTD/svg11.dtd">"#
+    )?;
+    writeln!(
+        out,
+        r#"<svg version="1.1" width="{w}" height="{h}" onload="init(evt)" viewBox="0 0 {w} {h}" xmlns="http://www.w3.org/2000/svg">"#,
+        w = IMAGE_WIDTH,
+        h = height
+    )?;
+    writeln!(
+        out,
+        "<style>\
+         text {{ font-family: Verdana, sans-serif; font-size: {}px; fill: rgb(0,0,0); }}\
+         #title {{ text-anchor: middle; font-size: {}px; }}\
+         #search, #unzoom {{ cursor: pointer; }}\
+         .frame:hover rect {{ stroke: black; stroke-width: 0.5; cursor: pointer; }}\
+         .hide {{ display: none; }}\
+         .parent {{ opacity: 0.5; }}\
+         </style>",
+        FONT_SIZE,
+        FONT_SIZE + 5.0
+    )?;
+    writeln!(
+        out,
+        "<script type=\"text/ecmascript\"><![CDATA[\n\
+         var xpad = {}, width = {}, fontsize = {}, fontwidth = {};{}]]></script>",
+        X_PAD, width, FONT_SIZE, FONT_WIDTH, SCRIPT
+    )?;
+    writeln!(
+        out,
+        r#"<rect x="0" y="0" width="{}" height="{}" fill="rgb(248,248,248)"/>"#,
+        IMAGE_WIDTH, height
+    )?;
+    writeln!(
+        out,
+        r#"<text id="title" x="{}" y="{}">{}: {}</text>"#,
+        IMAGE_WIDTH / 2.0,
+        FONT_SIZE * 2.0,
+        title,
+        escape(&graph.event)
+    )?;
+    writeln!(
+        out,
+        r#"<text id="unzoom" class="hide" x="{}" y="{}">Reset Zoom</text>"#,
+        X_PAD,
+        FONT_SIZE * 2.0
+    )?;
+    writeln!(
+        out,
+        r#"<text id="search" x="{}" y="{}">Search</text>"#,
+        IMAGE_WIDTH - X_PAD - 100.0,
+        FONT_SIZE * 2.0
+    )?;
+    writeln!(
+        out,
+        r#"<text id="details" x="{}" y="{}"> </text>"#,
+        X_PAD,
+        height - BOTTOM_PAD / 2.0
+    )?;
+    writeln!(
+        out,
+        r#"<text id="matched" x="{}" y="{}"> </text>"#,
+        IMAGE_WIDTH - X_PAD - 100.0,
+        height - BOTTOM_PAD / 2.0
+    )?;
+
+    writeln!(out, r#"<g id="frames">"#)?;
+    for rect in &rects {
+        let y = if options.icicle {
+            TOP_PAD + rect.depth as f64 * FRAME_HEIGHT
+        } else {
+            height - BOTTOM_PAD - (rect.depth + 1) as f64 * FRAME_HEIGHT
+        };
+        let name = escape(rect.name);
+        let mut info = format!(
+            "{} ({} {}, {:.2}%",
+            name,
+            rect.frame.period,
+            escape(&graph.event),
+            100.0 * rect.frame.period as f64 / total as f64
+        );
+        if let Some(dso) = &rect.frame.dso {
+            info.push_str(&format!(", {}", escape(dso)));
+        }
+        if graph.baseline.is_some() {
+            info.push_str(&format!(", {:+.2}%", delta(rect.frame)));
+        }
+        info.push(')');
+        let fill = match graph.baseline {
+            Some(_) => diff_color(delta(rect.frame), max_delta),
+            None => color(options.color, rect.name, rect.frame),
+        };
+        writeln!(
+            out,
+            r#"<g class="frame" data-name="{name}" data-x="{x:.3}" data-w="{w:.3}" data-depth="{d}"><title>{info}</title><rect x="{rx:.3}" y="{y}" width="{w:.3}" height="{h}" fill="{fill}" rx="2" ry="2"/><text x="{tx:.3}" y="{ty}">{label}</text></g>"#,
+            name = name,
+            x = rect.x,
+            w = rect.width,
+            d = rect.depth,
+            info = info,
+            rx = X_PAD + rect.x,
+            y = y,
+            h = FRAME_HEIGHT - 1.0,
+            fill = fill,
+            tx = X_PAD + rect.x + 3.0,
+            ty = y + FRAME_HEIGHT - 4.5,
+            label = escape(&label(rect.name, rect.width)),
+        )?;
+    }
+    writeln!(out, "</g>")?;
+    writeln!(out, "</svg>")
+}
+
+#[cfg(test)]
+#[test]
+fn flamegraph_test() {
+    let frame = |name: &str, kernel| (name.to_string(), Some(Rc::from("/bin/app")), kernel);
+    let mut root = Frame::default();
+    root.insert(vec![frame("main", false), frame("work", false)], 3);
+    root.insert(vec![frame("main", false), frame("sys_write", true)], 1);
+    root.insert(vec![frame("main", false), frame("<tiny>", false)], 0);
+    assert_eq!(root.period, 4);
+    assert_eq!(root.children["main"].period, 4);
+    assert!(root.children["main"].children["sys_write"].kernel);
+
+    let graph = FlameGraph {
+        event: "cpu-clock".to_string(),
+        root,
+        baseline: None,
+    };
+    let mut options = FlameOptions {
+        icicle: false,
+        reverse: false,
+        color: FlameColor::Kernel,
+        max_stack: 127,
+        inline: true,
+    };
+    let svg = |options: &FlameOptions| {
+        let mut out = Vec::new();
+        write_svg(&mut out, &graph, options).unwrap();
+        String::from_utf8(out).unwrap()
+    };
+    let flame = svg(&options);
+    // all, main, sys_write and work: the empty frame is too narrow.
+    assert_eq!(flame.matches(r#"<g class="frame""#).count(), 4);
+    assert!(flame.contains(r#"data-name="all" data-x="0.000" data-w="1180.000""#));
+    assert!(flame.contains(r#"data-name="work" data-x="295.000" data-w="885.000""#));
+    assert!(!flame.contains("<tiny>"));
+    assert!(flame.contains("Flame Graph: cpu-clock"));
+    let y = |svg: &str, name: &str| -> f64 {
+        let at = svg.find(&format!(r#"data-name="{}""#, name)).unwrap();
+        let y = &svg[at..][svg[at..].find(" y=\"").unwrap() + 4..];
+        y[..y.find('"').unwrap()].parse().unwrap()
+    };
+    assert!(y(&flame, "work") < y(&flame, "main"));
+
+    options.icicle = true;
+    let icicle = svg(&options);
+    assert!(y(&icicle, "work") > y(&icicle, "main"));
+    assert!(icicle.contains("Icicle Graph"));
+
+    // work went from a quarter of the samples to three quarters.
+    let mut base = Frame::default();
+    base.insert(vec![frame("main", false), frame("work", false)], 1);
+    base.insert(vec![frame("main", false), frame("sys_write", true)], 3);
+    let base = FlameGraph {
+        event: "cpu-clock".to_string(),
+        root: base,
+        baseline: None,
+    };
+    let diff = graph.clone().diff(&base);
+    assert_eq!(diff.baseline, Some(4));
+    assert_eq!(diff.root.children["main"].children["work"].baseline, 1);
+    assert_eq!(diff.root.children["main"].children["<tiny>"].baseline, 0);
+    let mut out = Vec::new();
+    write_svg(&mut out, &diff, &options).unwrap();
+    let diff = String::from_utf8(out).unwrap();
+    assert!(diff.contains("Differential Flame Graph"));
+    assert!(diff.contains("+50.00%"));
+    assert_eq!(diff_color(50.0, 50.0), "rgb(255,45,45)");
+    assert_eq!(diff_color(-50.0, 50.0), "rgb(45,45,255)");
+    assert_eq!(diff_color(0.0, 0.0), "rgb(255,255,255)");
+
+    assert_eq!(label("main::work", 1000.0), "main::work");
+    assert_eq!(label("main::work", 50.0), "main:..");
+    assert_eq!(label("main::work", 10.0), "");
+    assert_eq!(escape("<a & 'b'>"), "&lt;a &amp; &apos;b&apos;&gt;");
+    assert!("flame".parse::<FlameColor>().is_err());
+}
+
+#[test]
+fn build_test() {
+    let data = PerfData::open("tests/fixtures/two-events.data").unwrap();
+    let options = FlameOptions {
+        icicle: false,
+        reverse: true,
+        color: FlameColor::Dso,
+        max_stack: 127,
+        inline: false,
+    };
+    let graph = build(&data, &options, Demangle::Full, &Filter::default());
+    // 52 samples of 500us, 27 of them in libc and 25 in dash.
+    let samples = data.samples_of(0);
+    assert_eq!(samples.len(), 52);
+    assert_eq!(graph.root.period, 26_000_000);
+    assert_eq!(
+        graph.root.period,
+        samples.iter().map(|s| s.period).sum::<u64>()
+    );
+    assert_eq!(graph.event, "cpu-clock");
+    // Reversed, so the root's children are the innermost frames.
+    let in_dso = |path: &str| -> u64 {
+        graph
+            .root
+            .children
+            .values()
+            .filter(|f| f.dso.as_deref() == Some(path))
+            .map(|f| f.period)
+            .sum()
+    };
+    assert_eq!(in_dso("/usr/lib/x86_64-linux-gnu/libc.so.6"), 13_500_000);
+    assert_eq!(in_dso("/usr/bin/dash"), 12_500_000);
+    let first = &graph.root.children[&super::first_frame_name(&data)];
+    assert_eq!(
+        first.dso.as_deref(),
+        Some("/usr/lib/x86_64-linux-gnu/libc.so.6")
+    );
+    assert!(!first.kernel && first.period >= 500_000);
+}
@@ -0,0 +1,109 @@
+//! Collapsed stacks for `ruperf report --folded`: one line per
+//! distinct call chain, its functions from the outermost separated by
+//! `;` and followed by the number of samples, as `flamegraph.pl` and
+//! other flame graph tools read them.
+
+use super::{for_each_stack, frame_name, Filter};
+use crate::data::PerfData;
+use crate::session::Session;
+use crate::symbols::Demangle;
+use std::collections::BTreeMap;
+use std::io::prelude::*;
+
+/// What goes into a collapsed stack.
+#[derive(Debug, Clone)]
+pub struct FoldedOptions {
+    /// Start each stack with `comm-pid` of the sampled process.
+    pub per_process: bool,
+    /// Add `_[k]` to kernel functions.
+    pub annotate_kernel: bool,
+    pub max_stack: usize,
+    pub inline: bool,
+}
+
+/// Count the samples of each call chain of the first event in `data`
+/// that `filter` keeps, keyed by the collapsed stack.
+pub fn collapse(
+    data: &PerfData,
+    options: &FoldedOptions,
+    demangle: Demangle,
+    filter: &Filter,
+) -> BTreeMap<String, u64> {
+    let mut stacks = BTreeMap::new();
+    let mut session = Session::new();
+    session.set_demangle(demangle);
+    session.set_inline(options.inline);
+    for_each_stack(
+        data,
+        session,
+        options.max_stack,
+        filter,
+        |session, sample, frames| {
+            let mut names = Vec::with_capacity(frames.len() + 1);
+            if options.per_process {
+                let comm = session.comm(sample.pid, sample.tid);
+                names.push(format!("{}-{}", comm.replace(';', ":"), sample.pid));
+            }
+            for location in frames.iter().rev() {
+                // `;` separates the frames, so it can't be in a name.
+                let mut name = frame_name(location).replace(';', ":");
+                if options.annotate_kernel && location.kernel {
+                    name.push_str("_[k]");
+                }
+                names.push(name);
+            }
+            *stacks.entry(names.join(";")).or_insert(0) += 1;
+        },
+    );
+    stacks
+}
+
+/// Print `stacks` one per line.
+pub fn print(out: &mut impl Write, stacks: &BTreeMap<String, u64>) -> std::io::Result<()> {
+    for (stack, count) in stacks {
+        writeln!(out, "{} {}", stack, count)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[test]
+fn folded_test() {
+    let data = PerfData::open("tests/fixtures/two-events.data").unwrap();
+    let options = FoldedOptions {
+        per_process: true,
+        annotate_kernel: true,
+        max_stack: 127,
+        inline: false,
+    };
+    let stacks = collapse(&data, &options, Demangle::Full, &Filter::default());
+    // The fixture recorded `sh` without call chains: one frame a stack.
+    assert_eq!(stacks.values().sum::<u64>(), 52);
+    assert_eq!(
+        stacks.values().sum::<u64>(),
+        data.samples_of(0).len() as u64
+    );
+    assert!(
+        stacks
+            .keys()
+            .all(|stack| stack.starts_with("sh-27577;") && stack.matches(';').count() == 1),
+        "{:?}",
+        stacks
+    );
+    let first = format!("sh-27577;{}", super::first_frame_name(&data));
+    assert!(stacks[&first] >= 1, "{:?}", stacks);
+
+    let mut text = Vec::new();
+    print(&mut text, &stacks).unwrap();
+    let text = String::from_utf8(text).unwrap();
+    assert_eq!(text.lines().count(), stacks.len());
+    assert!(text
+        .lines()
+        .all(|line| line.rsplit(' ').next().unwrap().parse::<u64>().is_ok()));
+
+    let nobody = Filter {
+        pids: vec![u32::MAX],
+        ..Filter::default()
+    };
+    assert!(collapse(&data, &options, Demangle::Full, &nobody).is_empty());
+}
@@ -0,0 +1,571 @@
+//! Firefox Profiler profiles for `ruperf report --gecko`: the first
+//! event's samples in the processed profile format that
+//! <https://profiler.firefox.com> opens, one thread per sampled thread
+//! with its own stack, frame and function tables, and a marker for
+//! each stretch a thread ran on a CPU when the recording has context
+//! switch records. See
+//! <https://github.com/firefox-devtools/profiler/blob/main/docs-developer/CHANGELOG-formats.md>.
+
+use super::{for_each_stack, Filter};
+use crate::data::{PerfData, Record};
+use crate::session::{Location, Session};
+use crate::symbols::Demangle;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::io::prelude::*;
+use std::rc::Rc;
+
+/// The version of the processed format written, which the profiler
+/// upgrades from when it opens the file.
+const PROCESSED_PROFILE_VERSION: u32 = 47;
+
+/// The Gecko profile format version that goes with it.
+const GECKO_VERSION: u32 = 27;
+
+/// Indices into the categories in `meta`.
+const CATEGORY_USER: usize = 1;
+const CATEGORY_KERNEL: usize = 2;
+
+/// `phase` of a marker with a start and an end.
+const INTERVAL: u8 = 1;
+
+/// `type` of a resource that is a library.
+const RESOURCE_LIBRARY: u32 = 1;
+
+/// What goes into a profile.
+#[derive(Debug, Clone)]
+pub struct GeckoOptions {
+    pub max_stack: usize,
+    pub inline: bool,
+}
+
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SamplesTable {
+    length: usize,
+    stack: Vec<usize>,
+    /// In milliseconds.
+    time: Vec<f64>,
+    weight: Option<Vec<u64>>,
+    weight_type: &'static str,
+}
+
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct MarkerTable {
+    length: usize,
+    category: Vec<usize>,
+    data: Vec<Value>,
+    end_time: Vec<Option<f64>>,
+    name: Vec<usize>,
+    phase: Vec<u8>,
+    start_time: Vec<Option<f64>>,
+}
+
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct StackTable {
+    length: usize,
+    frame: Vec<usize>,
+    prefix: Vec<Option<usize>>,
+    category: Vec<usize>,
+    subcategory: Vec<usize>,
+}
+
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct FrameTable {
+    length: usize,
+    address: Vec<i64>,
+    inline_depth: Vec<u32>,
+    category: Vec<usize>,
+    subcategory: Vec<usize>,
+    func: Vec<usize>,
+    native_symbol: Vec<Option<usize>>,
+    #[serde(rename = "innerWindowID")]
+    inner_window_id: Vec<Option<u64>>,
+    implementation: Vec<Option<usize>>,
+    line: Vec<Option<u32>>,
+    column: Vec<Option<u32>>,
+}
+
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct FuncTable {
+    length: usize,
+    name: Vec<usize>,
+    #[serde(rename = "isJS")]
+    is_js: Vec<bool>,
+    #[serde(rename = "relevantForJS")]
+    relevant_for_js: Vec<bool>,
+    resource: Vec<i64>,
+    file_name: Vec<Option<usize>>,
+    line_number: Vec<Option<u32>>,
+    column_number: Vec<Option<u32>>,
+}
+
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ResourceTable {
+    length: usize,
+    lib: Vec<usize>,
+    name: Vec<usize>,
+    host: Vec<Option<usize>>,
+    #[serde(rename = "type")]
+    kind: Vec<u32>,
+}
+
+/// Left empty: frames carry their function instead.
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct NativeSymbols {
+    length: usize,
+    lib_index: Vec<usize>,
+    address: Vec<u64>,
+    name: Vec<usize>,
+    function_size: Vec<Option<u64>>,
+}
+
+/// A sampled thread and the tables its samples point into.
+#[derive(Debug, Default)]
+struct Thread {
+    name: String,
+    process_name: String,
+    pid: u32,
+    tid: u32,
+    samples: SamplesTable,
+    markers: MarkerTable,
+    stacks: StackTable,
+    frames: FrameTable,
+    funcs: FuncTable,
+    resources: ResourceTable,
+    strings: Vec<String>,
+    string_index: HashMap<String, usize>,
+    stack_index: HashMap<(Option<usize>, usize), usize>,
+    frame_index: HashMap<(u64, usize, u32), usize>,
+    func_index: HashMap<(usize, i64, Option<usize>), usize>,
+    resource_index: HashMap<usize, i64>,
+}
+
+impl Thread {
+    fn string(&mut self, string: &str) -> usize {
+        if let Some(&i) = self.string_index.get(string) {
+            return i;
+        }
+        self.strings.push(string.to_string());
+        self.string_index
+            .insert(string.to_string(), self.strings.len() - 1);
+        self.strings.len() - 1
+    }
+
+    /// The resource for library `lib`, or -1 without one.
+    fn resource(&mut self, lib: Option<usize>, libs: &[Lib]) -> i64 {
+        let lib = match lib {
+            Some(lib) => lib,
+            None => return -1,
+        };
+        if let Some(&i) = self.resource_index.get(&lib) {
+            return i;
+        }
+        let name = self.string(&libs[lib].name);
+        let table = &mut self.resources;
+        table.lib.push(lib);
+        table.name.push(name);
+        table.host.push(None);
+        table.kind.push(RESOURCE_LIBRARY);
+        table.length += 1;
+        let i = table.length as i64 - 1;
+        self.resource_index.insert(lib, i);
+        i
+    }
+
+    fn func(&mut self, location: &Location, resource: i64) -> usize {
+        let name = match &location.symbol {
+            Some(symbol) => symbol.name.clone(),
+            None => format!("{:#x}", location.address),
+        };
+        let name = self.string(&name);
+        let file = location
+            .srcline
+            .as_ref()
+            .map(|srcline| srcline.file.clone());
+        let file = file.map(|file| self.string(&file));
+        if let Some(&i) = self.func_index.get(&(name, resource, file)) {
+            return i;
+        }
+        let table = &mut self.funcs;
+        table.name.push(name);
+        table.is_js.push(false);
+        table.relevant_for_js.push(false);
+        table.resource.push(resource);
+        table.file_name.push(file);
+        table.line_number.push(None);
+        table.column_number.push(None);
+        table.length += 1;
+        self.func_index
+            .insert((name, resource, file), table.length - 1);
+        table.length - 1
+    }
+
+    fn frame(&mut self, location: &Location, libs: &mut Libs, inline_depth: u32) -> usize {
+        let lib = libs.lib(location.dso.as_ref());
+        let resource = self.resource(lib, &libs.libs);
+        let func = self.func(location, resource);
+        let key = (location.address, func, inline_depth);
+        if let Some(&i) = self.frame_index.get(&key) {
+            return i;
+        }
+        let category = if location.kernel {
+            CATEGORY_KERNEL
+        } else {
+            CATEGORY_USER
+        };
+        let table = &mut self.frames;
+        // Addresses are relative to the library, -1 when unknown.
+        table.address.push(if lib.is_some() {
+            location.address as i64
+        } else {
+            -1
+        });
+        table.inline_depth.push(inline_depth);
+        table.category.push(category);
+        table.subcategory.push(0);
+        table.func.push(func);
+        table.native_symbol.push(None);
+        table.inner_window_id.push(None);
+        table.implementation.push(None);
+        table
+            .line
+            .push(location.srcline.as_ref().map(|srcline| srcline.line));
+        table.column.push(None);
+        table.length += 1;
+        self.frame_index.insert(key, table.length - 1);
+        table.length - 1
+    }
+
+    fn stack(&mut self, prefix: Option<usize>, frame: usize) -> usize {
+        if let Some(&i) = self.stack_index.get(&(prefix, frame)) {
+            return i;
+        }
+        let table = &mut self.stacks;
+        table.frame.push(frame);
+        table.prefix.push(prefix);
+        table.category.push(self.frames.category[frame]);
+        table.subcategory.push(0);
+        table.length += 1;
+        self.stack_index.insert((prefix, frame), table.length - 1);
+        table.length - 1
+    }
+
+    /// Add a sample at `time` ms, with its call chain innermost first.
+    fn sample(&mut self, time: f64, frames: &[Location], libs: &mut Libs) {
+        let mut stack = None;
+        let mut inline_depth = 0;
+        for location in frames.iter().rev() {
+            // A function inlined into the frame before it, from the root.
+            inline_depth = if location.inlined {
+                inline_depth + 1
+            } else {
+                0
+            };
+            let frame = self.frame(location, libs, inline_depth);
+            stack = Some(self.stack(stack, frame));
+        }
+        let stack = match stack {
+            Some(stack) => stack,
+            None => return,
+        };
+        self.samples.stack.push(stack);
+        self.samples.time.push(time);
+        self.samples.length += 1;
+    }
+
+    /// Add a marker for a stretch the thread ran on `cpu`.
+    fn running(&mut self, start: f64, end: f64, cpu: u32) {
+        let name = self.string("Running");
+        let table = &mut self.markers;
+        table.category.push(CATEGORY_KERNEL);
+        table.data.push(json!({ "type": "Running", "cpu": cpu }));
+        table.end_time.push(Some(end));
+        table.name.push(name);
+        table.phase.push(INTERVAL);
+        table.start_time.push(Some(start));
+        table.length += 1;
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "processType": "default",
+            "processStartupTime": 0,
+            "processShutdownTime": null,
+            "registerTime": 0,
+            "unregisterTime": null,
+            "pausedRanges": [],
+            "name": self.name,
+            "isMainThread": self.pid == self.tid,
+            "processName": self.process_name,
+            "pid": self.pid.to_string(),
+            "tid": self.tid,
+            "samples": self.samples,
+            "markers": self.markers,
+            "stackTable": self.stacks,
+            "frameTable": self.frames,
+            "funcTable": self.funcs,
+            "resourceTable": self.resources,
+            "nativeSymbols": NativeSymbols::default(),
+            "stringArray": self.strings,
+        })
+    }
+}
+
+/// A mapped file, as the profiler's `libs` describe it.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Lib {
+    arch: String,
+    name: String,
+    path: String,
+    debug_name: String,
+    debug_path: String,
+    breakpad_id: String,
+    code_id: Option<String>,
+}
+
+/// Libraries by path, with the build ids recorded for them.
+#[derive(Debug, Default)]
+struct Libs {
+    libs: Vec<Lib>,
+    index: HashMap<Rc<str>, usize>,
+    build_ids: HashMap<String, Vec<u8>>,
+}
+
+impl Libs {
+    fn lib(&mut self, path: Option<&Rc<str>>) -> Option<usize> {
+        let path = path?;
+        if let Some(&i) = self.index.get(path) {
+            return Some(i);
+        }
+        let name = path.rsplit('/').next().unwrap_or(path).to_string();
+        let build_id = self.build_ids.get(&**path);
+        self.libs.push(Lib {
+            arch: String::new(),
+            name: name.clone(),
+            path: path.to_string(),
+            debug_name: name,
+            debug_path: path.to_string(),
+            breakpad_id: build_id.map(|id| breakpad_id(id)).unwrap_or_default(),
+            code_id: build_id.map(|id| id.iter().map(|b| format!("{:02x}", b)).collect()),
+        });
+        self.index.insert(path.clone(), self.libs.len() - 1);
+        Some(self.libs.len() - 1)
+    }
+}
+
+/// The id Breakpad gives an ELF file: the first 16 bytes of its build
+/// id read as a GUID, in upper case hex, with an age of 0.
+fn breakpad_id(build_id: &[u8]) -> String {
+    let mut guid = [0; 16];
+    let len = build_id.len().min(16);
+    guid[..len].copy_from_slice(&build_id[..len]);
+    guid[0..4].reverse();
+    guid[4..6].reverse();
+    guid[6..8].reverse();
+    let mut id: String = guid.iter().map(|b| format!("{:02X}", b)).collect();
+    id.push('0');
+    id
+}
+
+/// Gather the first event's samples in `data` that `filter` keeps,
+/// and the context switches of the threads they were taken in, into
+/// a processed profile.
+pub fn build(
+    data: &PerfData,
+    options: &GeckoOptions,
+    demangle: Demangle,
+    filter: &Filter,
+) -> Value {
+    let mut libs = Libs::default();
+    for build_id in data.features.build_ids.iter().flatten() {
+        libs.build_ids
+            .insert(build_id.filename.clone(), build_id.id.clone());
+    }
+
+    // When each thread ran, in nanoseconds, by tid.
+    let mut running: BTreeMap<u32, Vec<(u64, u64, u32)>> = BTreeMap::new();
+    let mut switched_in = HashMap::new();
+    let mut start = u64::MAX;
+    for record in data.ordered_records() {
+        match record {
+            Record::Switch(switch) if !switch.out => {
+                switched_in.insert(switch.tid, (switch.time, switch.cpu));
+            }
+            Record::Switch(switch) => {
+                if let Some((time, cpu)) = switched_in.remove(&switch.tid) {
+                    start = start.min(time);
+                    running
+                        .entry(switch.tid)
+                        .or_default()
+                        .push((time, switch.time, cpu));
+                }
+            }
+            Record::Sample(sample) => start = start.min(sample.time),
+            _ => {}
+        }
+    }
+    let ms = |time: u64| time.saturating_sub(start) as f64 / 1e6;
+
+    let mut threads: BTreeMap<u32, Thread> = BTreeMap::new();
+    let mut session = Session::new();
+    session.set_demangle(demangle);
+    session.set_inline(options.inline);
+    session.set_srclines(true);
+    for_each_stack(
+        data,
+        session,
+        options.max_stack,
+        filter,
+        |session, sample, frames| {
+            let thread = threads.entry(sample.tid).or_insert_with(|| Thread {
+                pid: sample.pid,
+                tid: sample.tid,
+                samples: SamplesTable {
+                    weight_type: "samples",
+                    ..SamplesTable::default()
+                },
+                ..Thread::default()
+            });
+            thread.name = session.comm(sample.pid, sample.tid);
+            thread.process_name = session.comm(sample.pid, sample.pid);
+            thread.sample(ms(sample.time), frames, &mut libs);
+        },
+    );
+    for (tid, thread) in threads.iter_mut() {
+        for &(from, to, cpu) in running.get(tid).into_iter().flatten() {
+            thread.running(ms(from), ms(to), cpu);
+        }
+    }
+
+    let event = if data.attrs.is_empty() {
+        String::new()
+    } else {
+        data.event_name(0)
+    };
+    let category = |name: &str, color: &str| json!({ "name": name, "color": color, "subcategories": ["Other"] });
+    json!({
+        "meta": {
+            "interval": 1.0,
+            "startTime": 0,
+            "processType": 0,
+            "product": event,
+            "stackwalk": 1,
+            "debug": false,
+            "version": GECKO_VERSION,
+            "preprocessedProfileVersion": PROCESSED_PROFILE_VERSION,
+            "symbolicated": true,
+            "categories": [
+                category("Other", "grey"),
+                category("User", "yellow"),
+                category("Kernel", "orange"),
+            ],
+            "markerSchema": [{
+                "name": "Running",
+                "display": ["marker-chart", "marker-table", "timeline-overview"],
+                "tooltipLabel": "Running on CPU {marker.data.cpu}",
+                "data": [{ "key": "cpu", "label": "CPU", "format": "integer" }],
+            }],
+            "extensions": { "length": 0, "baseURL": [], "id": [], "name": [] },
+        },
+        "libs": libs.libs,
+        "pages": [],
+        "counters": [],
+        "threads": threads.values().map(Thread::to_json).collect::<Vec<_>>(),
+    })
+}
+
+/// Write `profile` as JSON.
+pub fn write(out: &mut impl Write, profile: &Value) -> std::io::Result<()> {
+    serde_json::to_writer(&mut *out, profile)?;
+    writeln!(out)
+}
+
+#[cfg(test)]
+#[test]
+fn gecko_test() {
+    assert_eq!(
+        breakpad_id(&[
+            0xb0, 0xa8, 0xe4, 0x03, 0xec, 0x50, 0x76, 0x46, 0x07, 0x83, 0xed, 0x4f, 0x1e, 0x71,
+            0x29, 0xe4, 0x9b, 0x09
+        ]),
+        "03E4A8B050EC46760783ED4F1E7129E40"
+    );
+
+    let data = PerfData::open("tests/fixtures/two-events.data").unwrap();
+    let options = GeckoOptions {
+        max_stack: 127,
+        inline: false,
+    };
+    let profile = build(&data, &options, Demangle::Full, &Filter::default());
+    let threads = profile["threads"].as_array().unwrap();
+    assert!(!threads.is_empty());
+    let mut total = 0;
+    for thread in threads {
+        let table = &thread["samples"];
+        let length = table["length"].as_u64().unwrap() as usize;
+        assert_eq!(table["stack"].as_array().unwrap().len(), length);
+        total += length;
+        // Every stack's prefix comes before it, and every frame's
+        // function has a name in the string array.
+        let stacks = &thread["stackTable"];
+        for (i, prefix) in stacks["prefix"].as_array().unwrap().iter().enumerate() {
+            assert!(prefix.as_u64().is_none_or(|p| (p as usize) < i));
+        }
+        let strings = thread["stringArray"].as_array().unwrap().len() as u64;
+        let funcs = &thread["funcTable"];
+        for frame_func in thread["frameTable"]["func"].as_array().unwrap() {
+            let name = &funcs["name"][frame_func.as_u64().unwrap() as usize];
+            assert!(name.as_u64().unwrap() < strings);
+        }
+        assert!(thread["pid"].is_string());
+    }
+    assert_eq!(total, data.samples_of(0).len());
+
+    // `sh` alone, in one thread, sampled 52 times.
+    assert_eq!(threads.len(), 1);
+    let thread = &threads[0];
+    assert_eq!(
+        (&thread["name"], &thread["pid"], &thread["tid"]),
+        (&json!("sh"), &json!("27577"), &json!(27577))
+    );
+    let table = &thread["samples"];
+    assert_eq!(table["length"], 52);
+    let times = table["time"].as_array().unwrap();
+    assert_eq!(times[0], 0.0);
+    assert!(times.windows(2).all(|w| w[0].as_f64() <= w[1].as_f64()));
+    // The first sample's innermost frame, by way of each table.
+    let stack = table["stack"][0].as_u64().unwrap() as usize;
+    let frame = thread["stackTable"]["frame"][stack].as_u64().unwrap() as usize;
+    let func = thread["frameTable"]["func"][frame].as_u64().unwrap() as usize;
+    let name = thread["funcTable"]["name"][func].as_u64().unwrap() as usize;
+    assert_eq!(
+        thread["stringArray"][name],
+        json!(super::first_frame_name(&data))
+    );
+    let libs: Vec<&str> = profile["libs"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|lib| lib["path"].as_str().unwrap())
+        .collect();
+    assert!(libs.contains(&"/usr/lib/x86_64-linux-gnu/libc.so.6"));
+    assert!(libs.contains(&"/usr/bin/dash"));
+
+    let empty = build(
+        &PerfData::default(),
+        &options,
+        Demangle::Full,
+        &Filter::default(),
+    );
+    assert_eq!(empty["meta"]["product"], "");
+    assert!(empty["threads"].as_array().unwrap().is_empty());
+}
@@ -0,0 +1,675 @@
+//! pprof profiles for `ruperf report --pprof`: the call chains of the
+//! first event's samples as a gzipped `profile.proto` message, with the
+//! mapped files, their build ids and the functions and source lines of
+//! every frame. See
+//! <https://github.com/google/pprof/blob/main/proto/profile.proto>.
+
+use super::{for_each_stack, Filter};
+use crate::data::PerfData;
+use crate::session::{self, Session};
+use crate::symbols::Demangle;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::collections::HashMap;
+use std::io::{self, prelude::*};
+use std::rc::Rc;
+
+/// What goes into a profile.
+#[derive(Debug, Clone)]
+pub struct PprofOptions {
+    pub max_stack: usize,
+    pub inline: bool,
+}
+
+/// A `profile.proto` `Profile`. Strings are indices into `string_table`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Profile {
+    pub sample_type: Vec<ValueType>,
+    pub sample: Vec<Sample>,
+    pub mapping: Vec<Mapping>,
+    pub location: Vec<Location>,
+    pub function: Vec<Function>,
+    pub string_table: Vec<String>,
+    pub duration_nanos: i64,
+    pub default_sample_type: i64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ValueType {
+    pub ty: i64,
+    pub unit: i64,
+}
+
+/// The samples taken with one call chain in one thread.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Sample {
+    /// Innermost first.
+    pub location_id: Vec<u64>,
+    /// One per sample type.
+    pub value: Vec<i64>,
+    pub label: Vec<Label>,
+}
+
+/// A string or number attached to a sample.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Label {
+    pub key: i64,
+    pub str: i64,
+    pub num: i64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Mapping {
+    pub id: u64,
+    pub memory_start: u64,
+    pub memory_limit: u64,
+    pub file_offset: u64,
+    pub filename: i64,
+    pub build_id: i64,
+    pub has_functions: bool,
+    pub has_line_numbers: bool,
+    pub has_inline_frames: bool,
+}
+
+/// An address, with the functions inlined there before the one
+/// they were inlined into.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Location {
+    pub id: u64,
+    pub mapping_id: u64,
+    pub address: u64,
+    pub line: Vec<Line>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Line {
+    pub function_id: u64,
+    pub line: i64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Function {
+    pub id: u64,
+    pub name: i64,
+    pub system_name: i64,
+    pub filename: i64,
+}
+
+/// Protobuf wire types.
+const VARINT: u64 = 0;
+const LEN: u64 = 2;
+
+fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// A varint field, left out when 0 as proto3 does.
+fn put_uint(out: &mut Vec<u8>, field: u64, value: u64) {
+    if value != 0 {
+        put_varint(out, field << 3 | VARINT);
+        put_varint(out, value);
+    }
+}
+
+fn put_int(out: &mut Vec<u8>, field: u64, value: i64) {
+    put_uint(out, field, value as u64);
+}
+
+fn put_bytes(out: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    put_varint(out, field << 3 | LEN);
+    put_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+fn put_packed(out: &mut Vec<u8>, field: u64, values: impl Iterator<Item = u64>) {
+    let mut packed = Vec::new();
+    values.for_each(|value| put_varint(&mut packed, value));
+    if !packed.is_empty() {
+        put_bytes(out, field, &packed);
+    }
+}
+
+fn put_message(out: &mut Vec<u8>, field: u64, message: impl FnOnce(&mut Vec<u8>)) {
+    let mut bytes = Vec::new();
+    message(&mut bytes);
+    put_bytes(out, field, &bytes);
+}
+
+impl ValueType {
+    fn encode(&self, out: &mut Vec<u8>) {
+        put_int(out, 1, self.ty);
+        put_int(out, 2, self.unit);
+    }
+}
+
+impl Sample {
+    fn encode(&self, out: &mut Vec<u8>) {
+        put_packed(out, 1, self.location_id.iter().copied());
+        put_packed(out, 2, self.value.iter().map(|&value| value as u64));
+        for label in &self.label {
+            put_message(out, 3, |out| {
+                put_int(out, 1, label.key);
+                put_int(out, 2, label.str);
+                put_int(out, 3, label.num);
+            });
+        }
+    }
+}
+
+impl Mapping {
+    fn encode(&self, out: &mut Vec<u8>) {
+        put_uint(out, 1, self.id);
+        put_uint(out, 2, self.memory_start);
+        put_uint(out, 3, self.memory_limit);
+        put_uint(out, 4, self.file_offset);
+        put_int(out, 5, self.filename);
+        put_int(out, 6, self.build_id);
+        put_uint(out, 7, self.has_functions as u64);
+        put_uint(out, 9, self.has_line_numbers as u64);
+        put_uint(out, 10, self.has_inline_frames as u64);
+    }
+}
+
+impl Location {
+    fn encode(&self, out: &mut Vec<u8>) {
+        put_uint(out, 1, self.id);
+        put_uint(out, 2, self.mapping_id);
+        put_uint(out, 3, self.address);
+        for line in &self.line {
+            put_message(out, 4, |out| {
+                put_uint(out, 1, line.function_id);
+                put_int(out, 2, line.line);
+            });
+        }
+    }
+}
+
+impl Function {
+    fn encode(&self, out: &mut Vec<u8>) {
+        put_uint(out, 1, self.id);
+        put_int(out, 2, self.name);
+        put_int(out, 3, self.system_name);
+        put_int(out, 4, self.filename);
+    }
+}
+
+impl Profile {
+    /// The protobuf encoding of the profile, before compression.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        for value_type in &self.sample_type {
+            put_message(&mut out, 1, |out| value_type.encode(out));
+        }
+        for sample in &self.sample {
+            put_message(&mut out, 2, |out| sample.encode(out));
+        }
+        for mapping in &self.mapping {
+            put_message(&mut out, 3, |out| mapping.encode(out));
+        }
+        for location in &self.location {
+            put_message(&mut out, 4, |out| location.encode(out));
+        }
+        for function in &self.function {
+            put_message(&mut out, 5, |out| function.encode(out));
+        }
+        for string in &self.string_table {
+            put_bytes(&mut out, 6, string.as_bytes());
+        }
+        put_int(&mut out, 10, self.duration_nanos);
+        put_int(&mut out, 14, self.default_sample_type);
+        out
+    }
+}
+
+/// Gathers the profile, handing out ids for what it has not seen yet.
+#[derive(Default)]
+struct Builder {
+    profile: Profile,
+    strings: HashMap<String, i64>,
+    build_ids: HashMap<String, String>,
+    /// Mapping ids by start, end, offset and path.
+    mappings: HashMap<(u64, u64, u64, Rc<str>), u64>,
+    /// The mapping of kernel addresses, which grows to cover all of them.
+    kernel: Option<u64>,
+    /// Location ids by mapping id and address.
+    locations: HashMap<(u64, u64), u64>,
+    /// Function ids by name and file.
+    functions: HashMap<(String, String), u64>,
+    /// Indices of samples by call chain, pid and tid.
+    samples: HashMap<(Vec<u64>, u32, u32), usize>,
+    inline: bool,
+}
+
+impl Builder {
+    fn string(&mut self, string: &str) -> i64 {
+        if let Some(&i) = self.strings.get(string) {
+            return i;
+        }
+        let i = self.profile.string_table.len() as i64;
+        self.profile.string_table.push(string.to_string());
+        self.strings.insert(string.to_string(), i);
+        i
+    }
+
+    fn new_mapping(&mut self, start: u64, end: u64, pgoff: u64, path: &str) -> u64 {
+        let id = self.profile.mapping.len() as u64 + 1;
+        let build_id = self.build_ids.get(path).cloned().unwrap_or_default();
+        let mapping = Mapping {
+            id,
+            memory_start: start,
+            memory_limit: end,
+            file_offset: pgoff,
+            filename: self.string(path),
+            build_id: self.string(&build_id),
+            has_functions: true,
+            has_line_numbers: true,
+            has_inline_frames: self.inline,
+        };
+        self.profile.mapping.push(mapping);
+        id
+    }
+
+    /// The id of the mapping `location` is in, 0 if none.
+    fn mapping(&mut self, session: &Session, pid: u32, location: &session::Location) -> u64 {
+        if location.kernel {
+            let path = location.dso.as_deref().unwrap_or("[kernel.kallsyms]");
+            let (ip, end) = (location.ip, location.ip.saturating_add(1));
+            let id = match self.kernel {
+                Some(id) => id,
+                None => {
+                    let id = self.new_mapping(ip, end, 0, path);
+                    self.kernel = Some(id);
+                    id
+                }
+            };
+            let mapping = &mut self.profile.mapping[id as usize - 1];
+            mapping.memory_start = mapping.memory_start.min(ip);
+            mapping.memory_limit = mapping.memory_limit.max(end);
+            return id;
+        }
+        let map = match session.map_at(pid, location.ip) {
+            Some(map) => map,
+            None => return 0,
+        };
+        let key = (map.start, map.end, map.pgoff, map.path);
+        if let Some(&id) = self.mappings.get(&key) {
+            return id;
+        }
+        let id = self.new_mapping(key.0, key.1, key.2, &key.3);
+        self.mappings.insert(key, id);
+        id
+    }
+
+    /// The line of `location` in its function, when it has a symbol.
+    fn line(&mut self, location: &session::Location) -> Option<Line> {
+        let name = &location.symbol.as_ref()?.name;
+        let (file, line) = match &location.srcline {
+            Some(srcline) => (srcline.file.as_str(), i64::from(srcline.line)),
+            None => ("", 0),
+        };
+        let key = (name.clone(), file.to_string());
+        let function_id = match self.functions.get(&key) {
+            Some(&id) => id,
+            None => {
+                let id = self.profile.function.len() as u64 + 1;
+                let name = self.string(name);
+                let function = Function {
+                    id,
+                    name,
+                    // Names are only kept demangled.
+                    system_name: name,
+                    filename: self.string(file),
+                };
+                self.profile.function.push(function);
+                self.functions.insert(key, id);
+                id
+            }
+        };
+        Some(Line { function_id, line })
+    }
+
+    /// The location ids of `frames`, folding inlined functions
+    /// into the location of the function they were inlined into.
+    fn locations(&mut self, session: &Session, pid: u32, frames: &[session::Location]) -> Vec<u64> {
+        let mut ids = Vec::new();
+        let mut lines = Vec::new();
+        for location in frames {
+            lines.extend(self.line(location));
+            if location.inlined {
+                continue;
+            }
+            let mapping_id = self.mapping(session, pid, location);
+            let next = self.profile.location.len() as u64 + 1;
+            let id = *self
+                .locations
+                .entry((mapping_id, location.ip))
+                .or_insert(next);
+            if id == next {
+                self.profile.location.push(Location {
+                    id,
+                    mapping_id,
+                    address: location.ip,
+                    line: std::mem::take(&mut lines),
+                });
+            }
+            lines.clear();
+            ids.push(id);
+        }
+        ids
+    }
+}
+
+/// Gather the call chains of the first event's samples in `data` that
+/// `filter` keeps into a profile, counting samples and events.
+pub fn build(
+    data: &PerfData,
+    options: &PprofOptions,
+    demangle: Demangle,
+    filter: &Filter,
+) -> Profile {
+    let mut builder = Builder {
+        inline: options.inline,
+        ..Builder::default()
+    };
+    builder.string("");
+    for build_id in data.features.build_ids.iter().flatten() {
+        let hex: String = build_id.id.iter().map(|b| format!("{:02x}", b)).collect();
+        builder.build_ids.insert(build_id.filename.clone(), hex);
+    }
+    let event = if data.attrs.is_empty() {
+        String::new()
+    } else {
+        data.event_name(0)
+    };
+    let samples = ValueType {
+        ty: builder.string("samples"),
+        unit: builder.string("count"),
+    };
+    let events = ValueType {
+        ty: builder.string(&event),
+        unit: builder.string("events"),
+    };
+    builder.profile.default_sample_type = events.ty;
+    builder.profile.sample_type = vec![samples, events];
+    let (pid, tid, comm) = (
+        builder.string("pid"),
+        builder.string("tid"),
+        builder.string("comm"),
+    );
+
+    let mut session = Session::new();
+    session.set_demangle(demangle);
+    session.set_inline(options.inline);
+    session.set_srclines(true);
+    let mut times = None;
+    for_each_stack(
+        data,
+        session,
+        options.max_stack,
+        filter,
+        |session, sample, frames| {
+            let (first, last) = times.get_or_insert((sample.time, sample.time));
+            *first = sample.time.min(*first);
+            *last = sample.time.max(*last);
+            let ids = builder.locations(session, sample.pid, frames);
+            let key = (ids, sample.pid, sample.tid);
+            let period = sample.period as i64;
+            if let Some(&i) = builder.samples.get(&key) {
+                let value = &mut builder.profile.sample[i].value;
+                value[0] += 1;
+                value[1] += period;
+                return;
+            }
+            let name = builder.string(&session.comm(sample.pid, sample.tid));
+            builder
+                .samples
+                .insert(key.clone(), builder.profile.sample.len());
+            builder.profile.sample.push(Sample {
+                location_id: key.0,
+                value: vec![1, period],
+                label: vec![
+                    Label {
+                        key: pid,
+                        num: i64::from(sample.pid),
+                        ..Label::default()
+                    },
+                    Label {
+                        key: tid,
+                        num: i64::from(sample.tid),
+                        ..Label::default()
+                    },
+                    Label {
+                        key: comm,
+                        str: name,
+                        ..Label::default()
+                    },
+                ],
+            });
+        },
+    );
+    if let Some((first, last)) = times {
+        builder.profile.duration_nanos = (last - first) as i64;
+    }
+    builder.profile
+}
+
+/// Write `profile` gzipped, as pprof reads it.
+pub fn write(out: impl Write, profile: &Profile) -> io::Result<()> {
+    let mut gzip = GzEncoder::new(out, Compression::default());
+    gzip.write_all(&profile.encode())?;
+    gzip.finish()?.flush()
+}
+
+/// `profile.proto` as prost derives it, to read profiles back with a
+/// decoder that shares no code with `Profile::encode`. Field numbers
+/// are those of the upstream file.
+#[cfg(test)]
+mod proto {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Profile {
+        #[prost(message, repeated, tag = "1")]
+        pub sample_type: Vec<ValueType>,
+        #[prost(message, repeated, tag = "2")]
+        pub sample: Vec<Sample>,
+        #[prost(message, repeated, tag = "3")]
+        pub mapping: Vec<Mapping>,
+        #[prost(message, repeated, tag = "4")]
+        pub location: Vec<Location>,
+        #[prost(message, repeated, tag = "5")]
+        pub function: Vec<Function>,
+        #[prost(string, repeated, tag = "6")]
+        pub string_table: Vec<String>,
+        #[prost(int64, tag = "7")]
+        pub drop_frames: i64,
+        #[prost(int64, tag = "8")]
+        pub keep_frames: i64,
+        #[prost(int64, tag = "9")]
+        pub time_nanos: i64,
+        #[prost(int64, tag = "10")]
+        pub duration_nanos: i64,
+        #[prost(message, optional, tag = "11")]
+        pub period_type: Option<ValueType>,
+        #[prost(int64, tag = "12")]
+        pub period: i64,
+        #[prost(int64, repeated, tag = "13")]
+        pub comment: Vec<i64>,
+        #[prost(int64, tag = "14")]
+        pub default_sample_type: i64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ValueType {
+        #[prost(int64, tag = "1")]
+        pub r#type: i64,
+        #[prost(int64, tag = "2")]
+        pub unit: i64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Sample {
+        #[prost(uint64, repeated, tag = "1")]
+        pub location_id: Vec<u64>,
+        #[prost(int64, repeated, tag = "2")]
+        pub value: Vec<i64>,
+        #[prost(message, repeated, tag = "3")]
+        pub label: Vec<Label>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Label {
+        #[prost(int64, tag = "1")]
+        pub key: i64,
+        #[prost(int64, tag = "2")]
+        pub str: i64,
+        #[prost(int64, tag = "3")]
+        pub num: i64,
+        #[prost(int64, tag = "4")]
+        pub num_unit: i64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Mapping {
+        #[prost(uint64, tag = "1")]
+        pub id: u64,
+        #[prost(uint64, tag = "2")]
+        pub memory_start: u64,
+        #[prost(uint64, tag = "3")]
+        pub memory_limit: u64,
+        #[prost(uint64, tag = "4")]
+        pub file_offset: u64,
+        #[prost(int64, tag = "5")]
+        pub filename: i64,
+        #[prost(int64, tag = "6")]
+        pub build_id: i64,
+        #[prost(bool, tag = "7")]
+        pub has_functions: bool,
+        #[prost(bool, tag = "8")]
+        pub has_filenames: bool,
+        #[prost(bool, tag = "9")]
+        pub has_line_numbers: bool,
+        #[prost(bool, tag = "10")]
+        pub has_inline_frames: bool,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Location {
+        #[prost(uint64, tag = "1")]
+        pub id: u64,
+        #[prost(uint64, tag = "2")]
+        pub mapping_id: u64,
+        #[prost(uint64, tag = "3")]
+        pub address: u64,
+        #[prost(message, repeated, tag = "4")]
+        pub line: Vec<Line>,
+        #[prost(bool, tag = "5")]
+        pub is_folded: bool,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Line {
+        #[prost(uint64, tag = "1")]
+        pub function_id: u64,
+        #[prost(int64, tag = "2")]
+        pub line: i64,
+        #[prost(int64, tag = "3")]
+        pub column: i64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Function {
+        #[prost(uint64, tag = "1")]
+        pub id: u64,
+        #[prost(int64, tag = "2")]
+        pub name: i64,
+        #[prost(int64, tag = "3")]
+        pub system_name: i64,
+        #[prost(int64, tag = "4")]
+        pub filename: i64,
+        #[prost(int64, tag = "5")]
+        pub start_line: i64,
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn pprof_test() {
+    use flate2::read::GzDecoder;
+    use prost::Message;
+
+    let data = PerfData::open("tests/fixtures/two-events.data").unwrap();
+    let options = PprofOptions {
+        max_stack: 127,
+        inline: false,
+    };
+    let profile = build(&data, &options, Demangle::Full, &Filter::default());
+    let mut gzipped = Vec::new();
+    write(&mut gzipped, &profile).unwrap();
+    let mut bytes = Vec::new();
+    GzDecoder::new(&gzipped[..])
+        .read_to_end(&mut bytes)
+        .unwrap();
+    let decoded = proto::Profile::decode(&bytes[..]).unwrap();
+    assert!(proto::Profile::decode(&bytes[..bytes.len() - 1]).is_err());
+
+    let string = |i: i64| decoded.string_table[i as usize].as_str();
+    assert_eq!(string(0), "");
+    let types: Vec<(&str, &str)> = decoded
+        .sample_type
+        .iter()
+        .map(|t| (string(t.r#type), string(t.unit)))
+        .collect();
+    assert_eq!(types, [("samples", "count"), ("cpu-clock", "events")]);
+    assert_eq!(string(decoded.default_sample_type), "cpu-clock");
+
+    // 52 samples of 500us from `sh`, pid and tid 27577.
+    let samples = data.samples_of(0);
+    assert_eq!(
+        decoded.duration_nanos,
+        (samples.last().unwrap().time - samples[0].time) as i64
+    );
+    let values = decoded.sample.iter().fold((0, 0), |(n, period), sample| {
+        (n + sample.value[0], period + sample.value[1])
+    });
+    assert_eq!(values, (52, 26_000_000));
+    for sample in &decoded.sample {
+        let labels: Vec<(&str, &str, i64)> = sample
+            .label
+            .iter()
+            .map(|l| (string(l.key), string(l.str), l.num))
+            .collect();
+        assert_eq!(
+            labels,
+            [("pid", "", 27577), ("tid", "", 27577), ("comm", "sh", 0)]
+        );
+        assert!(!sample.location_id.is_empty());
+        for &id in &sample.location_id {
+            let location = &decoded.location[id as usize - 1];
+            assert_eq!(location.id, id);
+            let mapping = &decoded.mapping[location.mapping_id as usize - 1];
+            assert_eq!(mapping.id, location.mapping_id);
+        }
+    }
+    let files: Vec<&str> = decoded.mapping.iter().map(|m| string(m.filename)).collect();
+    assert!(files.contains(&"/usr/lib/x86_64-linux-gnu/libc.so.6"));
+    assert!(files.contains(&"/usr/bin/dash"));
+    let first = super::first_frame_name(&data);
+    assert!(decoded.function.iter().any(|f| string(f.name) == first));
+
+    // What prost read is what was built.
+    assert_eq!(decoded.string_table, profile.string_table);
+    assert_eq!(decoded.sample.len(), profile.sample.len());
+    for (read, built) in decoded.sample.iter().zip(&profile.sample) {
+        assert_eq!(read.location_id, built.location_id);
+        assert_eq!(read.value, built.value);
+    }
+    for (read, built) in decoded.location.iter().zip(&profile.location) {
+        assert_eq!(
+            (read.id, read.mapping_id, read.address),
+            (built.id, built.mapping_id, built.address)
+        );
+    }
+}
@@ -0,0 +1,507 @@
+//! # Script driver.
+//! <p> Usage: <em> ruperf script [-i FILE] [-F FIELDS] [-G] [--no-demangle | --strip-hash] [--json-lines | --chrome-trace JSON] </em>
+//! Prints every sample of a perf.data file on its own line, in the
+//! layout `perf script` uses, with its call chain below it when it
+//! was recorded with one. With `--json-lines` each sample is a JSON
+//! object instead, for jq or pandas, and `--chrome-trace` writes a
+//! timeline for Perfetto UI. </p>
+
+pub mod chrome_trace;
+
+extern crate structopt;
+use crate::data::{PerfData, Record, Sample};
+use crate::session::{Location, Session};
+use crate::symbols::DemangleOptions;
+use crate::utils::ParseError;
+use serde::Serialize;
+use std::io::prelude::*;
+use std::path::PathBuf;
+use std::str::FromStr;
+use structopt::StructOpt;
+
+/// Configuration settings for running script.
+/// See `./ruperf script --help` for more information.
+#[derive(Debug, StructOpt)]
+pub struct ScriptOptions {
+    #[structopt(
+        short,
+        long,
+        default_value = "perf.data",
+        help = "File to read samples from",
+        parse(from_os_str)
+    )]
+    pub input: PathBuf,
+
+    #[structopt(
+        short = "F",
+        long,
+        default_value = "comm,tid,cpu,time,period,event,ip,sym,symoff,dso",
+        help = "Fields to print: comm, pid, tid, cpu, time, period, event, ip, sym, symoff, dso, srcline"
+    )]
+    pub fields: Fields,
+
+    #[structopt(short = "G", long = "hide-call-graph", help = "Leave call chains out")]
+    pub hide_call_graph: bool,
+
+    #[structopt(
+        long = "max-stack",
+        default_value = "127",
+        help = "Frames of each call chain to print"
+    )]
+    pub max_stack: usize,
+
+    #[structopt(flatten)]
+    pub demangle: DemangleOptions,
+
+    #[structopt(long = "json-lines", help = "Print each sample as a line of JSON")]
+    pub json_lines: bool,
+
+    #[structopt(
+        long = "chrome-trace",
+        help = "Write a timeline in the Trace Event Format to this file",
+        parse(from_os_str),
+        conflicts_with = "json-lines"
+    )]
+    pub chrome_trace: Option<PathBuf>,
+}
+
+/// A part of a sample that can be printed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Comm,
+    Pid,
+    Tid,
+    Cpu,
+    Time,
+    Period,
+    Event,
+    Ip,
+    Sym,
+    SymOff,
+    Dso,
+    Srcline,
+}
+
+impl FromStr for Field {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "comm" => Ok(Field::Comm),
+            "pid" => Ok(Field::Pid),
+            "tid" => Ok(Field::Tid),
+            "cpu" => Ok(Field::Cpu),
+            "time" => Ok(Field::Time),
+            "period" => Ok(Field::Period),
+            "event" => Ok(Field::Event),
+            "ip" => Ok(Field::Ip),
+            "sym" => Ok(Field::Sym),
+            "symoff" => Ok(Field::SymOff),
+            "dso" => Ok(Field::Dso),
+            "srcline" => Ok(Field::Srcline),
+            _ => Err(ParseError::InvalidField(s.to_string())),
+        }
+    }
+}
+
+/// A comma separated list of `Field`s.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fields(pub Vec<Field>);
+
+impl FromStr for Fields {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(|field| field.trim().parse())
+            .collect::<Result<_, _>>()
+            .map(Fields)
+    }
+}
+
+impl Fields {
+    fn has(&self, field: Field) -> bool {
+        self.0.contains(&field)
+    }
+
+    /// Whether any field needs the sample's address resolved.
+    fn has_frame(&self) -> bool {
+        [
+            Field::Ip,
+            Field::Sym,
+            Field::SymOff,
+            Field::Dso,
+            Field::Srcline,
+        ]
+        .iter()
+        .any(|field| self.has(*field))
+    }
+}
+
+/// Where a sample, or a frame of its call chain, was taken.
+/// Only the fields asked for are set.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Frame {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sym: Option<String>,
+    /// From the start of `sym`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub symoff: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dso: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub srcline: Option<String>,
+}
+
+/// A decoded sample. Only the fields asked for are set.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ScriptSample {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comm: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pid: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tid: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu: Option<u32>,
+    /// In nanoseconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub period: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event: Option<String>,
+    /// Where the sample was taken.
+    #[serde(flatten)]
+    pub frame: Frame,
+    /// The sample's call chain, innermost first, when it was recorded
+    /// with one. The first frame is where the sample was taken.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub callchain: Vec<Frame>,
+}
+
+fn frame(fields: &Fields, location: &Location) -> Frame {
+    let symbol = location.symbol.as_ref();
+    Frame {
+        ip: Some(location.ip).filter(|_| fields.has(Field::Ip)),
+        sym: symbol
+            .map(|symbol| symbol.name.clone())
+            .filter(|_| fields.has(Field::Sym)),
+        symoff: symbol
+            .map(|symbol| location.address - symbol.start)
+            .filter(|_| fields.has(Field::SymOff)),
+        dso: location
+            .dso
+            .as_ref()
+            .map(|dso| dso.to_string())
+            .filter(|_| fields.has(Field::Dso)),
+        srcline: location
+            .srcline
+            .as_ref()
+            .map(|srcline| srcline.to_string())
+            .filter(|_| fields.has(Field::Srcline)),
+    }
+}
+
+/// Decode the parts of `sample` in `fields`, with its call chain of
+/// up to `max_stack` frames unless that is 0.
+fn decode(
+    fields: &Fields,
+    session: &mut Session,
+    sample: &Sample,
+    event: &str,
+    max_stack: usize,
+) -> ScriptSample {
+    let has = |field| fields.has(field);
+    let mut decoded = ScriptSample {
+        comm: Some(session.comm(sample.pid, sample.tid)).filter(|_| has(Field::Comm)),
+        pid: Some(sample.pid).filter(|_| has(Field::Pid)),
+        tid: Some(sample.tid).filter(|_| has(Field::Tid)),
+        cpu: Some(sample.cpu).filter(|_| has(Field::Cpu)),
+        time: Some(sample.time).filter(|_| has(Field::Time)),
+        period: Some(sample.period).filter(|_| has(Field::Period)),
+        event: Some(event.to_string()).filter(|_| has(Field::Event)),
+        ..Default::default()
+    };
+    if !fields.has_frame() {
+        return decoded;
+    }
+    let chained = !sample.callchain.is_empty() || sample.user_regs.abi != 0;
+    if chained && max_stack > 0 {
+        decoded.callchain = session
+            .callchain(sample, max_stack)
+            .iter()
+            .map(|location| frame(fields, location))
+            .collect();
+        decoded.frame = decoded.callchain[0].clone();
+    } else {
+        let location = session.resolve(sample.pid, sample.ip, sample.is_kernel());
+        decoded.frame = frame(fields, &location);
+    }
+    decoded
+}
+
+/// Print `frame` as `perf script` does: `ip sym+off (dso)`.
+fn print_frame(out: &mut dyn Write, fields: &Fields, frame: &Frame) -> std::io::Result<()> {
+    if let Some(ip) = frame.ip {
+        write!(out, "{:>16x}", ip)?;
+    }
+    if fields.has(Field::Sym) {
+        write!(out, " {}", frame.sym.as_deref().unwrap_or("[unknown]"))?;
+        if let Some(offset) = frame.symoff {
+            write!(out, "+{:#x}", offset)?;
+        }
+    }
+    if fields.has(Field::Dso) {
+        write!(out, " ({})", frame.dso.as_deref().unwrap_or("[unknown]"))?;
+    }
+    if fields.has(Field::Srcline) {
+        write!(out, "\n  {}", frame.srcline.as_deref().unwrap_or("??:0"))?;
+    }
+    Ok(())
+}
+
+/// Print `sample` on a line in the layout of `perf script`,
+/// followed by its call chain, a frame per line.
+pub fn print_sample(
+    out: &mut dyn Write,
+    fields: &Fields,
+    sample: &ScriptSample,
+) -> std::io::Result<()> {
+    if let Some(comm) = &sample.comm {
+        write!(out, "{:>16} ", comm)?;
+    }
+    match (sample.pid, sample.tid) {
+        (Some(pid), Some(tid)) => write!(out, "{:>5}/{:<5} ", pid, tid)?,
+        (Some(id), None) | (None, Some(id)) => write!(out, "{:>5} ", id)?,
+        (None, None) => {}
+    }
+    if let Some(cpu) = sample.cpu {
+        write!(out, "[{:03}] ", cpu)?;
+    }
+    if let Some(time) = sample.time {
+        write!(
+            out,
+            "{:>5}.{:06}: ",
+            time / 1_000_000_000,
+            time % 1_000_000_000 / 1000
+        )?;
+    }
+    if let Some(period) = sample.period {
+        write!(out, "{:>10} ", period)?;
+    }
+    if let Some(event) = &sample.event {
+        write!(out, "{}: ", event)?;
+    }
+    if sample.callchain.is_empty() {
+        if fields.has_frame() {
+            write!(out, " ")?;
+            print_frame(out, fields, &sample.frame)?;
+        }
+        return writeln!(out);
+    }
+    writeln!(out)?;
+    for frame in &sample.callchain {
+        write!(out, "\t")?;
+        print_frame(out, fields, frame)?;
+        writeln!(out)?;
+    }
+    writeln!(out)
+}
+
+/// Decode every sample in `data`, in time order, and pass it to `f`.
+pub fn samples<F>(data: &PerfData, options: &ScriptOptions, mut f: F) -> std::io::Result<()>
+where
+    F: FnMut(ScriptSample) -> std::io::Result<()>,
+{
+    let mut session = Session::new();
+    session.set_demangle(options.demangle.mode());
+    session.set_srclines(options.fields.has(Field::Srcline));
+    let events: Vec<String> = (0..data.attrs.len()).map(|i| data.event_name(i)).collect();
+    let max_stack = if options.hide_call_graph {
+        0
+    } else {
+        options.max_stack
+    };
+    for record in data.ordered_records() {
+        let sample = match record {
+            Record::Sample(sample) => sample,
+            other => {
+                session.update(&other);
+                continue;
+            }
+        };
+        let event = match data.attr_index(sample.id) {
+            Some(index) => &events[index],
+            None => continue,
+        };
+        f(decode(
+            &options.fields,
+            &mut session,
+            &sample,
+            event,
+            max_stack,
+        ))?;
+    }
+    Ok(())
+}
+
+/// Entry point for `ruperf script`.
+pub fn run_script(options: ScriptOptions) -> i32 {
+    let data = match PerfData::open(&options.input) {
+        Ok(data) => data,
+        Err(e) => {
+            eprintln!("Could not read {}: {}", options.input.display(), e);
+            return 1;
+        }
+    };
+    if let Some(path) = &options.chrome_trace {
+        let events = chrome_trace::trace_events(&data, &options);
+        let written = std::fs::File::create(path).and_then(|file| {
+            let mut out = std::io::BufWriter::new(file);
+            chrome_trace::write(&mut out, &events)?;
+            out.flush()
+        });
+        return match written {
+            Ok(()) => {
+                eprintln!("Wrote {} trace events to {}", events.len(), path.display());
+                0
+            }
+            Err(e) => {
+                eprintln!("Could not write {}: {}", path.display(), e);
+                1
+            }
+        };
+    }
+    let out = &mut std::io::BufWriter::new(std::io::stdout());
+    let result = samples(&data, &options, |sample| {
+        if options.json_lines {
+            writeln!(out, "{}", serde_json::to_string(&sample).unwrap())
+        } else {
+            print_sample(out, &options.fields, &sample)
+        }
+    })
+    .and_then(|_| out.flush());
+    match result {
+        Ok(()) => 0,
+        // Output is often piped into `head`.
+        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn print_sample_test() {
+    let fields: Fields = "comm,pid,tid,cpu,time,period,event,ip,sym,symoff,dso"
+        .parse()
+        .unwrap();
+    let frame = Frame {
+        ip: Some(0x55d0c2a0123a),
+        sym: Some("main::spin".to_string()),
+        symoff: Some(0x1a),
+        dso: Some("/tmp/spin".to_string()),
+        srcline: None,
+    };
+    let mut sample = ScriptSample {
+        comm: Some("spin".to_string()),
+        pid: Some(42),
+        tid: Some(43),
+        cpu: Some(1),
+        time: Some(12_345_678_901_234),
+        period: Some(250000),
+        event: Some("cpu-clock".to_string()),
+        frame: frame.clone(),
+        callchain: Vec::new(),
+    };
+    let mut out = Vec::new();
+    print_sample(&mut out, &fields, &sample).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        concat!(
+            "            spin    42/43    [001] 12345.678901:     250000 cpu-clock:  ",
+            "    55d0c2a0123a main::spin+0x1a (/tmp/spin)\n"
+        )
+    );
+
+    sample.callchain = vec![
+        frame,
+        Frame {
+            ip: Some(0x1000),
+            ..Default::default()
+        },
+    ];
+    let mut out = Vec::new();
+    print_sample(&mut out, &fields, &sample).unwrap();
+    let text = String::from_utf8(out).unwrap();
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(lines.len(), 4);
+    assert!(lines[0].ends_with("cpu-clock: "));
+    assert_eq!(lines[2], "\t            1000 [unknown] ([unknown])");
+    assert_eq!(lines[3], "");
+
+    let json = serde_json::to_value(&sample).unwrap();
+    assert_eq!(json["sym"], "main::spin");
+    assert_eq!(json["callchain"][1]["ip"], 0x1000);
+    assert!(json.get("srcline").is_none());
+    assert!("comm,bogus".parse::<Fields>().is_err());
+}
+
+#[test]
+fn script_test() {
+    let options = ScriptOptions::from_iter(&[
+        "script",
+        "-i",
+        "tests/fixtures/two-events.data",
+        "-F",
+        "comm,pid,tid,time,period,event,ip,dso",
+    ]);
+    let data = PerfData::open(&options.input).unwrap();
+    let mut decoded = Vec::new();
+    samples(&data, &options, |sample| {
+        decoded.push(sample);
+        Ok(())
+    })
+    .unwrap();
+    // 52 samples of each event, interleaved in time order.
+    assert_eq!(decoded.len(), 104);
+    assert_eq!(
+        decoded.len(),
+        data.samples_of(0).len() + data.samples_of(1).len()
+    );
+    let mut out = Vec::new();
+    for sample in &decoded[..3] {
+        print_sample(&mut out, &options.fields, sample).unwrap();
+    }
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        concat!(
+            "              sh 27577/27577  3471.339810:     500000 cpu-clock:  ",
+            "    7f8a50bfbf0a (/usr/lib/x86_64-linux-gnu/libc.so.6)\n",
+            "              sh 27577/27577  3471.339817:     500000 task-clock:  ",
+            "    7f8a50bfbf0a (/usr/lib/x86_64-linux-gnu/libc.so.6)\n",
+            "              sh 27577/27577  3471.340310:     500000 cpu-clock:  ",
+            "    7f8a50babd7e (/usr/lib/x86_64-linux-gnu/libc.so.6)\n",
+        )
+    );
+
+    let options = ScriptOptions::from_iter(&[
+        "script",
+        "-i",
+        "tests/fixtures/two-events.data",
+        "-F",
+        "tid,event",
+    ]);
+    let mut decoded = Vec::new();
+    samples(&data, &options, |sample| {
+        decoded.push(sample);
+        Ok(())
+    })
+    .unwrap();
+    assert!(decoded
+        .iter()
+        .all(|s| s.tid == Some(27577) && s.comm.is_none() && s.frame.ip.is_none()));
+}
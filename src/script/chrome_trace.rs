@@ -0,0 +1,211 @@
+//! Timelines for `ruperf script --chrome-trace`: a recording in the
+//! Trace Event Format that Perfetto UI and `chrome://tracing` open.
+//! Each sample is an instant event on its thread, with the fields
+//! asked for by `-F` as its arguments, and the running total of each
+//! event's periods is a counter track per process. Threads are named
+//! after their comm, exits are marked, and when the recording has
+//! context switch records each stretch a thread ran for is a slice.
+
+use super::{decode, Field, ScriptOptions};
+use crate::data::{PerfData, Record};
+use crate::session::Session;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::{BTreeSet, HashMap};
+use std::io::prelude::*;
+
+/// An entry of `traceEvents`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TraceEvent {
+    pub name: String,
+    #[serde(skip_serializing_if = "str::is_empty")]
+    pub cat: String,
+    /// The phase: `i` instant, `C` counter, `X` complete, `M` metadata.
+    pub ph: &'static str,
+    /// In microseconds.
+    pub ts: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dur: Option<f64>,
+    pub pid: u32,
+    pub tid: u32,
+    /// The scope of instant events, `t` for their thread.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub s: Option<&'static str>,
+    #[serde(skip_serializing_if = "Value::is_null")]
+    pub args: Value,
+}
+
+fn micros(nanos: u64) -> f64 {
+    nanos as f64 / 1000.0
+}
+
+/// Every sample and sideband record of `data` as trace events,
+/// followed by the names of the processes and threads seen.
+pub fn trace_events(data: &PerfData, options: &ScriptOptions) -> Vec<TraceEvent> {
+    let mut session = Session::new();
+    session.set_demangle(options.demangle.mode());
+    session.set_srclines(options.fields.has(Field::Srcline));
+    let names: Vec<String> = (0..data.attrs.len()).map(|i| data.event_name(i)).collect();
+    let max_stack = if options.hide_call_graph {
+        0
+    } else {
+        options.max_stack
+    };
+    let mut events = Vec::new();
+    let mut threads = BTreeSet::new();
+    // Periods so far by process and event, for the counter tracks.
+    let mut totals: HashMap<(u32, usize), u64> = HashMap::new();
+    // When each thread was last switched in, and on which CPU.
+    let mut running: HashMap<u32, (u64, u32)> = HashMap::new();
+    for record in data.ordered_records() {
+        match &record {
+            Record::Sample(sample) => {
+                let (index, event) = match data.attr_index(sample.id) {
+                    Some(index) => (index, &names[index]),
+                    None => continue,
+                };
+                threads.insert((sample.pid, sample.tid));
+                let decoded = decode(&options.fields, &mut session, sample, event, max_stack);
+                events.push(TraceEvent {
+                    name: decoded.frame.sym.clone().unwrap_or_else(|| event.clone()),
+                    cat: event.clone(),
+                    ph: "i",
+                    ts: micros(sample.time),
+                    dur: None,
+                    pid: sample.pid,
+                    tid: sample.tid,
+                    s: Some("t"),
+                    args: serde_json::to_value(&decoded).unwrap(),
+                });
+                let total = totals.entry((sample.pid, index)).or_insert(0);
+                *total += sample.period;
+                events.push(TraceEvent {
+                    name: event.clone(),
+                    cat: String::new(),
+                    ph: "C",
+                    ts: micros(sample.time),
+                    dur: None,
+                    pid: sample.pid,
+                    tid: sample.pid,
+                    s: None,
+                    args: json!({ "total": *total }),
+                });
+                continue;
+            }
+            Record::Comm(comm) => {
+                threads.insert((comm.pid, comm.tid));
+            }
+            Record::Exit(task) => {
+                threads.insert((task.pid, task.tid));
+                events.push(TraceEvent {
+                    name: "exit".to_string(),
+                    cat: "task".to_string(),
+                    ph: "i",
+                    ts: micros(task.time),
+                    dur: None,
+                    pid: task.pid,
+                    tid: task.tid,
+                    s: Some("t"),
+                    args: Value::Null,
+                });
+            }
+            Record::Switch(switch) if !switch.out => {
+                running.insert(switch.tid, (switch.time, switch.cpu));
+            }
+            Record::Switch(switch) => {
+                if let Some((start, cpu)) = running.remove(&switch.tid) {
+                    threads.insert((switch.pid, switch.tid));
+                    events.push(TraceEvent {
+                        name: "running".to_string(),
+                        cat: "sched".to_string(),
+                        ph: "X",
+                        ts: micros(start),
+                        dur: Some(micros(switch.time.saturating_sub(start))),
+                        pid: switch.pid,
+                        tid: switch.tid,
+                        s: None,
+                        args: json!({ "cpu": cpu }),
+                    });
+                }
+            }
+            _ => {}
+        }
+        session.update(&record);
+    }
+
+    let processes: BTreeSet<u32> = threads.iter().map(|(pid, _)| *pid).collect();
+    let metadata = |name: &str, pid, tid, value: String| TraceEvent {
+        name: name.to_string(),
+        cat: String::new(),
+        ph: "M",
+        ts: 0.0,
+        dur: None,
+        pid,
+        tid,
+        s: None,
+        args: json!({ "name": value }),
+    };
+    for pid in processes {
+        events.push(metadata("process_name", pid, pid, session.comm(pid, pid)));
+    }
+    for (pid, tid) in threads {
+        events.push(metadata("thread_name", pid, tid, session.comm(pid, tid)));
+    }
+    events
+}
+
+/// Write `events` as a JSON trace.
+pub fn write(out: &mut impl Write, events: &[TraceEvent]) -> std::io::Result<()> {
+    let trace = json!({
+        "traceEvents": events,
+        "displayTimeUnit": "ns",
+    });
+    serde_json::to_writer(&mut *out, &trace)?;
+    writeln!(out)
+}
+
+#[cfg(test)]
+#[test]
+fn chrome_trace_test() {
+    use structopt::StructOpt;
+
+    let options = ScriptOptions::from_iter(&["script", "-i", "tests/fixtures/two-events.data"]);
+    let data = PerfData::open(&options.input).unwrap();
+    let events = trace_events(&data, &options);
+    // 52 samples of 500us for each of cpu-clock and task-clock.
+    let samples = data.samples_of(0).len() + data.samples_of(1).len();
+    assert_eq!(samples, 104);
+    let counters: Vec<&TraceEvent> = events.iter().filter(|e| e.ph == "C").collect();
+    assert_eq!(counters.len(), samples);
+    for event in ["cpu-clock", "task-clock"] {
+        let totals: Vec<u64> = counters
+            .iter()
+            .filter(|e| e.name == event && e.pid == 27577)
+            .map(|e| e.args["total"].as_u64().unwrap())
+            .collect();
+        assert_eq!(totals.len(), 52);
+        assert_eq!(totals[0], 500_000);
+        assert!(totals.windows(2).all(|w| w[1] == w[0] + 500_000));
+        assert_eq!(totals[51], 26_000_000);
+    }
+    let instants: Vec<&TraceEvent> = events
+        .iter()
+        .filter(|e| e.ph == "i" && e.cat != "task")
+        .collect();
+    assert_eq!(instants.len(), samples);
+    assert!(instants.iter().all(|e| e.args["tid"] == e.tid));
+    // Every thread with samples is named.
+    for event in &instants {
+        assert!(events
+            .iter()
+            .any(|e| e.name == "thread_name" && (e.pid, e.tid) == (event.pid, event.tid)));
+    }
+
+    let mut out = Vec::new();
+    write(&mut out, &events).unwrap();
+    let trace: Value = serde_json::from_slice(&out).unwrap();
+    assert_eq!(trace["traceEvents"].as_array().unwrap().len(), events.len());
+    let first = &trace["traceEvents"][0];
+    assert_eq!((&first["ph"], &first["s"]), (&json!("i"), &json!("t")));
+    assert!(first.get("dur").is_none());
+}
@@ -0,0 +1,441 @@
+//! # Session.
+//! Replays the sideband records of a recording (mmaps, comms
+//! and forks) so that sample addresses can be resolved to the
+//! process, file, function and source line they were taken in.
+
+use crate::bindings::*;
+use crate::data::{Mmap, Record, Sample};
+use crate::srcline::{DebugInfo, SourceLine};
+use crate::symbols::{self, Demangle, Dso, Symbol};
+use crate::unwind::{self, Registers, Stack, UnwindInfo};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// A file mapped into a process.
+#[derive(Debug, Clone)]
+pub struct Map {
+    pub start: u64,
+    pub end: u64,
+    /// Offset in the file of `start`.
+    pub pgoff: u64,
+    pub path: Rc<str>,
+}
+
+/// Where an address points.
+#[derive(Debug, Clone, Default)]
+pub struct Location {
+    /// The address as the process saw it.
+    pub ip: u64,
+    /// The mapped file, or `[kernel.kallsyms]`.
+    pub dso: Option<Rc<str>>,
+    pub symbol: Option<Symbol>,
+    /// The address as the file's symbols see it,
+    /// or the raw address when nothing is mapped there.
+    pub address: u64,
+    /// Whether the address is in the kernel.
+    pub kernel: bool,
+    /// Where the address is in the source, when source
+    /// lines are looked up and the file has debug info.
+    pub srcline: Option<SourceLine>,
+    /// Whether `symbol` is a function inlined into the next frame.
+    pub inlined: bool,
+}
+
+/// The state of every process seen so far.
+#[derive(Default)]
+pub struct Session {
+    comms: HashMap<u32, String>,
+    maps: HashMap<u32, Vec<Map>>,
+    dsos: HashMap<Rc<str>, Option<Rc<Dso>>>,
+    unwind_info: HashMap<Rc<str>, Option<Rc<UnwindInfo>>>,
+    debug_info: HashMap<Rc<str>, Option<Rc<DebugInfo>>>,
+    kernel: Option<Rc<Dso>>,
+    srclines: bool,
+    inline: bool,
+    demangle: Demangle,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Look up the source line of every resolved address.
+    pub fn set_srclines(&mut self, srclines: bool) {
+        self.srclines = srclines;
+    }
+
+    /// Add the functions inlined at each frame to call chains.
+    pub fn set_inline(&mut self, inline: bool) {
+        self.inline = inline;
+    }
+
+    /// How to show symbol names. Demangled in full by default.
+    pub fn set_demangle(&mut self, demangle: Demangle) {
+        self.demangle = demangle;
+    }
+
+    /// `symbol`, renamed as `set_demangle` asks.
+    fn demangled(&self, symbol: &Symbol) -> Symbol {
+        Symbol {
+            name: symbols::demangle(&symbol.name, self.demangle),
+            ..symbol.clone()
+        }
+    }
+
+    /// Apply a sideband record. Samples are ignored.
+    pub fn update(&mut self, record: &Record) {
+        match record {
+            Record::Mmap(mmap) => self.map(mmap),
+            Record::Comm(comm) => {
+                if comm.exec {
+                    // The old address space is gone.
+                    self.maps.remove(&comm.pid);
+                }
+                self.comms.insert(comm.tid, comm.comm.clone());
+            }
+            Record::Fork(task) => {
+                if let Some(comm) = self.comms.get(&task.ptid).cloned() {
+                    self.comms.insert(task.tid, comm);
+                }
+                if task.pid != task.ppid {
+                    if let Some(maps) = self.maps.get(&task.ppid).cloned() {
+                        self.maps.insert(task.pid, maps);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn map(&mut self, mmap: &Mmap) {
+        let new = Map {
+            start: mmap.start,
+            end: mmap.start + mmap.len,
+            pgoff: mmap.pgoff,
+            path: Rc::from(mmap.filename.as_str()),
+        };
+        let maps = self.maps.entry(mmap.pid).or_default();
+        // Cut away whatever the new map covers.
+        let mut kept = Vec::with_capacity(maps.len() + 2);
+        for map in maps.drain(..) {
+            if map.end <= new.start || map.start >= new.end {
+                kept.push(map);
+                continue;
+            }
+            if map.start < new.start {
+                kept.push(Map {
+                    end: new.start,
+                    ..map.clone()
+                });
+            }
+            if map.end > new.end {
+                kept.push(Map {
+                    start: new.end,
+                    pgoff: map.pgoff + (new.end - map.start),
+                    ..map
+                });
+            }
+        }
+        kept.push(new);
+        kept.sort_by_key(|map| map.start);
+        *maps = kept;
+    }
+
+    /// The name of thread `tid`, falling back to its process.
+    pub fn comm(&self, pid: u32, tid: u32) -> String {
+        self.comms
+            .get(&tid)
+            .or_else(|| self.comms.get(&pid))
+            .cloned()
+            .unwrap_or_else(|| format!(":{}", tid))
+    }
+
+    /// The loaded symbols for `path`, if it could be read.
+    pub fn dso(&mut self, path: &str) -> Option<Rc<Dso>> {
+        if let Some(dso) = self.dsos.get(path) {
+            return dso.clone();
+        }
+        let dso = Dso::load(path).map(Rc::new);
+        self.dsos.insert(Rc::from(path), dso.clone());
+        dso
+    }
+
+    /// The debug info for `path`, if it or its split debug file has any.
+    pub fn debug_info(&mut self, path: &str) -> Option<Rc<DebugInfo>> {
+        if let Some(info) = self.debug_info.get(path) {
+            return info.clone();
+        }
+        let info = DebugInfo::load(path).map(Rc::new);
+        self.debug_info.insert(Rc::from(path), info.clone());
+        info
+    }
+
+    fn kernel(&mut self) -> Rc<Dso> {
+        self.kernel
+            .get_or_insert_with(|| Rc::new(Dso::kernel()))
+            .clone()
+    }
+
+    /// The file mapped at `ip` in process `pid`.
+    pub fn map_at(&self, pid: u32, ip: u64) -> Option<Map> {
+        let maps = self.maps.get(&pid)?;
+        let index = maps.partition_point(|map| map.start <= ip).checked_sub(1)?;
+        Some(&maps[index]).filter(|map| ip < map.end).cloned()
+    }
+
+    /// The call frame information covering `ip` in process
+    /// `pid`, and `ip` as that file sees it.
+    fn unwind_info(&mut self, pid: u32, ip: u64) -> Option<(Rc<UnwindInfo>, u64)> {
+        let map = self.map_at(pid, ip)?;
+        let address = self
+            .dso(&map.path)?
+            .offset_to_address(ip - map.start + map.pgoff);
+        let info = match self.unwind_info.get(&map.path) {
+            Some(info) => info.clone(),
+            None => {
+                let info = UnwindInfo::load(&map.path).map(Rc::new);
+                self.unwind_info.insert(map.path, info.clone());
+                info
+            }
+        }?;
+        Some((info, address))
+    }
+
+    /// Resolve `ip` in process `pid`. Kernel addresses
+    /// are looked up in the running kernel's symbols.
+    pub fn resolve(&mut self, pid: u32, ip: u64, kernel: bool) -> Location {
+        if kernel {
+            let dso = self.kernel();
+            return Location {
+                dso: Some(Rc::from(dso.path.as_str())),
+                symbol: dso.find(ip).map(|symbol| self.demangled(symbol)),
+                ip,
+                address: ip,
+                kernel,
+                ..Default::default()
+            };
+        }
+        let map = match self.map_at(pid, ip) {
+            Some(map) => map,
+            None => {
+                return Location {
+                    ip,
+                    address: ip,
+                    ..Default::default()
+                }
+            }
+        };
+        let offset = ip - map.start + map.pgoff;
+        match self.dso(&map.path) {
+            Some(dso) => {
+                let address = dso.offset_to_address(offset);
+                let srcline = if self.srclines {
+                    self.debug_info(&map.path)
+                        .and_then(|info| info.srcline(address))
+                } else {
+                    None
+                };
+                Location {
+                    ip,
+                    dso: Some(map.path),
+                    symbol: dso.find(address).map(|symbol| self.demangled(symbol)),
+                    address,
+                    kernel,
+                    srcline,
+                    inlined: false,
+                }
+            }
+            None => Location {
+                ip,
+                dso: Some(map.path),
+                symbol: None,
+                address: offset,
+                kernel,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// `location` preceded by the functions inlined at it, innermost
+    /// first, with `lookup` being the address to look for in the file.
+    fn inline_frames(&mut self, location: Location, lookup: u64) -> Vec<Location> {
+        let info = match &location.dso {
+            Some(dso) if !location.kernel => self.debug_info(dso),
+            _ => None,
+        };
+        let mut frames = info.map(|info| info.frames(lookup)).unwrap_or_default();
+        let outer = match frames.pop() {
+            Some(outer) => outer,
+            None => return vec![location],
+        };
+        let mut locations: Vec<Location> = frames
+            .into_iter()
+            .map(|frame| Location {
+                symbol: frame.function.map(|name| Symbol {
+                    name: symbols::demangle(&name, self.demangle),
+                    start: location.address,
+                    size: 0,
+                }),
+                srcline: frame.srcline,
+                inlined: true,
+                ..location.clone()
+            })
+            .collect();
+        locations.push(Location {
+            srcline: outer.srcline.or(location.srcline),
+            ..location
+        });
+        locations
+    }
+
+    /// Resolve a frame of a call chain. Callers are return addresses,
+    /// which may already be past the end of the calling function, so
+    /// the call itself is looked up.
+    /// With inlining on, the functions inlined there come first.
+    fn frame(&mut self, pid: u32, ip: u64, kernel: bool, caller: bool) -> Vec<Location> {
+        let lookup = if caller { ip - 1 } else { ip };
+        let mut location = self.resolve(pid, lookup, kernel);
+        let address = location.address;
+        location.address += ip - lookup;
+        location.ip = ip;
+        if self.inline {
+            self.inline_frames(location, address)
+        } else {
+            vec![location]
+        }
+    }
+
+    /// Resolve the call chain of `sample`, innermost frame first. The
+    /// `PERF_CONTEXT_*` markers in the chain say whether the addresses
+    /// after them are in the kernel or in user space. Samples with a
+    /// copy of the user stack have their user frames unwound from it.
+    /// At most `max_stack` frames are kept; a sample without a chain
+    /// gives just its `ip`.
+    pub fn callchain(&mut self, sample: &Sample, max_stack: usize) -> Vec<Location> {
+        let max_stack = max_stack.max(1);
+        let mut kernel = sample.is_kernel();
+        let mut frames = Vec::new();
+        for &ip in &sample.callchain {
+            if ip >= perf_callchain_context_PERF_CONTEXT_MAX {
+                kernel = ip == perf_callchain_context_PERF_CONTEXT_KERNEL
+                    || ip == perf_callchain_context_PERF_CONTEXT_GUEST_KERNEL;
+                continue;
+            }
+            // A zero return address ends a frame pointer walk.
+            if ip == 0 || frames.len() >= max_stack {
+                break;
+            }
+            let caller = !frames.is_empty();
+            frames.extend(self.frame(sample.pid, ip, kernel, caller));
+        }
+
+        if sample.user_regs.abi != 0 && frames.len() < max_stack {
+            let registers = Registers::from_perf(
+                (0..64).filter_map(|i| sample.user_regs.get(i).map(|value| (i, value))),
+            );
+            let stack = Stack {
+                sp: registers.sp().unwrap_or(0),
+                bytes: &sample.user_stack,
+            };
+            let pid = sample.pid;
+            let ips = unwind::unwind(
+                registers,
+                stack,
+                |ip| self.unwind_info(pid, ip),
+                max_stack - frames.len(),
+            );
+            for ip in ips {
+                let caller = !frames.is_empty();
+                frames.extend(self.frame(pid, ip, false, caller));
+            }
+        }
+
+        if frames.is_empty() {
+            frames = self.frame(sample.pid, sample.ip, sample.is_kernel(), false);
+        }
+        frames.truncate(max_stack);
+        frames
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn maps_test() {
+    use crate::data::{Comm, Task};
+
+    let mut session = Session::new();
+    let mmap = |start, len, pgoff, filename: &str| {
+        Record::Mmap(Mmap {
+            pid: 1,
+            tid: 1,
+            start,
+            len,
+            pgoff,
+            filename: filename.to_string(),
+            cpumode: 2,
+        })
+    };
+    session.update(&Record::Comm(Comm {
+        pid: 1,
+        tid: 1,
+        comm: "app".to_string(),
+        exec: true,
+    }));
+    session.update(&mmap(0x1000, 0x3000, 0, "/nonexistent/a"));
+    // Punch a hole in the middle of the first map.
+    session.update(&mmap(0x2000, 0x1000, 0, "/nonexistent/b"));
+    session.update(&Record::Fork(Task {
+        pid: 2,
+        ppid: 1,
+        tid: 2,
+        ptid: 1,
+        time: 0,
+    }));
+
+    let at = |session: &mut Session, pid, ip| {
+        let location = session.resolve(pid, ip, false);
+        (location.dso.map(|d| d.to_string()), location.address)
+    };
+    assert_eq!(
+        at(&mut session, 1, 0x1800),
+        (Some("/nonexistent/a".to_string()), 0x800)
+    );
+    assert_eq!(
+        at(&mut session, 1, 0x2800),
+        (Some("/nonexistent/b".to_string()), 0x800)
+    );
+    // The part after the hole keeps its file offset.
+    assert_eq!(
+        at(&mut session, 1, 0x3800),
+        (Some("/nonexistent/a".to_string()), 0x2800)
+    );
+    assert_eq!(at(&mut session, 1, 0x9000), (None, 0x9000));
+    // Children inherit maps and names.
+    assert_eq!(
+        at(&mut session, 2, 0x1800).0,
+        Some("/nonexistent/a".to_string())
+    );
+    assert_eq!(session.comm(2, 2), "app");
+    assert_eq!(session.comm(3, 3), ":3");
+
+    let sample = Sample {
+        pid: 1,
+        ip: 0x1800,
+        callchain: vec![
+            perf_callchain_context_PERF_CONTEXT_KERNEL,
+            0xffff_ffff_8100_0000,
+            perf_callchain_context_PERF_CONTEXT_USER,
+            0x1800,
+            0x2800,
+            0x9000,
+        ],
+        ..Default::default()
+    };
+    let frames = session.callchain(&sample, 3);
+    let kernel: Vec<bool> = frames.iter().map(|f| f.kernel).collect();
+    assert_eq!(kernel, vec![true, false, false]);
+    // Callers keep their own address, though looked up one byte earlier.
+    assert_eq!(frames[2].address, 0x800);
+    assert_eq!(frames[2].dso.as_deref(), Some("/nonexistent/b"));
+}
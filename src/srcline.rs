@@ -0,0 +1,185 @@
+//! # Source lines.
+//! Resolves addresses to the file and line they were compiled from,
+//! and to the functions inlined there, using the `.debug_info` and
+//! `.debug_line` sections of ELF files. Stripped files are looked up
+//! in `/usr/lib/debug` by build id or by their `.gnu_debuglink`.
+
+use gimli::{EndianRcSlice, RunTimeEndian};
+use object::{Object, ObjectSection};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+type Reader = EndianRcSlice<RunTimeEndian>;
+
+/// Where split debug info is installed.
+const DEBUG_DIR: &str = "/usr/lib/debug";
+
+/// A line of source code.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SourceLine {
+    pub file: String,
+    /// 0 if unknown.
+    pub line: u32,
+}
+
+impl fmt::Display for SourceLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+/// A function at an address, which may have been inlined into the next
+/// frame. `srcline` is where the address is in this function.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InlineFrame {
+    pub function: Option<String>,
+    pub srcline: Option<SourceLine>,
+}
+
+/// The DWARF debug info of one file.
+pub struct DebugInfo {
+    context: addr2line::Context<Reader>,
+}
+
+impl DebugInfo {
+    /// Read the debug info of the ELF file at `path`, or of its
+    /// split debug file when it has been stripped.
+    pub fn load(path: &str) -> Option<Self> {
+        let data = std::fs::read(path).ok()?;
+        let file = object::File::parse(&*data).ok()?;
+        if file.section_by_name(".debug_info").is_some() {
+            return Self::parse(&file);
+        }
+        let build_id = file.build_id().ok().flatten();
+        let debuglink = file.gnu_debuglink().ok().flatten().map(|(name, _)| name);
+        debug_paths(Path::new(path), build_id, debuglink)
+            .into_iter()
+            .filter_map(|path| std::fs::read(path).ok())
+            .find_map(|data| Self::parse(&object::File::parse(&*data).ok()?))
+    }
+
+    fn parse(file: &object::File) -> Option<Self> {
+        file.section_by_name(".debug_info")?;
+        let endian = if file.is_little_endian() {
+            RunTimeEndian::Little
+        } else {
+            RunTimeEndian::Big
+        };
+        let dwarf = gimli::Dwarf::load(|id| -> Result<Reader, gimli::Error> {
+            let data = file
+                .section_by_name(id.name())
+                .and_then(|section| section.uncompressed_data().ok())
+                .unwrap_or_default();
+            Ok(EndianRcSlice::new(Rc::from(&*data), endian))
+        })
+        .ok()?;
+        let context = addr2line::Context::from_dwarf(dwarf).ok()?;
+        Some(Self { context })
+    }
+
+    /// The source line `address` was compiled from, as the file sees it.
+    pub fn srcline(&self, address: u64) -> Option<SourceLine> {
+        let location = self.context.find_location(address).ok()??;
+        Some(SourceLine {
+            file: location.file?.to_string(),
+            line: location.line.unwrap_or(0),
+        })
+    }
+
+    /// The functions at `address`, innermost first: those inlined there,
+    /// then the function they were inlined into. Empty without debug info
+    /// for the address.
+    pub fn frames(&self, address: u64) -> Vec<InlineFrame> {
+        let mut frames = Vec::new();
+        let mut iter = match self.context.find_frames(address).skip_all_loads() {
+            Ok(iter) => iter,
+            Err(_) => return frames,
+        };
+        while let Ok(Some(frame)) = iter.next() {
+            frames.push(InlineFrame {
+                function: frame
+                    .function
+                    .and_then(|f| f.raw_name().ok().map(|name| name.into_owned())),
+                srcline: frame.location.and_then(|location| {
+                    Some(SourceLine {
+                        file: location.file?.to_string(),
+                        line: location.line.unwrap_or(0),
+                    })
+                }),
+            });
+        }
+        frames
+    }
+}
+
+/// The places the split debug info of the file at `path` may be, the
+/// way gdb looks for it: by build id, then next to the file and under
+/// `/usr/lib/debug` by its `.gnu_debuglink` name.
+fn debug_paths(path: &Path, build_id: Option<&[u8]>, debuglink: Option<&[u8]>) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    if let Some((first, rest)) = build_id.and_then(|id| id.split_first()) {
+        let rest: String = rest.iter().map(|b| format!("{:02x}", b)).collect();
+        paths.push(
+            Path::new(DEBUG_DIR)
+                .join(".build-id")
+                .join(format!("{:02x}", first))
+                .join(format!("{}.debug", rest)),
+        );
+    }
+    let name = debuglink.and_then(|name| std::str::from_utf8(name).ok());
+    if let (Some(name), Some(dir)) = (name, path.parent()) {
+        paths.push(dir.join(name));
+        paths.push(dir.join(".debug").join(name));
+        let relative = dir.strip_prefix("/").unwrap_or(dir);
+        paths.push(Path::new(DEBUG_DIR).join(relative).join(name));
+    }
+    paths
+}
+
+#[cfg(test)]
+#[test]
+fn debug_paths_test() {
+    let paths = debug_paths(
+        Path::new("/usr/bin/app"),
+        Some(&[0xab, 0xcd, 0x01]),
+        Some(b"app.debug"),
+    );
+    let paths: Vec<&str> = paths.iter().map(|p| p.to_str().unwrap()).collect();
+    assert_eq!(
+        paths,
+        vec![
+            "/usr/lib/debug/.build-id/ab/cd01.debug",
+            "/usr/bin/app.debug",
+            "/usr/bin/.debug/app.debug",
+            "/usr/lib/debug/usr/bin/app.debug",
+        ]
+    );
+    assert!(debug_paths(Path::new("/usr/bin/app"), None, None).is_empty());
+}
+
+#[test]
+fn srcline_test() {
+    use crate::symbols::{demangle, Demangle, Dso};
+
+    let exe = std::env::current_exe().unwrap();
+    let exe = exe.to_str().unwrap();
+    let dso = Dso::load(exe).unwrap();
+    let name = "ruperf::srcline::debug_paths_test";
+    let symbol = dso
+        .symbols()
+        .iter()
+        .find(|s| demangle(&s.name, Demangle::StripHash) == name)
+        .unwrap();
+    let info = DebugInfo::load(exe).unwrap();
+    let srcline = info.srcline(symbol.start).unwrap();
+    assert!(srcline.file.ends_with("srcline.rs"), "{}", srcline);
+    assert!(srcline.line > 0);
+    let frames = info.frames(symbol.start);
+    let outer = frames.last().unwrap();
+    assert!(outer
+        .function
+        .as_deref()
+        .unwrap()
+        .contains("debug_paths_test"));
+}
@@ -1,16 +1,40 @@
 //! # Stat driver.
 //! <p> Usage: <em> ruperf stat [COMMAND] [ARGS] </em>
 //! Where COMMAND and ARGS are a shell command and it's arguments. </p>
+//!
+//! With `-I <ms>` the counts of each interval are printed as the command
+//! runs, and `--chrome-trace` writes them as counter tracks to open in
+//! Perfetto UI.
+
+mod budget;
+mod compare;
+mod control;
+mod diff;
+pub(crate) mod rusage;
+mod stats;
+
+pub use control::Interval;
+pub use diff::{run_stat_diff, StatDiffOptions};
 
 extern crate structopt;
 use crate::event::open::*;
+use crate::script::chrome_trace::{self, TraceEvent};
 use crate::utils::ParseError;
+use budget::Assertion;
+use control::{parse_delay, parse_interval, Control, ControlSpec, Gate};
 use os_pipe::pipe;
+use rusage::ResourceUsage;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::fs::File;
 use std::io::prelude::*;
+use std::io::IsTerminal;
 use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
 use std::process::Command;
 use std::str::{self, FromStr};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
 
 /// Supported events
@@ -19,6 +43,7 @@ pub enum StatEvent {
     Cycles,
     Instructions,
     TaskClock,
+    CpuClock,
     ContextSwitches,
     L1DCacheRead,
     L1DCacheWrite,
@@ -34,6 +59,7 @@ impl FromStr for StatEvent {
             "cycles" => Ok(StatEvent::Cycles),
             "instructions" => Ok(StatEvent::Instructions),
             "task-clock" => Ok(StatEvent::TaskClock),
+            "cpu-clock" => Ok(StatEvent::CpuClock),
             "context-switches" => Ok(StatEvent::ContextSwitches),
             "L1D-cache-reads" => Ok(StatEvent::L1DCacheRead),
             "L1D-cache-writes" => Ok(StatEvent::L1DCacheWrite),
@@ -44,6 +70,23 @@ impl FromStr for StatEvent {
     }
 }
 
+impl StatEvent {
+    /// Name of the event as given to `--event`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StatEvent::Cycles => "cycles",
+            StatEvent::Instructions => "instructions",
+            StatEvent::TaskClock => "task-clock",
+            StatEvent::CpuClock => "cpu-clock",
+            StatEvent::ContextSwitches => "context-switches",
+            StatEvent::L1DCacheRead => "L1D-cache-reads",
+            StatEvent::L1DCacheWrite => "L1D-cache-writes",
+            StatEvent::L1DCacheReadMiss => "L1D-cache-read-misses",
+            StatEvent::L1ICacheReadMiss => "L1I-cache-read-misses",
+        }
+    }
+}
+
 /// Match on each supported event to parse from command line.
 /// Note that the context-switches event runs in kernel mode
 /// and requires a perf_event_paranoid setting < 1.
@@ -53,6 +96,7 @@ impl ToString for StatEvent {
             StatEvent::Cycles => "cycles".to_string(),
             StatEvent::Instructions => "instructions".to_string(),
             StatEvent::TaskClock => "task clock".to_string(),
+            StatEvent::CpuClock => "cpu clock".to_string(),
             StatEvent::ContextSwitches => "context switches".to_string(),
             StatEvent::L1DCacheRead => "L1D-cache-reads".to_string(),
             StatEvent::L1DCacheWrite => "L1D-cache-writes".to_string(),
@@ -71,12 +115,97 @@ pub struct StatOptions {
     #[structopt(short, long, help = "Event to collect", number_of_values = 1)]
     pub event: Vec<StatEvent>,
 
+    #[structopt(
+        short,
+        long,
+        default_value = "1",
+        help = "Run the command n times and print the mean"
+    )]
+    pub repeat: usize,
+
+    #[structopt(
+        long = "assert",
+        help = "Fail when a counter or metric breaks a budget, e.g. 'instructions<2.5e9'",
+        number_of_values = 1
+    )]
+    pub assertions: Vec<Assertion>,
+
+    #[structopt(
+        long,
+        help = "Load assertions from a TOML or JSON budget file",
+        parse(from_os_str)
+    )]
+    pub budget: Option<PathBuf>,
+
+    #[structopt(
+        short = "D",
+        long,
+        default_value = "0",
+        allow_hyphen_values = true,
+        parse(try_from_str = parse_delay),
+        help = "Wait this many ms before counting, or -1 to start disabled"
+    )]
+    pub delay: i64,
+
+    #[structopt(
+        long,
+        help = "Toggle counters with commands written to a FIFO, e.g. 'fifo:ctl,ack'"
+    )]
+    pub control: Option<ControlSpec>,
+
+    #[structopt(
+        short = "I",
+        long = "interval-print",
+        parse(try_from_str = parse_interval),
+        help = "Print how much each counter grew every this many ms"
+    )]
+    pub interval: Option<Duration>,
+
+    #[structopt(
+        long = "chrome-trace",
+        requires = "interval",
+        conflicts_with = "compare",
+        help = "Write the -I counts as counter tracks of a Trace Event Format file",
+        parse(from_os_str)
+    )]
+    pub chrome_trace: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        help = "Compare two commands separated by ':', alternating their runs",
+        conflicts_with = "json"
+    )]
+    pub compare: bool,
+
+    #[structopt(long, help = "Print results as JSON, for use with `ruperf stat-diff`")]
+    pub json: bool,
+
+    #[structopt(
+        short,
+        long,
+        help = "Write results to a file instead of stdout",
+        parse(from_os_str)
+    )]
+    pub output: Option<PathBuf>,
+
     // Allows multiple arguments to be passed, collects everything remaining on
     // the command line
     #[structopt(required = true, help = "Command to run")]
     pub command: Vec<String>,
 }
 
+/// Events counted when none are given on the command line.
+const DEFAULT_EVENTS: [StatEvent; 8] = [
+    StatEvent::Cycles,
+    StatEvent::Instructions,
+    StatEvent::TaskClock,
+    StatEvent::ContextSwitches,
+    StatEvent::L1DCacheRead,
+    StatEvent::L1DCacheWrite,
+    StatEvent::L1DCacheReadMiss,
+    StatEvent::L1ICacheReadMiss,
+];
+
 struct Counter {
     event: Event,
     start: isize,
@@ -85,21 +214,10 @@ struct Counter {
 
 impl Counter {
     /// Generate list of timers for a given `pid`.
-    pub fn counters(options: &mut StatOptions, pid: i32) -> Vec<Counter> {
+    pub fn counters(events: &[StatEvent], pid: i32) -> Vec<Counter> {
         let mut counters: Vec<Counter> = Vec::new();
 
-        if options.event.is_empty() {
-            options.event.push(StatEvent::Cycles);
-            options.event.push(StatEvent::Instructions);
-            options.event.push(StatEvent::TaskClock);
-            options.event.push(StatEvent::ContextSwitches);
-            options.event.push(StatEvent::L1DCacheRead);
-            options.event.push(StatEvent::L1DCacheWrite);
-            options.event.push(StatEvent::L1DCacheReadMiss);
-            options.event.push(StatEvent::L1ICacheReadMiss);
-        }
-
-        for event in &options.event {
+        for event in events {
             counters.push(Counter {
                 event: Event::new(*event, Some(pid)),
                 start: 0,
@@ -111,6 +229,75 @@ impl Counter {
     }
 }
 
+/// Counter values and derived metrics from a single run of a command.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StatRun {
+    /// Counter deltas keyed by event name, as given to `--event`.
+    pub counters: BTreeMap<String, f64>,
+    /// Ratios and resource usage derived from the counters.
+    pub metrics: BTreeMap<String, f64>,
+    #[serde(skip)]
+    pub usage: ResourceUsage,
+    /// The counts of each `-I` interval.
+    #[serde(skip)]
+    pub intervals: Vec<Interval>,
+}
+
+/// Results of `ruperf stat --json`. Every run is
+/// kept so that `ruperf stat-diff` can test whether
+/// a change between two reports is significant.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StatReport {
+    pub command: Vec<String>,
+    pub runs: Vec<StatRun>,
+}
+
+impl StatRun {
+    fn new(counters: &[Counter], usage: ResourceUsage) -> Self {
+        let counters: BTreeMap<String, f64> = counters
+            .iter()
+            .map(|c| {
+                (
+                    c.event.event.as_str().to_string(),
+                    (c.stop - c.start) as f64,
+                )
+            })
+            .collect();
+        let mut metrics = BTreeMap::new();
+        let wall = usage.wall.as_nanos() as f64;
+        if let (Some(cycles), Some(instructions)) =
+            (counters.get("cycles"), counters.get("instructions"))
+        {
+            if *cycles > 0.0 {
+                metrics.insert("IPC".to_string(), instructions / cycles);
+            }
+        }
+        if let Some(task_clock) = counters.get("task-clock") {
+            if wall > 0.0 {
+                metrics.insert("CPUs-utilized".to_string(), task_clock / wall);
+            }
+        }
+        metrics.insert("seconds-time-elapsed".to_string(), usage.wall.as_secs_f64());
+        metrics.insert("seconds-user".to_string(), usage.user.as_secs_f64());
+        metrics.insert("seconds-sys".to_string(), usage.sys.as_secs_f64());
+        metrics.insert("max-rss-kb".to_string(), usage.max_rss as f64);
+        Self {
+            counters,
+            metrics,
+            usage,
+            intervals: Vec::new(),
+        }
+    }
+
+    /// Look up a counter or metric by name.
+    pub fn get(&self, name: &str) -> Option<f64> {
+        self.counters
+            .get(name)
+            .or_else(|| self.metrics.get(name))
+            .copied()
+    }
+}
+
 pub fn launch_stat_process(
     instant: Instant,
     command: Vec<String>,
@@ -142,69 +329,351 @@ pub fn launch_stat_process(
     }
 }
 
-/// Run perf stat on the given command and event combinations.
+/// Run the command once with a counter for each of `events`,
+/// letting `gate` decide when the counters are enabled.
 /// Currently starts and stops a cycles timer in serial for each event specified.
-pub fn run_stat(options: StatOptions) {
+/// Fails if the gate cannot switch the counters, after killing the command.
+fn run_once(command: &[String], events: &[StatEvent], gate: &mut Gate) -> std::io::Result<StatRun> {
     // In future rather than starting and stopping counter
     // in series for each event, events will have the ability
     // to be added in groups that will coordinate their timing.
-    let mut options = options;
-
     let (reader, mut writer) = pipe().unwrap();
     let (mut parent_reader, parent_writer) = pipe().unwrap();
     let child_reader = reader.try_clone().unwrap();
     let child_writer = parent_writer.try_clone().unwrap();
 
     let instant = Instant::now();
-    let pid_child = launch_stat_process(
-        instant.clone(),
-        options.command.clone(),
-        child_reader,
-        child_writer,
-    );
-    let mut counters = Counter::counters(&mut options, pid_child);
+    let pid_child = launch_stat_process(instant, command.to_vec(), child_reader, child_writer);
+    let mut counters = Counter::counters(events, pid_child);
 
     let mut buffer: [u8; 16] = [0; 16];
-    let mut status: libc::c_int = 0;
-    // Start all the counters.
+    // Start all the counters, unless the gate holds them back.
     for counter in counters.iter_mut() {
-        counter.start = counter.event.start_counter().unwrap();
+        counter.start = if gate.starts_enabled() {
+            counter.event.start_counter().unwrap()
+        } else {
+            counter.event.fd.read().unwrap()
+        };
     }
     // Notify child we are ready.
     writer.write_all(&[1]).unwrap();
     writer.flush().unwrap();
     let nread = parent_reader.read(&mut buffer).unwrap();
-    let result = unsafe { libc::waitpid(pid_child, (&mut status) as *mut libc::c_int, 0) };
+    // Leave the child unreaped until `/proc/<pid>/io` has been read.
+    if gate.is_active() {
+        if let Err(e) = gate.run(pid_child, &counters, Instant::now()) {
+            unsafe { libc::kill(pid_child, libc::SIGKILL) };
+            let _ = rusage::reap(pid_child);
+            return Err(e);
+        }
+    } else {
+        rusage::wait_exit(pid_child).expect("Could not wait for child");
+    }
     // Let's see how long they took.
     let stop_time: u128 = instant.elapsed().as_nanos();
     for counter in counters.iter_mut() {
         counter.stop = counter.event.stop_counter().unwrap();
     }
+    let io = rusage::read_proc_io(pid_child);
+    let (_, mut usage) = rusage::reap(pid_child).expect("Could not reap child");
     let start_time = u128::from_ne_bytes(buffer);
     let t = stop_time - start_time;
     assert_eq!(nread, 16);
-    assert_eq!(result, pid_child);
     // Don't forget to drop the writer!
     drop(writer);
 
-    println!(
-        "Performance counter stats for '{}:'\n",
-        options.command.get(0).unwrap()
-    );
+    usage.wall = Duration::from_nanos(t as u64);
+    usage.read_bytes = io.map(|(read, _)| read);
+    usage.write_bytes = io.map(|(_, write)| write);
 
-    for counter in counters {
-        if matches!(counter.event.event, StatEvent::TaskClock) {
-            println!(
-                " {:.2} msec task-clock\n CPU utilized: {:.3}",
-                (counter.stop - counter.start) as f64 / 1_000_000.0,
-                (counter.stop - counter.start) as f64 / t as f64
-            );
+    let mut run = StatRun::new(&counters, usage);
+    run.intervals = std::mem::take(&mut gate.intervals);
+    Ok(run)
+}
+
+/// Run perf stat on the given command and event combinations.
+/// Returns the exit code for `ruperf`, which is non-zero
+/// when any `--assert` or budget file assertion fails.
+pub fn run_stat(options: StatOptions) -> i32 {
+    let mut options = options;
+    if options.event.is_empty() {
+        options.event.extend_from_slice(&DEFAULT_EVENTS);
+    }
+    if let Some(path) = &options.budget {
+        match budget::load(path) {
+            Ok(assertions) => options.assertions.extend(assertions),
+            Err(e) => {
+                eprintln!("{}", e);
+                return 1;
+            }
+        }
+    }
+
+    let mut gate = Gate {
+        delay: options.delay,
+        interval: options.interval,
+        ..Gate::default()
+    };
+    if let Some(spec) = &options.control {
+        match Control::open(spec) {
+            Ok(control) => gate.control = Some(control),
+            Err(e) => {
+                eprintln!("Could not open {}: {}", spec.ctl.display(), e);
+                return 1;
+            }
+        }
+    }
+
+    let mut out: Box<dyn Write> = match &options.output {
+        Some(path) => match File::create(path) {
+            Ok(file) => Box::new(file),
+            Err(e) => {
+                eprintln!("Could not create {}: {}", path.display(), e);
+                return 1;
+            }
+        },
+        None => Box::new(std::io::stdout()),
+    };
+    // Keep escape codes out of files and CI logs.
+    let color = options.output.is_none() && std::io::stdout().is_terminal();
+
+    if options.compare {
+        let (a, b) = match compare::split_commands(&options.command) {
+            Some(commands) => commands,
+            None => {
+                eprintln!("--compare expects two commands separated by ':'");
+                return 1;
+            }
+        };
+        let repeat = options.repeat.max(1);
+        let (runs_a, runs_b) =
+            match compare::run_interleaved(&a, &b, &options.event, repeat, &mut gate) {
+                Ok(runs) => runs,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return 1;
+                }
+            };
+        let rows = compare::compare(&options.event, &runs_a, &runs_b);
+        match compare::print_comparison(&mut out, &a, &b, repeat, &rows) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => return 0,
+            Err(e) => {
+                eprintln!("{}", e);
+                return 1;
+            }
+        }
+        // Budgets apply to the new command.
+        return check_budget(&mut out, &options.assertions, &runs_b, color);
+    }
+
+    let runs: Vec<StatRun> = match (0..options.repeat.max(1))
+        .map(|_| run_once(&options.command, &options.event, &mut gate))
+        .collect()
+    {
+        Ok(runs) => runs,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+
+    if let Some(path) = &options.chrome_trace {
+        let events = interval_trace(&options.command, &runs);
+        let written = File::create(path).and_then(|file| {
+            let mut out = std::io::BufWriter::new(file);
+            chrome_trace::write(&mut out, &events)?;
+            out.flush()
+        });
+        if let Err(e) = written {
+            eprintln!("Could not write {}: {}", path.display(), e);
+            return 1;
+        }
+    }
+
+    if options.json {
+        let report = StatReport {
+            command: options.command.clone(),
+            runs,
+        };
+        writeln!(out, "{}", serde_json::to_string_pretty(&report).unwrap()).unwrap();
+        // Keep the JSON parseable by sending the budget table elsewhere.
+        let stderr = std::io::stderr();
+        let color = stderr.is_terminal();
+        return check_budget(&mut stderr.lock(), &options.assertions, &report.runs, color);
+    }
+
+    print_runs(&mut out, &options.command, &options.event, &runs).unwrap();
+    check_budget(&mut out, &options.assertions, &runs, color)
+}
+
+/// The `-I` counts of `runs` as counter events, a process per run
+/// with a track per event. Each count is drawn from the start of its
+/// interval until the next one begins.
+fn interval_trace(command: &[String], runs: &[StatRun]) -> Vec<TraceEvent> {
+    let mut events = Vec::new();
+    for (pid, run) in (1..).zip(runs) {
+        events.push(TraceEvent {
+            name: "process_name".to_string(),
+            cat: String::new(),
+            ph: "M",
+            ts: 0.0,
+            dur: None,
+            pid,
+            tid: pid,
+            s: None,
+            args: json!({ "name": format!("{} (run {})", command[0], pid) }),
+        });
+        let mut start = Duration::ZERO;
+        for interval in &run.intervals {
+            for (name, delta) in &interval.deltas {
+                events.push(TraceEvent {
+                    name: name.clone(),
+                    cat: String::new(),
+                    ph: "C",
+                    ts: start.as_secs_f64() * 1e6,
+                    dur: None,
+                    pid,
+                    tid: pid,
+                    s: None,
+                    args: json!({ name.as_str(): delta }),
+                });
+            }
+            start = interval.time;
+        }
+    }
+    events
+}
+
+/// Evaluate assertions, print the outcome to `out`, in colour
+/// if `color`, and return the exit code for `ruperf`.
+fn check_budget(
+    out: &mut dyn Write,
+    assertions: &[Assertion],
+    runs: &[StatRun],
+    color: bool,
+) -> i32 {
+    if assertions.is_empty() {
+        return 0;
+    }
+    let results = budget::evaluate(assertions, runs);
+    budget::print_results(out, &results, color).unwrap();
+    if results.iter().all(|r| r.passed) {
+        0
+    } else {
+        1
+    }
+}
+
+/// Print counters for one or more runs of `command`. Repeated runs
+/// are shown as their mean with the relative standard deviation.
+fn print_runs(
+    out: &mut dyn Write,
+    command: &[String],
+    events: &[StatEvent],
+    runs: &[StatRun],
+) -> std::io::Result<()> {
+    writeln!(
+        out,
+        "Performance counter stats for '{}'{}:\n",
+        command.first().unwrap(),
+        if runs.len() > 1 {
+            format!(" ({} runs)", runs.len())
+        } else {
+            String::new()
+        }
+    )?;
+
+    for event in events {
+        let samples: Vec<f64> = runs
+            .iter()
+            .filter_map(|run| run.get(event.as_str()))
+            .collect();
+        let value = stats::mean(&samples);
+        let variation = if runs.len() > 1 {
+            format!("  ( +- {:.2}% )", stats::relative_stddev(&samples) * 100.0)
+        } else {
+            String::new()
+        };
+        if matches!(event, StatEvent::TaskClock) {
+            let wall: Vec<f64> = runs
+                .iter()
+                .map(|run| run.usage.wall.as_nanos() as f64)
+                .collect();
+            writeln!(
+                out,
+                " {:.2} msec task-clock{}\n CPU utilized: {:.3}",
+                value / 1_000_000.0,
+                variation,
+                value / stats::mean(&wall)
+            )?;
         } else {
-            println!(
-                " Number of {}: {}",
-                counter.event.event.to_string(),
-                counter.stop - counter.start
-            );
+            writeln!(
+                out,
+                " Number of {}: {}{}",
+                event.to_string(),
+                value.round(),
+                variation
+            )?;
         }
     }
+
+    let usage: Vec<ResourceUsage> = runs.iter().map(|run| run.usage).collect();
+    ResourceUsage::mean(&usage).print(out)
+}
+
+#[cfg(test)]
+#[test]
+fn interval_trace_test() {
+    let interval = |ms, deltas: &[(&str, isize)]| Interval {
+        time: Duration::from_millis(ms),
+        deltas: deltas.iter().map(|(n, d)| (n.to_string(), *d)).collect(),
+    };
+    let run = StatRun {
+        intervals: vec![
+            interval(100, &[("task-clock", 90_000_000), ("context-switches", 3)]),
+            interval(150, &[("task-clock", 40_000_000), ("context-switches", 0)]),
+        ],
+        ..StatRun::default()
+    };
+    let command = vec!["./bench".to_string()];
+    let events = interval_trace(&command, &[run.clone(), run]);
+    assert_eq!(events.len(), 10);
+    assert_eq!(events[0].args["name"], "./bench (run 1)");
+    let counters: Vec<(&str, f64, u32, &serde_json::Value)> = events
+        .iter()
+        .filter(|e| e.ph == "C" && e.pid == 1)
+        .map(|e| (e.name.as_str(), e.ts, e.pid, &e.args))
+        .collect();
+    assert_eq!(
+        counters,
+        [
+            ("task-clock", 0.0, 1, &json!({ "task-clock": 90_000_000 })),
+            (
+                "context-switches",
+                0.0,
+                1,
+                &json!({ "context-switches": 3 })
+            ),
+            (
+                "task-clock",
+                100_000.0,
+                1,
+                &json!({ "task-clock": 40_000_000 })
+            ),
+            (
+                "context-switches",
+                100_000.0,
+                1,
+                &json!({ "context-switches": 0 })
+            ),
+        ]
+    );
+    assert_eq!(events[5].args["name"], "./bench (run 2)");
+
+    // --compare runs never write the trace, so the two cannot be combined.
+    let args = ["stat", "-I", "100", "--chrome-trace", "t.json"];
+    assert!(StatOptions::from_iter_safe(args.iter().chain(&["true"])).is_ok());
+    let compare = ["--compare", "true", ":", "true"];
+    assert!(StatOptions::from_iter_safe(args.iter().chain(&compare)).is_err());
 }
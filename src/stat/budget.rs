@@ -0,0 +1,252 @@
+//! Performance budgets for `ruperf stat`.
+//!
+//! An assertion such as `instructions<2.5e9` or `IPC>1.2` compares
+//! a counter or metric against a threshold. Assertions are given
+//! with `--assert`, or loaded from a budget file that can be checked
+//! into a repository, in TOML:
+//!
+//! ```toml
+//! assert = ["instructions<2.5e9", "IPC>1.2"]
+//! ```
+//!
+//! or JSON:
+//!
+//! ```json
+//! { "assert": ["instructions<2.5e9", "IPC>1.2"] }
+//! ```
+
+use super::stats;
+use super::StatRun;
+use crate::utils::ParseError;
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Comparison used by an assertion.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Op {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Op {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Op::Lt => "<",
+            Op::Le => "<=",
+            Op::Gt => ">",
+            Op::Ge => ">=",
+        }
+    }
+
+    fn holds(&self, value: f64, threshold: f64) -> bool {
+        match self {
+            Op::Lt => value < threshold,
+            Op::Le => value <= threshold,
+            Op::Gt => value > threshold,
+            Op::Ge => value >= threshold,
+        }
+    }
+}
+
+/// A bound on a counter or metric, e.g. `instructions<2.5e9`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Assertion {
+    pub name: String,
+    pub op: Op,
+    pub threshold: f64,
+}
+
+/// Parse `<name><op><threshold>` where op is one of `<`, `<=`, `>`, `>=`.
+impl FromStr for Assertion {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseError::InvalidAssertion(s.to_string());
+        let at = s.find(&['<', '>'][..]).ok_or_else(invalid)?;
+        let (name, rest) = s.split_at(at);
+        let (op, threshold) = match rest {
+            r if r.starts_with("<=") => (Op::Le, &r[2..]),
+            r if r.starts_with(">=") => (Op::Ge, &r[2..]),
+            r if r.starts_with('<') => (Op::Lt, &r[1..]),
+            r => (Op::Gt, &r[1..]),
+        };
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(invalid());
+        }
+        let threshold = threshold.trim().parse::<f64>().map_err(|_| invalid())?;
+        Ok(Assertion {
+            name: name.to_string(),
+            op,
+            threshold,
+        })
+    }
+}
+
+impl fmt::Display for Assertion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.name, self.op.as_str(), self.threshold)
+    }
+}
+
+/// Outcome of checking one assertion.
+#[derive(Debug, Clone)]
+pub struct AssertionResult {
+    pub assertion: Assertion,
+    /// Mean value over all runs, or `None` if it was not collected.
+    pub value: Option<f64>,
+    pub passed: bool,
+}
+
+/// Check each assertion against the mean of its value over `runs`.
+/// An assertion on a counter or metric that was not collected fails.
+pub fn evaluate(assertions: &[Assertion], runs: &[StatRun]) -> Vec<AssertionResult> {
+    assertions
+        .iter()
+        .map(|assertion| {
+            let samples: Vec<f64> = runs.iter().filter_map(|r| r.get(&assertion.name)).collect();
+            let value = if samples.len() == runs.len() && !samples.is_empty() {
+                Some(stats::mean(&samples))
+            } else {
+                None
+            };
+            let passed = value.is_some_and(|v| assertion.op.holds(v, assertion.threshold));
+            AssertionResult {
+                assertion: assertion.clone(),
+                value,
+                passed,
+            }
+        })
+        .collect()
+}
+
+/// Print a pass/fail table for evaluated assertions. Failures are
+/// shown in red when `color` is set, which callers only do for terminals.
+pub fn print_results(
+    out: &mut dyn Write,
+    results: &[AssertionResult],
+    color: bool,
+) -> std::io::Result<()> {
+    writeln!(out)?;
+    writeln!(out, " {:<40} {:>20}  Result", "Budget", "Value")?;
+    for result in results {
+        let value = match result.value {
+            Some(v) => format!("{:.3}", v),
+            None => "<not counted>".to_string(),
+        };
+        let outcome = match (result.passed, color) {
+            (true, _) => "pass",
+            (false, true) => "\x1b[0;31mFAIL\x1b[0m",
+            (false, false) => "FAIL",
+        };
+        writeln!(
+            out,
+            " {:<40} {:>20}  {}",
+            result.assertion.to_string(),
+            value,
+            outcome
+        )?;
+    }
+    Ok(())
+}
+
+/// Layout of a budget file.
+#[derive(Debug, Deserialize)]
+struct BudgetFile {
+    #[serde(rename = "assert")]
+    assertions: Vec<String>,
+}
+
+/// Load assertions from a budget file. Files ending
+/// in `.json` are read as JSON, anything else as TOML.
+pub fn load(path: &Path) -> Result<Vec<Assertion>, ParseError> {
+    let file_error = |e: String| ParseError::BudgetFile(path.display().to_string(), e);
+    let contents = fs::read_to_string(path).map_err(|e| file_error(e.to_string()))?;
+    let budget: BudgetFile = if path.extension().is_some_and(|ext| ext == "json") {
+        serde_json::from_str(&contents).map_err(|e| file_error(e.to_string()))?
+    } else {
+        toml::from_str(&contents).map_err(|e| file_error(e.to_string()))?
+    };
+    budget
+        .assertions
+        .iter()
+        .map(|a| a.parse::<Assertion>())
+        .collect()
+}
+
+#[cfg(test)]
+#[test]
+fn parse_assertion_test() {
+    let a: Assertion = "instructions<2.5e9".parse().unwrap();
+    assert_eq!(a.name, "instructions");
+    assert_eq!(a.op, Op::Lt);
+    assert_eq!(a.threshold, 2.5e9);
+    let a: Assertion = "IPC >= 1.2".parse().unwrap();
+    assert_eq!(a.name, "IPC");
+    assert_eq!(a.op, Op::Ge);
+    assert_eq!(a.threshold, 1.2);
+    assert!("cycles".parse::<Assertion>().is_err());
+    assert!("<5".parse::<Assertion>().is_err());
+    assert!("cycles<lots".parse::<Assertion>().is_err());
+}
+
+#[test]
+fn evaluate_test() {
+    let run = |instructions: f64| {
+        let mut run = StatRun::default();
+        run.counters
+            .insert("instructions".to_string(), instructions);
+        run
+    };
+    let runs = [run(100.0), run(200.0)];
+    let assertions = [
+        "instructions<=150".parse().unwrap(),
+        "instructions>160".parse().unwrap(),
+        "cycles<1".parse().unwrap(),
+    ];
+    let results = evaluate(&assertions, &runs);
+    assert!(results[0].passed);
+    assert_eq!(results[0].value, Some(150.0));
+    assert!(!results[1].passed);
+    assert!(!results[2].passed);
+    assert_eq!(results[2].value, None);
+
+    let mut plain = Vec::new();
+    print_results(&mut plain, &results, false).unwrap();
+    let plain = String::from_utf8(plain).unwrap();
+    assert!(plain.contains("FAIL") && !plain.contains('\x1b'));
+    let mut colored = Vec::new();
+    print_results(&mut colored, &results, true).unwrap();
+    assert!(String::from_utf8(colored)
+        .unwrap()
+        .contains("\x1b[0;31mFAIL"));
+}
+
+#[test]
+fn load_budget_test() {
+    let dir = std::env::temp_dir();
+    let toml_path = dir.join(format!("ruperf-budget-{}.toml", std::process::id()));
+    fs::write(
+        &toml_path,
+        "assert = [\"instructions<2.5e9\", \"IPC>1.2\"]\n",
+    )
+    .unwrap();
+    let json_path = dir.join(format!("ruperf-budget-{}.json", std::process::id()));
+    fs::write(
+        &json_path,
+        r#"{"assert": ["instructions<2.5e9", "IPC>1.2"]}"#,
+    )
+    .unwrap();
+    let from_toml = load(&toml_path).unwrap();
+    let from_json = load(&json_path).unwrap();
+    fs::remove_file(toml_path).unwrap();
+    fs::remove_file(json_path).unwrap();
+    assert_eq!(from_toml, from_json);
+    assert_eq!(from_toml.len(), 2);
+    assert_eq!(from_toml[1].name, "IPC");
+}
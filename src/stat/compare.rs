@@ -0,0 +1,174 @@
+//! A/B comparison of two commands for `ruperf stat --compare`.
+//!
+//! The commands are given on one command line separated by `:`,
+//! as in `ruperf stat --compare -r 20 -- ./old : ./new`. Their
+//! runs are interleaved, alternating which goes first, so that
+//! drift in machine state affects both commands alike.
+
+use super::control::Gate;
+use super::stats;
+use super::{run_once, StatEvent, StatRun};
+use std::io::Write;
+
+/// Confidence level for the interval around each ratio.
+const CONFIDENCE: f64 = 0.95;
+
+/// Split a command line on a lone `:` into two commands.
+pub fn split_commands(command: &[String]) -> Option<(Vec<String>, Vec<String>)> {
+    let at = command.iter().position(|arg| arg == ":")?;
+    let (a, b) = (&command[..at], &command[at + 1..]);
+    if a.is_empty() || b.is_empty() {
+        return None;
+    }
+    Some((a.to_vec(), b.to_vec()))
+}
+
+/// Run `a` and `b` alternately `repeat` times each.
+pub fn run_interleaved(
+    a: &[String],
+    b: &[String],
+    events: &[StatEvent],
+    repeat: usize,
+    gate: &mut Gate,
+) -> std::io::Result<(Vec<StatRun>, Vec<StatRun>)> {
+    let mut runs_a = Vec::with_capacity(repeat);
+    let mut runs_b = Vec::with_capacity(repeat);
+    for i in 0..repeat {
+        if i % 2 == 0 {
+            runs_a.push(run_once(a, events, gate)?);
+            runs_b.push(run_once(b, events, gate)?);
+        } else {
+            runs_b.push(run_once(b, events, gate)?);
+            runs_a.push(run_once(a, events, gate)?);
+        }
+    }
+    Ok((runs_a, runs_b))
+}
+
+/// Which way a value should move to count as an improvement.
+fn higher_is_better(name: &str) -> Option<bool> {
+    match name {
+        "IPC" => Some(true),
+        "CPUs-utilized" => None,
+        _ => Some(false),
+    }
+}
+
+/// One row of the comparison table.
+#[derive(Debug, Clone)]
+pub struct Comparison {
+    pub name: String,
+    pub a: f64,
+    pub b: f64,
+    /// `b / a`, or `None` when `a` is 0.
+    pub ratio: Option<f64>,
+    /// Fieller confidence interval for the ratio, if both sides have
+    /// repeated runs and `a` is clearly nonzero.
+    pub interval: Option<(f64, f64)>,
+    /// `Some('A')` or `Some('B')` when the interval excludes a ratio of 1.
+    pub winner: Option<char>,
+}
+
+impl Comparison {
+    fn new(name: &str, a: &[f64], b: &[f64]) -> Self {
+        let (ma, mb) = (stats::mean(a), stats::mean(b));
+        let ratio = if ma != 0.0 { Some(mb / ma) } else { None };
+        let interval = stats::fieller_interval(a, b, CONFIDENCE);
+        let winner = match (interval, higher_is_better(name)) {
+            (Some((lo, _)), Some(higher)) if lo > 1.0 => Some(if higher { 'B' } else { 'A' }),
+            (Some((_, hi)), Some(higher)) if hi < 1.0 => Some(if higher { 'A' } else { 'B' }),
+            _ => None,
+        };
+        Comparison {
+            name: name.to_string(),
+            a: ma,
+            b: mb,
+            ratio,
+            interval,
+            winner,
+        }
+    }
+}
+
+/// Compare every counter, then every metric, collected for both commands.
+pub fn compare(events: &[StatEvent], runs_a: &[StatRun], runs_b: &[StatRun]) -> Vec<Comparison> {
+    let samples = |runs: &[StatRun], name: &str| -> Vec<f64> {
+        runs.iter().filter_map(|r| r.get(name)).collect()
+    };
+    let metrics: Vec<String> = runs_a
+        .first()
+        .map(|r| r.metrics.keys().cloned().collect())
+        .unwrap_or_default();
+    events
+        .iter()
+        .map(|e| e.as_str().to_string())
+        .chain(metrics)
+        .map(|name| Comparison::new(&name, &samples(runs_a, &name), &samples(runs_b, &name)))
+        .collect()
+}
+
+/// Print the comparison side by side.
+pub fn print_comparison(
+    out: &mut dyn Write,
+    a: &[String],
+    b: &[String],
+    repeat: usize,
+    rows: &[Comparison],
+) -> std::io::Result<()> {
+    writeln!(
+        out,
+        "Performance counter comparison over {} interleaved runs each:\n",
+        repeat
+    )?;
+    writeln!(out, "   A: {}", a.join(" "))?;
+    writeln!(out, "   B: {}\n", b.join(" "))?;
+    writeln!(
+        out,
+        " {:<24} {:>18} {:>18} {:>8}  {:<20} Winner",
+        "Counter",
+        "A",
+        "B",
+        "B/A",
+        format!("{}% CI", CONFIDENCE * 100.0)
+    )?;
+    for row in rows {
+        let ratio = row
+            .ratio
+            .map_or_else(|| "-".to_string(), |r| format!("{:.3}", r));
+        let interval = row.interval.map_or_else(
+            || "-".to_string(),
+            |(lo, hi)| format!("[{:.3}, {:.3}]", lo, hi),
+        );
+        let winner = row.winner.map_or_else(String::new, |w| w.to_string());
+        writeln!(
+            out,
+            " {:<24} {:>18.3} {:>18.3} {:>8}  {:<20} {}",
+            row.name, row.a, row.b, ratio, interval, winner
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[test]
+fn split_commands_test() {
+    let args = |s: &str| s.split(' ').map(String::from).collect::<Vec<_>>();
+    let (a, b) = split_commands(&args("./old -n 1 : ./new -n 1")).unwrap();
+    assert_eq!(a, args("./old -n 1"));
+    assert_eq!(b, args("./new -n 1"));
+    assert!(split_commands(&args("./old ./new")).is_none());
+    assert!(split_commands(&args("./old :")).is_none());
+}
+
+#[test]
+fn comparison_test() {
+    let row = Comparison::new("cycles", &[100.0, 102.0, 98.0], &[80.0, 81.0, 79.0]);
+    assert!((row.ratio.unwrap() - 0.8).abs() < 1e-12);
+    let (lo, hi) = row.interval.unwrap();
+    assert!(lo < 0.8 && 0.8 < hi && hi < 1.0);
+    assert_eq!(row.winner, Some('B'));
+    let row = Comparison::new("IPC", &[1.0, 1.1, 0.9], &[2.0, 2.1, 1.9]);
+    assert_eq!(row.winner, Some('B'));
+    let row = Comparison::new("cycles", &[100.0, 150.0, 50.0], &[101.0, 149.0, 52.0]);
+    assert_eq!(row.winner, None);
+}
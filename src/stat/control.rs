@@ -0,0 +1,382 @@
+//! Control over when counters run during `ruperf stat`.
+//!
+//! `--delay <ms>` enables counters some time after the command
+//! starts, and `-D -1` leaves them disabled until told otherwise.
+//! `--control fifo:ctl[,ack]` follows the protocol of `perf stat`:
+//! the workload, or a script, writes `enable`, `disable`, `snapshot`
+//! or `ping` lines to the `ctl` FIFO, and each command is answered
+//! with `ack` on the optional `ack` FIFO once it has taken effect.
+//! `-I <ms>` prints how much each counter grew every interval.
+
+extern crate libc;
+use super::rusage::has_exited;
+use super::Counter;
+use crate::utils::ParseError;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// Longest wait between checks when the child cannot be polled.
+const FALLBACK_POLL: Duration = Duration::from_millis(10);
+
+/// FIFO paths given to `--control fifo:ctl[,ack]`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ControlSpec {
+    pub ctl: PathBuf,
+    pub ack: Option<PathBuf>,
+}
+
+impl FromStr for ControlSpec {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseError::InvalidControl(s.to_string());
+        let fifos = s.strip_prefix("fifo:").ok_or_else(invalid)?;
+        let mut paths = fifos.splitn(2, ',');
+        let ctl = paths.next().filter(|p| !p.is_empty()).ok_or_else(invalid)?;
+        let ack = paths.next().filter(|p| !p.is_empty()).map(PathBuf::from);
+        Ok(ControlSpec {
+            ctl: PathBuf::from(ctl),
+            ack,
+        })
+    }
+}
+
+/// Parse `-D`: milliseconds to wait, or -1 to start disabled.
+pub fn parse_delay(s: &str) -> Result<i64, ParseError> {
+    match s.parse::<i64>() {
+        Ok(delay) if delay >= -1 => Ok(delay),
+        _ => Err(ParseError::InvalidDelay(s.to_string())),
+    }
+}
+
+/// Parse `-I`: milliseconds between printed counts.
+pub fn parse_interval(s: &str) -> Result<Duration, ParseError> {
+    match s.parse::<u64>() {
+        Ok(ms) if ms > 0 => Ok(Duration::from_millis(ms)),
+        _ => Err(ParseError::InvalidInterval(s.to_string())),
+    }
+}
+
+/// A command read from the control FIFO.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ControlCommand {
+    Enable,
+    Disable,
+    Snapshot,
+    Ping,
+}
+
+impl FromStr for ControlCommand {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "enable" => Ok(ControlCommand::Enable),
+            "disable" => Ok(ControlCommand::Disable),
+            "snapshot" => Ok(ControlCommand::Snapshot),
+            "ping" => Ok(ControlCommand::Ping),
+            other => Err(ParseError::InvalidControl(other.to_string())),
+        }
+    }
+}
+
+/// Open control and acknowledgement FIFOs.
+#[derive(Debug)]
+pub struct Control {
+    ctl: File,
+    ack: Option<File>,
+    /// Bytes read from `ctl` that do not yet form a whole line.
+    pending: String,
+}
+
+impl Control {
+    /// Open the FIFOs named by `spec`. They are opened read-write
+    /// and non-blocking so neither end has to be connected yet.
+    pub fn open(spec: &ControlSpec) -> std::io::Result<Self> {
+        let open = |path: &PathBuf| {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open(path)
+        };
+        Ok(Control {
+            ctl: open(&spec.ctl)?,
+            ack: match &spec.ack {
+                Some(path) => Some(open(path)?),
+                None => None,
+            },
+            pending: String::new(),
+        })
+    }
+
+    /// Read whatever commands are waiting in the FIFO.
+    fn read_commands(&mut self) -> Vec<String> {
+        let mut buf = [0u8; 512];
+        loop {
+            match self.ctl.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => self.pending.push_str(&String::from_utf8_lossy(&buf[..n])),
+                Err(_) => break,
+            }
+        }
+        let mut lines: Vec<String> = self.pending.split('\n').map(String::from).collect();
+        // The last piece is an incomplete line, or empty.
+        self.pending = lines.pop().unwrap_or_default();
+        lines.retain(|l| !l.trim().is_empty());
+        lines
+    }
+
+    fn acknowledge(&mut self) {
+        if let Some(ack) = &mut self.ack {
+            let _ = ack.write_all(b"ack\n");
+        }
+    }
+}
+
+/// Decides when counters are switched on and off while the command runs.
+#[derive(Debug, Default)]
+pub struct Gate {
+    /// Milliseconds to wait before enabling counters.
+    /// 0 enables them immediately, -1 waits for `enable`.
+    pub delay: i64,
+    pub control: Option<Control>,
+    /// Time between the counts printed by `-I`.
+    pub interval: Option<Duration>,
+    /// The counts of each interval so far.
+    pub intervals: Vec<Interval>,
+}
+
+/// How much each counter grew over one `-I` interval.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Interval {
+    /// Since the command started, at the end of the interval.
+    pub time: Duration,
+    /// By event name, as given to `--event`.
+    pub deltas: Vec<(String, isize)>,
+}
+
+impl Gate {
+    /// Whether counters should be enabled as soon as the command starts.
+    pub fn starts_enabled(&self) -> bool {
+        self.delay == 0
+    }
+
+    /// Whether there is anything to do while the command runs.
+    pub fn is_active(&self) -> bool {
+        self.delay != 0 || self.control.is_some() || self.interval.is_some()
+    }
+
+    /// Wait for `pid` to exit, leaving it unreaped, while enabling
+    /// counters after the delay, serving control commands and
+    /// keeping the counts of each interval.
+    pub fn run(&mut self, pid: i32, counters: &[Counter], started: Instant) -> std::io::Result<()> {
+        let pidfd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) } as i32;
+        let result = self.wait(pid, pidfd, counters, started);
+        if pidfd >= 0 {
+            unsafe { libc::close(pidfd) };
+        }
+        result
+    }
+
+    /// The loop of `run`, polling `pidfd` when there is one.
+    fn wait(
+        &mut self,
+        pid: i32,
+        pidfd: i32,
+        counters: &[Counter],
+        started: Instant,
+    ) -> std::io::Result<()> {
+        let mut enable_at = if self.delay > 0 {
+            Some(started + Duration::from_millis(self.delay as u64))
+        } else {
+            None
+        };
+        let mut tick = self.interval.map(|interval| started + interval);
+        let mut last: Vec<isize> = counters.iter().map(|c| c.start).collect();
+
+        loop {
+            let next = match (enable_at, tick) {
+                (Some(enable), Some(tick)) => Some(enable.min(tick)),
+                (enable, tick) => enable.or(tick),
+            };
+            let mut timeout = next.map(|at| at.saturating_duration_since(Instant::now()));
+            if pidfd < 0 {
+                timeout = Some(timeout.map_or(FALLBACK_POLL, |t| t.min(FALLBACK_POLL)));
+            }
+            let mut fds = Vec::with_capacity(2);
+            if pidfd >= 0 {
+                fds.push(libc::pollfd {
+                    fd: pidfd,
+                    events: libc::POLLIN,
+                    revents: 0,
+                });
+            }
+            if let Some(control) = &self.control {
+                fds.push(libc::pollfd {
+                    fd: control.ctl.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                });
+            }
+            let timeout_ms = timeout.map_or(-1, |t| t.as_millis() as i32);
+            let ret =
+                unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout_ms) };
+            if ret == -1 {
+                let err = std::io::Error::last_os_error();
+                if err.kind() != std::io::ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            }
+
+            if let Some(at) = enable_at {
+                if Instant::now() >= at {
+                    set_enabled(counters, true)?;
+                    enable_at = None;
+                }
+            }
+            if let (Some(at), Some(interval)) = (tick, self.interval) {
+                if Instant::now() >= at {
+                    self.count_interval(counters, &mut last, started);
+                    tick = Some(at + interval);
+                }
+            }
+            if self.control.is_some() {
+                self.serve(counters, started)?;
+            }
+            if has_exited(pid)? {
+                // The last interval ends early, with the command.
+                if self.interval.is_some() {
+                    self.count_interval(counters, &mut last, started);
+                }
+                return Ok(());
+            }
+        }
+    }
+
+    /// Print and keep how much each counter grew since `last`.
+    fn count_interval(&mut self, counters: &[Counter], last: &mut [isize], started: Instant) {
+        let time = started.elapsed();
+        let mut deltas = Vec::with_capacity(counters.len());
+        for (counter, last) in counters.iter().zip(last.iter_mut()) {
+            let value = counter.event.fd.read().unwrap_or(*last);
+            let name = counter.event.event.as_str();
+            eprintln!(
+                "{:>15.9} {:>18} {}",
+                time.as_secs_f64(),
+                value - *last,
+                name
+            );
+            deltas.push((name.to_string(), value - *last));
+            *last = value;
+        }
+        self.intervals.push(Interval { time, deltas });
+    }
+
+    /// Act on every command waiting in the control FIFO.
+    fn serve(&mut self, counters: &[Counter], started: Instant) -> std::io::Result<()> {
+        let control = self.control.as_mut().unwrap();
+        for line in control.read_commands() {
+            match line.parse::<ControlCommand>() {
+                Ok(ControlCommand::Enable) => set_enabled(counters, true)?,
+                Ok(ControlCommand::Disable) => set_enabled(counters, false)?,
+                Ok(ControlCommand::Snapshot) => snapshot(counters, started),
+                Ok(ControlCommand::Ping) => {}
+                Err(e) => {
+                    eprintln!("{}", e);
+                    continue;
+                }
+            }
+            control.acknowledge();
+        }
+        Ok(())
+    }
+}
+
+/// Switch every counter on or off, stopping at the first that fails.
+fn set_enabled(counters: &[Counter], enabled: bool) -> std::io::Result<()> {
+    let action = if enabled { "enable" } else { "disable" };
+    for counter in counters {
+        let result = if enabled {
+            counter.event.fd.enable()
+        } else {
+            counter.event.fd.disable()
+        };
+        if result.is_err() {
+            let err = std::io::Error::last_os_error();
+            return Err(std::io::Error::new(
+                err.kind(),
+                format!(
+                    "Could not {} {}: {}",
+                    action,
+                    counter.event.event.as_str(),
+                    err
+                ),
+            ));
+        }
+    }
+    eprintln!("Events {}d", action);
+    Ok(())
+}
+
+/// Print the counts so far without disturbing the counters.
+fn snapshot(counters: &[Counter], started: Instant) {
+    let values: Vec<String> = counters
+        .iter()
+        .map(|c| {
+            let value = c.event.fd.read().unwrap_or(0) - c.start;
+            format!("{} {}", value, c.event.event.as_str())
+        })
+        .collect();
+    eprintln!(
+        "{:>12.6} snapshot: {}",
+        started.elapsed().as_secs_f64(),
+        values.join(", ")
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn parse_control_test() {
+    let spec: ControlSpec = "fifo:ctl.fifo,ack.fifo".parse().unwrap();
+    assert_eq!(spec.ctl, PathBuf::from("ctl.fifo"));
+    assert_eq!(spec.ack, Some(PathBuf::from("ack.fifo")));
+    let spec: ControlSpec = "fifo:ctl.fifo".parse().unwrap();
+    assert_eq!(spec.ack, None);
+    assert!("fd:3,4".parse::<ControlSpec>().is_err());
+    assert!("fifo:".parse::<ControlSpec>().is_err());
+    assert_eq!(
+        "enable\n".parse::<ControlCommand>().unwrap(),
+        ControlCommand::Enable
+    );
+    assert!("start".parse::<ControlCommand>().is_err());
+    assert_eq!(parse_delay("-1").unwrap(), -1);
+    assert_eq!(parse_delay("250").unwrap(), 250);
+    assert!(parse_delay("-2").is_err());
+    assert!(parse_delay("soon").is_err());
+    assert_eq!(parse_interval("100").unwrap(), Duration::from_millis(100));
+    assert!(parse_interval("0").is_err());
+    assert!(parse_interval("-5").is_err());
+}
+
+#[test]
+fn read_commands_test() {
+    let dir = std::env::temp_dir();
+    let ctl = dir.join(format!("ruperf-ctl-{}.fifo", std::process::id()));
+    let path = std::ffi::CString::new(ctl.to_str().unwrap()).unwrap();
+    assert_eq!(unsafe { libc::mkfifo(path.as_ptr(), 0o600) }, 0);
+    let mut control = Control::open(&ControlSpec {
+        ctl: ctl.clone(),
+        ack: None,
+    })
+    .unwrap();
+    let mut writer = OpenOptions::new().write(true).open(&ctl).unwrap();
+    writer.write_all(b"enable\ndisa").unwrap();
+    assert_eq!(control.read_commands(), vec!["enable".to_string()]);
+    writer.write_all(b"ble\n").unwrap();
+    assert_eq!(control.read_commands(), vec!["disable".to_string()]);
+    std::fs::remove_file(ctl).unwrap();
+}
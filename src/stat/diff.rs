@@ -0,0 +1,202 @@
+//! # Stat diff driver.
+//! <p> Usage: <em> ruperf stat-diff [OPTION] BASE NEW </em>
+//! where BASE and NEW are results saved with `ruperf stat --json`. </p>
+//!
+//! Prints the change in every counter and metric found in both
+//! files. When both sides hold repeated runs, a Welch t-test on
+//! the per-run samples decides whether a change is significant.
+
+use super::stats;
+use super::StatReport;
+use crate::utils::ParseError;
+use std::fs;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+
+/// Configuration settings for running stat-diff.
+#[derive(Debug, StructOpt)]
+pub struct StatDiffOptions {
+    #[structopt(
+        parse(from_os_str),
+        help = "Baseline results from `ruperf stat --json`"
+    )]
+    pub base: PathBuf,
+
+    #[structopt(parse(from_os_str), help = "New results to compare with the baseline")]
+    pub new: PathBuf,
+
+    #[structopt(
+        long,
+        help = "Fail when a counter or metric changes by more than this percentage"
+    )]
+    pub threshold: Option<f64>,
+
+    #[structopt(
+        long,
+        default_value = "0.05",
+        help = "Significance level for the Welch t-test"
+    )]
+    pub alpha: f64,
+}
+
+/// Change in one counter or metric between two reports.
+#[derive(Debug, Clone)]
+pub struct Delta {
+    pub name: String,
+    pub base: f64,
+    pub new: f64,
+    /// Percentage change, or `None` when the baseline is 0.
+    pub change: Option<f64>,
+    /// Welch t-test p-value, if both sides have repeated runs.
+    pub p_value: Option<f64>,
+}
+
+impl Delta {
+    fn new(name: &str, base: &[f64], new: &[f64]) -> Self {
+        let (b, n) = (stats::mean(base), stats::mean(new));
+        Delta {
+            name: name.to_string(),
+            base: b,
+            new: n,
+            change: if b != 0.0 {
+                Some((n - b) / b.abs() * 100.0)
+            } else {
+                None
+            },
+            p_value: stats::welch_t_test(base, new),
+        }
+    }
+
+    /// Whether the change cannot be put down to noise. Changes
+    /// between single runs are always taken at face value.
+    pub fn is_significant(&self, alpha: f64) -> bool {
+        self.p_value.is_none_or(|p| p < alpha)
+    }
+
+    /// Whether the change breaks a `--threshold` of `limit` percent.
+    pub fn exceeds(&self, limit: f64, alpha: f64) -> bool {
+        let exceeded = match self.change {
+            Some(c) => c.abs() > limit,
+            // Any change from a baseline of 0 is unbounded.
+            None => self.new != self.base,
+        };
+        exceeded && self.is_significant(alpha)
+    }
+}
+
+/// Pair up the counters, then the metrics,
+/// that are present in both reports.
+pub fn deltas(base: &StatReport, new: &StatReport) -> Vec<Delta> {
+    let samples = |report: &StatReport, name: &str| -> Vec<f64> {
+        report.runs.iter().filter_map(|r| r.get(name)).collect()
+    };
+    let first = |report: &StatReport| report.runs.first().cloned().unwrap_or_default();
+    let (base_first, new_first) = (first(base), first(new));
+    let names = base_first
+        .counters
+        .keys()
+        .filter(|k| new_first.counters.contains_key(*k))
+        .chain(
+            base_first
+                .metrics
+                .keys()
+                .filter(|k| new_first.metrics.contains_key(*k)),
+        );
+    names
+        .map(|name| Delta::new(name, &samples(base, name), &samples(new, name)))
+        .collect()
+}
+
+/// Load a report written by `ruperf stat --json`.
+fn load(path: &Path) -> Result<StatReport, ParseError> {
+    let file_error = |e: String| ParseError::StatReport(path.display().to_string(), e);
+    let contents = fs::read_to_string(path).map_err(|e| file_error(e.to_string()))?;
+    serde_json::from_str(&contents).map_err(|e| file_error(e.to_string()))
+}
+
+/// Print the change between two `ruperf stat --json` reports.
+/// Returns a non-zero exit code if a `--threshold` is broken.
+pub fn run_stat_diff(options: StatDiffOptions) -> i32 {
+    let (base, new) = match (load(&options.base), load(&options.new)) {
+        (Ok(base), Ok(new)) => (base, new),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+
+    println!(
+        " {:<28} {:>18} {:>18} {:>10} {:>9}",
+        "Counter", "Base", "New", "Change", "p-value"
+    );
+    let mut failed = false;
+    let fail = if std::io::stdout().is_terminal() {
+        "  \x1b[0;31mFAIL\x1b[0m"
+    } else {
+        "  FAIL"
+    };
+    for delta in deltas(&base, &new) {
+        let change = delta
+            .change
+            .map_or_else(|| "-".to_string(), |c| format!("{:+.2}%", c));
+        let p_value = delta
+            .p_value
+            .map_or_else(|| "-".to_string(), |p| format!("{:.3}", p));
+        let mut marker = if delta.p_value.is_some() && delta.is_significant(options.alpha) {
+            "  *"
+        } else {
+            ""
+        };
+        if let Some(limit) = options.threshold {
+            if delta.exceeds(limit, options.alpha) {
+                failed = true;
+                marker = fail;
+            }
+        }
+        println!(
+            " {:<28} {:>18.3} {:>18.3} {:>10} {:>9}{}",
+            delta.name, delta.base, delta.new, change, p_value, marker
+        );
+    }
+    if base.runs.len() > 1 && new.runs.len() > 1 {
+        println!("\n * significant at p < {}", options.alpha);
+    }
+
+    if failed {
+        1
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn deltas_test() {
+    use super::StatRun;
+    let report = |values: &[f64]| StatReport {
+        command: vec!["bench".to_string()],
+        runs: values
+            .iter()
+            .map(|v| {
+                let mut run = StatRun::default();
+                run.counters.insert("instructions".to_string(), *v);
+                run.counters.insert("cycles".to_string(), 100.0);
+                run
+            })
+            .collect(),
+    };
+    let base = report(&[100.0, 101.0, 99.0, 100.0]);
+    let new = report(&[120.0, 121.0, 119.0, 120.0]);
+    let deltas = deltas(&base, &new);
+    assert_eq!(deltas.len(), 2);
+    let cycles = &deltas[0];
+    assert_eq!(cycles.name, "cycles");
+    assert_eq!(cycles.change, Some(0.0));
+    assert!(!cycles.exceeds(1.0, 0.05));
+    let instructions = &deltas[1];
+    assert!((instructions.change.unwrap() - 20.0).abs() < 1e-9);
+    assert!(instructions.is_significant(0.05));
+    assert!(instructions.exceeds(10.0, 0.05));
+    assert!(!instructions.exceeds(25.0, 0.05));
+}
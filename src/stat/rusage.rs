@@ -0,0 +1,206 @@
+//! Resource usage of a profiled command.
+//!
+//! Collects what `wait4()` reports about a finished child
+//! (user and system time, maximum resident set size, context
+//! switches and block I/O) together with the byte counts from
+//! `/proc/<pid>/io`. The latter disappear once the child is
+//! reaped, so they are read while the child is still a zombie.
+
+extern crate libc;
+use std::fs;
+use std::io::Write;
+use std::time::Duration;
+
+/// Resource usage for a single run of a command.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ResourceUsage {
+    pub wall: Duration,
+    pub user: Duration,
+    pub sys: Duration,
+    /// Maximum resident set size in kilobytes.
+    pub max_rss: i64,
+    pub voluntary_switches: i64,
+    pub involuntary_switches: i64,
+    /// Block input operations.
+    pub inblock: i64,
+    /// Block output operations.
+    pub oublock: i64,
+    /// Bytes fetched from the storage layer, if `/proc/<pid>/io` was readable.
+    pub read_bytes: Option<u64>,
+    /// Bytes sent to the storage layer, if `/proc/<pid>/io` was readable.
+    pub write_bytes: Option<u64>,
+}
+
+/// Block until `pid` has exited without reaping it,
+/// so its `/proc` entries stay around.
+pub fn wait_exit(pid: i32) -> Result<(), std::io::Error> {
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    let ret = unsafe {
+        libc::waitid(
+            libc::P_PID,
+            pid as libc::id_t,
+            &mut info,
+            libc::WEXITED | libc::WNOWAIT,
+        )
+    };
+    if ret == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Check, without reaping, whether `pid` has exited.
+pub fn has_exited(pid: i32) -> std::io::Result<bool> {
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    let ret = unsafe {
+        libc::waitid(
+            libc::P_PID,
+            pid as libc::id_t,
+            &mut info,
+            libc::WEXITED | libc::WNOHANG | libc::WNOWAIT,
+        )
+    };
+    if ret == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(unsafe { info.si_pid() } != 0)
+}
+
+/// Reap `pid`, returning its wait status and resource usage.
+/// `wall` is filled in by the caller.
+pub fn reap(pid: i32) -> Result<(i32, ResourceUsage), std::io::Error> {
+    let mut status: libc::c_int = 0;
+    let mut ru: libc::rusage = unsafe { std::mem::zeroed() };
+    let ret = unsafe { libc::wait4(pid, &mut status, 0, &mut ru) };
+    if ret == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let usage = ResourceUsage {
+        user: timeval_to_duration(ru.ru_utime),
+        sys: timeval_to_duration(ru.ru_stime),
+        max_rss: ru.ru_maxrss as i64,
+        voluntary_switches: ru.ru_nvcsw as i64,
+        involuntary_switches: ru.ru_nivcsw as i64,
+        inblock: ru.ru_inblock as i64,
+        oublock: ru.ru_oublock as i64,
+        ..Default::default()
+    };
+    Ok((status, usage))
+}
+
+/// Read `read_bytes` and `write_bytes` from `/proc/<pid>/io`.
+/// Returns `None` if the file cannot be read, which is the
+/// case for processes owned by other users.
+pub fn read_proc_io(pid: i32) -> Option<(u64, u64)> {
+    let contents = fs::read_to_string(format!("/proc/{}/io", pid)).ok()?;
+    parse_proc_io(&contents)
+}
+
+/// Pull `read_bytes` and `write_bytes` out of
+/// the contents of a `/proc/<pid>/io` file.
+fn parse_proc_io(contents: &str) -> Option<(u64, u64)> {
+    let mut read = None;
+    let mut write = None;
+    for line in contents.lines() {
+        let mut parts = line.splitn(2, ':');
+        let key = parts.next()?.trim();
+        let value = parts.next().and_then(|v| v.trim().parse::<u64>().ok());
+        match key {
+            "read_bytes" => read = value,
+            "write_bytes" => write = value,
+            _ => {}
+        }
+    }
+    Some((read?, write?))
+}
+
+fn timeval_to_duration(tv: libc::timeval) -> Duration {
+    Duration::new(tv.tv_sec as u64, (tv.tv_usec as u32) * 1000)
+}
+
+impl ResourceUsage {
+    /// Average the usage of repeated runs. Byte counts are only
+    /// averaged if every run was able to read `/proc/<pid>/io`.
+    pub fn mean(runs: &[ResourceUsage]) -> ResourceUsage {
+        let n = runs.len().max(1) as u32;
+        let avg = |f: fn(&ResourceUsage) -> i64| runs.iter().map(f).sum::<i64>() / n as i64;
+        let avg_bytes = |f: fn(&ResourceUsage) -> Option<u64>| {
+            runs.iter()
+                .map(f)
+                .sum::<Option<u64>>()
+                .map(|total| total / n as u64)
+        };
+        ResourceUsage {
+            wall: runs.iter().map(|r| r.wall).sum::<Duration>() / n,
+            user: runs.iter().map(|r| r.user).sum::<Duration>() / n,
+            sys: runs.iter().map(|r| r.sys).sum::<Duration>() / n,
+            max_rss: avg(|r| r.max_rss),
+            voluntary_switches: avg(|r| r.voluntary_switches),
+            involuntary_switches: avg(|r| r.involuntary_switches),
+            inblock: avg(|r| r.inblock),
+            oublock: avg(|r| r.oublock),
+            read_bytes: avg_bytes(|r| r.read_bytes),
+            write_bytes: avg_bytes(|r| r.write_bytes),
+        }
+    }
+
+    /// Print usage in the style of `perf stat` followed
+    /// by the extra fields reported by `/usr/bin/time -v`.
+    pub fn print(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        writeln!(out)?;
+        writeln!(
+            out,
+            " {:>15.9} seconds time elapsed",
+            self.wall.as_secs_f64()
+        )?;
+        writeln!(out)?;
+        writeln!(out, " {:>15.9} seconds user", self.user.as_secs_f64())?;
+        writeln!(out, " {:>15.9} seconds sys", self.sys.as_secs_f64())?;
+        writeln!(out)?;
+        writeln!(out, " {:>15} KB maximum resident set size", self.max_rss)?;
+        writeln!(
+            out,
+            " {:>15} voluntary context switches",
+            self.voluntary_switches
+        )?;
+        writeln!(
+            out,
+            " {:>15} involuntary context switches",
+            self.involuntary_switches
+        )?;
+        writeln!(out, " {:>15} block input operations", self.inblock)?;
+        writeln!(out, " {:>15} block output operations", self.oublock)?;
+        if let (Some(read), Some(write)) = (self.read_bytes, self.write_bytes) {
+            writeln!(out, " {:>15} bytes read", read)?;
+            writeln!(out, " {:>15} bytes written", write)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn parse_proc_io_test() {
+    let contents = "rchar: 3864\n\
+                    wchar: 0\n\
+                    syscr: 10\n\
+                    syscw: 0\n\
+                    read_bytes: 4096\n\
+                    write_bytes: 8192\n\
+                    cancelled_write_bytes: 0\n";
+    assert_eq!(parse_proc_io(contents), Some((4096, 8192)));
+    assert_eq!(parse_proc_io("rchar: 1\n"), None);
+}
+
+#[test]
+fn reap_child_test() {
+    let pid = unsafe { libc::fork() };
+    if pid == 0 {
+        unsafe { libc::_exit(3) };
+    }
+    wait_exit(pid).unwrap();
+    let (status, usage) = reap(pid).unwrap();
+    assert!(libc::WIFEXITED(status));
+    assert_eq!(libc::WEXITSTATUS(status), 3);
+    assert!(usage.max_rss > 0);
+}
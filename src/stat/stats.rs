@@ -0,0 +1,256 @@
+//! Summary statistics over the samples
+//! collected by repeated `ruperf stat` runs.
+
+/// Arithmetic mean. Returns 0 for no samples.
+pub fn mean(samples: &[f64]) -> f64 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    samples.iter().sum::<f64>() / samples.len() as f64
+}
+
+/// Unbiased sample variance. Returns 0 for fewer than two samples.
+pub fn variance(samples: &[f64]) -> f64 {
+    if samples.len() < 2 {
+        return 0.0;
+    }
+    let m = mean(samples);
+    samples.iter().map(|x| (x - m) * (x - m)).sum::<f64>() / (samples.len() - 1) as f64
+}
+
+/// Sample standard deviation.
+pub fn stddev(samples: &[f64]) -> f64 {
+    variance(samples).sqrt()
+}
+
+/// Standard deviation of the mean relative to the mean,
+/// as printed by `perf stat -r`.
+pub fn relative_stddev(samples: &[f64]) -> f64 {
+    let m = mean(samples);
+    if m == 0.0 {
+        return 0.0;
+    }
+    stddev(samples) / (samples.len() as f64).sqrt() / m
+}
+
+/// Difference in means `mean(b) - mean(a)`, its standard error and
+/// the Welch-Satterthwaite degrees of freedom. Returns `None` if
+/// either side has fewer than two samples.
+fn welch(a: &[f64], b: &[f64]) -> Option<(f64, f64, f64)> {
+    if a.len() < 2 || b.len() < 2 {
+        return None;
+    }
+    let (na, nb) = (a.len() as f64, b.len() as f64);
+    let (va, vb) = (variance(a) / na, variance(b) / nb);
+    let se2 = va + vb;
+    let df = if se2 == 0.0 {
+        na + nb - 2.0
+    } else {
+        se2 * se2 / (va * va / (na - 1.0) + vb * vb / (nb - 1.0))
+    };
+    Some((mean(b) - mean(a), se2.sqrt(), df))
+}
+
+/// Two-sided p-value of Welch's unequal variances t-test for a
+/// difference in means between `a` and `b`. Returns `None` if
+/// either side has fewer than two samples.
+pub fn welch_t_test(a: &[f64], b: &[f64]) -> Option<f64> {
+    let (diff, se, df) = welch(a, b)?;
+    if se == 0.0 {
+        // Both sides are constant, so any difference is certain.
+        return Some(if diff == 0.0 { 1.0 } else { 0.0 });
+    }
+    Some(student_t_sf2(diff / se, df))
+}
+
+/// Fieller's confidence interval for `mean(b) / mean(a)` at the
+/// given level, accounting for the uncertainty of both means. The
+/// degrees of freedom are Welch-Satterthwaite's for
+/// `mean(b) - r * mean(a)`.
+/// Returns `None` if either side has fewer than two samples, or if
+/// `mean(a)` is not significantly different from 0, in which case
+/// the interval is unbounded.
+pub fn fieller_interval(a: &[f64], b: &[f64], confidence: f64) -> Option<(f64, f64)> {
+    if a.len() < 2 || b.len() < 2 {
+        return None;
+    }
+    let (na, nb) = (a.len() as f64, b.len() as f64);
+    let (ma, mb) = (mean(a), mean(b));
+    let (va, vb) = (variance(a) / na, variance(b) / nb);
+    if ma == 0.0 {
+        return None;
+    }
+    let r = mb / ma;
+    let (wa, wb) = (r * r * va, vb);
+    let df = if wa + wb == 0.0 {
+        na + nb - 2.0
+    } else {
+        (wa + wb) * (wa + wb) / (wa * wa / (na - 1.0) + wb * wb / (nb - 1.0))
+    };
+    let t2 = student_t_quantile(confidence, df).powi(2);
+    // The ratios r with (mb - r * ma)^2 <= t^2 * (vb + r^2 * va).
+    let d = ma * ma - t2 * va;
+    if d <= 0.0 {
+        return None;
+    }
+    let h = (t2 * (d * vb + mb * mb * va)).sqrt();
+    Some(((ma * mb - h) / d, (ma * mb + h) / d))
+}
+
+/// The `t` for which `P(|T| <= t) = confidence`, found by bisection.
+pub fn student_t_quantile(confidence: f64, df: f64) -> f64 {
+    let (mut lo, mut hi) = (0.0, 1.0e4);
+    for _ in 0..200 {
+        let mid = (lo + hi) / 2.0;
+        if 1.0 - student_t_sf2(mid, df) < confidence {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    (lo + hi) / 2.0
+}
+
+/// Two-sided tail probability `P(|T| > |t|)` of
+/// Student's t distribution with `df` degrees of freedom.
+pub fn student_t_sf2(t: f64, df: f64) -> f64 {
+    incomplete_beta(df / 2.0, 0.5, df / (df + t * t))
+}
+
+/// Regularized incomplete beta function `I_x(a, b)`.
+/// Source: Numerical Recipes in C, 2nd ed., section 6.4 (`betai`).
+fn incomplete_beta(a: f64, b: f64, x: f64) -> f64 {
+    if x <= 0.0 {
+        return 0.0;
+    }
+    if x >= 1.0 {
+        return 1.0;
+    }
+    let front =
+        (ln_gamma(a + b) - ln_gamma(a) - ln_gamma(b) + a * x.ln() + b * (1.0 - x).ln()).exp();
+    // The continued fraction converges fastest on this side.
+    if x < (a + 1.0) / (a + b + 2.0) {
+        front * beta_cf(a, b, x) / a
+    } else {
+        1.0 - front * beta_cf(b, a, 1.0 - x) / b
+    }
+}
+
+/// Continued fraction for the incomplete beta function.
+/// Source: Numerical Recipes in C, 2nd ed., section 6.4 (`betacf`).
+fn beta_cf(a: f64, b: f64, x: f64) -> f64 {
+    const MAX_ITERATIONS: usize = 200;
+    const EPS: f64 = 3.0e-14;
+    const FPMIN: f64 = 1.0e-300;
+    let (qab, qap, qam) = (a + b, a + 1.0, a - 1.0);
+    let mut c = 1.0;
+    let mut d = 1.0 - qab * x / qap;
+    if d.abs() < FPMIN {
+        d = FPMIN;
+    }
+    d = 1.0 / d;
+    let mut h = d;
+    for m in 1..=MAX_ITERATIONS {
+        let m = m as f64;
+        let m2 = 2.0 * m;
+        let aa = m * (b - m) * x / ((qam + m2) * (a + m2));
+        d = 1.0 + aa * d;
+        if d.abs() < FPMIN {
+            d = FPMIN;
+        }
+        c = 1.0 + aa / c;
+        if c.abs() < FPMIN {
+            c = FPMIN;
+        }
+        d = 1.0 / d;
+        h *= d * c;
+        let aa = -(a + m) * (qab + m) * x / ((a + m2) * (qap + m2));
+        d = 1.0 + aa * d;
+        if d.abs() < FPMIN {
+            d = FPMIN;
+        }
+        c = 1.0 + aa / c;
+        if c.abs() < FPMIN {
+            c = FPMIN;
+        }
+        d = 1.0 / d;
+        let del = d * c;
+        h *= del;
+        if (del - 1.0).abs() < EPS {
+            break;
+        }
+    }
+    h
+}
+
+/// Natural log of the gamma function for `x > 0`.
+/// Source: Numerical Recipes in C, 2nd ed., section 6.1 (`gammln`).
+fn ln_gamma(x: f64) -> f64 {
+    const COF: [f64; 6] = [
+        76.180_091_729_471_46,
+        -86.505_320_329_416_77,
+        24.014_098_240_830_91,
+        -1.231_739_572_450_155,
+        0.120_865_097_386_617_9e-2,
+        -0.539_523_938_495_3e-5,
+    ];
+    let mut y = x;
+    let tmp = x + 5.5;
+    let tmp = tmp - (x + 0.5) * tmp.ln();
+    let mut ser = 1.000_000_000_190_015;
+    for c in COF.iter() {
+        y += 1.0;
+        ser += c / y;
+    }
+    -tmp + (2.506_628_274_631_000_5 * ser / x).ln()
+}
+
+#[cfg(test)]
+#[test]
+fn summary_test() {
+    let samples = [2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0];
+    assert_eq!(mean(&samples), 5.0);
+    assert!((variance(&samples) - 32.0 / 7.0).abs() < 1e-12);
+    assert_eq!(stddev(&[1.0]), 0.0);
+    assert_eq!(mean(&[]), 0.0);
+}
+
+#[test]
+fn student_t_test() {
+    // Reference values from numerically integrating the density.
+    assert!((student_t_sf2(2.0, 10.0) - 0.073_388_03).abs() < 1e-6);
+    assert!((student_t_sf2(0.0, 5.0) - 1.0).abs() < 1e-12);
+    assert!((student_t_sf2(-3.5, 3.0) - 0.039_481).abs() < 1e-5);
+}
+
+#[test]
+fn welch_t_test_test() {
+    let a = [19.8, 20.4, 19.6, 17.8, 18.5, 18.9, 18.3, 18.9, 19.5, 22.0];
+    let b = [28.2, 26.6, 20.1, 23.3, 25.2, 22.1, 17.7, 27.6, 20.6, 13.7];
+    // t = -2.074 with 10.21 degrees of freedom.
+    let p = welch_t_test(&a, &b).unwrap();
+    assert!((p - 0.064_28).abs() < 1e-4, "p = {}", p);
+    assert_eq!(welch_t_test(&a[..1], &b), None);
+    assert_eq!(welch_t_test(&[1.0, 1.0], &[1.0, 1.0]), Some(1.0));
+}
+
+#[test]
+fn fieller_interval_test() {
+    // Two-sided 95% critical values for 10 and 2 degrees of freedom.
+    assert!((student_t_quantile(0.95, 10.0) - 2.228_139).abs() < 1e-5);
+    assert!((student_t_quantile(0.95, 2.0) - 4.302_653).abs() < 1e-5);
+    // With an exact baseline this is b's interval scaled by 1 / 10.
+    let a = [10.0, 10.0, 10.0];
+    let b = [11.0, 12.0, 13.0];
+    let (lo, hi) = fieller_interval(&a, &b, 0.95).unwrap();
+    let h = student_t_quantile(0.95, 2.0) * (1.0f64 / 3.0).sqrt() / 10.0;
+    assert!((lo - (1.2 - h)).abs() < 1e-9);
+    assert!((hi - (1.2 + h)).abs() < 1e-9);
+    // A noisy baseline skews the interval towards larger ratios.
+    let a = [9.0, 10.0, 11.0];
+    let (lo, hi) = fieller_interval(&a, &b, 0.95).unwrap();
+    assert!(lo < 1.2 && 1.2 < hi && hi - 1.2 > 1.2 - lo);
+    // A baseline indistinguishable from 0 has no bounded interval.
+    assert_eq!(fieller_interval(&[-1.0, 1.0, 0.1], &b, 0.95), None);
+    assert_eq!(fieller_interval(&a[..1], &b, 0.95), None);
+}
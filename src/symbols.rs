@@ -0,0 +1,286 @@
+//! # Symbols.
+//! Resolves addresses to function names, using the `.symtab`
+//! (or `.dynsym` when stripped) of ELF files, and `/proc/kallsyms`
+//! for the kernel, and turns mangled Rust and C++ names back into
+//! the ones in the source.
+
+use object::{Object, ObjectSegment, ObjectSymbol, SymbolKind};
+use structopt::StructOpt;
+
+/// A function in a `Dso`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    /// Address of the first instruction, as in the file.
+    pub start: u64,
+    /// Length in bytes, 0 if unknown.
+    pub size: u64,
+}
+
+/// How symbol names are shown.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Demangle {
+    /// As they are in the file.
+    Off,
+    /// Demangled, Rust's `::h<hash>` suffixes included.
+    #[default]
+    Full,
+    /// Demangled, without Rust's hashes.
+    StripHash,
+}
+
+/// The demangling flags shared by the commands that show symbols.
+#[derive(Debug, Clone, Copy, Default, StructOpt)]
+pub struct DemangleOptions {
+    #[structopt(
+        long = "no-demangle",
+        help = "Show symbol names as they are in the file"
+    )]
+    pub no_demangle: bool,
+
+    #[structopt(
+        long = "strip-hash",
+        help = "Leave the hashes off demangled Rust names",
+        conflicts_with = "no-demangle"
+    )]
+    pub strip_hash: bool,
+}
+
+impl DemangleOptions {
+    pub fn mode(&self) -> Demangle {
+        if self.no_demangle {
+            Demangle::Off
+        } else if self.strip_hash {
+            Demangle::StripHash
+        } else {
+            Demangle::Full
+        }
+    }
+}
+
+/// Demangle a Rust (legacy or v0) or Itanium C++ `name`.
+/// Names in neither scheme are returned as they are.
+pub fn demangle(name: &str, mode: Demangle) -> String {
+    if mode == Demangle::Off {
+        return name.to_string();
+    }
+    if let Ok(demangled) = rustc_demangle::try_demangle(name) {
+        return match mode {
+            Demangle::StripHash => format!("{:#}", demangled),
+            _ => demangled.to_string(),
+        };
+    }
+    if name.starts_with("_Z") {
+        let options = cpp_demangle::DemangleOptions::default();
+        if let Some(demangled) = cpp_demangle::Symbol::new(name)
+            .ok()
+            .and_then(|symbol| symbol.demangle(&options).ok())
+        {
+            return demangled;
+        }
+    }
+    name.to_string()
+}
+
+/// A loadable part of an ELF file.
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    offset: u64,
+    size: u64,
+    vaddr: u64,
+}
+
+/// A shared object, executable or the kernel, with its symbols.
+#[derive(Debug, Default)]
+pub struct Dso {
+    pub path: String,
+    /// Sorted by `start`.
+    symbols: Vec<Symbol>,
+    segments: Vec<Segment>,
+}
+
+impl Dso {
+    /// Read the symbols of the ELF file at `path`.
+    pub fn load(path: &str) -> Option<Self> {
+        let data = std::fs::read(path).ok()?;
+        let file = object::File::parse(&*data).ok()?;
+        let text = |symbols: &mut dyn Iterator<Item = object::Symbol>| -> Vec<Symbol> {
+            symbols
+                .filter(|s| s.kind() == SymbolKind::Text && s.address() != 0)
+                .filter_map(|s| {
+                    Some(Symbol {
+                        name: s.name().ok()?.to_string(),
+                        start: s.address(),
+                        size: s.size(),
+                    })
+                })
+                .collect()
+        };
+        let mut symbols = text(&mut file.symbols());
+        if symbols.is_empty() {
+            symbols = text(&mut file.dynamic_symbols());
+        }
+        let segments = file
+            .segments()
+            .filter_map(|s| {
+                let (offset, size) = s.file_range();
+                Some(Segment {
+                    offset,
+                    size,
+                    vaddr: s.address(),
+                })
+                .filter(|_| size > 0)
+            })
+            .collect();
+        Some(Self::new(path, symbols, segments))
+    }
+
+    /// The running kernel's symbols, from `/proc/kallsyms`. Empty
+    /// when `kptr_restrict` hides the addresses.
+    pub fn kernel() -> Self {
+        let symbols = std::fs::read_to_string("/proc/kallsyms")
+            .map(|text| parse_kallsyms(&text))
+            .unwrap_or_default();
+        Self::new("[kernel.kallsyms]", symbols, Vec::new())
+    }
+
+    fn new(path: &str, mut symbols: Vec<Symbol>, segments: Vec<Segment>) -> Self {
+        symbols.sort_by(|a, b| a.start.cmp(&b.start).then(b.size.cmp(&a.size)));
+        // Aliases share an address; keep the first, largest one.
+        symbols.dedup_by_key(|s| s.start);
+        Self {
+            path: path.to_string(),
+            symbols,
+            segments,
+        }
+    }
+
+    /// Turn an offset into the file into the address the file's
+    /// symbols use. Files without segments use offsets directly.
+    pub fn offset_to_address(&self, offset: u64) -> u64 {
+        self.segments
+            .iter()
+            .find(|s| offset >= s.offset && offset < s.offset + s.size)
+            .map_or(offset, |s| offset - s.offset + s.vaddr)
+    }
+
+    /// The symbol containing `address`. Symbols without
+    /// a size are taken to run up to the next symbol.
+    pub fn find(&self, address: u64) -> Option<&Symbol> {
+        let index = match self.symbols.binary_search_by(|s| s.start.cmp(&address)) {
+            Ok(index) => index,
+            Err(0) => return None,
+            Err(index) => index - 1,
+        };
+        let symbol = &self.symbols[index];
+        Some(symbol).filter(|_| address < self.end(symbol))
+    }
+
+    /// The address just past `symbol`. Symbols without
+    /// a size are taken to run up to the next symbol.
+    pub fn end(&self, symbol: &Symbol) -> u64 {
+        if symbol.size > 0 {
+            return symbol.start + symbol.size;
+        }
+        let next = self.symbols.partition_point(|s| s.start <= symbol.start);
+        self.symbols.get(next).map_or(u64::MAX, |next| next.start)
+    }
+
+    /// Every symbol, sorted by address.
+    pub fn symbols(&self) -> &[Symbol] {
+        &self.symbols
+    }
+}
+
+/// Parse the text symbols out of `/proc/kallsyms`,
+/// lines of "address type name [module]".
+fn parse_kallsyms(text: &str) -> Vec<Symbol> {
+    text.lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let start = u64::from_str_radix(fields.next()?, 16).ok()?;
+            let kind = fields.next()?;
+            let name = fields.next()?;
+            if start == 0 || !matches!(kind, "t" | "T" | "w" | "W") {
+                return None;
+            }
+            Some(Symbol {
+                name: name.to_string(),
+                start,
+                size: 0,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+#[test]
+fn find_test() {
+    let symbol = |name: &str, start, size| Symbol {
+        name: name.to_string(),
+        start,
+        size,
+    };
+    let dso = Dso::new(
+        "test",
+        vec![
+            symbol("c", 0x300, 0),
+            symbol("a", 0x100, 0x10),
+            symbol("b", 0x200, 0),
+            symbol("b_alias", 0x200, 0),
+        ],
+        Vec::new(),
+    );
+    assert_eq!(dso.find(0x0ff), None);
+    assert_eq!(dso.find(0x105).unwrap().name, "a");
+    // Past the end of a sized symbol.
+    assert_eq!(dso.find(0x150), None);
+    // Unsized symbols run up to the next one.
+    assert_eq!(dso.find(0x2ff).unwrap().name, "b");
+    assert_eq!(dso.find(0x1000).unwrap().name, "c");
+    assert_eq!(dso.symbols().len(), 3);
+}
+
+#[test]
+fn parse_kallsyms_test() {
+    let symbols = parse_kallsyms(
+        "ffffffff81000000 T _stext\n\
+         ffffffff81000010 t helper\n\
+         ffffffff82000000 D some_data\n\
+         0000000000000000 T hidden\n\
+         ffffffffc0000000 t mod_fn\t[my_module]\n",
+    );
+    let names: Vec<&str> = symbols.iter().map(|s| s.name.as_str()).collect();
+    assert_eq!(names, vec!["_stext", "helper", "mod_fn"]);
+}
+
+#[test]
+fn load_test() {
+    let exe = std::env::current_exe().unwrap();
+    let dso = Dso::load(exe.to_str().unwrap()).unwrap();
+    let symbol = dso
+        .symbols()
+        .iter()
+        .find(|s| s.name.contains("parse_kallsyms"))
+        .unwrap();
+    assert_eq!(dso.find(symbol.start).unwrap().start, symbol.start);
+}
+
+#[test]
+fn demangle_test() {
+    let legacy = "_ZN4core3ptr13drop_in_place17h0123456789abcdefE";
+    assert_eq!(
+        demangle(legacy, Demangle::Full),
+        "core::ptr::drop_in_place::h0123456789abcdef"
+    );
+    assert_eq!(
+        demangle(legacy, Demangle::StripHash),
+        "core::ptr::drop_in_place"
+    );
+    assert_eq!(demangle(legacy, Demangle::Off), legacy);
+    let v0 = "_RNvNtCs1234_7mycrate3foo3bar";
+    assert_eq!(demangle(v0, Demangle::Full), "mycrate[3c1c0]::foo::bar");
+    assert_eq!(demangle(v0, Demangle::StripHash), "mycrate::foo::bar");
+    assert_eq!(demangle("_ZN3foo3barEi", Demangle::Full), "foo::bar(int)");
+    assert_eq!(demangle("main", Demangle::Full), "main");
+}
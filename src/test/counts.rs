@@ -1,12 +1,12 @@
 use std::io::Read;
 use std::io::Write;
 
-use crate::event::open::Event;
-use crate::stat::StatEvent;
 use crate::test::RunSettings;
 use crate::test::Test;
 use crate::test::TestResult;
 use os_pipe::pipe;
+use ruperf::event::open::Event;
+use ruperf::stat::StatEvent;
 use std::os::unix::process::CommandExt;
 use std::process::Command;
 
@@ -3,11 +3,11 @@
 //! stop_counter() methods. A simple program with a lot
 //! of instructions is ran and the outputs are compared.
 
-use crate::event::open::Event;
-use crate::stat::StatEvent;
 use crate::test::RunSettings;
 use crate::test::Test;
 use crate::test::TestResult;
+use ruperf::event::open::Event;
+use ruperf::stat::StatEvent;
 
 // Since the event tests do very similar things, this function takes
 // the event and compres the two results, failing if anything is weird.
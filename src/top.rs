@@ -0,0 +1,633 @@
+//! # Top driver.
+//! <p> Usage: <em> ruperf top [-e EVENT] [-p PID | -a] [-F FREQ] [-d DELAY] [-s KEY] [--stdio] </em>
+//! Samples a running process, or every CPU, and redraws a table of
+//! the functions taking the most samples every `DELAY` seconds. Older
+//! samples fade by `--decay` at each refresh, so the table follows
+//! what is hot now. The table can be sorted by another key, narrowed
+//! to one shared object or thread, and a function annotated. </p>
+
+extern crate structopt;
+pub mod tui;
+
+use crate::annotate::{self, Annotation, Hits};
+use crate::bindings::*;
+use crate::data::{Comm, Mmap, PerfData, Record, Sample};
+use crate::record::{max_sample_rate, online_cpus, parse_mmap_pages, Period, Sampler};
+use crate::report::{entry_key, SortKey};
+use crate::session::Session;
+use crate::stat::StatEvent;
+use crate::symbols::{Demangle, DemangleOptions};
+use crate::utils::{AnnotateError, RecordError};
+use std::collections::{HashMap, HashSet};
+use std::io::prelude::*;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
+
+/// Rows whose share decays below this, in percent, are dropped.
+const MIN_OVERHEAD: f64 = 0.01;
+
+/// Rows printed at each refresh with `--stdio`.
+const STDIO_ROWS: usize = 40;
+
+/// Configuration settings for running top. Every CPU is sampled
+/// unless a process is given. Cycles are sampled if no events are
+/// specified. See `./ruperf top --help` for more information.
+#[derive(Debug, StructOpt)]
+pub struct TopOptions {
+    #[structopt(short, long, help = "Event to sample", number_of_values = 1)]
+    pub event: Vec<StatEvent>,
+
+    #[structopt(short, long, help = "Sample this process and its threads")]
+    pub pid: Option<i32>,
+
+    #[structopt(
+        short,
+        long = "all-cpus",
+        help = "Sample every process on every CPU (the default)",
+        conflicts_with = "pid"
+    )]
+    pub all_cpus: bool,
+
+    #[structopt(
+        short = "F",
+        long,
+        default_value = "4000",
+        help = "Sample at this average rate in Hz"
+    )]
+    pub freq: u64,
+
+    #[structopt(short, long, default_value = "1", help = "Seconds between refreshes")]
+    pub delay: f64,
+
+    #[structopt(
+        long,
+        default_value = "0.875",
+        help = "Share of their weight older samples keep at each refresh"
+    )]
+    pub decay: f64,
+
+    #[structopt(
+        short,
+        long,
+        default_value = "symbol",
+        help = "Group samples by comm, pid, dso, symbol or srcline"
+    )]
+    pub sort: SortKey,
+
+    #[structopt(short = "n", long, help = "Stop after this many refreshes")]
+    pub iterations: Option<usize>,
+
+    #[structopt(
+        short,
+        long = "mmap-pages",
+        default_value = "128",
+        parse(try_from_str = parse_mmap_pages),
+        help = "Data pages in each ring buffer, a power of two"
+    )]
+    pub mmap_pages: usize,
+
+    #[structopt(flatten)]
+    pub demangle: DemangleOptions,
+
+    #[structopt(long, help = "Print the table at each refresh instead of drawing it")]
+    pub stdio: bool,
+}
+
+/// What the table is narrowed to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Zoom {
+    Dso(Rc<str>),
+    Thread(u32),
+}
+
+/// Samples sharing the sort key.
+#[derive(Debug, Clone)]
+pub struct Row {
+    pub key: String,
+    /// Period of the row's samples, faded at each refresh.
+    pub weight: f64,
+    /// Share of the table's weight, in percent.
+    pub overhead: f64,
+    /// Samples since the last refresh.
+    pub samples: u64,
+    /// Where the last sample was taken, to zoom into.
+    pub dso: Option<Rc<str>>,
+    pub tid: u32,
+    /// The function the last sample was taken in, to annotate.
+    function: Option<(Rc<str>, u64)>,
+}
+
+/// The rows of one event, with their samples by instruction.
+pub struct Table {
+    sort: SortKey,
+    zoom: Option<Zoom>,
+    decay: f64,
+    /// Which event the rows are for.
+    event: usize,
+    events: usize,
+    rows: HashMap<String, Row>,
+    /// Every event's samples of the functions rows were last sampled
+    /// in, for annotating. Pruned with the rows at each refresh.
+    hits: HashMap<(Rc<str>, u64), Hits>,
+}
+
+impl Table {
+    /// An empty table of `events` events, sorted by `sort`.
+    pub fn new(sort: SortKey, decay: f64, events: usize) -> Self {
+        Self {
+            sort,
+            zoom: None,
+            decay,
+            event: 0,
+            events,
+            rows: HashMap::new(),
+            hits: HashMap::new(),
+        }
+    }
+
+    pub fn sort(&self) -> SortKey {
+        self.sort
+    }
+
+    pub fn zoom(&self) -> Option<&Zoom> {
+        self.zoom.as_ref()
+    }
+
+    pub fn event(&self) -> usize {
+        self.event
+    }
+
+    /// Group by `sort` from now on.
+    pub fn set_sort(&mut self, sort: SortKey) {
+        self.sort = sort;
+        self.rows.clear();
+    }
+
+    /// Only count samples matching `zoom` from now on.
+    pub fn set_zoom(&mut self, zoom: Option<Zoom>) {
+        self.zoom = zoom;
+        self.rows.clear();
+    }
+
+    /// Show the next event's samples.
+    pub fn next_event(&mut self) {
+        self.event = (self.event + 1) % self.events;
+        self.rows.clear();
+    }
+
+    /// Count a sample of the `index`th event.
+    pub fn add(&mut self, session: &mut Session, sample: &Sample, index: usize) {
+        let location = session.resolve(sample.pid, sample.ip, sample.is_kernel());
+        let function = match (&location.dso, &location.symbol) {
+            (Some(dso), Some(symbol)) => {
+                let events = self.events;
+                self.hits
+                    .entry((dso.clone(), symbol.start))
+                    .or_insert_with(|| Hits::new(dso.clone(), symbol.clone(), events))
+                    .add(location.address, index, sample.period);
+                Some((dso.clone(), symbol.start))
+            }
+            _ => None,
+        };
+        if index != self.event {
+            return;
+        }
+        let zoomed = match &self.zoom {
+            Some(Zoom::Dso(dso)) => location.dso.as_ref() == Some(dso),
+            Some(Zoom::Thread(tid)) => sample.tid == *tid,
+            None => true,
+        };
+        if !zoomed {
+            return;
+        }
+        let key = entry_key(&[self.sort], session, sample, &location)
+            .pop()
+            .unwrap_or_default();
+        let row = self.rows.entry(key.clone()).or_insert_with(|| Row {
+            key,
+            weight: 0.0,
+            overhead: 0.0,
+            samples: 0,
+            dso: None,
+            tid: 0,
+            function: None,
+        });
+        row.weight += sample.period as f64;
+        row.samples += 1;
+        row.dso = location.dso;
+        row.tid = sample.tid;
+        row.function = function;
+    }
+
+    /// The rows, heaviest first. Then fade every row for the next
+    /// refresh and drop those that no longer matter, along with the
+    /// samples of functions that no row shown now can annotate.
+    pub fn refresh(&mut self) -> Vec<Row> {
+        let total: f64 = self.rows.values().map(|row| row.weight).sum();
+        let mut rows: Vec<Row> = self
+            .rows
+            .values()
+            .map(|row| Row {
+                overhead: 100.0 * row.weight / total.max(1.0),
+                ..row.clone()
+            })
+            .collect();
+        rows.sort_by(|a, b| b.weight.partial_cmp(&a.weight).unwrap());
+
+        let decay = self.decay;
+        self.rows.retain(|_, row| {
+            row.weight *= decay;
+            row.samples = 0;
+            100.0 * row.weight / total.max(1.0) >= MIN_OVERHEAD
+        });
+        let shown: HashSet<&(Rc<str>, u64)> = rows
+            .iter()
+            .filter_map(|row| row.function.as_ref())
+            .collect();
+        self.hits.retain(|function, _| shown.contains(function));
+        rows
+    }
+}
+
+/// Live sampling state: the events, the processes
+/// they were taken in, and the table they go into.
+pub struct Top {
+    sampler: Sampler,
+    session: Session,
+    /// Records read since the last collect.
+    data: PerfData,
+    events: Vec<String>,
+    mode: Demangle,
+    pub table: Table,
+    /// Samples since the start.
+    pub samples: u64,
+    /// Samples the kernel dropped since the start.
+    pub lost: u64,
+    pub delay: Duration,
+    pub iterations: Option<usize>,
+}
+
+impl Top {
+    /// Names of the events sampled.
+    pub fn events(&self) -> &[String] {
+        &self.events
+    }
+
+    /// Wait up to `timeout` for the ring buffers to fill.
+    pub fn wait(&self, timeout: Duration) {
+        self.sampler.poll(-1, timeout.as_millis() as i32);
+    }
+
+    /// Read every waiting record into the table.
+    pub fn collect(&mut self) {
+        let data = &mut self.data.data;
+        self.sampler.drain(|record| data.extend_from_slice(record));
+        for record in self.data.ordered_records() {
+            let sample = match record {
+                Record::Sample(sample) => sample,
+                Record::Lost { lost, .. } => {
+                    self.lost += lost;
+                    continue;
+                }
+                other => {
+                    self.session.update(&other);
+                    continue;
+                }
+            };
+            if let Some(index) = self.data.attr_index(sample.id) {
+                self.samples += 1;
+                self.table.add(&mut self.session, &sample, index);
+            }
+        }
+        self.data.data.clear();
+    }
+
+    /// Group by `sort` from now on, resolving source lines only
+    /// when they are the key.
+    pub fn set_sort(&mut self, sort: SortKey) {
+        self.session.set_srclines(sort == SortKey::Srcline);
+        self.table.set_sort(sort);
+    }
+
+    /// Spread the samples of the function `row` was last
+    /// sampled in over its instructions.
+    pub fn annotate(&mut self, row: &Row) -> Result<Annotation, AnnotateError> {
+        let hits = &self.table.hits;
+        let hits = row
+            .function
+            .as_ref()
+            .and_then(|function| hits.get(function))
+            .ok_or_else(|| AnnotateError::NoSamples(row.key.clone()))?;
+        annotate::annotate(&self.events, &mut self.session, hits, true, self.mode)
+    }
+
+    /// One line describing what is sampled, for above the table.
+    pub fn status(&self) -> String {
+        let zoom = match self.table.zoom() {
+            Some(Zoom::Dso(dso)) => format!(", dso {}", dso),
+            Some(Zoom::Thread(tid)) => format!(", thread {}", tid),
+            None => String::new(),
+        };
+        let lost = if self.lost > 0 {
+            format!(", {} lost", self.lost)
+        } else {
+            String::new()
+        };
+        format!(
+            "Samples: {} of event '{}'{}, sorted by {}{}",
+            self.samples,
+            self.events[self.table.event()],
+            lost,
+            self.table.sort().as_str(),
+            zoom
+        )
+    }
+}
+
+/// Every process running now.
+fn all_pids() -> Vec<u32> {
+    std::fs::read_dir("/proc")
+        .map(|dir| {
+            dir.filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// The threads of process `pid`.
+fn threads(pid: u32) -> Vec<u32> {
+    std::fs::read_dir(format!("/proc/{}/task", pid))
+        .map(|dir| {
+            dir.filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// The executable file mappings in the `/proc/<pid>/maps` of `pid`.
+fn parse_maps(pid: u32, maps: &str) -> Vec<Mmap> {
+    maps.lines()
+        .filter_map(|line| {
+            // start-end perms offset dev inode path
+            let mut fields = line.splitn(6, ' ');
+            let mut range = fields.next()?.splitn(2, '-');
+            let start = u64::from_str_radix(range.next()?, 16).ok()?;
+            let end = u64::from_str_radix(range.next()?, 16).ok()?;
+            let perms = fields.next()?;
+            let pgoff = u64::from_str_radix(fields.next()?, 16).ok()?;
+            let filename = fields.nth(2)?.trim_start();
+            if !perms.contains('x') || !filename.starts_with('/') {
+                return None;
+            }
+            Some(Mmap {
+                pid,
+                tid: pid,
+                start,
+                len: end - start,
+                pgoff,
+                filename: filename.to_string(),
+                cpumode: PERF_RECORD_MISC_USER as u16,
+            })
+        })
+        .collect()
+}
+
+/// Tell `session` the names and mappings of processes that were
+/// running before sampling started, as the kernel only reports new ones.
+fn synthesize(session: &mut Session, pids: &[u32]) {
+    for &pid in pids {
+        for tid in threads(pid) {
+            if let Ok(comm) = std::fs::read_to_string(format!("/proc/{}/task/{}/comm", pid, tid)) {
+                session.update(&Record::Comm(Comm {
+                    pid,
+                    tid,
+                    comm: comm.trim_end().to_string(),
+                    exec: false,
+                }));
+            }
+        }
+        if let Ok(maps) = std::fs::read_to_string(format!("/proc/{}/maps", pid)) {
+            for mmap in parse_maps(pid, &maps) {
+                session.update(&Record::Mmap(mmap));
+            }
+        }
+    }
+}
+
+/// Open `events` on the threads of `options.pid`, or on every CPU.
+fn open(options: &TopOptions, events: &[StatEvent]) -> Result<Sampler, RecordError> {
+    let cpus = online_cpus();
+    let targets: Vec<(i32, i32)> = match options.pid {
+        // Inherited events can only be mapped per CPU.
+        Some(pid) => threads(pid as u32)
+            .into_iter()
+            .flat_map(|tid| cpus.iter().map(move |&cpu| (tid as i32, cpu)))
+            .collect(),
+        None => cpus.into_iter().map(|cpu| (-1, cpu)).collect(),
+    };
+    let period = Period::Freq(max_sample_rate(options.freq));
+    Sampler::open(events, &targets, period, None, false, options.mmap_pages)
+}
+
+/// Print the table once, for `--stdio`.
+fn print_table(out: &mut dyn Write, top: &Top, rows: &[Row]) -> std::io::Result<()> {
+    writeln!(out, "{}", top.status())?;
+    writeln!(
+        out,
+        "{:>8}  {:>8}  {}",
+        "Overhead",
+        "Samples",
+        top.table.sort().header()
+    )?;
+    for row in rows.iter().take(STDIO_ROWS) {
+        writeln!(
+            out,
+            "{:>7.2}%  {:>8}  {}",
+            row.overhead, row.samples, row.key
+        )?;
+    }
+    writeln!(out)
+}
+
+/// Refresh the table every `delay` and print it, until
+/// `iterations` refreshes have been printed.
+fn run_stdio(top: &mut Top) -> std::io::Result<()> {
+    let out = &mut std::io::stdout();
+    let mut refreshes = 0;
+    while top.iterations.is_none_or(|n| refreshes < n) {
+        let deadline = Instant::now() + top.delay;
+        while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+            top.wait(left);
+            top.collect();
+        }
+        let rows = top.table.refresh();
+        print_table(out, top, &rows)?;
+        refreshes += 1;
+    }
+    Ok(())
+}
+
+/// Run top, falling back from cycles to cpu-clock when no event
+/// was asked for and the CPU has no cycle counter (e.g. in a VM).
+/// Returns the process exit code.
+pub fn run_top(options: TopOptions) -> i32 {
+    let mut events = options.event.clone();
+    let fallback = events.is_empty();
+    if fallback {
+        events.push(StatEvent::Cycles);
+    }
+    let mut opened = open(&options, &events);
+    if fallback && matches!(opened, Err(RecordError::Open(..))) {
+        eprintln!("cycles is not supported here, sampling cpu-clock instead");
+        events = vec![StatEvent::CpuClock];
+        opened = open(&options, &events);
+    }
+    let sampler = match opened {
+        Ok(sampler) => sampler,
+        Err(e) => {
+            eprintln!("{}", e);
+            if options.pid.is_none() {
+                eprintln!(
+                    "Sampling every CPU needs CAP_PERFMON or \
+                     /proc/sys/kernel/perf_event_paranoid at 0 or below. Try -p PID."
+                );
+            }
+            return 1;
+        }
+    };
+    if let Err(e) = sampler.enable() {
+        eprintln!("Could not start sampling: {}", e);
+        return 1;
+    }
+
+    let mode = options.demangle.mode();
+    let mut session = Session::new();
+    session.set_demangle(mode);
+    session.set_srclines(options.sort == SortKey::Srcline);
+    let pids = match options.pid {
+        Some(pid) => vec![pid as u32],
+        None => all_pids(),
+    };
+    synthesize(&mut session, &pids);
+
+    let data = PerfData {
+        attrs: sampler.attrs().to_vec(),
+        ..Default::default()
+    };
+    let mut top = Top {
+        sampler,
+        session,
+        data,
+        events: events.iter().map(|e| e.as_str().to_string()).collect(),
+        mode,
+        table: Table::new(options.sort, options.decay.clamp(0.0, 1.0), events.len()),
+        samples: 0,
+        lost: 0,
+        delay: Duration::from_secs_f64(options.delay.max(0.1)),
+        iterations: options.iterations,
+    };
+    let result = if options.stdio {
+        run_stdio(&mut top)
+    } else {
+        tui::run(&mut top)
+    };
+    match result {
+        Ok(()) => 0,
+        // Output is often piped into `head`.
+        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn parse_maps_test() {
+    let maps = "\
+55d0c2a00000-55d0c2a02000 r--p 00000000 fd:01 1234                       /usr/bin/cat
+55d0c2a02000-55d0c2a06000 r-xp 00002000 fd:01 1234                       /usr/bin/cat
+7f0000000000-7f0000021000 rw-p 00000000 00:00 0
+7f1000001000-7f1000002000 r-xp 00001000 fd:01 99                         /opt/my app/lib.so
+7ffd1e5f1000-7ffd1e5f3000 r-xp 00000000 00:00 0                          [vdso]
+";
+    let mmaps = parse_maps(42, maps);
+    assert_eq!(mmaps.len(), 2);
+    assert_eq!(mmaps[0].start, 0x55d0c2a02000);
+    assert_eq!(mmaps[0].len, 0x4000);
+    assert_eq!(mmaps[0].pgoff, 0x2000);
+    assert_eq!(mmaps[0].filename, "/usr/bin/cat");
+    assert_eq!(mmaps[0].pid, 42);
+    assert_eq!(mmaps[1].filename, "/opt/my app/lib.so");
+}
+
+#[test]
+fn table_test() {
+    let mut session = Session::new();
+    let mut table = Table::new(SortKey::Symbol, 0.25, 1);
+    let sample = |ip, tid| Sample {
+        ip,
+        pid: 1,
+        tid,
+        period: 100,
+        cpumode: PERF_RECORD_MISC_USER as u16,
+        ..Default::default()
+    };
+    for _ in 0..3 {
+        table.add(&mut session, &sample(0x1000, 1), 0);
+    }
+    table.add(&mut session, &sample(0x2000, 2), 0);
+
+    let rows = table.refresh();
+    assert_eq!(rows.len(), 2);
+    assert!(
+        rows[0].key.ends_with("0x0000000000001000"),
+        "{}",
+        rows[0].key
+    );
+    assert_eq!(rows[0].samples, 3);
+    assert_eq!(rows[0].overhead, 75.0);
+
+    // Old samples fade, so a new one outweighs them.
+    table.add(&mut session, &sample(0x2000, 2), 0);
+    let rows = table.refresh();
+    assert!(
+        rows[0].key.ends_with("0x0000000000002000"),
+        "{}",
+        rows[0].key
+    );
+    assert_eq!(rows[0].weight, 125.0);
+    assert_eq!(rows[0].samples, 1);
+    assert_eq!(rows[1].weight, 75.0);
+    assert_eq!(rows[1].samples, 0);
+
+    table.set_zoom(Some(Zoom::Thread(1)));
+    table.add(&mut session, &sample(0x1000, 1), 0);
+    table.add(&mut session, &sample(0x2000, 2), 0);
+    let rows = table.refresh();
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0].overhead, 100.0);
+
+    // Only functions of the rows shown keep their samples.
+    let function = |start| (Rc::from("/bin/app"), start);
+    let symbol = |start| crate::symbols::Symbol {
+        name: format!("f{}", start),
+        start,
+        size: 0x100,
+    };
+    for start in [0x1000, 0x2000] {
+        table.hits.insert(
+            function(start),
+            Hits::new(Rc::from("/bin/app"), symbol(start), 1),
+        );
+    }
+    table
+        .rows
+        .values_mut()
+        .for_each(|row| row.function = Some(function(0x1000)));
+    table.refresh();
+    assert!(table.hits.contains_key(&function(0x1000)));
+    assert!(!table.hits.contains_key(&function(0x2000)));
+}
@@ -0,0 +1,242 @@
+//! Terminal UI for `ruperf top`: the table, redrawn at every
+//! refresh, and a scrollable annotation of the selected function.
+
+use super::{Row, Top, Zoom};
+use crate::annotate::print_annotation;
+use crate::report::SortKey;
+use crossterm::cursor::{Hide, MoveTo, Show};
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::style::{Attribute, Print, SetAttribute};
+use crossterm::terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::{execute, queue};
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+/// How long to wait for a key before reading the ring buffers again.
+const KEY_WAIT: Duration = Duration::from_millis(50);
+
+/// Lines above the rows: status, help and column headers.
+const HEADER_LINES: usize = 3;
+
+const HELP: &str = "up/down select  s sort  d zoom dso  t zoom thread  \
+                    e event  a annotate  q quit";
+
+const ANNOTATION_HELP: &str = "up/down/pgup/pgdn scroll  q/esc back";
+
+/// The order `s` goes through the sort keys in.
+const SORT_KEYS: [SortKey; 5] = [
+    SortKey::Symbol,
+    SortKey::Dso,
+    SortKey::Comm,
+    SortKey::Pid,
+    SortKey::Srcline,
+];
+
+/// What is on screen.
+#[derive(Default)]
+struct View {
+    rows: Vec<Row>,
+    selected: usize,
+    /// Lines of the annotation being shown, and the first one on screen.
+    annotation: Option<(Vec<String>, usize)>,
+    /// Shown instead of the help line until the next key.
+    message: Option<String>,
+}
+
+/// What a key asks for.
+enum Action {
+    None,
+    Redraw,
+    Quit,
+}
+
+/// Show the table until the user quits or `top.iterations` refreshes
+/// have passed, restoring the terminal either way.
+pub fn run(top: &mut Top) -> io::Result<()> {
+    let mut out = io::stdout();
+    terminal::enable_raw_mode()?;
+    execute!(out, EnterAlternateScreen, Hide)?;
+    let result = event_loop(top, &mut out);
+    execute!(out, Show, LeaveAlternateScreen)?;
+    terminal::disable_raw_mode()?;
+    result
+}
+
+fn event_loop(top: &mut Top, out: &mut impl Write) -> io::Result<()> {
+    let mut view = View::default();
+    let mut refreshes = 0;
+    let mut next = Instant::now() + top.delay;
+    draw(out, top, &view)?;
+    loop {
+        if event::poll(KEY_WAIT)? {
+            match event::read()? {
+                Event::Key(key) if key.kind != KeyEventKind::Release => {
+                    match handle_key(top, &mut view, key) {
+                        Action::Quit => return Ok(()),
+                        Action::Redraw => draw(out, top, &view)?,
+                        Action::None => {}
+                    }
+                }
+                Event::Resize(..) => draw(out, top, &view)?,
+                _ => {}
+            }
+        }
+        top.collect();
+        if Instant::now() >= next {
+            next += top.delay;
+            view.rows = top.table.refresh();
+            view.selected = view.selected.min(view.rows.len().saturating_sub(1));
+            refreshes += 1;
+            if top.iterations.is_some_and(|n| refreshes >= n) {
+                return Ok(());
+            }
+            draw(out, top, &view)?;
+        }
+    }
+}
+
+fn handle_key(top: &mut Top, view: &mut View, key: KeyEvent) -> Action {
+    let page = terminal::size().map_or(20, |(_, h)| (h as usize).saturating_sub(HEADER_LINES));
+    view.message = None;
+    if key.modifiers.contains(KeyModifiers::CONTROL) && key.code == KeyCode::Char('c') {
+        return Action::Quit;
+    }
+    if let Some((lines, first)) = &mut view.annotation {
+        let last = lines.len().saturating_sub(1);
+        match key.code {
+            KeyCode::Esc | KeyCode::Char('q') | KeyCode::Left => view.annotation = None,
+            KeyCode::Up | KeyCode::Char('k') => *first = first.saturating_sub(1),
+            KeyCode::Down | KeyCode::Char('j') => *first = (*first + 1).min(last),
+            KeyCode::PageUp => *first = first.saturating_sub(page),
+            KeyCode::PageDown | KeyCode::Char(' ') => *first = (*first + page).min(last),
+            KeyCode::Home => *first = 0,
+            _ => return Action::None,
+        }
+        return Action::Redraw;
+    }
+
+    let last = view.rows.len().saturating_sub(1);
+    let selected = view.rows.get(view.selected).cloned();
+    match key.code {
+        KeyCode::Esc | KeyCode::Char('q') => return Action::Quit,
+        KeyCode::Up | KeyCode::Char('k') => view.selected = view.selected.saturating_sub(1),
+        KeyCode::Down | KeyCode::Char('j') => view.selected = (view.selected + 1).min(last),
+        KeyCode::PageUp => view.selected = view.selected.saturating_sub(page),
+        KeyCode::PageDown => view.selected = (view.selected + page).min(last),
+        KeyCode::Home => view.selected = 0,
+        KeyCode::Char('s') => {
+            let at = SORT_KEYS.iter().position(|k| *k == top.table.sort());
+            let next = SORT_KEYS[at.map_or(0, |i| (i + 1) % SORT_KEYS.len())];
+            top.set_sort(next);
+            view.message = Some(format!(
+                "Sorting by {} from the next refresh",
+                next.as_str()
+            ));
+        }
+        KeyCode::Char('d') => {
+            let zoom = match (top.table.zoom(), selected.and_then(|row| row.dso)) {
+                (Some(Zoom::Dso(_)), _) => None,
+                (_, Some(dso)) => Some(Zoom::Dso(dso)),
+                (zoom, None) => {
+                    view.message = Some("No shared object to zoom into".to_string());
+                    zoom.cloned()
+                }
+            };
+            top.table.set_zoom(zoom);
+        }
+        KeyCode::Char('t') => {
+            let zoom = match (top.table.zoom(), selected) {
+                (Some(Zoom::Thread(_)), _) => None,
+                (_, Some(row)) => Some(Zoom::Thread(row.tid)),
+                (zoom, None) => zoom.cloned(),
+            };
+            top.table.set_zoom(zoom);
+        }
+        KeyCode::Char('e') => top.table.next_event(),
+        KeyCode::Char('a') | KeyCode::Enter | KeyCode::Right => {
+            let row = match selected {
+                Some(row) => row,
+                None => return Action::None,
+            };
+            match top.annotate(&row) {
+                Ok(annotation) => {
+                    let mut text = Vec::new();
+                    print_annotation(&mut text, &annotation).unwrap();
+                    let lines = String::from_utf8_lossy(&text)
+                        .lines()
+                        .map(str::to_string)
+                        .collect();
+                    view.annotation = Some((lines, 0));
+                }
+                Err(e) => view.message = Some(e.to_string()),
+            }
+        }
+        _ => return Action::None,
+    }
+    Action::Redraw
+}
+
+/// Print `line` on row `y`, cut to `width` and clearing what was there.
+fn line(out: &mut impl Write, y: usize, width: usize, text: &str) -> io::Result<()> {
+    let text: String = text.chars().take(width).collect();
+    queue!(
+        out,
+        MoveTo(0, y as u16),
+        Print(text),
+        Clear(ClearType::UntilNewLine)
+    )
+}
+
+fn draw(out: &mut impl Write, top: &Top, view: &View) -> io::Result<()> {
+    let (width, height) = terminal::size()?;
+    let (width, height) = (width as usize, height as usize);
+    let help = view.message.as_deref();
+    if let Some((lines, first)) = &view.annotation {
+        line(out, 0, width, help.unwrap_or(ANNOTATION_HELP))?;
+        let mut shown = lines.iter().skip(*first);
+        for y in 1..height {
+            line(out, y, width, shown.next().map_or("", String::as_str))?;
+        }
+        return out.flush();
+    }
+
+    line(out, 0, width, &top.status())?;
+    line(out, 1, width, help.unwrap_or(HELP))?;
+    queue!(out, SetAttribute(Attribute::Bold))?;
+    line(
+        out,
+        2,
+        width,
+        &format!(
+            "{:>8}  {:>8}  {}",
+            "Overhead",
+            "Samples",
+            top.table.sort().header()
+        ),
+    )?;
+    queue!(out, SetAttribute(Attribute::Reset))?;
+
+    // Keep the selection on screen.
+    let rows = height.saturating_sub(HEADER_LINES);
+    let first = (view.selected + 1).saturating_sub(rows);
+    for y in 0..rows {
+        let i = first + y;
+        let text = match view.rows.get(i) {
+            Some(row) => format!("{:>7.2}%  {:>8}  {}", row.overhead, row.samples, row.key),
+            None => String::new(),
+        };
+        if i == view.selected && !view.rows.is_empty() {
+            queue!(out, SetAttribute(Attribute::Reverse))?;
+            line(
+                out,
+                HEADER_LINES + y,
+                width,
+                &format!("{:<1$}", text, width),
+            )?;
+            queue!(out, SetAttribute(Attribute::Reset))?;
+        } else {
+            line(out, HEADER_LINES + y, width, &text)?;
+        }
+    }
+    out.flush()
+}
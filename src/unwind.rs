@@ -0,0 +1,427 @@
+//! # Unwind.
+//! Walks a user stack copied with a sample (`PERF_SAMPLE_STACK_USER`),
+//! starting from the registers sampled with it (`PERF_SAMPLE_REGS_USER`),
+//! using the `.eh_frame` or `.debug_frame` call frame information of the
+//! mapped files. Unlike frame pointers, this works for code built
+//! without them. Only x86_64 is supported.
+
+use gimli::{
+    BaseAddresses, CfaRule, DebugFrame, EhFrame, EndianSlice, LittleEndian, RegisterRule,
+    UnwindContext, UnwindSection,
+};
+use object::{Object, ObjectSection};
+use std::convert::TryInto;
+use std::rc::Rc;
+
+type Slice<'a> = EndianSlice<'a, LittleEndian>;
+
+/// DWARF register numbers are below this. The return address
+/// column, 16, holds the instruction pointer.
+const DWARF_REGISTERS: usize = 17;
+const DWARF_SP: usize = 7;
+const DWARF_RA: usize = 16;
+
+/// Registers to ask the kernel for: `PERF_REG_X86_*` up to `R15`,
+/// without the segment registers x86_64 does not sample.
+pub const PERF_REGS_MASK: u64 = 0xff_0fff;
+
+/// The DWARF number of each `PERF_REG_X86_*` register, in order.
+const PERF_TO_DWARF: [Option<usize>; 24] = [
+    Some(0),  // AX
+    Some(3),  // BX
+    Some(2),  // CX
+    Some(1),  // DX
+    Some(4),  // SI
+    Some(5),  // DI
+    Some(6),  // BP
+    Some(7),  // SP
+    Some(16), // IP
+    None,     // FLAGS
+    None,     // CS
+    None,     // SS
+    None,     // DS
+    None,     // ES
+    None,     // FS
+    None,     // GS
+    Some(8),
+    Some(9),
+    Some(10),
+    Some(11),
+    Some(12),
+    Some(13),
+    Some(14),
+    Some(15), // R15
+];
+
+/// A register file, indexed by DWARF register number.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Registers([Option<u64>; DWARF_REGISTERS]);
+
+impl Registers {
+    /// Registers from a sample, given the `PERF_REG_X86_*`
+    /// index and value of each one.
+    pub fn from_perf<I: IntoIterator<Item = (u32, u64)>>(regs: I) -> Self {
+        let mut registers = Self::default();
+        for (index, value) in regs {
+            if let Some(Some(dwarf)) = PERF_TO_DWARF.get(index as usize) {
+                registers.0[*dwarf] = Some(value);
+            }
+        }
+        registers
+    }
+
+    pub fn get(&self, register: u16) -> Option<u64> {
+        self.0.get(register as usize).copied().flatten()
+    }
+
+    pub fn set(&mut self, register: u16, value: Option<u64>) {
+        if let Some(slot) = self.0.get_mut(register as usize) {
+            *slot = value;
+        }
+    }
+
+    /// The instruction pointer.
+    pub fn ip(&self) -> Option<u64> {
+        self.0[DWARF_RA]
+    }
+
+    /// The stack pointer.
+    pub fn sp(&self) -> Option<u64> {
+        self.0[DWARF_SP]
+    }
+}
+
+/// A copy of the stack starting at address `sp`.
+#[derive(Debug, Clone, Copy)]
+pub struct Stack<'a> {
+    pub sp: u64,
+    pub bytes: &'a [u8],
+}
+
+impl<'a> Stack<'a> {
+    /// The word at `address`, if it was copied.
+    fn read(&self, address: u64) -> Option<u64> {
+        let offset = address.checked_sub(self.sp)? as usize;
+        let word = self.bytes.get(offset..offset.checked_add(8)?)?;
+        Some(u64::from_le_bytes(word.try_into().unwrap()))
+    }
+}
+
+/// Where an FDE lives.
+#[derive(Debug, Clone, Copy)]
+struct Fde {
+    start: u64,
+    end: u64,
+    offset: usize,
+    /// In `.debug_frame` rather than `.eh_frame`.
+    debug: bool,
+}
+
+/// How to find a register's value in the caller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Rule {
+    Undefined,
+    SameValue,
+    /// Saved at CFA + n.
+    Offset(i64),
+    /// Is CFA + n.
+    ValOffset(i64),
+    /// Is in another register.
+    Register(u16),
+}
+
+/// What the call frame information says at one address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Row {
+    /// The CFA is this register plus an offset.
+    cfa: (u16, i64),
+    /// Rules that differ from `SameValue`.
+    rules: Vec<(u16, Rule)>,
+}
+
+impl Row {
+    fn rule(&self, register: u16) -> Rule {
+        self.rules
+            .iter()
+            .find(|(r, _)| *r == register)
+            .map_or(Rule::SameValue, |(_, rule)| *rule)
+    }
+}
+
+/// The call frame information of one ELF file.
+#[derive(Debug, Default)]
+pub struct UnwindInfo {
+    eh_frame: Vec<u8>,
+    eh_frame_address: u64,
+    debug_frame: Vec<u8>,
+    text_address: u64,
+    /// Sorted by `start`.
+    fdes: Vec<Fde>,
+}
+
+impl UnwindInfo {
+    /// Read the call frame information of the ELF file at `path`.
+    pub fn load(path: &str) -> Option<Self> {
+        let data = std::fs::read(path).ok()?;
+        let file = object::File::parse(&*data).ok()?;
+        let section = |name| {
+            file.section_by_name(name)
+                .and_then(|s| Some((s.data().ok()?.to_vec(), s.address())))
+                .unwrap_or_default()
+        };
+        let (eh_frame, eh_frame_address) = section(".eh_frame");
+        let (debug_frame, _) = section(".debug_frame");
+        let text_address = file.section_by_name(".text").map_or(0, |s| s.address());
+        Some(Self::new(
+            eh_frame,
+            eh_frame_address,
+            debug_frame,
+            text_address,
+        ))
+    }
+
+    /// Index the FDEs in `.eh_frame`, loaded at `eh_frame_address`,
+    /// and `.debug_frame`.
+    pub fn new(
+        eh_frame: Vec<u8>,
+        eh_frame_address: u64,
+        debug_frame: Vec<u8>,
+        text_address: u64,
+    ) -> Self {
+        let mut info = Self {
+            eh_frame,
+            eh_frame_address,
+            debug_frame,
+            text_address,
+            fdes: Vec::new(),
+        };
+        let bases = info.bases();
+        let mut fdes = Vec::new();
+        index(&info.eh_frame(), &bases, false, &mut fdes);
+        index(&info.debug_frame(), &bases, true, &mut fdes);
+        fdes.sort_by_key(|fde| fde.start);
+        info.fdes = fdes;
+        info
+    }
+
+    fn bases(&self) -> BaseAddresses {
+        BaseAddresses::default()
+            .set_eh_frame(self.eh_frame_address)
+            .set_text(self.text_address)
+    }
+
+    fn eh_frame(&self) -> EhFrame<Slice<'_>> {
+        EhFrame::new(&self.eh_frame, LittleEndian)
+    }
+
+    fn debug_frame(&self) -> DebugFrame<Slice<'_>> {
+        let mut section = DebugFrame::new(&self.debug_frame, LittleEndian);
+        section.set_address_size(8);
+        section
+    }
+
+    /// The rules at `address`, as the file sees it.
+    fn row(&self, address: u64) -> Option<Row> {
+        let index = self
+            .fdes
+            .partition_point(|fde| fde.start <= address)
+            .checked_sub(1)?;
+        let fde = self.fdes[index];
+        if address >= fde.end {
+            return None;
+        }
+        let bases = self.bases();
+        if fde.debug {
+            row(&self.debug_frame(), &bases, fde.offset, address)
+        } else {
+            row(&self.eh_frame(), &bases, fde.offset, address)
+        }
+    }
+}
+
+/// Add every FDE in `section` to `fdes`.
+fn index<'a, S: UnwindSection<Slice<'a>>>(
+    section: &S,
+    bases: &BaseAddresses,
+    debug: bool,
+    fdes: &mut Vec<Fde>,
+) {
+    let mut entries = section.entries(bases);
+    while let Ok(Some(entry)) = entries.next() {
+        if let gimli::CieOrFde::Fde(partial) = entry {
+            if let Ok(fde) = partial.parse(S::cie_from_offset) {
+                fdes.push(Fde {
+                    start: fde.initial_address(),
+                    end: fde.initial_address() + fde.len(),
+                    offset: fde.offset(),
+                    debug,
+                });
+            }
+        }
+    }
+}
+
+/// Evaluate the FDE at `offset` in `section` up to `address`.
+fn row<'a, S: UnwindSection<Slice<'a>>>(
+    section: &S,
+    bases: &BaseAddresses,
+    offset: usize,
+    address: u64,
+) -> Option<Row> {
+    let fde = section
+        .fde_from_offset(bases, S::Offset::from(offset), S::cie_from_offset)
+        .ok()?;
+    let mut context = UnwindContext::new();
+    let row = fde
+        .unwind_info_for_address(section, bases, &mut context, address)
+        .ok()?;
+    let cfa = match row.cfa() {
+        CfaRule::RegisterAndOffset { register, offset } => (register.0, *offset),
+        // DWARF expressions, as in PLT entries, are not supported.
+        CfaRule::Expression(_) => return None,
+    };
+    let mut rules: Vec<(u16, Rule)> = row
+        .registers()
+        .map(|(register, rule)| {
+            let rule = match rule {
+                RegisterRule::SameValue => Rule::SameValue,
+                RegisterRule::Offset(n) => Rule::Offset(*n),
+                RegisterRule::ValOffset(n) => Rule::ValOffset(*n),
+                RegisterRule::Register(r) => Rule::Register(r.0),
+                _ => Rule::Undefined,
+            };
+            (register.0, rule)
+        })
+        .collect();
+    // gimli leaves out undefined registers. An undefined return
+    // address, as in `_start`, marks the outermost frame.
+    let ra = fde.cie().return_address_register().0;
+    if rules.iter().all(|(register, _)| *register != ra) {
+        rules.push((ra, Rule::Undefined));
+    }
+    Some(Row { cfa, rules })
+}
+
+/// Unwind `stack` from `registers`, returning the instruction pointer
+/// of each frame, innermost first, at most `max_frames` of them.
+/// `lookup` gives the call frame information covering an address and
+/// the address as that file sees it; unwinding stops at addresses
+/// it knows nothing of, or when the stack runs out.
+pub fn unwind<F>(registers: Registers, stack: Stack, mut lookup: F, max_frames: usize) -> Vec<u64>
+where
+    F: FnMut(u64) -> Option<(Rc<UnwindInfo>, u64)>,
+{
+    let mut registers = registers;
+    let mut ips = Vec::new();
+    while let Some(ip) = registers.ip() {
+        if ip == 0 || ips.len() >= max_frames {
+            break;
+        }
+        ips.push(ip);
+        // Callers are at return addresses, which may be past the call's function.
+        let pc = if ips.len() > 1 { ip - 1 } else { ip };
+        let row = match lookup(pc).and_then(|(info, address)| info.row(address)) {
+            Some(row) => row,
+            None => break,
+        };
+        let cfa = match registers.get(row.cfa.0) {
+            Some(base) => (base as i64).wrapping_add(row.cfa.1) as u64,
+            None => break,
+        };
+        let mut caller = registers;
+        for register in 0..DWARF_REGISTERS as u16 {
+            let value = match row.rule(register) {
+                Rule::Undefined => None,
+                Rule::SameValue => registers.get(register),
+                Rule::Offset(n) => stack.read((cfa as i64).wrapping_add(n) as u64),
+                Rule::ValOffset(n) => Some((cfa as i64).wrapping_add(n) as u64),
+                Rule::Register(r) => registers.get(r),
+            };
+            caller.set(register, value);
+        }
+        caller.set(DWARF_SP as u16, Some(cfa));
+        // The stack only grows down; anything else is a corrupt frame.
+        if cfa <= registers.sp().unwrap_or(0) {
+            break;
+        }
+        registers = caller;
+    }
+    ips
+}
+
+#[cfg(test)]
+#[test]
+fn unwind_test() {
+    // A .debug_frame with one CIE (CFA = rsp + 8, return address at
+    // CFA - 8) and two FDEs: `leaf` at 0x1000 pushes rbp and uses it
+    // as the frame pointer, `caller` at 0x2000 only moves rsp.
+    #[rustfmt::skip]
+    let debug_frame: Vec<u8> = [
+        // CIE: length, id, version 1, no augmentation, code and data
+        // alignment 1 and -8, return address register 16.
+        &[20, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 1, 0, 1, 0x78, 16][..],
+        // def_cfa rsp+8; offset r16 at cfa-8; padding.
+        &[0x0c, 7, 8, 0x90, 1, 0, 0, 0, 0, 0, 0],
+        // leaf: length, CIE at 0, start, range.
+        &[32, 0, 0, 0, 0, 0, 0, 0],
+        &0x1000u64.to_le_bytes(), &0x100u64.to_le_bytes(),
+        // advance 1; def_cfa_offset 16; offset r6 at cfa-16;
+        // advance 3; def_cfa_register rbp; padding.
+        &[0x41, 0x0e, 16, 0x86, 2, 0x43, 0x0d, 6, 0, 0, 0, 0],
+        // caller.
+        &[24, 0, 0, 0, 0, 0, 0, 0],
+        &0x2000u64.to_le_bytes(), &0x100u64.to_le_bytes(),
+        // advance 4; def_cfa_offset 32.
+        &[0x44, 0x0e, 32, 0],
+    ]
+    .concat();
+    let info = Rc::new(UnwindInfo::new(Vec::new(), 0, debug_frame, 0));
+
+    // leaf's frame: saved rbp and the return address into caller,
+    // then caller's 24 bytes of locals and its return address.
+    let mut bytes = vec![0u8; 0x40];
+    let mut put = |address: u64, value: u64| {
+        let offset = (address - 0x7000) as usize;
+        bytes[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    };
+    put(0x7010, 0x7abc);
+    put(0x7018, 0x2050);
+    put(0x7038, 0x3000);
+    let stack = Stack {
+        sp: 0x7000,
+        bytes: &bytes,
+    };
+    // Deep in leaf, after its prologue.
+    let registers = Registers::from_perf(vec![(8, 0x1010), (7, 0x7000), (6, 0x7010)]);
+    assert_eq!(
+        (registers.ip(), registers.sp()),
+        (Some(0x1010), Some(0x7000))
+    );
+
+    let lookup = |address: u64| Some((info.clone(), address)).filter(|_| address < 0x3000);
+    assert_eq!(
+        unwind(registers, stack, lookup, 10),
+        vec![0x1010, 0x2050, 0x3000]
+    );
+    assert_eq!(unwind(registers, stack, lookup, 2), vec![0x1010, 0x2050]);
+
+    // Without the return address on the stack, unwinding stops.
+    let short = Stack {
+        sp: 0x7000,
+        bytes: &bytes[..0x18],
+    };
+    assert_eq!(unwind(registers, short, lookup, 10), vec![0x1010]);
+}
+
+#[test]
+fn outermost_frame_test() {
+    use crate::symbols::Dso;
+
+    // `_start` says its return address is undefined.
+    let exe = std::env::current_exe().unwrap();
+    let exe = exe.to_str().unwrap();
+    let dso = Dso::load(exe).unwrap();
+    let start = dso.symbols().iter().find(|s| s.name == "_start").unwrap();
+    let row = UnwindInfo::load(exe).unwrap().row(start.start).unwrap();
+    assert!(row.rules.iter().any(|(_, rule)| *rule == Rule::Undefined));
+}
@@ -1,4 +1,5 @@
-//! Errors for `ruperf stat`.
+//! Errors for `ruperf stat`, `ruperf record`, `ruperf report`, `ruperf annotate`,
+//! `ruperf script` and the library interface.
 use thiserror::Error;
 
 /// Parse errors for CLI
@@ -6,4 +7,69 @@ use thiserror::Error;
 pub enum ParseError {
     #[error("Invalid Event")]
     InvalidEvent,
+    #[error("Invalid assertion '{0}', expected e.g. 'instructions<2.5e9'")]
+    InvalidAssertion(String),
+    #[error("Could not load budget file {0}: {1}")]
+    BudgetFile(String, String),
+    #[error("Could not load stat results {0}: {1}")]
+    StatReport(String, String),
+    #[error("Invalid control '{0}', expected e.g. 'fifo:ctl,ack' or 'enable'")]
+    InvalidControl(String),
+    #[error("Invalid delay '{0}', expected milliseconds or -1")]
+    InvalidDelay(String),
+    #[error("Invalid interval '{0}', expected milliseconds above 0")]
+    InvalidInterval(String),
+    #[error("Invalid number of pages '{0}', expected a power of two")]
+    InvalidMmapPages(String),
+    #[error("Invalid sort key '{0}', expected comm, pid, dso, symbol or srcline")]
+    InvalidSortKey(String),
+    #[error("Invalid call graph order '{0}', expected callee or caller")]
+    InvalidCallOrder(String),
+    #[error("Invalid call graph '{0}', expected fp or dwarf[,size]")]
+    InvalidCallGraph(String),
+    #[error("Invalid flame graph colours '{0}', expected kernel or dso")]
+    InvalidFlameColor(String),
+    #[error("Invalid weights '{0}', expected e.g. '1,2'")]
+    InvalidWeights(String),
+    #[error(
+        "Invalid field '{0}', expected comm, pid, tid, cpu, time, period, event, ip, sym, symoff, dso or srcline"
+    )]
+    InvalidField(String),
+}
+
+/// Errors from measuring code with `Counters`
+#[derive(Error, Debug)]
+pub enum CounterError {
+    #[error("Unknown event '{0}'")]
+    InvalidEvent(String),
+    #[error("Could not open a counter for {0}")]
+    Open(String),
+    #[error("Could not read the counter for {0}")]
+    Read(String),
+}
+
+/// Errors from `ruperf record`
+#[derive(Error, Debug)]
+pub enum RecordError {
+    #[error("Could not open {0} on CPU {1}: {2}")]
+    Open(String, i32, std::io::Error),
+    #[error("Ring buffers need a power of two pages, not {0}")]
+    MmapPages(usize),
+    #[error("Could not map a ring buffer with {0} pages: {1}")]
+    Mmap(usize, std::io::Error),
+    #[error("Could not write {0}: {1}")]
+    Output(String, std::io::Error),
+}
+
+/// Errors from `ruperf annotate`
+#[derive(Error, Debug)]
+pub enum AnnotateError {
+    #[error("No samples in {0}")]
+    NoSamples(String),
+    #[error("Could not read the code of {0} from {1}")]
+    Code(String, String),
+    #[error("Cannot disassemble {0:?} code")]
+    Architecture(object::Architecture),
+    #[error("Could not disassemble {0}: {1}")]
+    Disassemble(String, String),
 }